@@ -0,0 +1,193 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! End-to-end benchmark: snapshot and restore a synthetic directory tree.
+
+use blob_store::{BlobStoreBackend};
+use chunker::{ChunkerOptions};
+use fingerprint;
+use hat::{Hat, FamilySettings, SnapshotOptions, CheckoutOptions, DEFAULT_CHUNK_SIZE};
+use key_store::{ByteCounts};
+
+use std::io::{File, UserDir};
+use std::io::fs::{mkdir_recursive};
+use std::rand::{Rng, task_rng};
+use time;
+
+
+/// The number of generated files per directory.
+static FILES_PER_DIR: uint = 100;
+
+
+/// The shape of the generated tree.
+pub struct BenchOptions {
+  /// The number of files to generate.
+  pub files: uint,
+
+  /// The size of each file in bytes.
+  pub file_size: uint,
+
+  /// The percentage of files that are copies of an earlier file.
+  pub duplication: uint,
+
+  /// The blob size of the repository.
+  pub max_blob_size: uint,
+}
+
+impl BenchOptions {
+  pub fn new() -> BenchOptions {
+    BenchOptions{files: 1000, file_size: 64 * 1024, duplication: 20,
+                 max_blob_size: 4 * 1024 * 1024}
+  }
+}
+
+
+/// The time taken by one stage of the benchmark.
+pub struct Stage {
+  pub name: &'static str,
+  pub bytes: u64,
+  pub nanoseconds: u64,
+}
+
+impl Stage {
+  /// Throughput in MiB per second.
+  pub fn throughput(&self) -> f64 {
+    if self.nanoseconds == 0 { return 0.0 }
+    (self.bytes as f64 / (1024.0 * 1024.0)) / (self.nanoseconds as f64 / 1e9)
+  }
+}
+
+/// Time `f`, which returns the number of bytes it processed.
+fn timed(name: &'static str, f: || -> u64) -> Stage {
+  let started = time::precise_time_ns();
+  let bytes = f();
+  Stage{name: name, bytes: bytes, nanoseconds: time::precise_time_ns() - started}
+}
+
+fn subdir(dir: &Path, name: &str) -> Path {
+  let mut path = dir.clone();
+  path.push(name);
+  path
+}
+
+/// Fill `dir` with `options.files` files. Returns the total number of bytes written.
+fn generate_tree(dir: &Path, options: &BenchOptions) -> u64 {
+  let mut rng = task_rng();
+  let mut originals: Vec<Vec<u8>> = Vec::new();
+  let mut total = 0u64;
+
+  for i in range(0, options.files) {
+    let mut path = subdir(dir, format!("d{:04u}", i / FILES_PER_DIR).as_slice());
+    if i % FILES_PER_DIR == 0 {
+      mkdir_recursive(&path, UserDir).unwrap();
+    }
+    path.push(format!("f{:06u}", i));
+
+    let duplicate = originals.len() > 0 && rng.gen_range(0u, 100) < options.duplication;
+    let data = if duplicate {
+      originals[rng.gen_range(0, originals.len())].clone()
+    } else {
+      let mut data = Vec::from_elem(options.file_size, 0u8);
+      rng.fill_bytes(data.as_mut_slice());
+      originals.push(data.clone());
+      data
+    };
+
+    File::create(&path).and_then(|mut f| f.write(data.as_slice())).unwrap();
+    total += data.len() as u64;
+  }
+  total
+}
+
+/// Generate a tree in `scratch_dir`, then snapshot it into a new repository that stores its
+/// blobs in `backend`, restore it, and check that the restored tree matches.
+/// Returns the time taken by each stage, and how many bytes were deduplicated.
+pub fn run<B: BlobStoreBackend + Clone + Send>(scratch_dir: &Path, backend: B,
+                                               options: &BenchOptions,
+                                               settings: FamilySettings)
+                                               -> Result<(Vec<Stage>, ByteCounts), String> {
+  let source = subdir(scratch_dir, "source");
+  let restore = subdir(scratch_dir, "restore");
+  let repository_root = subdir(scratch_dir, "repo");
+  for dir in [&source, &restore, &repository_root].iter() {
+    mkdir_recursive(*dir, UserDir).unwrap();
+  }
+
+  let mut stages = Vec::new();
+  stages.push(timed("generate", || generate_tree(&source, options)));
+  let bytes = stages[0].bytes;
+
+  let hat = match Hat::open_repository(&repository_root, backend, options.max_blob_size,
+                                       None, None, None) {
+    Ok(hat) => hat,
+    Err(e) => return Err(format!("Could not open repository in {}: {}",
+                                 repository_root.display(), e)),
+  };
+  let family = match hat.open_family_with_settings("bench".to_string(), settings) {
+    Some(family) => family,
+    None => return Err("Could not open family 'bench'.".to_string()),
+  };
+
+  stages.push(timed("snapshot", || {
+    family.snapshot_dir(source.clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+    bytes
+  }));
+  stages.push(timed("checkout", || {
+    family.checkout_in_dir(&mut restore.clone(), None, &CheckoutOptions::new()).unwrap();
+    bytes
+  }));
+
+  // Both trees are read in full:
+  let mut matches = false;
+  stages.push(timed("verify", || {
+    let chunker = ChunkerOptions::new(DEFAULT_CHUNK_SIZE);
+    matches = fingerprint::fingerprint_of_dir(&source, &chunker).ok() ==
+      fingerprint::fingerprint_of_dir(&restore, &chunker).ok();
+    2 * bytes
+  }));
+  if !matches {
+    return Err("The restored tree differs from the generated tree.".to_string());
+  }
+  Ok((stages, family.byte_counts()))
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use blob_store::{MemoryBackend};
+  use hat::{FamilySettings};
+
+  use std::io::{TempDir};
+
+  #[test]
+  fn small_bench() {
+    let dir = TempDir::new("hat-bench").unwrap();
+    let mut options = BenchOptions::new();
+    options.files = 30;
+    options.file_size = 1000;
+    // All files but the first are copies of it:
+    options.duplication = 100;
+
+    let (stages, counts) = run(dir.path(), MemoryBackend::new(), &options, FamilySettings::new())
+      .unwrap();
+    let names: Vec<&'static str> = stages.iter().map(|stage| stage.name).collect();
+    assert_eq!(names, vec!["generate", "snapshot", "checkout", "verify"]);
+    assert_eq!(stages[0].bytes, 30000);
+    assert_eq!(counts.new_bytes + counts.dedup_bytes, 30000);
+    assert!(counts.dedup_bytes > 0);
+  }
+}
@@ -17,8 +17,10 @@
 use sodiumoxide::randombytes::{randombytes};
 
 use std::collections::hashmap::{HashMap};
+use std::collections::treemap::{TreeMap};
 use serialize::hex::{ToHex};
 
+use keys::{unlock_database};
 use process::{Process, MsgHandler};
 use sqlite3::database::{Database};
 
@@ -29,7 +31,7 @@ use sqlite3::{open};
 
 pub type BlobIndexProcess = Process<Msg, Reply, BlobIndex>;
 
-#[deriving(Clone, Show)]
+#[deriving(Clone, Show, Eq, PartialEq)]
 pub struct BlobDesc {
   pub name: Vec<u8>,
   pub id: i64,
@@ -41,44 +43,214 @@ pub enum Msg {
 
   /// Report that this blob is in the process of being committed to persistent storage. If a
   /// blob is in this state when the system starts up, it may or may not exist in the persistent
-  /// storage, but **should not** be referenced elsewhere, and is therefore safe to delete.
-  InAir(BlobDesc),
+  /// storage, but **should not** be referenced elsewhere, and is therefore safe to delete (or to
+  /// resume uploading, see `ListInAir`). Includes the SHA-256 checksum of the blob's data.
+  InAir(BlobDesc, Vec<u8>),
 
   /// Report that this blob has been fully committed to persistent storage. We can now use its
   /// reference internally. Only committed blobs are considered "safe to use".
+  ///
+  /// A committed blob is not yet "indexed": the hash index entries that reference it may still be
+  /// lost in a crash, which would leave the blob unreferenced.
   CommitDone(BlobDesc),
+
+  /// List the blobs that are committed, but not yet known to be indexed.
+  /// Returns `UnindexedBlobs`.
+  ListUnindexed,
+
+  /// Report that all hash index entries referencing these blobs have been durably committed.
+  /// Returns `CommitOK`.
+  Indexed(Vec<BlobDesc>),
+
+  /// Forget an in-air blob whose upload cannot be resumed. Nothing references it.
+  /// Returns `CommitOK`.
+  RollBack(BlobDesc),
+
+  /// List the names and recorded checksums of all committed blobs (blobs from before checksums
+  /// were recorded are left out).
+  /// Returns `BlobChecksums`.
+  ListChecksums,
+
+  /// Look up the checksum recorded for a blob, to verify its data when it is read back.
+  /// Returns `Checksum` (`None` for blobs from before checksums were recorded).
+  FetchChecksum(Vec<u8>),
+
+  /// Look up a repository-wide setting (see `StoreSetting`).
+  /// Returns `Setting`.
+  FetchSetting(String),
+
+  /// Record a repository-wide setting, like the maintenance schedule.
+  /// Returns `CommitOK`.
+  StoreSetting(String, i64),
+
+  /// Report that these committed blobs are not referenced by the hash index, because their hash
+  /// entries were lost in a crash. Their space can be reclaimed.
+  /// Returns `CommitOK`.
+  Orphaned(Vec<BlobDesc>),
+
+  /// Run maintenance on the underlying storage (for sqlite, `VACUUM` and `ANALYZE`).
+  /// Returns `MaintenanceOK`.
+  Maintenance,
+
+  /// Check the underlying storage for corruption.
+  /// Returns `SelfCheckResult` with the problems found (empty if none).
+  SelfCheck,
+
+  /// List the names of all committed blobs.
+  /// Returns `BlobNames`.
+  ListCommitted,
+
+  /// List the blobs that are still in air, e.g. after a crash, so their upload can be resumed.
+  /// The listed blobs can be committed with `CommitDone`.
+  /// Returns `InAirBlobs`.
+  ListInAir,
+
+  /// List the names of all committed blobs in the order they should be verified: blobs that
+  /// were never verified first, then the least recently verified. Ties are in random order.
+  /// Returns `BlobNames`.
+  ListVerificationOrder,
+
+  /// Record that the data of these blobs was verified at the given time (in seconds since the
+  /// epoch).
+  /// Returns `CommitOK`.
+  Verified(Vec<Vec<u8>>, i64),
+
+  /// List the names of all committed blobs that are stored in the archive backend.
+  /// Returns `BlobNames`.
+  ListArchived,
+
+  /// Record that these blobs were moved to the archive backend (`true`), or back to the primary
+  /// backend (`false`).
+  /// Returns `CommitOK`.
+  Archived(Vec<Vec<u8>>, bool),
+
+  /// List the committed blobs that were given a storage hint other than the default, with the
+  /// code of the hint (see `blob_store::StorageHint::code`).
+  /// Returns `BlobHints`.
+  ListStorageHints,
+
+  /// Record that these blobs were given the storage hint with this code.
+  /// Returns `CommitOK`.
+  StorageHinted(Vec<Vec<u8>>, i64),
 }
 
 pub enum Reply {
   Reserved(BlobDesc),
   CommitOK,
+  MaintenanceOK,
+  SelfCheckResult(Vec<String>),
+  BlobNames(Vec<Vec<u8>>),
+  InAirBlobs(Vec<BlobDesc>),
+  UnindexedBlobs(Vec<BlobDesc>),
+  BlobChecksums(Vec<(Vec<u8>, Vec<u8>)>),
+  Checksum(Option<Vec<u8>>),
+  BlobHints(Vec<(Vec<u8>, i64)>),
+  Setting(Option<i64>),
 }
 
-pub struct BlobIndex {
-  dbh: Database,
-  next_id: i64,
-  reserved: HashMap<Vec<u8>, BlobDesc>,
+/// Persistence engine behind the blob index.
+///
+/// A blob moves through the states in-air, committed and indexed, in that order (see `Msg`).
+/// Changes are buffered until `commit()` makes them durable.
+pub trait BlobIndexStorage {
+  /// The largest blob id in use (`0` if there are no blobs).
+  fn max_id(&mut self) -> i64;
+
+  /// Record a blob as being in the process of being committed to persistent storage, along with
+  /// the checksum of its data.
+  fn insert_in_air(&mut self, blob: &BlobDesc, checksum: &[u8]);
+
+  /// Mark a blob that was previously recorded as in-air as committed.
+  fn set_committed(&mut self, blob: &BlobDesc);
+
+  /// Mark a committed blob as indexed.
+  fn set_indexed(&mut self, blob: &BlobDesc);
+
+  /// Mark a committed blob as orphaned.
+  fn set_orphaned(&mut self, blob: &BlobDesc);
+
+  /// Remove a blob that is recorded as in-air.
+  fn remove_in_air(&mut self, blob: &BlobDesc);
+
+  /// Durably commit all changes made so far.
+  fn commit(&mut self);
+
+  /// Perform housekeeping, like reclaiming unused space.
+  fn maintenance(&mut self);
+
+  /// Check the stored data for corruption. Returns a description of each problem found.
+  fn integrity_check(&mut self) -> Vec<String>;
+
+  /// List the names of all committed blobs (whether indexed or not).
+  fn list_committed(&mut self) -> Vec<Vec<u8>>;
+
+  /// List all blobs that are recorded as in-air.
+  fn list_in_air(&mut self) -> Vec<BlobDesc>;
+
+  /// List all blobs that are committed, but not indexed.
+  fn list_unindexed(&mut self) -> Vec<BlobDesc>;
+
+  /// List the name and checksum of every committed blob that has a checksum.
+  fn list_checksums(&mut self) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+  /// The checksum of a blob, if one was recorded for it.
+  fn checksum(&mut self, name: &[u8]) -> Option<Vec<u8>>;
+
+  /// Look up a named repository-wide setting.
+  fn setting(&mut self, name: &str) -> Option<i64>;
+
+  /// Record a named repository-wide setting.
+  fn set_setting(&mut self, name: &str, value: i64);
+
+  /// List the names of all committed blobs, least recently verified first (see
+  /// `ListVerificationOrder`).
+  fn list_verification_order(&mut self) -> Vec<Vec<u8>>;
+
+  /// Record when a blob was last verified.
+  fn set_verified(&mut self, name: &[u8], time: i64);
+
+  /// List the names of all committed blobs that are stored in the archive backend.
+  fn list_archived(&mut self) -> Vec<Vec<u8>>;
+
+  /// Record whether a blob is stored in the archive backend.
+  fn set_archived(&mut self, name: &[u8], archived: bool);
+
+  /// List the committed blobs with a storage hint other than the default (code `0`).
+  fn list_storage_hints(&mut self) -> Vec<(Vec<u8>, i64)>;
+
+  /// Record the storage hint of a blob.
+  fn set_storage_hint(&mut self, name: &[u8], hint: i64);
 }
 
 
-impl BlobIndex {
+// Values of the `tag` column:
+static TAG_INDEXED: uint = 0;
+static TAG_IN_AIR: uint = 1;
+static TAG_COMMITTED: uint = 2;
+static TAG_ORPHANED: uint = 3;
 
-  pub fn new(path: String) -> BlobIndex {
-    let mut hi = match open(path.as_slice()) {
-      Ok(dbh) => BlobIndex{
-        dbh: dbh,
-        next_id: -1,
-        reserved: HashMap::new(),
-      },
+
+/// The default `BlobIndexStorage`, backed by a sqlite database.
+pub struct SqliteBlobIndexStorage {
+  dbh: Database,
+}
+
+impl SqliteBlobIndexStorage {
+
+  pub fn new(path: String, key: Option<Vec<u8>>) -> SqliteBlobIndexStorage {
+    let mut storage = match open(path.as_slice()) {
+      Ok(dbh) => SqliteBlobIndexStorage{dbh: dbh},
       Err(err) => fail!(err.to_string()),
     };
-    hi.initialize();
-    hi
-  }
-
-  #[cfg(test)]
-  pub fn new_for_testing() -> BlobIndex {
-    BlobIndex::new(":memory:".to_string())
+    match key {
+      Some(ref key) => match unlock_database(&mut storage.dbh, key.as_slice()) {
+        Ok(()) => (),
+        Err(e) => fail!(e),
+      },
+      None => (),
+    }
+    storage.initialize();
+    storage
   }
 
   fn initialize(&mut self) {
@@ -88,14 +260,33 @@ impl BlobIndex {
                                   tag       INT)");
     self.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
                       BlobIndex_UniqueName ON blob_index(name)");
+    self.exec_or_die("CREATE TABLE IF NOT EXISTS
+                      repository_settings (name  TEXT PRIMARY KEY,
+                                           value INTEGER)");
+    if !self.has_column("blob_index", "checksum") {
+      self.exec_or_die("ALTER TABLE blob_index ADD COLUMN checksum BLOB");
+    }
+    if !self.has_column("blob_index", "verified") {
+      self.exec_or_die("ALTER TABLE blob_index ADD COLUMN verified INTEGER");
+    }
+    if !self.has_column("blob_index", "archived") {
+      self.exec_or_die("ALTER TABLE blob_index ADD COLUMN archived INTEGER DEFAULT 0");
+    }
+    if !self.has_column("blob_index", "storage_hint") {
+      self.exec_or_die("ALTER TABLE blob_index ADD COLUMN storage_hint INTEGER DEFAULT 0");
+    }
     self.exec_or_die("BEGIN");
-
-    self.refresh_next_id();
   }
 
-  fn new_blob_desc(&mut self) -> BlobDesc {
-    BlobDesc{name: randombytes(24),
-             id: self.next_id()}
+  fn has_column(&mut self, table: &str, column: &str) -> bool {
+    let mut cursor = self.prepare_or_die(format!("PRAGMA table_info({})", table).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      match cursor.get_text(1) {
+        Some(name) if name == column => return true,
+        _ => (),
+      }
+    }
+    false
   }
 
   fn exec_or_die(&mut self, sql: &str) {
@@ -122,9 +313,373 @@ impl BlobIndex {
     } else { None }
   }
 
+  fn list_tagged(&mut self, tag: uint) -> Vec<BlobDesc> {
+    let mut blobs = Vec::new();
+    let mut cursor = self.prepare_or_die(
+      format!("SELECT id, name FROM blob_index WHERE tag={}", tag).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      blobs.push(BlobDesc{id: cursor.get_int(0) as i64,
+                          name: cursor.get_blob(1).expect("name").into_vec()});
+    }
+    blobs
+  }
+}
+
+impl BlobIndexStorage for SqliteBlobIndexStorage {
+
+  fn max_id(&mut self) -> i64 {
+    self.select1("SELECT MAX(id) FROM blob_index").unwrap().get_int(0) as i64
+  }
+
+  fn insert_in_air(&mut self, blob: &BlobDesc, checksum: &[u8]) {
+    self.exec_or_die(format!(
+      "INSERT INTO blob_index (id, name, tag, checksum) VALUES ({}, x'{}', {}, x'{}')",
+      blob.id, blob.name.as_slice().to_hex(), TAG_IN_AIR, checksum.to_hex()).as_slice());
+  }
+
+  fn set_committed(&mut self, blob: &BlobDesc) {
+    self.exec_or_die(format!("UPDATE blob_index SET tag={} WHERE id={}",
+                             TAG_COMMITTED, blob.id).as_slice());
+  }
+
+  fn set_indexed(&mut self, blob: &BlobDesc) {
+    self.exec_or_die(format!("UPDATE blob_index SET tag={} WHERE id={} AND tag={}",
+                             TAG_INDEXED, blob.id, TAG_COMMITTED).as_slice());
+  }
+
+  fn set_orphaned(&mut self, blob: &BlobDesc) {
+    self.exec_or_die(format!("UPDATE blob_index SET tag={} WHERE id={} AND tag={}",
+                             TAG_ORPHANED, blob.id, TAG_COMMITTED).as_slice());
+  }
+
+  fn remove_in_air(&mut self, blob: &BlobDesc) {
+    self.exec_or_die(format!("DELETE FROM blob_index WHERE id={} AND tag={}",
+                             blob.id, TAG_IN_AIR).as_slice());
+  }
+
+  fn commit(&mut self) {
+    self.exec_or_die("COMMIT; BEGIN");
+  }
+
+  fn maintenance(&mut self) {
+    // VACUUM cannot run inside a transaction.
+    self.exec_or_die("COMMIT; VACUUM; ANALYZE; BEGIN");
+  }
+
+  fn integrity_check(&mut self) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut cursor = self.prepare_or_die("PRAGMA integrity_check");
+    while cursor.step() == SQLITE_ROW {
+      match cursor.get_text(0) {
+        Some(msg) if msg != "ok" => problems.push(format!("blob_index: {}", msg)),
+        _ => (),
+      }
+    }
+    problems
+  }
+
+  fn list_committed(&mut self) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT name FROM blob_index WHERE tag IN ({}, {})",
+      TAG_INDEXED, TAG_COMMITTED).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      names.push(cursor.get_blob(0).expect("name").into_vec());
+    }
+    names
+  }
+
+  fn list_in_air(&mut self) -> Vec<BlobDesc> {
+    self.list_tagged(TAG_IN_AIR)
+  }
+
+  fn list_unindexed(&mut self) -> Vec<BlobDesc> {
+    self.list_tagged(TAG_COMMITTED)
+  }
+
+  fn list_checksums(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut checksums = Vec::new();
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT name, checksum FROM blob_index WHERE tag IN ({}, {}) AND checksum IS NOT NULL",
+      TAG_INDEXED, TAG_COMMITTED).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      checksums.push((cursor.get_blob(0).expect("name").into_vec(),
+                      cursor.get_blob(1).expect("checksum").into_vec()));
+    }
+    checksums
+  }
+
+  fn checksum(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+    self.select1(format!("SELECT checksum FROM blob_index WHERE name=x'{}'",
+                         name.to_hex()).as_slice())
+      .and_then(|mut cursor| match cursor.get_blob(0) {
+        Some(checksum) if checksum.len() > 0 => Some(checksum.into_vec()),
+        _ => None,
+      })
+  }
+
+  fn setting(&mut self, name: &str) -> Option<i64> {
+    self.select1(format!("SELECT value FROM repository_settings WHERE name='{}'",
+                         name.replace("'", "''")).as_slice())
+      .map(|mut cursor| cursor.get_int(0) as i64)
+  }
+
+  fn set_setting(&mut self, name: &str, value: i64) {
+    self.exec_or_die(format!(
+      "INSERT OR REPLACE INTO repository_settings (name, value) VALUES ('{}', {})",
+      name.replace("'", "''"), value).as_slice());
+    self.commit();
+  }
+
+  fn list_verification_order(&mut self) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT name FROM blob_index WHERE tag IN ({}, {})
+       ORDER BY verified IS NOT NULL, verified, RANDOM()",
+      TAG_INDEXED, TAG_COMMITTED).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      names.push(cursor.get_blob(0).expect("name").into_vec());
+    }
+    names
+  }
+
+  fn set_verified(&mut self, name: &[u8], time: i64) {
+    self.exec_or_die(format!("UPDATE blob_index SET verified={} WHERE name=x'{}'",
+                             time, name.to_hex()).as_slice());
+  }
+
+  fn list_archived(&mut self) -> Vec<Vec<u8>> {
+    let mut names = Vec::new();
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT name FROM blob_index WHERE tag IN ({}, {}) AND archived=1",
+      TAG_INDEXED, TAG_COMMITTED).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      names.push(cursor.get_blob(0).expect("name").into_vec());
+    }
+    names
+  }
+
+  fn set_archived(&mut self, name: &[u8], archived: bool) {
+    self.exec_or_die(format!("UPDATE blob_index SET archived={} WHERE name=x'{}'",
+                             archived as uint, name.to_hex()).as_slice());
+  }
+
+  fn list_storage_hints(&mut self) -> Vec<(Vec<u8>, i64)> {
+    let mut hints = Vec::new();
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT name, storage_hint FROM blob_index WHERE tag IN ({}, {}) AND storage_hint!=0",
+      TAG_INDEXED, TAG_COMMITTED).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      hints.push((cursor.get_blob(0).expect("name").into_vec(), cursor.get_int(1) as i64));
+    }
+    hints
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: i64) {
+    self.exec_or_die(format!("UPDATE blob_index SET storage_hint={} WHERE name=x'{}'",
+                             hint, name.to_hex()).as_slice());
+  }
+}
+
+impl Drop for SqliteBlobIndexStorage {
+  fn drop(&mut self) {
+    self.exec_or_die("COMMIT");
+  }
+}
+
+
+/// A blob as recorded by `MemoryBlobIndexStorage`.
+struct MemoryBlob {
+  desc: BlobDesc,
+  tag: uint,
+  checksum: Vec<u8>,
+  verified: Option<i64>,
+  archived: bool,
+  storage_hint: i64,
+}
+
+impl MemoryBlob {
+  fn is_committed(&self) -> bool {
+    self.tag == TAG_INDEXED || self.tag == TAG_COMMITTED
+  }
+}
+
+/// A `BlobIndexStorage` that keeps its state in memory, e.g. for tests. Nothing is persisted.
+pub struct MemoryBlobIndexStorage {
+  blobs: TreeMap<i64, MemoryBlob>,
+  ids: HashMap<Vec<u8>, i64>,
+  settings: HashMap<String, i64>,
+}
+
+impl MemoryBlobIndexStorage {
+  pub fn new() -> MemoryBlobIndexStorage {
+    MemoryBlobIndexStorage{blobs: TreeMap::new(), ids: HashMap::new(), settings: HashMap::new()}
+  }
+
+  fn named<'a>(&'a mut self, name: &[u8]) -> Option<&'a mut MemoryBlob> {
+    match self.ids.find(&name.to_vec()) {
+      Some(id) => self.blobs.find_mut(id),
+      None => None,
+    }
+  }
+
+  /// Move a blob to tag `to`, if it has tag `from` (or any tag, if `from` is `None`).
+  fn retag(&mut self, blob: &BlobDesc, from: Option<uint>, to: uint) {
+    match self.blobs.find_mut(&blob.id) {
+      Some(stored) => if from.is_none() || from == Some(stored.tag) { stored.tag = to },
+      None => (),
+    }
+  }
+
+  fn list_tagged(&self, tag: uint) -> Vec<BlobDesc> {
+    self.blobs.iter().filter(|&(_, blob)| blob.tag == tag)
+                     .map(|(_, blob)| blob.desc.clone()).collect()
+  }
+
+  fn committed<'a>(&'a self) -> Vec<&'a MemoryBlob> {
+    self.blobs.iter().map(|(_, blob)| blob).filter(|blob| blob.is_committed()).collect()
+  }
+}
+
+impl BlobIndexStorage for MemoryBlobIndexStorage {
+
+  fn max_id(&mut self) -> i64 {
+    self.blobs.iter().map(|(&id, _)| id).max().unwrap_or(0)
+  }
+
+  fn insert_in_air(&mut self, blob: &BlobDesc, checksum: &[u8]) {
+    assert!(self.ids.find(&blob.name).is_none(), "blob names are unique");
+    self.ids.insert(blob.name.clone(), blob.id);
+    self.blobs.insert(blob.id, MemoryBlob{desc: blob.clone(), tag: TAG_IN_AIR,
+                                          checksum: checksum.to_vec(), verified: None,
+                                          archived: false, storage_hint: 0});
+  }
+
+  fn set_committed(&mut self, blob: &BlobDesc) {
+    self.retag(blob, None, TAG_COMMITTED);
+  }
+
+  fn set_indexed(&mut self, blob: &BlobDesc) {
+    self.retag(blob, Some(TAG_COMMITTED), TAG_INDEXED);
+  }
+
+  fn set_orphaned(&mut self, blob: &BlobDesc) {
+    self.retag(blob, Some(TAG_COMMITTED), TAG_ORPHANED);
+  }
+
+  fn remove_in_air(&mut self, blob: &BlobDesc) {
+    let in_air = self.blobs.find(&blob.id).map_or(false, |stored| stored.tag == TAG_IN_AIR);
+    if in_air {
+      let removed = self.blobs.pop(&blob.id).expect("blob");
+      self.ids.remove(&removed.desc.name);
+    }
+  }
+
+  fn commit(&mut self) {}
+
+  fn maintenance(&mut self) {}
+
+  fn integrity_check(&mut self) -> Vec<String> { vec![] }
+
+  fn list_committed(&mut self) -> Vec<Vec<u8>> {
+    self.committed().iter().map(|blob| blob.desc.name.clone()).collect()
+  }
+
+  fn list_in_air(&mut self) -> Vec<BlobDesc> {
+    self.list_tagged(TAG_IN_AIR)
+  }
+
+  fn list_unindexed(&mut self) -> Vec<BlobDesc> {
+    self.list_tagged(TAG_COMMITTED)
+  }
+
+  fn list_checksums(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    self.committed().iter().map(|blob| (blob.desc.name.clone(), blob.checksum.clone())).collect()
+  }
+
+  fn checksum(&mut self, name: &[u8]) -> Option<Vec<u8>> {
+    match self.named(name) {
+      Some(blob) => if blob.checksum.len() > 0 { Some(blob.checksum.clone()) } else { None },
+      None => None,
+    }
+  }
+
+  fn setting(&mut self, name: &str) -> Option<i64> {
+    self.settings.find(&name.to_string()).map(|&value| value)
+  }
+
+  fn set_setting(&mut self, name: &str, value: i64) {
+    self.settings.insert(name.to_string(), value);
+  }
+
+  fn list_verification_order(&mut self) -> Vec<Vec<u8>> {
+    // Never verified (`None`) sorts first; ties are broken by the random blob names.
+    let mut order: Vec<(Option<i64>, Vec<u8>)> =
+      self.committed().iter().map(|blob| (blob.verified, blob.desc.name.clone())).collect();
+    order.sort();
+    order.into_iter().map(|(_, name)| name).collect()
+  }
+
+  fn set_verified(&mut self, name: &[u8], time: i64) {
+    self.named(name).map(|blob| blob.verified = Some(time));
+  }
+
+  fn list_archived(&mut self) -> Vec<Vec<u8>> {
+    self.committed().iter().filter(|blob| blob.archived)
+                           .map(|blob| blob.desc.name.clone()).collect()
+  }
+
+  fn set_archived(&mut self, name: &[u8], archived: bool) {
+    self.named(name).map(|blob| blob.archived = archived);
+  }
+
+  fn list_storage_hints(&mut self) -> Vec<(Vec<u8>, i64)> {
+    self.committed().iter().filter(|blob| blob.storage_hint != 0)
+                           .map(|blob| (blob.desc.name.clone(), blob.storage_hint)).collect()
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: i64) {
+    self.named(name).map(|blob| blob.storage_hint = hint);
+  }
+}
+
+
+pub struct BlobIndex {
+  storage: Box<BlobIndexStorage>,
+  next_id: i64,
+  reserved: HashMap<Vec<u8>, BlobDesc>,
+}
+
+
+impl BlobIndex {
+
+  pub fn new(path: String, key: Option<Vec<u8>>) -> BlobIndex {
+    BlobIndex::with_storage(box SqliteBlobIndexStorage::new(path, key))
+  }
+
+  /// Create a blob index that persists its state through `storage`.
+  pub fn with_storage(storage: Box<BlobIndexStorage>) -> BlobIndex {
+    let mut bi = BlobIndex{
+      storage: storage,
+      next_id: -1,
+      reserved: HashMap::new(),
+    };
+    bi.refresh_next_id();
+    bi
+  }
+
+  #[cfg(test)]
+  pub fn new_for_testing() -> BlobIndex {
+    BlobIndex::new(":memory:".to_string(), None)
+  }
+
+  fn new_blob_desc(&mut self) -> BlobDesc {
+    BlobDesc{name: randombytes(24),
+             id: self.next_id()}
+  }
+
   fn refresh_next_id(&mut self) {
-    let id = self.select1("SELECT MAX(id) FROM blob_index").unwrap().get_int(0);
-    self.next_id = (id as i64) + 1;
+    self.next_id = self.storage.max_id() + 1;
   }
 
   fn next_id(&mut self) -> i64 {
@@ -139,28 +694,45 @@ impl BlobIndex {
     blob
   }
 
-  fn in_air(&mut self, blob: &BlobDesc) {
+  fn in_air(&mut self, blob: &BlobDesc, checksum: &[u8]) {
     assert!(self.reserved.find(&blob.name).is_some(), "blob was not reserved!");
-    self.exec_or_die(format!(
-      "INSERT INTO blob_index (id, name, tag) VALUES ({}, x'{}', {})",
-      blob.id, blob.name.as_slice().to_hex(), 1u).as_slice());
-    self.new_transaction();
+    self.storage.insert_in_air(blob, checksum);
+    self.storage.commit();
   }
 
-  fn new_transaction(&mut self) {
-    self.exec_or_die("COMMIT; BEGIN");
+  fn list_in_air(&mut self) -> Vec<BlobDesc> {
+    let blobs = self.storage.list_in_air();
+    // Whoever resumes these blobs will report them as committed:
+    for blob in blobs.iter() {
+      self.reserved.insert(blob.name.clone(), blob.clone());
+    }
+    blobs
   }
 
   fn commit_blob(&mut self, blob: &BlobDesc) {
     assert!(self.reserved.find(&blob.name).is_some(), "blob was not reserved!");
-    self.exec_or_die(format!("UPDATE blob_index SET tag=0 WHERE id={}", blob.id).as_slice());
-    self.new_transaction();
+    self.storage.set_committed(blob);
+    self.storage.commit();
   }
-}
 
-impl Drop for BlobIndex {
-  fn drop(&mut self) {
-    self.exec_or_die("COMMIT");
+  fn set_indexed(&mut self, blobs: &Vec<BlobDesc>) {
+    for blob in blobs.iter() {
+      self.storage.set_indexed(blob);
+    }
+    self.storage.commit();
+  }
+
+  fn set_orphaned(&mut self, blobs: &Vec<BlobDesc>) {
+    for blob in blobs.iter() {
+      self.storage.set_orphaned(blob);
+    }
+    self.storage.commit();
+  }
+
+  fn roll_back(&mut self, blob: &BlobDesc) {
+    self.storage.remove_in_air(blob);
+    self.storage.commit();
+    self.reserved.remove(&blob.name);
   }
 }
 
@@ -170,14 +742,159 @@ impl MsgHandler<Msg, Reply> for BlobIndex {
       Reserve => {
         return reply(Reserved(self.reserve()));
       },
-      InAir(blob) => {
-        self.in_air(&blob);
+      InAir(blob, checksum) => {
+        self.in_air(&blob, checksum.as_slice());
         return reply(CommitOK);
       },
       CommitDone(blob) => {
         self.commit_blob(&blob);
         return reply(CommitOK);
-      }
+      },
+      Maintenance => {
+        self.storage.maintenance();
+        return reply(MaintenanceOK);
+      },
+      SelfCheck => {
+        return reply(SelfCheckResult(self.storage.integrity_check()));
+      },
+      ListCommitted => {
+        return reply(BlobNames(self.storage.list_committed()));
+      },
+      ListInAir => {
+        return reply(InAirBlobs(self.list_in_air()));
+      },
+      ListUnindexed => {
+        return reply(UnindexedBlobs(self.storage.list_unindexed()));
+      },
+      Indexed(blobs) => {
+        self.set_indexed(&blobs);
+        return reply(CommitOK);
+      },
+      ListChecksums => {
+        return reply(BlobChecksums(self.storage.list_checksums()));
+      },
+      FetchChecksum(name) => {
+        return reply(Checksum(self.storage.checksum(name.as_slice())));
+      },
+      FetchSetting(name) => {
+        return reply(Setting(self.storage.setting(name.as_slice())));
+      },
+      StoreSetting(name, value) => {
+        self.storage.set_setting(name.as_slice(), value);
+        return reply(CommitOK);
+      },
+      RollBack(blob) => {
+        self.roll_back(&blob);
+        return reply(CommitOK);
+      },
+      Orphaned(blobs) => {
+        self.set_orphaned(&blobs);
+        return reply(CommitOK);
+      },
+      ListVerificationOrder => {
+        return reply(BlobNames(self.storage.list_verification_order()));
+      },
+      Verified(names, time) => {
+        for name in names.iter() {
+          self.storage.set_verified(name.as_slice(), time);
+        }
+        self.storage.commit();
+        return reply(CommitOK);
+      },
+      ListArchived => {
+        return reply(BlobNames(self.storage.list_archived()));
+      },
+      Archived(names, archived) => {
+        for name in names.iter() {
+          self.storage.set_archived(name.as_slice(), archived);
+        }
+        self.storage.commit();
+        return reply(CommitOK);
+      },
+      ListStorageHints => {
+        return reply(BlobHints(self.storage.list_storage_hints()));
+      },
+      StorageHinted(names, hint) => {
+        for name in names.iter() {
+          self.storage.set_storage_hint(name.as_slice(), hint);
+        }
+        self.storage.commit();
+        return reply(CommitOK);
+      },
     }
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use process::{MsgHandler};
+
+  fn send(index: &mut BlobIndex, msg: Msg) -> Reply {
+    let mut result = None;
+    index.handle(msg, |reply| result = Some(reply));
+    result.expect("a reply")
+  }
+
+  fn names(index: &mut BlobIndex, msg: Msg) -> Vec<Vec<u8>> {
+    match send(index, msg) {
+      BlobNames(names) => names,
+      _ => fail!("Unexpected reply from blob index."),
+    }
+  }
+
+  /// Take blobs through their states, and record what is known about them.
+  fn check_blob_states(mut index: BlobIndex) {
+    let mut blobs = Vec::new();
+    for checksum in vec![b"first".to_vec(), b"second".to_vec()].into_iter() {
+      let blob = match send(&mut index, Reserve) {
+        Reserved(blob) => blob,
+        _ => fail!("Unexpected reply from blob index."),
+      };
+      send(&mut index, InAir(blob.clone(), checksum));
+      blobs.push(blob);
+    }
+    match send(&mut index, ListInAir) {
+      InAirBlobs(in_air) => assert_eq!(in_air, blobs),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+
+    send(&mut index, CommitDone(blobs[0].clone()));
+    send(&mut index, RollBack(blobs[1].clone()));
+    assert_eq!(names(&mut index, ListCommitted), vec![blobs[0].name.clone()]);
+    match send(&mut index, ListUnindexed) {
+      UnindexedBlobs(unindexed) => assert_eq!(unindexed, vec![blobs[0].clone()]),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+    send(&mut index, Indexed(vec![blobs[0].clone()]));
+    match send(&mut index, ListUnindexed) {
+      UnindexedBlobs(unindexed) => assert_eq!(unindexed, vec![]),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+
+    match send(&mut index, FetchChecksum(blobs[0].name.clone())) {
+      Checksum(checksum) => assert_eq!(checksum, Some(b"first".to_vec())),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+    send(&mut index, Archived(vec![blobs[0].name.clone()], true));
+    assert_eq!(names(&mut index, ListArchived), vec![blobs[0].name.clone()]);
+
+    send(&mut index, StoreSetting("blob_size".to_string(), 42));
+    match send(&mut index, FetchSetting("blob_size".to_string())) {
+      Setting(value) => assert_eq!(value, Some(42)),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+  }
+
+  #[test]
+  fn blob_states() {
+    check_blob_states(BlobIndex::new_for_testing());
+  }
+
+  #[test]
+  fn blob_states_in_memory() {
+    check_blob_states(BlobIndex::with_storage(box MemoryBlobIndexStorage::new()));
+  }
+}
@@ -17,16 +17,25 @@
 use std::sync::{Arc, Mutex};
 
 use serialize::{json, Encodable, Decodable};
-use serialize::hex::{ToHex};
+use sodiumoxide::crypto::hash::{sha256};
+use serialize::hex::{FromHex, ToHex};
 use serialize::json::{Json, ToJson, Decoder, from_str};
 
+use std::collections::hashmap::{HashMap, HashSet};
 use std::collections::treemap::{TreeMap};
 use std::collections::lru_cache::{LruCache};
 
-use std::io::{File};
+use std::cmp;
+use std::io::{Command, File, IoResult, Append, Open, Write, UserDir};
+use std::io::fs::{mkdir_recursive, readdir, rename, stat, unlink};
+use std::rand::{Rng, task_rng};
 use std::str;
+use time;
 
+use buffer_pool::{BufferPool};
+use crash_test;
 use process::{Process, MsgHandler};
+use keys::{BlobCipher};
 
 use blob_index;
 use blob_index::{BlobIndexProcess};
@@ -37,18 +46,265 @@ use blob_index::{BlobIndex};
 
 pub type BlobStoreProcess<B> = Process<Msg, Reply, BlobStore<B>>;
 
+/// The checksum recorded for each blob: the SHA-256 digest of its data.
+pub fn blob_checksum(data: &[u8]) -> Vec<u8> {
+  let sha256::Digest(digest) = sha256::hash(data);
+  digest.as_slice().into_vec()
+}
+
+/// Check blob `name`, as read from the backend, against the checksum recorded when it was stored
+/// (if any). The error names the blob, so that it can be restored from elsewhere.
+pub fn verify_blob(name: &[u8], data: &[u8], recorded: Option<&[u8]>) -> Result<(), String> {
+  match recorded {
+    Some(recorded) if recorded != blob_checksum(data).as_slice() => {
+      Err(format!("Blob {} is corrupt: the backend returned {} bytes with checksum {}, but the \
+                   blob was stored with checksum {}. Restore the blob from a mirror or archive \
+                   copy, or run verify to find what else is affected.", name.to_hex(),
+                  data.len(), blob_checksum(data).as_slice().to_hex(), recorded.to_hex()))
+    },
+    _ => Ok(()),
+  }
+}
+
+static TRANSIENT_SUFFIX: &'static str = " (transient error; the request can be retried)";
+
+/// Mark error `message` as transient: the request may well succeed if repeated, e.g. after a
+/// timeout or a dropped connection. Only such errors are retried (see `RetryingBackend`).
+pub fn transient(message: String) -> String {
+  format!("{}{}", message, TRANSIENT_SUFFIX)
+}
+
+pub fn is_transient(error: &str) -> bool {
+  error.ends_with(TRANSIENT_SUFFIX)
+}
+
+/// Size of the pieces that a blob is uploaded in, when the backend supports resuming.
+static UPLOAD_PIECE_SIZE: uint = 1024 * 1024;
+
+/// The most bytes of blobs kept in memory for planned reads (see `PlanReads`). Beyond this, blobs
+/// are fetched again for each read.
+static READ_PLAN_BYTES: uint = 256 * 1024 * 1024;
+
+/// How often a blob is expected to be read, for backends that store rarely read data for less
+/// (e.g. the storage classes of S3).
+#[deriving(Clone, Show, PartialEq)]
+pub enum StorageHint {
+  /// Data of recent snapshots. New blobs are stored like this.
+  FrequentAccess,
+
+  /// Data only used by older snapshots, read to restore those and to verify the repository.
+  InfrequentAccess,
+
+  /// Data that is kept in case it is needed, and rarely read, if ever.
+  ArchiveAccess,
+}
+
+impl StorageHint {
+  pub fn from_name(name: &str) -> Option<StorageHint> {
+    match name {
+      "frequent" => Some(FrequentAccess),
+      "infrequent" => Some(InfrequentAccess),
+      "archive" => Some(ArchiveAccess),
+      _ => None,
+    }
+  }
+
+  /// The hint as recorded in the blob index.
+  pub fn code(&self) -> i64 {
+    match *self { FrequentAccess => 0, InfrequentAccess => 1, ArchiveAccess => 2 }
+  }
+
+  pub fn from_code(code: i64) -> Option<StorageHint> {
+    match code { 0 => Some(FrequentAccess), 1 => Some(InfrequentAccess),
+                 2 => Some(ArchiveAccess), _ => None }
+  }
+}
+
+/// What a backend can do, as found by `BlobStoreBackend::probe`.
+#[deriving(Clone, Show, PartialEq)]
+pub struct BackendProbe {
+  /// Whether stored blobs can be deleted.
+  pub supports_delete: bool,
+  /// Whether stored blobs can be listed.
+  pub supports_listing: bool,
+  /// How long it took to read a small blob, in milliseconds.
+  pub latency_ms: u64,
+  /// The space left for new blobs in bytes, if the backend knows it.
+  pub free_bytes: Option<u64>,
+}
+
 pub trait BlobStoreBackend {
   fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String>;
   fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String>;
+
+  /// Store a blob as with `store()`, in the way that suits how often it will be read. Backends
+  /// without a choice store it as with `store()`.
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], _hint: StorageHint)
+                     -> Result<(), String> {
+    self.store(name, data)
+  }
+
+  /// Change how a stored blob is stored, to suit how often it will be read from now on.
+  fn set_storage_hint(&mut self, _name: &[u8], _hint: StorageHint) -> Result<(), String> {
+    Err("Backend does not support storage hints.".to_string())
+  }
+
+  /// Whether an interrupted upload can be resumed with `stored_length()` and `append()`.
+  fn supports_resume(&self) -> bool { false }
+
+  /// The number of bytes stored so far for a (possibly partially uploaded) blob.
+  fn stored_length(&mut self, _name: &[u8]) -> Result<uint, String> {
+    Err("Backend does not support resuming uploads.".to_string())
+  }
+
+  /// Append `data` to a (possibly empty or non-existent) partially uploaded blob.
+  fn append(&mut self, _name: &[u8], _data: &[u8]) -> Result<(), String> {
+    Err("Backend does not support resuming uploads.".to_string())
+  }
+
+  /// Complete a blob uploaded with `append()`, once all of its data has been appended. This is
+  /// also called for a blob that was completed before, if an upload is resumed after that.
+  fn finish_append(&mut self, _name: &[u8]) -> Result<(), String> {
+    Ok(())
+  }
+
+  /// The checksum of a stored blob (see `blob_checksum`) as kept in the backend's own metadata,
+  /// so that the blob can be verified without downloading it. Returns `None` if the backend does
+  /// not keep checksums.
+  fn stored_checksum(&mut self, _name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    Ok(None)
+  }
+
+  /// Remove a stored blob, e.g. once it has been moved to another backend.
+  fn delete(&mut self, _name: &[u8]) -> Result<(), String> {
+    Err("Backend does not support deleting blobs.".to_string())
+  }
+
+  /// Whether `set_retention` is supported.
+  fn supports_retention(&self) -> bool { false }
+
+  /// Keep a stored blob from being deleted or replaced until `until` (in seconds since the
+  /// epoch), even by clients that are otherwise allowed to delete blobs, e.g. with S3 Object
+  /// Lock. A retention can be extended, but not shortened.
+  fn set_retention(&mut self, _name: &[u8], _until: u64) -> Result<(), String> {
+    Err("Backend does not support retention.".to_string())
+  }
+
+  /// The names of all stored blobs.
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    Err("Backend does not support listing blobs.".to_string())
+  }
+
+  /// Whether `list()` is supported. It is not tried by `probe()`, as listing a large store takes
+  /// long.
+  fn supports_listing(&self) -> bool { false }
+
+  /// Check that the backend can be reached and written to, and find out what it can do. Returns
+  /// an error if it cannot. The default is `probe_backend`.
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    probe_backend(self)
+  }
+}
+
+/// A name for a throwaway blob, used to check a backend.
+fn probe_name() -> Vec<u8> {
+  let suffix: String = task_rng().gen_ascii_chars().take(16).collect();
+  format!("hat-probe-{}", suffix).into_bytes()
+}
+
+/// Write a small probe blob to `backend`, time reading it back and delete it again. Fails if the
+/// blob cannot be written or read back, i.e. if the backend is unreachable or read-only.
+pub fn probe_backend<B: BlobStoreBackend>(backend: &mut B) -> Result<BackendProbe, String> {
+  let name = probe_name();
+  let data: Vec<u8> = Vec::from_fn(64, |_| task_rng().gen::<u8>());
+  try!(backend.store(name.as_slice(), data.as_slice()).map_err(|e| {
+    format!("could not write a probe blob: {}", e)
+  }));
+  let started = time::precise_time_ns();
+  let read = backend.retrieve(name.as_slice());
+  let latency_ms = (time::precise_time_ns() - started) / 1000000;
+  match read {
+    Ok(ref read) if *read == data => (),
+    Ok(_) => return Err("the probe blob read back differs from the one written".to_string()),
+    Err(e) => return Err(format!("could not read the probe blob back: {}", e)),
+  }
+  Ok(BackendProbe{supports_delete: backend.delete(name.as_slice()).is_ok(),
+                  supports_listing: backend.supports_listing(),
+                  latency_ms: latency_ms,
+                  free_bytes: None})
+}
+
+/// Exercise `backend` with a probe blob: write it, read it back, compare the backend's checksum
+/// (if it keeps one), find it in the listing and delete it again. Returns the outcome of each
+/// step; if the probe cannot be written, there are no further steps.
+pub fn check_backend<B: BlobStoreBackend>(backend: &mut B)
+                                         -> Vec<(&'static str, Result<(), String>)> {
+  let mut rng = task_rng();
+  let name = probe_name();
+  let data: Vec<u8> = Vec::from_fn(4096, |_| rng.gen::<u8>());
+
+  let mut steps = Vec::new();
+  let stored = backend.store(name.as_slice(), data.as_slice());
+  let stored_ok = stored.is_ok();
+  steps.push(("write a probe blob", stored));
+  if !stored_ok {
+    return steps;
+  }
+
+  steps.push(("read it back", match backend.retrieve(name.as_slice()) {
+    Ok(ref read) if *read == data => Ok(()),
+    Ok(_) => Err("the data read back differs from the data written".to_string()),
+    Err(e) => Err(e),
+  }));
+  steps.push(("compare its checksum", backend.stored_checksum(name.as_slice()).and_then(|sum| {
+    match sum {
+      Some(ref sum) if *sum != blob_checksum(data.as_slice()) => {
+        Err("the backend reports a different checksum".to_string())
+      },
+      _ => Ok(()),
+    }
+  })));
+  steps.push(("list blobs", backend.list().and_then(|names| {
+    if names.contains(&name) { Ok(()) }
+    else { Err("the probe blob is missing from the listing".to_string()) }
+  })));
+  steps.push(("delete the probe blob", backend.delete(name.as_slice())));
+  steps
 }
 
 
+/// A backend that keeps blobs as files in a local directory.
+///
+/// Blobs are spread over subdirectories named after the first byte of their name, so that no
+/// directory grows to millions of files; blobs in the top directory (as stored by earlier
+/// versions) are still found. A blob is written to a `.part` file, synced to disk and only then
+/// renamed to its name, so a crash never leaves a truncated blob under that name, and a blob is
+/// durable before the blob index commits it.
 #[deriving(Clone)]
 pub struct FileBackend {
   root: Path,
   read_cache: Arc<Mutex<LruCache<Vec<u8>, Result<Vec<u8>, String>>>>,
 }
 
+/// The space left for files in `dir` in bytes, as reported by `df`.
+fn free_space(dir: &Path) -> Option<u64> {
+  let output = match Command::new("df").arg("-Pk").arg(dir).output() {
+    Ok(output) if output.status.success() => output.output,
+    _ => return None,
+  };
+  // The second line describes the file system; its fourth column is the number of free KiB:
+  str::from_utf8(output.as_slice())
+    .and_then(|text| text.lines().nth(1))
+    .and_then(|line| line.words().nth(3))
+    .and_then(|kib| from_str::<u64>(kib))
+    .map(|kib| kib * 1024)
+}
+
+/// Make the entries of `dir` durable, e.g. after a rename.
+fn sync_dir(dir: &Path) -> IoResult<()> {
+  File::open(dir).and_then(|mut d| d.fsync())
+}
+
 impl FileBackend {
 
   pub fn new(root: Path) -> FileBackend {
@@ -62,50 +318,596 @@ impl FileBackend {
   fn guarded_cache_put(&mut self, name: Vec<u8>, result: Result<Vec<u8>, String>) {
     self.read_cache.lock().put(name, result);
   }
+
+  /// Where blob `name` is stored: in the subdirectory for its first byte.
+  fn blob_path(&self, name: &[u8]) -> Path {
+    let hex = name.to_hex();
+    let mut path = self.root.clone();
+    path.push(hex.as_slice().slice_to(cmp::min(2, hex.len())));
+    path.push(hex.as_slice());
+    path
+  }
+
+  /// Where blob `name` is while it is being written.
+  fn part_path(&self, name: &[u8]) -> Path {
+    let mut path = self.blob_path(name);
+    path.set_extension("part");
+    path
+  }
+
+  /// The path of blob `name`, in the top directory if it was stored there by an earlier version.
+  fn existing_path(&self, name: &[u8]) -> Path {
+    let path = self.blob_path(name);
+    if path.exists() {
+      return path;
+    }
+    let mut flat = self.root.clone();
+    flat.push(name.to_hex());
+    if flat.exists() { flat } else { path }
+  }
+
+  /// Sync the `.part` file of blob `name` and rename it into place.
+  fn commit_part(&self, name: &[u8]) -> IoResult<()> {
+    let (part, path) = (self.part_path(name), self.blob_path(name));
+    try!(File::open_mode(&part, Open, Write).and_then(|mut f| f.fsync()));
+    try!(rename(&part, &path));
+    sync_dir(&path.dir_path())
+  }
+
+  /// Create the subdirectory of blob `name`, if it is the first blob there.
+  fn create_dir_of(&self, name: &[u8]) -> IoResult<()> {
+    let dir = self.blob_path(name).dir_path();
+    if dir.is_dir() {
+      return Ok(());
+    }
+    try!(mkdir_recursive(&dir, UserDir));
+    sync_dir(&self.root)
+  }
 }
 
 impl BlobStoreBackend for FileBackend {
 
   fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
-    let mut path = self.root.clone();
-    path.push(name.to_hex());
+    let path = self.blob_path(name);
+    let written = self.create_dir_of(name).and_then(|()| {
+      File::create(&self.part_path(name)).and_then(|mut file| file.write(data))
+    }).and_then(|()| self.commit_part(name));
+
+    match written {
+      Err(e) => {
+        let _ = unlink(&self.part_path(name));
+        Err(format!("{}: {}", path.display(), e))
+      },
+      Ok(()) => Ok(()),
+    }
+  }
 
-    let mut file = match File::create(&path) {
-      Err(e) => return Err(e.to_string()),
-      Ok(f) => f,
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    // Check for key in cache:
+    let name = name.into_vec();
+    let value_opt = self.guarded_cache_get(&name);
+    match value_opt {
+      Some(result) => return result,
+      None => (),
+    }
+
+    // Read key:
+    let path = self.existing_path(name.as_slice());
+
+    let mut fd = match File::open(&path) {
+      Ok(fd) => fd,
+      Err(e) => return Err(format!("{}: {}", path.display(), e)),
     };
 
-    match file.write(data) {
+    let res = fd.read_to_end().and_then(|data| {
+      Ok(data.into_vec()) }).or_else(|e| Err(e.to_string()));
+
+    // Update cache to contain key:
+    self.guarded_cache_put(name, res.clone());
+
+    return res;
+  }
+
+  fn supports_resume(&self) -> bool { true }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    // A blob that was renamed into place is complete. Blobs in the top directory are not
+    // resumed; they may be partial uploads of an earlier version.
+    let path = self.blob_path(name);
+    let path = if path.exists() { path } else { self.part_path(name) };
+    match stat(&path) {
+      Ok(st) => Ok(st.size as uint),
+      Err(_) if !path.exists() => Ok(0),
       Err(e) => Err(e.to_string()),
-      Ok(()) => Ok(()),
     }
   }
 
-  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
-    // Check for key in cache:
-    let name = name.into_vec();
-    let value_opt = self.guarded_cache_get(&name);
-    match value_opt {
-      Some(result) => return result,
-      None => (),
-    }
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let part = self.part_path(name);
+    self.create_dir_of(name).and_then(|()| File::open_mode(&part, Append, Write))
+      .and_then(|mut f| f.write(data))
+      .map_err(|e| format!("{}: {}", part.display(), e))
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    if !self.part_path(name).exists() && self.blob_path(name).exists() {
+      return Ok(());  // Completed before the upload was resumed.
+    }
+    self.commit_part(name).map_err(|e| format!("{}: {}", self.blob_path(name).display(), e))
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    let path = self.existing_path(name);
+    self.read_cache.lock().pop(&name.into_vec());
+    unlink(&path).map_err(|e| e.to_string())
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let paths = try!(readdir(&self.root).map_err(|e| {
+      format!("{}: {}", self.root.display(), e)
+    }));
+    let mut names = Vec::new();
+    for path in paths.into_iter() {
+      let files = if path.is_dir() {
+        try!(readdir(&path).map_err(|e| format!("{}: {}", path.display(), e)))
+      } else { vec![path] };
+      // Blobs being written end in `.part`, which is not hex:
+      names.extend(files.iter().filter_map(|path| {
+        path.filename_str().and_then(|name| name.from_hex().ok())
+      }));
+    }
+    Ok(names)
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    let mut probe = try!(probe_backend(self));
+    probe.free_bytes = free_space(&self.root);
+    Ok(probe)
+  }
+}
+
+
+/// A backend that keeps blobs in memory, e.g. for tests and demos of programs that embed hat. All
+/// clones of the backend share the same blobs. Storing a blob under a name that is already in
+/// use fails, since blob names are never reused.
+#[deriving(Clone)]
+pub struct MemoryBackend {
+  files: Arc<Mutex<TreeMap<Vec<u8>, Vec<u8>>>>,
+  hints: Arc<Mutex<HashMap<Vec<u8>, StorageHint>>>,
+  retention: Arc<Mutex<HashMap<Vec<u8>, u64>>>,
+}
+
+impl MemoryBackend {
+  pub fn new() -> MemoryBackend {
+    MemoryBackend{files: Arc::new(Mutex::new(TreeMap::new())),
+                  hints: Arc::new(Mutex::new(HashMap::new())),
+                  retention: Arc::new(Mutex::new(HashMap::new()))}
+  }
+
+  /// Until when a stored blob is kept from being deleted (see `set_retention`), if it is.
+  pub fn retention_of(&self, name: &[u8]) -> Option<u64> {
+    self.retention.lock().find(&name.into_vec()).map(|&until| until)
+  }
+
+  /// The storage hint of a stored blob (see `store_with_hint`), if it was given one.
+  pub fn hint_of(&self, name: &[u8]) -> Option<StorageHint> {
+    self.hints.lock().find(&name.into_vec()).map(|hint| hint.clone())
+  }
+
+  /// The number of blobs stored.
+  pub fn len(&self) -> uint {
+    self.files.lock().len()
+  }
+
+  /// The total size of the blobs stored.
+  pub fn stored_bytes(&self) -> uint {
+    self.files.lock().values().fold(0, |sum, data| sum + data.len())
+  }
+
+  fn guarded_insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String>{
+    let mut guarded_files = self.files.lock();
+    if guarded_files.contains_key(&key) {
+      return Err(format!("Key already exists: '{}'", key));
+    }
+    guarded_files.insert(key, value);
+    Ok(())
+  }
+
+  fn guarded_retrieve(&mut self, key: &[u8]) -> Result<Vec<u8>, String> {
+    let value_opt = self.files.lock().find(&key.into_vec()).map(|v| v.clone());
+    value_opt.map(|v| Ok(v)).unwrap_or_else(|| Err(format!("Unknown key: '{}'", key)))
+  }
+}
+
+impl BlobStoreBackend for MemoryBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.guarded_insert(name.to_owned(), data.into_vec())
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    self.guarded_retrieve(name)
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    try!(self.store(name, data));
+    self.hints.lock().insert(name.into_vec(), hint);
+    Ok(())
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    try!(self.guarded_retrieve(name));
+    self.hints.lock().insert(name.into_vec(), hint);
+    Ok(())
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.guarded_retrieve(name).map(|data| Some(blob_checksum(data.as_slice())))
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    match self.retention_of(name) {
+      Some(until) if until > time::get_time().sec as u64 => {
+        return Err(format!("Key is locked until {}: '{}'", until, name));
+      },
+      _ => (),
+    }
+    self.hints.lock().remove(&name.into_vec());
+    self.retention.lock().remove(&name.into_vec());
+    match self.files.lock().pop(&name.into_vec()) {
+      Some(_) => Ok(()),
+      None => Err(format!("Unknown key: '{}'", name)),
+    }
+  }
+
+  fn supports_retention(&self) -> bool { true }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    try!(self.guarded_retrieve(name));
+    let mut retention = self.retention.lock();
+    match retention.find(&name.into_vec()) {
+      Some(&current) if current > until => {
+        return Err(format!("Key is already locked until {}: '{}'", current, name));
+      },
+      _ => (),
+    }
+    retention.insert(name.into_vec(), until);
+    Ok(())
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    Ok(self.files.lock().keys().map(|k| k.clone()).collect())
+  }
+
+  fn supports_listing(&self) -> bool { true }
+}
+
+
+/// A backend that keeps new blobs on a fast primary backend, and reads blobs that have been moved
+/// to a cheaper archive backend from there. Which blobs are archived is recorded in the blob
+/// index, and shared between all clones of the backend (see `mark_archived`).
+#[deriving(Clone)]
+pub struct TieredBackend<P, A> {
+  primary: P,
+  archive: A,
+  archived: Arc<Mutex<HashSet<Vec<u8>>>>,
+}
+
+impl <P: BlobStoreBackend, A: BlobStoreBackend> TieredBackend<P, A> {
+
+  pub fn new(primary: P, archive: A) -> TieredBackend<P, A> {
+    TieredBackend{primary: primary, archive: archive,
+                  archived: Arc::new(Mutex::new(HashSet::new()))}
+  }
+
+  /// Read these blobs from the archive backend (`true`), or from the primary backend (`false`).
+  pub fn mark_archived(&self, names: Vec<Vec<u8>>, archived: bool) {
+    let mut set = self.archived.lock();
+    for name in names.into_iter() {
+      if archived { set.insert(name); } else { set.remove(&name); }
+    }
+  }
+
+  pub fn is_archived(&self, name: &[u8]) -> bool {
+    self.archived.lock().contains(&name.into_vec())
+  }
+
+  /// Copy a blob from the primary backend to the archive backend.
+  pub fn copy_to_archive(&mut self, name: &[u8]) -> Result<(), String> {
+    let data = try!(self.primary.retrieve(name));
+    self.archive.store(name, data.as_slice())
+  }
+
+  /// Copy a blob from the archive backend back to the primary backend.
+  pub fn copy_to_primary(&mut self, name: &[u8]) -> Result<(), String> {
+    let data = try!(self.archive.retrieve(name));
+    self.primary.store(name, data.as_slice())
+  }
+
+  pub fn delete_from_primary(&mut self, name: &[u8]) -> Result<(), String> {
+    self.primary.delete(name)
+  }
+
+  pub fn delete_from_archive(&mut self, name: &[u8]) -> Result<(), String> {
+    self.archive.delete(name)
+  }
+}
+
+impl <P: BlobStoreBackend, A: BlobStoreBackend> BlobStoreBackend for TieredBackend<P, A> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.primary.store(name, data)
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    if self.is_archived(name) { self.archive.retrieve(name) }
+    else { self.primary.retrieve(name) }
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    self.primary.store_with_hint(name, data, hint)
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    if self.is_archived(name) { self.archive.set_storage_hint(name, hint) }
+    else { self.primary.set_storage_hint(name, hint) }
+  }
+
+  fn supports_resume(&self) -> bool { self.primary.supports_resume() }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.primary.stored_length(name)
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.primary.append(name, data)
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.primary.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    if self.is_archived(name) { self.archive.stored_checksum(name) }
+    else { self.primary.stored_checksum(name) }
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    if self.is_archived(name) { self.archive.delete(name) }
+    else { self.primary.delete(name) }
+  }
+
+  fn supports_retention(&self) -> bool { self.primary.supports_retention() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    if self.is_archived(name) { self.archive.set_retention(name, until) }
+    else { self.primary.set_retention(name, until) }
+  }
+
+  fn supports_listing(&self) -> bool {
+    self.primary.supports_listing() && self.archive.supports_listing()
+  }
+
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.primary.probe()
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let mut names = try!(self.primary.list());
+    if self.archived.lock().len() > 0 {
+      names.push_all(try!(self.archive.list()).as_slice());
+      names.sort();
+      names.dedup();
+    }
+    Ok(names)
+  }
+}
+
+
+/// A backend that encrypts blobs and their names with a `BlobCipher` before they reach `backend`,
+/// so that the storage provider can read neither. Without a cipher, blobs pass through as they
+/// are.
+///
+/// Blobs are sealed whole, so uploads cannot be resumed, and checksums kept by the backend are of
+/// the sealed data: `stored_checksum` reports none, and blobs are checked once read instead.
+#[deriving(Clone)]
+pub struct EncryptedBackend<B> {
+  backend: B,
+  cipher: Option<BlobCipher>,
+}
+
+impl <B: BlobStoreBackend> EncryptedBackend<B> {
+  pub fn new(backend: B, cipher: Option<BlobCipher>) -> EncryptedBackend<B> {
+    EncryptedBackend{backend: backend, cipher: cipher}
+  }
+
+  fn stored_name(&self, name: &[u8]) -> Vec<u8> {
+    match self.cipher {
+      Some(ref cipher) => cipher.seal_name(name),
+      None => name.into_vec(),
+    }
+  }
+}
+
+impl <B: BlobStoreBackend> BlobStoreBackend for EncryptedBackend<B> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let stored = self.stored_name(name);
+    match self.cipher {
+      Some(ref cipher) => self.backend.store(stored.as_slice(), cipher.seal(data).as_slice()),
+      None => self.backend.store(name, data),
+    }
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let stored = self.stored_name(name);
+    let sealed = try!(self.backend.retrieve(stored.as_slice()));
+    match self.cipher {
+      Some(ref cipher) => cipher.open(sealed.as_slice()).ok_or_else(|| {
+        format!("Blob {} could not be decrypted: it was stored with another key, or modified \
+                 since.", name.to_hex())
+      }),
+      None => Ok(sealed),
+    }
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    let stored = self.stored_name(name);
+    match self.cipher {
+      Some(ref cipher) => {
+        self.backend.store_with_hint(stored.as_slice(), cipher.seal(data).as_slice(), hint)
+      },
+      None => self.backend.store_with_hint(name, data, hint),
+    }
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    let stored = self.stored_name(name);
+    self.backend.set_storage_hint(stored.as_slice(), hint)
+  }
+
+  fn supports_resume(&self) -> bool {
+    self.cipher.is_none() && self.backend.supports_resume()
+  }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.backend.stored_length(name)
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.backend.append(name, data)
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    if self.cipher.is_some() { Ok(None) } else { self.backend.stored_checksum(name) }
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    let stored = self.stored_name(name);
+    self.backend.delete(stored.as_slice())
+  }
+
+  fn supports_retention(&self) -> bool { self.backend.supports_retention() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    let stored = self.stored_name(name);
+    self.backend.set_retention(stored.as_slice(), until)
+  }
+
+  /// The names of the blobs stored with this cipher. Names that do not decrypt are left out.
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let names = try!(self.backend.list());
+    match self.cipher {
+      Some(ref cipher) => Ok(names.into_iter().filter_map(|name| {
+        cipher.open_name(name.as_slice())
+      }).collect()),
+      None => Ok(names),
+    }
+  }
+
+  fn supports_listing(&self) -> bool { self.backend.supports_listing() }
+
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.backend.probe()
+  }
+}
+
+
+/// A backend that can be cloned from behind a `Box`. Every backend that is `Clone` and `Send` is
+/// one.
+pub trait CloneableBackend: BlobStoreBackend {
+  fn clone_boxed(&self) -> Box<CloneableBackend + Send>;
+}
+
+impl <B: BlobStoreBackend + Clone + Send> CloneableBackend for B {
+  fn clone_boxed(&self) -> Box<CloneableBackend + Send> {
+    box self.clone() as Box<CloneableBackend + Send>
+  }
+}
+
+/// A backend whose type is chosen at run time, e.g. from configuration: `Hat<BoxedBackend>` and
+/// `BlobStore<BoxedBackend>` can use local, S3 or SFTP storage without being generic over it.
+pub struct BoxedBackend {
+  backend: Box<CloneableBackend + Send>,
+}
+
+impl BoxedBackend {
+  pub fn new<B: BlobStoreBackend + Clone + Send>(backend: B) -> BoxedBackend {
+    BoxedBackend{backend: box backend as Box<CloneableBackend + Send>}
+  }
+}
+
+impl Clone for BoxedBackend {
+  fn clone(&self) -> BoxedBackend {
+    BoxedBackend{backend: self.backend.clone_boxed()}
+  }
+}
+
+impl BlobStoreBackend for BoxedBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.backend.store(name, data)
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    self.backend.retrieve(name)
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    self.backend.store_with_hint(name, data, hint)
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    self.backend.set_storage_hint(name, hint)
+  }
+
+  fn supports_resume(&self) -> bool { self.backend.supports_resume() }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.backend.stored_length(name)
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.backend.append(name, data)
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.backend.stored_checksum(name)
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.delete(name)
+  }
 
-    // Read key:
-    let path = { let mut p = self.root.clone();
-                 p.push(name.as_slice().to_hex());
-                 p };
+  fn supports_retention(&self) -> bool { self.backend.supports_retention() }
 
-    let mut fd = File::open(&path).unwrap();
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    self.backend.set_retention(name, until)
+  }
 
-    let res = fd.read_to_end().and_then(|data| {
-      Ok(data.into_vec()) }).or_else(|e| Err(e.to_string()));
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    self.backend.list()
+  }
 
-    // Update cache to contain key:
-    self.guarded_cache_put(name, res.clone());
+  fn supports_listing(&self) -> bool { self.backend.supports_listing() }
 
-    return res;
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.backend.probe()
   }
-
 }
 
 
@@ -128,6 +930,34 @@ impl BlobID {
     self.to_json().to_string().as_bytes().into_vec()
   }
 
+  /// The length of the data that this `BlobID` points to.
+  pub fn length(&self) -> uint {
+    self.end - self.begin
+  }
+
+  /// The data that this `BlobID` points to, given the data of its whole blob. Returns `None` if
+  /// the range lies outside of the blob.
+  pub fn chunk_of<'a>(&self, blob: &'a [u8]) -> Option<&'a [u8]> {
+    if self.begin <= self.end && self.end <= blob.len() {
+      Some(blob.slice(self.begin, self.end))
+    } else { None }
+  }
+
+  /// Extract the name of the blob that the encoded `BlobID` points into. Empty chunks are not
+  /// stored in any blob, so their name is `None`.
+  pub fn blob_name_of(bytes: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let json = match str::from_utf8(bytes).and_then(|s| from_str(s).ok()) {
+      Some(json) => json,
+      None => return Err("invalid blob reference".to_string()),
+    };
+    let id: BlobID = match Decodable::decode(&mut Decoder::new(json)) {
+      Ok(id) => id,
+      Err(e) => return Err(e.to_string()),
+    };
+    if id.begin == 0 && id.end == 0 { Ok(None) }
+    else { Ok(Some(id.name)) }
+  }
+
 }
 
 impl ToJson for BlobID {
@@ -150,6 +980,20 @@ pub enum Msg {
   Retrieve(BlobID),
   /// Flush the current blob, independent of its size.
   Flush,
+  /// List the committed blobs that are not yet known to be indexed (see `blob_index::Msg`).
+  ListUnindexed,
+  /// Report that the hash index entries of these blobs have been durably committed.
+  MarkIndexed(Vec<blob_index::BlobDesc>),
+  /// Announce the reads of an upcoming restore: one blob name per chunk to be read. Each blob
+  /// that is read more than once is then fetched once, and kept until its last planned read.
+  /// Replaces any earlier plan; an empty plan frees the blobs kept.
+  PlanReads(Vec<Vec<u8>>),
+  /// List the names of all blobs stored by the backend, e.g. to find blobs that no index refers
+  /// to. Uploads in progress are completed first.
+  ListBlobs,
+  /// Delete a stored blob from the backend. Uploads in progress are completed first; the blob
+  /// currently being filled cannot be deleted.
+  DeleteBlob(Vec<u8>),
 }
 
 
@@ -158,6 +1002,11 @@ pub enum Reply {
   StoreOK(BlobID),
   RetrieveOK(Vec<u8>),
   FlushOK,
+  Unindexed(Vec<blob_index::BlobDesc>),
+  MarkIndexedOK,
+  PlanOK,
+  BlobList(Result<Vec<Vec<u8>>, String>),
+  DeleteResult(Result<(), String>),
 }
 
 
@@ -171,6 +1020,192 @@ pub struct BlobStore<B> {
   buffer_data_len: uint,
 
   max_blob_size: uint,
+
+  /// When set, `max_blob_size` is adjusted to the observed latency and throughput of the backend.
+  size_tuner: Option<BlobSizeTuner>,
+
+  /// When set, stored chunks are given back to this pool once they are copied into a blob.
+  buffer_pool: Option<BufferPool>,
+
+  /// Local copies of in-air blobs are kept here, so that their upload can be resumed after a crash.
+  spool_dir: Option<Path>,
+
+  /// When set, blobs are uploaded by these worker tasks while the next blob is being filled.
+  uploads: Option<UploadPool>,
+
+  read_plan: ReadPlan,
+}
+
+
+/// The planned reads of a restore (see `PlanReads`).
+struct ReadPlan {
+  /// Reads left per blob, for blobs that are read more than once.
+  reads_left: HashMap<Vec<u8>, uint>,
+  /// The blobs fetched so far, until their last planned read.
+  blobs: HashMap<Vec<u8>, Vec<u8>>,
+  bytes: uint,
+}
+
+impl ReadPlan {
+  fn new(reads: Vec<Vec<u8>>) -> ReadPlan {
+    let mut counts = HashMap::new();
+    for name in reads.into_iter() {
+      *counts.find_or_insert(name, 0u) += 1;
+    }
+    let reads_left = counts.into_iter().filter(|&(_, count)| count > 1).collect();
+    ReadPlan{reads_left: reads_left, blobs: HashMap::new(), bytes: 0}
+  }
+}
+
+
+/// A full blob that is in air, ready to be uploaded and committed.
+struct Upload {
+  desc: blob_index::BlobDesc,
+  blob: Vec<u8>,
+  spool_path: Option<Path>,
+  callbacks: Vec<(BlobID, proc(BlobID):Send -> ())>,
+}
+
+/// Worker tasks uploading blobs, each with its own clone of the backend.
+struct UploadPool {
+  jobs: SyncSender<Upload>,
+  done: Receiver<Result<(uint, u64), String>>,
+  pending: uint,
+}
+
+impl UploadPool {
+  fn new<B: BlobStoreBackend + Clone + Send>(workers: uint, backend: &B,
+                                              blob_index: &BlobIndexProcess) -> UploadPool {
+    let (jobs, job_rx) = sync_channel(workers);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    let (done_tx, done) = channel();
+    for _ in range(0, workers) {
+      let job_rx = job_rx.clone();
+      let done_tx = done_tx.clone();
+      let mut backend = backend.clone();
+      let blob_index = blob_index.clone();
+      spawn(proc() {
+        loop {
+          let job = job_rx.lock().recv_opt();
+          match job {
+            Ok(job) => {
+              let size = job.blob.len();
+              let res = upload(&mut backend, &blob_index, job).map(|ns| (size, ns));
+              if done_tx.send_opt(res).is_err() { break }
+            },
+            Err(()) => break,
+          }
+        }
+      });
+    }
+    UploadPool{jobs: jobs, done: done, pending: 0}
+  }
+}
+
+/// Upload the rest of a blob, starting at `offset`, in pieces that survive a crash.
+fn append_from<B: BlobStoreBackend>(backend: &mut B, name: &[u8], blob: &[u8], offset: uint)
+                                    -> Result<(), String> {
+  let mut offset = offset;
+  while offset < blob.len() {
+    let end = cmp::min(blob.len(), offset + UPLOAD_PIECE_SIZE);
+    try!(backend.append(name, blob.slice(offset, end)));
+    offset = end;
+  }
+  backend.finish_append(name)
+}
+
+/// Store an in-air blob, commit it and run the callbacks of its chunks. Returns the number of
+/// nanoseconds that storing the blob took.
+fn upload<B: BlobStoreBackend>(backend: &mut B, blob_index: &BlobIndexProcess, upload: Upload)
+                               -> Result<u64, String> {
+  let Upload{desc, blob, spool_path, callbacks} = upload;
+  let started = time::precise_time_ns();
+  match spool_path {
+    None => try!(backend.store(desc.name.as_slice(), blob.as_slice())),
+    Some(_) => try!(append_from(backend, desc.name.as_slice(), blob.as_slice(), 0)),
+  }
+  let elapsed = time::precise_time_ns() - started;
+  crash_test::point("blob_store: stored");
+  blob_index.send_reply(blob_index::CommitDone(desc));
+  spool_path.map(|path| { let _ = unlink(&path); });
+
+  crash_test::point("blob_store: committed");
+
+  // Go through callbacks
+  for (blobid, cb) in callbacks.into_iter() {
+    cb(blobid);
+  }
+  Ok(elapsed)
+}
+
+
+/// Upload time that the fixed per-blob latency may take, relative to the time spent transferring.
+static LATENCY_SHARE: f64 = 0.1;
+
+/// How much weight an observation keeps with each newer observation.
+static OBSERVATION_DECAY: f64 = 0.9;
+
+/// Picks a blob size within configured bounds from the observed cost of storing blobs.
+///
+/// Storing a blob of `n` bytes is modelled as taking `latency + n / throughput`. Both are fitted
+/// to recent observations, and the blob size is chosen so that latency is only a small share of
+/// each upload: small blobs for a fast local disk or NAS, large blobs for a high-latency cloud
+/// service.
+pub struct BlobSizeTuner {
+  min_size: uint,
+  max_size: uint,
+
+  // Exponentially decayed sums over observations of (size, nanoseconds):
+  weight: f64,
+  sum_n: f64,
+  sum_t: f64,
+  sum_nn: f64,
+  sum_nt: f64,
+}
+
+impl BlobSizeTuner {
+  pub fn new(min_size: uint, max_size: uint) -> BlobSizeTuner {
+    assert!(min_size > 0 && min_size <= max_size);
+    BlobSizeTuner{min_size: min_size, max_size: max_size,
+                  weight: 0.0, sum_n: 0.0, sum_t: 0.0, sum_nn: 0.0, sum_nt: 0.0}
+  }
+
+  /// The blob size to start with, before anything has been observed.
+  pub fn initial_size(&self) -> uint {
+    self.min_size
+  }
+
+  /// Record that storing a blob of `size` bytes took `nanoseconds`.
+  pub fn observe(&mut self, size: uint, nanoseconds: u64) {
+    let (n, t) = (size as f64, nanoseconds as f64);
+    self.weight = self.weight * OBSERVATION_DECAY + 1.0;
+    self.sum_n = self.sum_n * OBSERVATION_DECAY + n;
+    self.sum_t = self.sum_t * OBSERVATION_DECAY + t;
+    self.sum_nn = self.sum_nn * OBSERVATION_DECAY + n * n;
+    self.sum_nt = self.sum_nt * OBSERVATION_DECAY + n * t;
+  }
+
+  /// The blob size to use next, given the `current` one.
+  pub fn next_size(&self, current: uint) -> uint {
+    let mean_n = self.sum_n / self.weight;
+    let mean_t = self.sum_t / self.weight;
+    let var_n = self.sum_nn / self.weight - mean_n * mean_n;
+    let size = if self.weight == 0.0 || var_n <= (mean_n * 0.01) * (mean_n * 0.01) {
+      // Not enough spread in the observed sizes to tell latency from throughput yet; try a
+      // larger blob to learn more.
+      if current >= self.max_size / 2 { self.max_size } else { current * 2 }
+    } else {
+      let ns_per_byte = (self.sum_nt / self.weight - mean_n * mean_t) / var_n;
+      let latency = mean_t - ns_per_byte * mean_n;
+      if ns_per_byte <= 0.0 { self.max_size }
+      else if latency <= 0.0 { self.min_size }
+      else {
+        let size = latency / ns_per_byte / LATENCY_SHARE;
+        if size >= self.max_size as f64 { self.max_size } else { size as uint }
+      }
+    };
+    cmp::min(self.max_size, cmp::max(self.min_size, size))
+  }
 }
 
 
@@ -181,8 +1216,14 @@ fn empty_blob_desc() -> blob_index::BlobDesc {
 
 impl <B: BlobStoreBackend> BlobStore<B> {
 
+  /// Create a blob store. If the backend supports resuming uploads, and a `spool_dir` is given,
+  /// in-air blobs are uploaded in pieces from a local copy (see `resume_uploads`).
   pub fn new(index: BlobIndexProcess, backend: B,
-             max_blob_size: uint) -> BlobStore<B> {
+             max_blob_size: uint, spool_dir: Option<Path>) -> BlobStore<B> {
+    let spool_dir = if backend.supports_resume() { spool_dir } else { None };
+    spool_dir.as_ref().map(|dir| {
+      mkdir_recursive(dir, UserDir).unwrap();
+    });
     let mut bs = BlobStore{
       backend: backend,
       blob_index: index,
@@ -190,11 +1231,29 @@ impl <B: BlobStoreBackend> BlobStore<B> {
       buffer_data: Vec::new(),
       buffer_data_len: 0,
       max_blob_size: max_blob_size,
+      size_tuner: None,
+      buffer_pool: None,
+      spool_dir: spool_dir,
+      uploads: None,
+      read_plan: ReadPlan::new(Vec::new()),
     };
     bs.reserve_new_blob();
     bs
   }
 
+  /// Give the buffers of stored chunks back to `pool` once their data is copied into a blob.
+  pub fn recycle_buffers(&mut self, pool: BufferPool) {
+    self.buffer_pool = Some(pool);
+  }
+
+  /// Adapt the blob size to the backend, between `min_blob_size` and `max_blob_size`, instead of
+  /// always filling blobs up to `max_blob_size`.
+  pub fn adapt_blob_size(&mut self, min_blob_size: uint, max_blob_size: uint) {
+    let tuner = BlobSizeTuner::new(min_blob_size, max_blob_size);
+    self.max_blob_size = tuner.initial_size();
+    self.size_tuner = Some(tuner);
+  }
+
   #[cfg(test)]
   pub fn new_for_testing(backend: B, max_blob_size: uint) -> BlobStore<B> {
     let biP = Process::new(proc() { BlobIndex::new_for_testing() });
@@ -204,6 +1263,11 @@ impl <B: BlobStoreBackend> BlobStore<B> {
                            buffer_data: Vec::new(),
                            buffer_data_len: 0,
                            max_blob_size: max_blob_size,
+                           size_tuner: None,
+                           buffer_pool: None,
+                           spool_dir: None,
+                           uploads: None,
+                           read_plan: ReadPlan::new(Vec::new()),
                           };
     bs.reserve_new_blob();
     bs
@@ -223,47 +1287,191 @@ impl <B: BlobStoreBackend> BlobStore<B> {
     old_blob_desc
   }
 
-  fn backend_store(&mut self, name: &[u8], blob: &[u8]) {
-    match self.backend.store(name, blob) {
-      Ok(()) => (),
-      Err(s) => fail!(s),
+  fn spool_path(&self, name: &[u8]) -> Option<Path> {
+    self.spool_dir.as_ref().map(|dir| {
+      let mut path = dir.clone();
+      path.push(name.to_hex());
+      path
+    })
+  }
+
+  /// Resume the upload of blobs that were in air when the system last stopped, for which a local
+  /// copy was kept. Once fully uploaded, they are committed. Returns the number of resumed blobs.
+  pub fn resume_uploads(&mut self) -> uint {
+    if self.spool_dir.is_none() { return 0 }
+
+    let in_air = match self.blob_index.send_reply(blob_index::ListInAir) {
+      blob_index::InAirBlobs(blobs) => blobs,
+      _ => fail!("Unexpected reply from blob index."),
+    };
+
+    let mut resumed = 0;
+    for blob_desc in in_air.into_iter() {
+      let path = self.spool_path(blob_desc.name.as_slice()).expect("spool_dir is set");
+      let blob = match File::open(&path).and_then(|mut f| f.read_to_end()) {
+        Ok(blob) => blob,
+        Err(_) => continue,  // No local copy; nothing to resume.
+      };
+      let offset = match self.backend.stored_length(blob_desc.name.as_slice()) {
+        Ok(offset) if offset <= blob.len() => offset,
+        Ok(_) => {
+          warning!("Warning: Not resuming blob {}: stored blob is larger than local copy.",
+                   blob_desc.name.as_slice().to_hex());
+          continue;
+        },
+        Err(s) => fail!(s),
+      };
+
+      match append_from(&mut self.backend, blob_desc.name.as_slice(), blob.as_slice(), offset) {
+        Ok(()) => (),
+        Err(s) => fail!(s),
+      }
+      self.blob_index.send_reply(blob_index::CommitDone(blob_desc));
+      let _ = unlink(&path);
+      resumed += 1;
     }
+    resumed
   }
 
+  /// Read blob `name` from the backend, and check it against its recorded checksum before any of
+  /// its chunks are used.
   fn backend_read(&mut self, name: &[u8]) -> Vec<u8> {
-    match self.backend.retrieve(name) {
+    let data = match self.backend.retrieve(name) {
       Ok(data) => data,
       Err(s) => fail!(s),
+    };
+    let recorded = match self.blob_index.send_reply(blob_index::FetchChecksum(name.into_vec())) {
+      blob_index::Checksum(checksum) => checksum,
+      _ => fail!("Unexpected reply from blob index."),
+    };
+    match verify_blob(name, data.as_slice(), recorded.as_ref().map(|c| c.as_slice())) {
+      Ok(()) => data,
+      Err(e) => fail!(e),
+    }
+  }
+
+  /// Read the chunk `id`, as one of the planned reads of its blob (if any): blobs with more
+  /// reads left are fetched once and kept, as far as `READ_PLAN_BYTES` allows.
+  fn planned_read(&mut self, id: &BlobID) -> Vec<u8> {
+    let left = match self.read_plan.reads_left.find_mut(&id.name) {
+      Some(left) => { *left -= 1; Some(*left) },
+      None => None,
+    };
+    let left = match left {
+      Some(left) => left,
+      None => return self.backend_read(id.name.as_slice()).slice(id.begin, id.end).into_vec(),
+    };
+    if left == 0 {
+      self.read_plan.reads_left.remove(&id.name);
+    }
+    if !self.read_plan.blobs.contains_key(&id.name) {
+      let blob = self.backend_read(id.name.as_slice());
+      if left == 0 || self.read_plan.bytes + blob.len() > READ_PLAN_BYTES {
+        return blob.slice(id.begin, id.end).into_vec();
+      }
+      self.read_plan.bytes += blob.len();
+      self.read_plan.blobs.insert(id.name.clone(), blob);
+    }
+    let chunk = self.read_plan.blobs.find(&id.name).expect("blob is kept")
+                                    .slice(id.begin, id.end).into_vec();
+    if left == 0 {
+      let kept = self.read_plan.blobs.pop(&id.name).expect("blob is kept");
+      self.read_plan.bytes -= kept.len();
     }
+    chunk
   }
 
+  /// Write the current blob, with all chunks stored since the last flush. The small chunks that
+  /// come last before a `Flush` (end-of-file remainders, hash tree nodes) thus share one blob,
+  /// and one backend object, per flush.
   fn flush(&mut self) {
     if self.buffer_data_len == 0 { return }
 
     // Replace blob id
     let old_blob_desc = self.reserve_new_blob();
+    let blob_len = self.buffer_data_len;
     self.buffer_data_len = 0;
+    crash_test::point("blob_store: reserved");
 
     // Prepare blob
     let mut ready_callback = Vec::new();
-    let mut blob = Vec::new();
+    let mut blob = Vec::with_capacity(blob_len);
     loop {
       match self.buffer_data.shift() {
         Some((chunk_ref, chunk, cb)) => {
           ready_callback.push((chunk_ref, cb));
           blob.push_all(chunk.as_slice());
+          match self.buffer_pool {
+            Some(ref pool) => pool.put(chunk),
+            None => (),
+          }
         },
         None => break,
       }
     }
 
-    self.blob_index.send_reply(blob_index::InAir(old_blob_desc.clone()));
-    self.backend_store(old_blob_desc.name.as_slice(), blob.as_slice());
-    self.blob_index.send_reply(blob_index::CommitDone(old_blob_desc));
+    let checksum = blob_checksum(blob.as_slice());
+    let spool_path = self.spool_path(old_blob_desc.name.as_slice());
+    spool_path.as_ref().map(|path| {
+      // Keep a local copy while in air, so an interrupted upload can be resumed:
+      File::create(path).and_then(|mut f| f.write(blob.as_slice())).unwrap();
+    });
+    self.blob_index.send_reply(blob_index::InAir(old_blob_desc.clone(), checksum));
+    crash_test::point("blob_store: in air");
+
+    let job = Upload{desc: old_blob_desc, blob: blob, spool_path: spool_path,
+                     callbacks: ready_callback};
+    let inline_job = match self.uploads {
+      Some(ref mut pool) => {
+        pool.jobs.send(job);
+        pool.pending += 1;
+        None
+      },
+      None => Some(job),
+    };
+    match inline_job {
+      Some(job) => {
+        let size = job.blob.len();
+        match upload(&mut self.backend, &self.blob_index, job) {
+          Ok(ns) => self.observe_upload(size, ns),
+          Err(s) => fail!(s),
+        }
+      },
+      None => self.collect_uploads(false),
+    }
+  }
+
+  fn observe_upload(&mut self, size: uint, nanoseconds: u64) {
+    match self.size_tuner {
+      Some(ref mut tuner) => {
+        tuner.observe(size, nanoseconds);
+        self.max_blob_size = tuner.next_size(self.max_blob_size);
+      },
+      None => (),
+    }
+  }
 
-    // Go through callbacks
-    for (blobid, cb) in ready_callback.move_iter() {
-      cb(blobid);
+  /// Account for uploads finished by the upload workers. With `wait`, block until all uploads
+  /// are done.
+  fn collect_uploads(&mut self, wait: bool) {
+    loop {
+      let res = match self.uploads {
+        Some(ref mut pool) if pool.pending > 0 => {
+          let res = if wait { pool.done.recv() } else {
+            match pool.done.try_recv() {
+              Ok(res) => res,
+              Err(_) => return,
+            }
+          };
+          pool.pending -= 1;
+          res
+        },
+        _ => return,
+      };
+      match res {
+        Ok((size, ns)) => self.observe_upload(size, ns),
+        Err(s) => fail!(s),
+      }
     }
   }
 
@@ -274,6 +1482,17 @@ impl <B: BlobStoreBackend> BlobStore<B> {
   }
 }
 
+impl <B: BlobStoreBackend + Clone + Send> BlobStore<B> {
+
+  /// Upload full blobs in `workers` parallel tasks, while the next blob is being filled. By
+  /// default, blobs are uploaded by the blob store itself, one at a time.
+  pub fn set_upload_workers(&mut self, workers: uint) {
+    if workers > 0 {
+      self.uploads = Some(UploadPool::new(workers, &self.backend, &self.blob_index));
+    }
+  }
+}
+
 impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
 
   fn handle(&mut self, msg: Msg, reply: |Reply|) {
@@ -293,7 +1512,7 @@ impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
                         end: new_size};
 
         self.buffer_data_len = new_size;
-        self.buffer_data.push((id.clone(), blob.into_vec(), cb));
+        self.buffer_data.push((id.clone(), blob, cb));
 
         // To avoid unnecessary blocking, we reply with the ID *before* possibly flushing.
         reply(StoreOK(id));
@@ -306,16 +1525,45 @@ impl <B: BlobStoreBackend> MsgHandler<Msg, Reply> for BlobStore<B> {
         if id.begin == 0 && id.end == 0 {
           return reply(RetrieveOK(vec![].into_vec()));
         }
-        let blob = self.backend_read(id.name.as_slice());
-        let chunk = blob.slice(id.begin, id.end);
-        return reply(RetrieveOK(chunk.into_vec()));
+        return reply(RetrieveOK(self.planned_read(&id)));
+      },
+
+      PlanReads(reads) => {
+        self.read_plan = ReadPlan::new(reads);
+        return reply(PlanOK);
       },
 
       Flush => {
         self.flush();
+        self.collect_uploads(true);
         return reply(FlushOK)
       },
 
+      ListUnindexed => {
+        match self.blob_index.send_reply(blob_index::ListUnindexed) {
+          blob_index::UnindexedBlobs(blobs) => return reply(Unindexed(blobs)),
+          _ => fail!("Unexpected reply from blob index."),
+        }
+      },
+
+      MarkIndexed(blobs) => {
+        self.blob_index.send_reply(blob_index::Indexed(blobs));
+        return reply(MarkIndexedOK)
+      },
+
+      ListBlobs => {
+        self.collect_uploads(true);
+        return reply(BlobList(self.backend.list()));
+      },
+
+      DeleteBlob(name) => {
+        if name == self.blob_desc.name {
+          return reply(DeleteResult(Err("The blob is still being written.".to_string())));
+        }
+        self.collect_uploads(true);
+        return reply(DeleteResult(self.backend.delete(name.as_slice())));
+      },
+
     }
   }
 
@@ -329,46 +1577,17 @@ pub mod tests {
   use quickcheck::{Config, Testable, gen};
   use quickcheck::{quickcheck_config};
 
+  use blob_index::{BlobDesc, BlobIndex, BlobIndexProcess};
+  use blob_index;
+  use keys::{BlobCipher, RepositoryKey};
   use process::{Process};
 
-  use std::sync::{Arc, Mutex};
-  use std::collections::treemap::{TreeMap};
-
-  #[deriving(Clone)]
-  pub struct MemoryBackend {
-    files: Arc<Mutex<TreeMap<Vec<u8>, Vec<u8>>>>
-  }
-
-  impl MemoryBackend {
-    pub fn new() -> MemoryBackend {
-      MemoryBackend{files: Arc::new(Mutex::new(TreeMap::new()))}
-    }
-
-    fn guarded_insert(&mut self, key: Vec<u8>, value: Vec<u8>) -> Result<(), String>{
-      let mut guarded_files = self.files.lock();
-      if guarded_files.contains_key(&key) {
-        return Err(format!("Key already exists: '{}'", key));
-      }
-      guarded_files.insert(key, value);
-      Ok(())
-    }
-
-    fn guarded_retrieve(&mut self, key: &[u8]) -> Result<Vec<u8>, String> {
-      let value_opt = self.files.lock().find(&key.into_vec()).map(|v| v.clone());
-      value_opt.map(|v| Ok(v)).unwrap_or_else(|| Err(format!("Unknown key: '{}'", key)))
-    }
-  }
-
-  impl BlobStoreBackend for MemoryBackend {
-
-    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
-      self.guarded_insert(name.to_owned(), data.into_vec())
-    }
+  use serialize::hex::{ToHex};
 
-    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
-      self.guarded_retrieve(name)
-    }
-  }
+  use std::sync::{Arc, Mutex};
+  use std::io::{File, TempDir};
+  use std::task;
+  use time;
 
   #[deriving(Clone)]
   pub struct DevNullBackend;
@@ -439,6 +1658,43 @@ pub mod tests {
   }
 
 
+  #[test]
+  fn identity_with_upload_workers() {
+    fn prop(chunks: Vec<Vec<u8>>) -> bool {
+      let mut backend = MemoryBackend::new();
+
+      let local_backend = backend.clone();
+      let bsP: BlobStoreProcess<MemoryBackend> = Process::new(proc() {
+        let mut bs = BlobStore::new_for_testing(local_backend, 64);
+        bs.set_upload_workers(3);
+        bs
+      });
+
+      let mut ids = Vec::new();
+      for chunk in chunks.iter() {
+        match bsP.send_reply(Store(chunk.as_slice().into_vec(), proc(_){})) {
+          StoreOK(id) => { ids.push((id, chunk)); },
+          _ => fail!("Unexpected reply from blob store."),
+        }
+      }
+
+      assert_eq!(bsP.send_reply(Flush), FlushOK);
+
+      // All uploads are done once the flush is acknowledged:
+      for &(ref id, chunk) in ids.iter() {
+        if chunk.len() > 0 {
+          match backend.retrieve(id.name.as_slice()) {
+            Ok(blob) => assert_eq!(id.chunk_of(blob.as_slice()), Some(chunk.as_slice())),
+            Err(e) => fail!(e),
+          }
+        }
+      }
+
+      return true;
+    }
+    qcheck(prop);
+  }
+
   #[test]
   fn identity_with_excessive_flushing() {
     fn prop(chunks: Vec<Vec<u8>>) -> bool {
@@ -493,4 +1749,384 @@ pub mod tests {
     qcheck(prop);
   }
 
+  #[test]
+  fn committed_blobs_are_indexed_when_marked() {
+    let bsP: BlobStoreProcess<MemoryBackend> =
+      Process::new(proc() { BlobStore::new_for_testing(MemoryBackend::new(), 1024) });
+    bsP.send_reply(Store(b"data".into_vec(), proc(_) {}));
+    bsP.send_reply(Flush);
+
+    let unindexed = match bsP.send_reply(ListUnindexed) {
+      Unindexed(blobs) => blobs,
+      _ => fail!("Unexpected reply from blob store."),
+    };
+    assert_eq!(unindexed.len(), 1);
+
+    bsP.send_reply(MarkIndexed(unindexed));
+    assert_eq!(bsP.send_reply(ListUnindexed), Unindexed(vec![]));
+  }
+
+  #[test]
+  fn blob_size_tuner() {
+    let mut tuner = BlobSizeTuner::new(1024, 1024 * 1024);
+    assert_eq!(tuner.initial_size(), 1024);
+    assert_eq!(tuner.next_size(1024), 2048);
+
+    // 1ms latency and 1 byte/ns: latency is 10% of the upload time at 10MB, above the bound.
+    tuner.observe(1024, 1000000 + 1024);
+    tuner.observe(2048, 1000000 + 2048);
+    assert_eq!(tuner.next_size(2048), 1024 * 1024);
+
+    // Without latency, the smallest size is just as good:
+    let mut tuner = BlobSizeTuner::new(1024, 1024 * 1024);
+    tuner.observe(1024, 1024);
+    tuner.observe(4096, 4096);
+    assert_eq!(tuner.next_size(4096), 1024);
+
+    // 10us latency and 1 byte/ns:
+    let mut tuner = BlobSizeTuner::new(1024, 1024 * 1024);
+    tuner.observe(1024, 10000 + 1024);
+    tuner.observe(4096, 10000 + 4096);
+    let size = tuner.next_size(4096);
+    assert!(size > 99000 && size < 101000);
+  }
+
+  /// Counts the blobs retrieved from a `MemoryBackend`.
+  #[deriving(Clone)]
+  struct CountingBackend {
+    inner: MemoryBackend,
+    retrieved: Arc<Mutex<uint>>,
+  }
+
+  impl BlobStoreBackend for CountingBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+      self.inner.store(name, data)
+    }
+
+    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+      *self.retrieved.lock() += 1;
+      self.inner.retrieve(name)
+    }
+  }
+
+  #[test]
+  fn interrupted_uploads_are_resumed() {
+    let (dir, spool) = (TempDir::new("hat-blobs").unwrap(), TempDir::new("hat-spool").unwrap());
+    let mut backend = FileBackend::new(dir.path().clone());
+    let biP: BlobIndexProcess = Process::new(proc() { BlobIndex::new_for_testing() });
+    fn reserve(biP: &BlobIndexProcess) -> BlobDesc {
+      match biP.send_reply(blob_index::Reserve) {
+        blob_index::Reserved(desc) => desc,
+        _ => fail!("Unexpected reply from blob index."),
+      }
+    }
+
+    // The process stopped while two blobs were in air. Part of the first one was uploaded, and
+    // its local copy was kept; the second one has no local copy.
+    let blob = Vec::from_elem(1000, 7u8);
+    let (spooled, lost) = (reserve(&biP), reserve(&biP));
+    biP.send_reply(blob_index::InAir(spooled.clone(), blob_checksum(blob.as_slice())));
+    biP.send_reply(blob_index::InAir(lost.clone(), blob_checksum(b"lost")));
+    let spool_path = spool.path().join(spooled.name.as_slice().to_hex());
+    File::create(&spool_path).write(blob.as_slice()).unwrap();
+    backend.append(spooled.name.as_slice(), blob.slice_to(300)).unwrap();
+
+    let mut bs = BlobStore::new(biP.clone(), backend.clone(), 1024, Some(spool.path().clone()));
+    assert_eq!(bs.resume_uploads(), 1);
+    assert_eq!(backend.retrieve(spooled.name.as_slice()), Ok(blob));
+    assert!(!spool_path.exists());
+
+    // The resumed blob is committed; the other one is left for recovery to roll back:
+    match biP.send_reply(blob_index::ListCommitted) {
+      blob_index::BlobNames(names) => assert_eq!(names, vec![spooled.name.clone()]),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+    match biP.send_reply(blob_index::ListInAir) {
+      blob_index::InAirBlobs(blobs) => assert_eq!(blobs, vec![lost]),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+  }
+
+  #[test]
+  fn list_and_delete_blobs() {
+    let backend = MemoryBackend::new();
+    let local_backend = backend.clone();
+    let bsP: BlobStoreProcess<MemoryBackend> =
+      Process::new(proc() { BlobStore::new_for_testing(local_backend, 1024) });
+    let id = match bsP.send_reply(Store(b"data".into_vec(), proc(_) {})) {
+      StoreOK(id) => id,
+      _ => fail!("Unexpected reply from blob store."),
+    };
+    assert_eq!(bsP.send_reply(DeleteBlob(id.name.clone())),
+               DeleteResult(Err("The blob is still being written.".to_string())));
+    bsP.send_reply(Flush);
+
+    assert_eq!(bsP.send_reply(ListBlobs), BlobList(Ok(vec![id.name.clone()])));
+    assert_eq!(bsP.send_reply(DeleteBlob(id.name.clone())), DeleteResult(Ok(())));
+    assert_eq!(bsP.send_reply(ListBlobs), BlobList(Ok(vec![])));
+    assert_eq!(backend.len(), 0);
+  }
+
+  #[test]
+  fn planned_reads_fetch_blobs_once() {
+    let retrieved = Arc::new(Mutex::new(0u));
+    let backend = CountingBackend{inner: MemoryBackend::new(), retrieved: retrieved.clone()};
+    let bsP: BlobStoreProcess<CountingBackend> =
+      Process::new(proc() { BlobStore::new_for_testing(backend, 1024) });
+
+    let ids: Vec<BlobID> = range(0u8, 3).map(|i| {
+      match bsP.send_reply(Store(Vec::from_elem(100, i), proc(_) {})) {
+        StoreOK(id) => id,
+        _ => fail!("Unexpected reply from blob store."),
+      }
+    }).collect();
+    bsP.send_reply(Flush);
+    assert!(ids.iter().all(|id| id.name == ids[0].name));
+
+    bsP.send_reply(PlanReads(Vec::from_elem(6, ids[0].name.clone())));
+    for _ in range(0u, 2) {
+      for (i, id) in ids.iter().enumerate() {
+        assert_eq!(bsP.send_reply(Retrieve(id.clone())), RetrieveOK(Vec::from_elem(100, i as u8)));
+      }
+    }
+    assert_eq!(*retrieved.lock(), 1);
+
+    // Reads beyond the plan fetch the blob again:
+    bsP.send_reply(Retrieve(ids[0].clone()));
+    assert_eq!(*retrieved.lock(), 2);
+  }
+
+  #[test]
+  fn retention_only_grows() {
+    let mut backend = MemoryBackend::new();
+    assert!(backend.supports_retention());
+    backend.store([1], b"locked").unwrap();
+
+    let until = time::get_time().sec as u64 + 60;
+    backend.set_retention([1], until).unwrap();
+    assert_eq!(backend.retention_of([1]), Some(until));
+
+    // Retention can be extended, but not shortened, and only of stored blobs:
+    backend.set_retention([1], until + 60).unwrap();
+    assert!(backend.set_retention([1], until).is_err());
+    assert_eq!(backend.retention_of([1]), Some(until + 60));
+    assert!(backend.set_retention([2], until).is_err());
+    assert!(!FileBackend::new(Path::new("unused")).supports_retention());
+
+    // Locked blobs cannot be deleted:
+    backend.store([3], b"unlocked").unwrap();
+    assert!(backend.delete([1]).is_err());
+    backend.delete([3]).unwrap();
+  }
+
+  #[test]
+  fn blobs_are_verified_against_their_checksum() {
+    let checksum = blob_checksum(b"data");
+    assert_eq!(verify_blob([1], b"data", Some(checksum.as_slice())), Ok(()));
+    assert_eq!(verify_blob([1], b"date", None), Ok(()));
+    let error = verify_blob([1], b"date", Some(checksum.as_slice())).unwrap_err();
+    assert!(error.as_slice().starts_with("Blob 01 is corrupt"), "{}", error);
+  }
+
+  #[test]
+  fn corrupt_blobs_are_not_read() {
+    let mut backend = MemoryBackend::new();
+    let local_backend = backend.clone();
+    let bsP: BlobStoreProcess<MemoryBackend> =
+      Process::new(proc() { BlobStore::new_for_testing(local_backend, 1024) });
+    let id = match bsP.send_reply(Store(b"data".into_vec(), proc(_) {})) {
+      StoreOK(id) => id,
+      _ => fail!("Unexpected reply from blob store."),
+    };
+    bsP.send_reply(Flush);
+    assert_eq!(bsP.send_reply(Retrieve(id.clone())), RetrieveOK(b"data".into_vec()));
+
+    backend.delete(id.name.as_slice()).unwrap();
+    backend.store(id.name.as_slice(), b"date").unwrap();
+    let read = task::try(proc() { bsP.send_reply(Retrieve(id)) });
+    assert!(read.is_err());
+  }
+
+  #[test]
+  fn backend_check() {
+    let mut backend = MemoryBackend::new();
+    for &(step, ref result) in check_backend(&mut backend).iter() {
+      assert!(result.is_ok(), "{}: {}", step, result);
+    }
+    assert_eq!(backend.list(), Ok(vec![]));
+
+    let failed: Vec<&'static str> = check_backend(&mut DevNullBackend).into_iter()
+      .filter(|&(_, ref result)| result.is_err()).map(|(step, _)| step).collect();
+    assert_eq!(failed, vec!["read it back", "list blobs", "delete the probe blob"]);
+  }
+
+  #[test]
+  fn backend_probe() {
+    let mut backend = MemoryBackend::new();
+    let probe = backend.probe().unwrap();
+    assert!(probe.supports_delete && probe.supports_listing);
+    assert_eq!(probe.free_bytes, None);
+    assert_eq!(backend.len(), 0);
+
+    // A backend that does not keep what is written is not usable:
+    assert!(DevNullBackend.probe().is_err());
+
+    let dir = TempDir::new("hat-probe").unwrap();
+    let probe = FileBackend::new(dir.path().clone()).probe().unwrap();
+    assert!(probe.supports_delete && probe.supports_listing);
+  }
+
+  #[test]
+  fn memory_backend_is_shared_between_clones() {
+    let mut backend = MemoryBackend::new();
+    let mut clone = backend.clone();
+    backend.store([1], b"blob").unwrap();
+    assert!(clone.store([1], b"other").is_err());
+    assert_eq!(clone.retrieve([1]), Ok(b"blob".to_vec()));
+    assert_eq!(clone.stored_checksum([1]), Ok(Some(blob_checksum(b"blob"))));
+    assert_eq!((backend.len(), backend.stored_bytes()), (1, 4));
+  }
+
+  #[test]
+  fn boxed_backends() {
+    let memory = MemoryBackend::new();
+    let dir = TempDir::new("hat-boxed-backend").unwrap();
+    let backends = vec![BoxedBackend::new(memory.clone()),
+                        BoxedBackend::new(FileBackend::new(dir.path().clone()))];
+    for backend in backends.into_iter() {
+      let bsP: BlobStoreProcess<BoxedBackend> =
+        Process::new(proc() { BlobStore::new_for_testing(backend, 1024) });
+      let id = match bsP.send_reply(Store(b"data".into_vec(), proc(_) {})) {
+        StoreOK(id) => id,
+        _ => fail!("Unexpected reply from blob store."),
+      };
+      bsP.send_reply(Flush);
+      assert_eq!(bsP.send_reply(Retrieve(id)), RetrieveOK(b"data".into_vec()));
+    }
+    assert_eq!(memory.len(), 1);
+
+    let mut boxed = BoxedBackend::new(memory.clone());
+    assert!(boxed.supports_listing());
+    assert_eq!(boxed.clone().list().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn encrypted_backends_hide_names_and_data() {
+    let memory = MemoryBackend::new();
+    let cipher = BlobCipher::new(&RepositoryKey::new(b"secret"));
+    let mut backend = EncryptedBackend::new(memory.clone(), Some(cipher.clone()));
+    backend.store([1, 2], b"some blob data").unwrap();
+    backend.store_with_hint([3], b"more data", InfrequentAccess).unwrap();
+    assert_eq!(backend.retrieve([1, 2]), Ok(b"some blob data".into_vec()));
+    assert!(!backend.supports_resume());
+    assert_eq!(backend.stored_checksum([1, 2]), Ok(None));
+
+    let mut stored = memory.clone();
+    let names = stored.list().unwrap();
+    assert_eq!(names.len(), 2);
+    for name in names.iter() {
+      assert!(*name != vec![1, 2] && *name != vec![3]);
+      let data = stored.retrieve(name.as_slice()).unwrap();
+      assert!(!data.as_slice().windows(4).any(|w| w == b"data"));
+    }
+    assert_eq!(memory.hint_of(cipher.seal_name([3]).as_slice()), Some(InfrequentAccess));
+
+    let mut names = backend.list().unwrap();
+    names.sort();
+    assert_eq!(names, vec![vec![1, 2], vec![3]]);
+    backend.delete([3]).unwrap();
+    assert_eq!(memory.len(), 1);
+
+    // Another key finds nothing, and cannot read what it is given:
+    let other = BlobCipher::new(&RepositoryKey::new(b"other secret"));
+    let mut wrong = EncryptedBackend::new(memory.clone(), Some(other.clone()));
+    assert_eq!(wrong.list(), Ok(vec![]));
+    assert!(wrong.retrieve([1, 2]).is_err());
+    assert!(other.open(stored.retrieve(cipher.seal_name([1, 2]).as_slice()).unwrap()
+                            .as_slice()).is_none());
+
+    // Without a cipher, blobs pass through:
+    let mut plain = EncryptedBackend::new(memory.clone(), None);
+    plain.store([4], b"plain").unwrap();
+    assert_eq!(stored.retrieve([4]), Ok(b"plain".into_vec()));
+  }
+
+  #[test]
+  fn locked_blobs_cannot_be_deleted() {
+    let memory = MemoryBackend::new();
+    let cipher = BlobCipher::new(&RepositoryKey::new(b"secret"));
+    let encrypted = EncryptedBackend::new(memory.clone(), Some(cipher.clone()));
+    let mut backend = TieredBackend::new(encrypted, FileBackend::new(Path::new("unused")));
+    assert!(backend.supports_retention());
+    backend.store([1], b"locked").unwrap();
+    backend.store([2], b"unlocked").unwrap();
+
+    let until = time::get_time().sec as u64 + 60;
+    backend.set_retention([1], until).unwrap();
+    assert_eq!(memory.retention_of(cipher.seal_name([1]).as_slice()), Some(until));
+    assert!(backend.delete([1]).is_err());
+    backend.delete([2]).unwrap();
+
+    // Retention can be extended, but not shortened:
+    backend.set_retention([1], until + 60).unwrap();
+    assert!(backend.set_retention([1], until).is_err());
+    assert!(backend.set_retention([2], until).is_err());
+    assert!(!FileBackend::new(Path::new("unused")).supports_retention());
+  }
+
+  #[test]
+  fn storage_hints() {
+    let mut backend = MemoryBackend::new();
+    backend.store([1], b"blob").unwrap();
+    backend.store_with_hint([2], b"blob", InfrequentAccess).unwrap();
+    assert_eq!(backend.hint_of([1]), None);
+    assert_eq!(backend.hint_of([2]), Some(InfrequentAccess));
+
+    // Hints go to the backend that holds the blob:
+    let archive = MemoryBackend::new();
+    let mut tiered = TieredBackend::new(backend.clone(), archive.clone());
+    tiered.copy_to_archive([1]).unwrap();
+    tiered.mark_archived(vec![vec![1]], true);
+    tiered.set_storage_hint([1], ArchiveAccess).unwrap();
+    tiered.set_storage_hint([2], FrequentAccess).unwrap();
+    assert_eq!((archive.hint_of([1]), backend.hint_of([1])), (Some(ArchiveAccess), None));
+    assert_eq!(backend.hint_of([2]), Some(FrequentAccess));
+    assert!(tiered.set_storage_hint([3], ArchiveAccess).is_err());
+
+    assert!(DevNullBackend.set_storage_hint([1], ArchiveAccess).is_err());
+    assert_eq!(StorageHint::from_code(InfrequentAccess.code()), Some(InfrequentAccess));
+    assert_eq!(StorageHint::from_name("archive"), Some(ArchiveAccess));
+  }
+
+  #[test]
+  fn file_backend_layout_and_resume() {
+    let dir = TempDir::new("hat-file-backend").unwrap();
+    let mut backend = FileBackend::new(dir.path().clone());
+
+    backend.store([0xab, 0x01], b"blob").unwrap();
+    assert!(dir.path().join("ab").join("ab01").exists());
+    assert!(!dir.path().join("ab").join("ab01.part").exists());
+
+    // Blobs stored in the top directory by earlier versions:
+    File::create(&dir.path().join("cd02")).and_then(|mut f| f.write(b"old")).unwrap();
+    assert_eq!(backend.retrieve([0xcd, 0x02]), Ok(b"old".into_vec()));
+
+    // A resumed upload only appears under its name once it is finished:
+    backend.append([0xef], b"first ").unwrap();
+    assert_eq!(backend.stored_length([0xef]), Ok(6));
+    let mut names = backend.list().unwrap();
+    names.sort();
+    assert_eq!(names, vec![vec![0xab, 0x01], vec![0xcd, 0x02]]);
+
+    backend.append([0xef], b"second").unwrap();
+    backend.finish_append([0xef]).unwrap();
+    backend.finish_append([0xef]).unwrap();
+    assert_eq!(backend.stored_length([0xef]), Ok(12));
+    assert_eq!(backend.retrieve([0xef]), Ok(b"first second".into_vec()));
+
+    backend.delete([0xcd, 0x02]).unwrap();
+    backend.delete([0xef]).unwrap();
+    assert_eq!(backend.list(), Ok(vec![vec![0xab, 0x01]]));
+  }
+
 }
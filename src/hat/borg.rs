@@ -0,0 +1,198 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Import of Borg archives.
+//!
+//! Each archive of a Borg repository becomes a snapshot of a family, oldest first, so that the
+//! history is preserved. Archives are streamed through `borg export-tar` and never extracted to
+//! disk. The `borg` command must be installed, and is asked for the repository passphrase in the
+//! usual way (e.g. `BORG_PASSPHRASE`).
+
+use blob_store::{BlobStoreBackend};
+use hat::{Family};
+
+use serialize::{Decodable};
+use serialize::json;
+
+use std::io::process::{Command, InheritFd};
+use std::str;
+use time;
+
+
+#[deriving(Decodable)]
+struct ArchiveListing {
+  name: String,
+  start: String,
+}
+
+#[deriving(Decodable)]
+struct RepositoryListing {
+  archives: Vec<ArchiveListing>,
+}
+
+#[deriving(Clone, Show)]
+pub struct BorgArchive {
+  pub name: String,
+
+  /// When the archive was started, in seconds since the epoch. Borg reports local times without
+  /// a timezone; they are taken as UTC. Newer versions add the timezone, which is honoured.
+  pub started: u64,
+}
+
+/// Parse the output of `borg list --json`: the archives it lists, oldest first.
+pub fn parse_archives(out: &[u8]) -> Result<Vec<BorgArchive>, String> {
+  let json = match str::from_utf8(out).and_then(|s| json::from_str(s).ok()) {
+    Some(json) => json,
+    None => return Err("borg list printed invalid JSON".to_string()),
+  };
+  let listing: RepositoryListing = match Decodable::decode(&mut json::Decoder::new(json)) {
+    Ok(listing) => listing,
+    Err(e) => return Err(format!("Unexpected output of borg list: {}", e)),
+  };
+
+  let mut archives = Vec::new();
+  for archive in listing.archives.into_iter() {
+    let started = match parse_start(archive.start.as_slice()) {
+      Some(started) => started,
+      None => return Err(format!("Archive '{}' has an invalid start time: {}", archive.name,
+                                 archive.start)),
+    };
+    archives.push(BorgArchive{name: archive.name, started: started});
+  }
+  archives.sort_by(|a, b| a.started.cmp(&b.started));
+  Ok(archives)
+}
+
+/// Parse an archive start time, e.g. "2017-08-07T12:27:20.000000" or "2017-08-07T14:27:20+02:00",
+/// into seconds since the epoch. Fractions of a second are left out; without a timezone, the time
+/// is taken as UTC.
+fn parse_start(start: &str) -> Option<u64> {
+  if start.len() < 19 || !start.bytes().all(|b| b < 128) { return None }
+  let seconds = match time::strptime(start.slice_to(19), "%Y-%m-%dT%H:%M:%S") {
+    Ok(tm) => tm.to_timespec().sec,
+    Err(_) => return None,
+  };
+
+  let mut rest = start.slice_from(19);
+  if rest.starts_with(".") {
+    let digits = rest.slice_from(1).bytes().take_while(|b| *b >= b'0' && *b <= b'9').count();
+    rest = rest.slice_from(1 + digits);
+  }
+  let offset = match rest {
+    "" | "Z" => 0,
+    _ if rest.len() == 5 || (rest.len() == 6 && rest.char_at(3) == ':') => {
+      let sign = match rest.char_at(0) { '+' => 1, '-' => -1, _ => return None };
+      let hours = from_str::<i64>(rest.slice(1, 3));
+      let minutes = from_str::<i64>(rest.slice_from(rest.len() - 2));
+      match (hours, minutes) {
+        (Some(hours), Some(minutes)) if hours >= 0 && minutes >= 0 && minutes < 60 => {
+          sign * (hours * 60 + minutes) * 60
+        },
+        _ => return None,
+      }
+    },
+    _ => return None,
+  };
+  let seconds = seconds - offset;
+  if seconds < 0 { None } else { Some(seconds as u64) }
+}
+
+/// List the archives in the Borg repository `repository`, oldest first.
+pub fn list_archives(repository: &str) -> Result<Vec<BorgArchive>, String> {
+  let out = match Command::new("borg").arg("list").arg("--json").arg(repository)
+                                      .stdin(InheritFd(0)).output() {
+    Ok(ref out) if out.status.success() => out.output.clone(),
+    Ok(out) => return Err(format!("borg list failed ({}): {}", out.status,
+                                  String::from_utf8_lossy(out.error.as_slice()))),
+    Err(e) => return Err(format!("borg: {}", e)),
+  };
+  parse_archives(out.as_slice())
+}
+
+/// Snapshot `archive` of the Borg repository `repository` into `family`, and publish the snapshot
+/// with the archive's start time. Returns the number of files and directories imported.
+pub fn import_archive<B: BlobStoreBackend + Clone + Send>(family: &Family<B>, repository: &str,
+                                                          archive: &BorgArchive)
+                                                          -> Result<uint, String> {
+  let location = format!("{}::{}", repository, archive.name);
+  // Borg may ask for the passphrase, and report problems, on the terminal:
+  let mut process = match Command::new("borg").arg("export-tar").arg(location.as_slice())
+                                              .arg("-").stdin(InheritFd(0)).stderr(InheritFd(2))
+                                              .spawn() {
+    Ok(process) => process,
+    Err(e) => return Err(format!("borg: {}", e)),
+  };
+  let stdout = process.stdout.take().expect("stdout is piped");
+  let imported = family.snapshot_tar(stdout, Some(archive.started));
+
+  let status = match process.wait() {
+    Ok(status) => status,
+    Err(e) => return Err(format!("borg: {}", e)),
+  };
+  let count = match imported {
+    Ok(count) => count,
+    Err(e) => return Err(format!("Could not read archive '{}': {}", archive.name, e)),
+  };
+  if !status.success() {
+    return Err(format!("borg export-tar failed for archive '{}' ({})", archive.name, status));
+  }
+
+  // Only publish the snapshot once the whole archive has been read:
+  family.flush();
+  Ok(count)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn started(out: &str) -> Result<Vec<(String, u64)>, String> {
+    parse_archives(out.as_bytes()).map(|archives| {
+      archives.into_iter().map(|a| (a.name, a.started)).collect()
+    })
+  }
+
+  #[test]
+  fn archives_are_listed_oldest_first() {
+    let out = r#"{"archives": [
+      {"name": "new", "start": "2017-08-07T12:27:20.000000", "id": "ab"},
+      {"name": "old", "start": "2016-01-02T03:04:05.123456", "id": "cd"}],
+      "repository": {"id": "ef"}}"#;
+    assert_eq!(started(out), Ok(vec![("old".to_string(), 1451703845),
+                                     ("new".to_string(), 1502108840)]));
+    assert_eq!(started(r#"{"archives": []}"#), Ok(vec![]));
+  }
+
+  #[test]
+  fn start_times_may_have_a_timezone() {
+    for start in ["2017-08-07T12:27:20", "2017-08-07T12:27:20.5", "2017-08-07T12:27:20Z",
+                  "2017-08-07T14:27:20.000000+02:00", "2017-08-07T10:57:20-0130"].iter() {
+      let out = format!(r#"{{"archives": [{{"name": "a", "start": "{}"}}]}}"#, start);
+      assert_eq!(started(out.as_slice()), Ok(vec![("a".to_string(), 1502108840)]));
+    }
+  }
+
+  #[test]
+  fn malformed_listings_are_errors() {
+    for out in ["", "{", "[]", r#"{"archives": [{"name": "a"}]}"#,
+                r#"{"archives": [{"name": "a", "start": "2017-08-07"}]}"#,
+                r#"{"archives": [{"name": "a", "start": "2017-08-07T12:27:2é"}]}"#,
+                r#"{"archives": [{"name": "a", "start": "2017-08-07T12:27:20 CEST"}]}"#,
+                r#"{"archives": [{"name": "a", "start": "2017-08-07T12:27:20+02:75"}]}"#].iter() {
+      assert!(started(*out).is_err(), "{}", out);
+    }
+    assert!(parse_archives(b"\xff").is_err());
+  }
+}
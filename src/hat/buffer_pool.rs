@@ -0,0 +1,91 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A pool of reusable chunk-sized buffers, shared between tasks.
+//!
+//! Files are read into buffers taken from the pool, and the buffers are given back once their
+//! data has been copied into a blob. Reusing them saves allocating and freeing a chunk-sized
+//! buffer for every read.
+
+use std::sync::{Arc, Mutex};
+
+
+#[deriving(Clone)]
+pub struct BufferPool {
+  buffers: Arc<Mutex<Vec<Vec<u8>>>>,
+  buffer_size: uint,
+  max_buffers: uint,
+}
+
+impl BufferPool {
+
+  /// Create a pool of buffers with room for `buffer_size` bytes, keeping at most `max_buffers`
+  /// unused buffers around.
+  pub fn new(buffer_size: uint, max_buffers: uint) -> BufferPool {
+    BufferPool{buffers: Arc::new(Mutex::new(Vec::new())),
+               buffer_size: buffer_size,
+               max_buffers: max_buffers}
+  }
+
+  /// The capacity of the buffers handed out by this pool.
+  pub fn buffer_size(&self) -> uint {
+    self.buffer_size
+  }
+
+  /// An empty buffer with room for at least `buffer_size` bytes.
+  pub fn get(&self) -> Vec<u8> {
+    match self.buffers.lock().pop() {
+      Some(buffer) => buffer,
+      None => Vec::with_capacity(self.buffer_size),
+    }
+  }
+
+  /// Give back a buffer that is no longer used. Buffers that are too small (e.g. not taken from
+  /// the pool), or that exceed the number of buffers to keep, are freed.
+  pub fn put(&self, buffer: Vec<u8>) {
+    if buffer.capacity() < self.buffer_size {
+      return;
+    }
+    let mut buffers = self.buffers.lock();
+    if buffers.len() < self.max_buffers {
+      let mut buffer = buffer;
+      buffer.clear();
+      buffers.push(buffer);
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn reuses_buffers() {
+    let pool = BufferPool::new(100, 1);
+    let mut buffer = pool.get();
+    assert!(buffer.capacity() >= 100);
+    buffer.push_all([1u8, 2, 3]);
+    let address = buffer.as_ptr();
+    pool.put(buffer);
+
+    let buffer = pool.get();
+    assert_eq!(buffer.len(), 0);
+    assert_eq!(buffer.as_ptr(), address);
+
+    // Small buffers are not kept:
+    pool.put(Vec::with_capacity(10));
+    assert!(pool.get().capacity() >= 100);
+  }
+}
@@ -0,0 +1,445 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Bundles: a single snapshot exported to one self-contained, encrypted file.
+//!
+//! A bundle holds the directories and files of a snapshot with a little metadata, and optionally
+//! the secrets of the repository it came from, so that it can be restored into any repository
+//! (e.g. after carrying it over on removable media, or from write-once archival media).
+//!
+//! The file starts with a magic string and a random nonce prefix, followed by frames that are
+//! each sealed with `secretbox` under a key derived from the bundle secret. The nonce of a frame
+//! is the prefix followed by the frame's number, so frames cannot be reordered, and the last
+//! frame is marked as such, so a truncated bundle is detected.
+//!
+//! Inside the frames, the metadata (as JSON) is followed by one record per entry, directories
+//! before their contents: a kind (`d` or `f`), the modification time and the path, and for files
+//! the data as length-prefixed pieces ending with an empty one. A record of kind `e` ends the
+//! bundle.
+
+use keys::{RepositoryKey};
+use tar;
+
+use serialize::{json, Decodable};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes::{randombytes};
+
+use std::cmp;
+use std::io::{IoError, IoResult, Reader, Writer, EndOfFile, InvalidInput};
+use std::slice::bytes::{copy_memory};
+use std::str;
+
+
+static MAGIC: &'static [u8] = b"HATBNDL1";
+static NONCE_PREFIX_BYTES: uint = 16;
+
+/// The largest number of bytes sealed in one frame.
+static FRAME_SIZE: uint = 1 << 20;
+
+/// A sealed frame holds a flag byte and an authenticator besides its data.
+static MAX_SEALED_FRAME: uint = FRAME_SIZE + 1 + 16;
+
+#[deriving(Clone, Show, PartialEq, Encodable, Decodable)]
+pub struct BundleInfo {
+  /// The family that the snapshot belongs to.
+  pub family: String,
+  /// The start time of the snapshot in seconds since the epoch, if it is known.
+  pub started: Option<u64>,
+  /// When the bundle was written, in seconds since the epoch.
+  pub created: u64,
+  /// The hex-encoded secrets of the repository's index key and chunk key, if they are included.
+  pub index_key: Option<String>,
+  pub chunk_key: Option<String>,
+}
+
+fn invalid(detail: &str) -> IoError {
+  IoError{kind: InvalidInput, desc: "Invalid bundle", detail: Some(detail.to_string())}
+}
+
+/// The end of the stream inside the frames is only expected after the end record.
+fn truncated(e: IoError) -> IoError {
+  if e.kind == EndOfFile { invalid("the bundle ends early") } else { e }
+}
+
+fn bundle_key(key: &RepositoryKey) -> secretbox::Key {
+  let derived = key.derive("bundle");
+  let mut bytes = [0u8, ..secretbox::KEYBYTES];
+  copy_memory(bytes, derived.as_slice());
+  secretbox::Key(bytes)
+}
+
+fn frame_nonce(prefix: &[u8], number: u64) -> secretbox::Nonce {
+  let mut bytes = [0u8, ..secretbox::NONCEBYTES];
+  copy_memory(bytes, prefix);
+  for i in range(0u, 8) {
+    bytes[NONCE_PREFIX_BYTES + i] = (number >> (56 - 8 * i)) as u8;
+  }
+  secretbox::Nonce(bytes)
+}
+
+
+/// Seals what is written to it into frames.
+struct SealedWriter<W> {
+  writer: W,
+  key: secretbox::Key,
+  prefix: Vec<u8>,
+  frames: u64,
+  buffer: Vec<u8>,
+}
+
+impl <W: Writer> SealedWriter<W> {
+  fn new(writer: W, key: &RepositoryKey) -> IoResult<SealedWriter<W>> {
+    let mut writer = writer;
+    let prefix = randombytes(NONCE_PREFIX_BYTES);
+    try!(writer.write(MAGIC));
+    try!(writer.write(prefix.as_slice()));
+    Ok(SealedWriter{writer: writer, key: bundle_key(key), prefix: prefix, frames: 0,
+                    buffer: Vec::new()})
+  }
+
+  fn write_frame(&mut self, last: bool) -> IoResult<()> {
+    let size = cmp::min(self.buffer.len(), FRAME_SIZE);
+    let mut frame = vec![if last { 1u8 } else { 0u8 }];
+    frame.push_all(self.buffer.slice_to(size));
+    let sealed = secretbox::seal(frame.as_slice(),
+                                 &frame_nonce(self.prefix.as_slice(), self.frames), &self.key);
+    self.frames += 1;
+    try!(self.writer.write_be_u32(sealed.len() as u32));
+    try!(self.writer.write(sealed.as_slice()));
+    self.buffer = self.buffer.slice_from(size).into_vec();
+    Ok(())
+  }
+
+  /// Seal what is left as the last frame.
+  fn finish(self) -> IoResult<W> {
+    let mut sealed = self;
+    try!(sealed.write_frame(true));
+    try!(sealed.writer.flush());
+    Ok(sealed.writer)
+  }
+}
+
+impl <W: Writer> Writer for SealedWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    self.buffer.push_all(buf);
+    // A full frame is kept back, so that `finish` always has a last frame to seal:
+    while self.buffer.len() > FRAME_SIZE {
+      try!(self.write_frame(false));
+    }
+    Ok(())
+  }
+}
+
+
+/// Reads the frames of a `SealedWriter`. Reading ends after the last frame.
+struct SealedReader<R> {
+  reader: R,
+  key: secretbox::Key,
+  prefix: Vec<u8>,
+  frames: u64,
+  frame: Vec<u8>,
+  pos: uint,
+  last: bool,
+}
+
+impl <R: Reader> SealedReader<R> {
+  fn new(reader: R, key: &RepositoryKey) -> IoResult<SealedReader<R>> {
+    let mut reader = reader;
+    match reader.read_exact(MAGIC.len()) {
+      Ok(ref magic) if magic.as_slice() == MAGIC => (),
+      Ok(_) => return Err(invalid("not a hat bundle")),
+      Err(e) => return Err(truncated(e)),
+    }
+    let prefix = try!(reader.read_exact(NONCE_PREFIX_BYTES).map_err(truncated));
+    Ok(SealedReader{reader: reader, key: bundle_key(key), prefix: prefix, frames: 0,
+                    frame: Vec::new(), pos: 0, last: false})
+  }
+
+  fn read_frame(&mut self) -> IoResult<()> {
+    let size = try!(self.reader.read_be_u32().map_err(truncated)) as uint;
+    if size > MAX_SEALED_FRAME {
+      return Err(invalid("the bundle is corrupt"));
+    }
+    let sealed = try!(self.reader.read_exact(size).map_err(truncated));
+    let frame = match secretbox::open(sealed.as_slice(),
+                                      &frame_nonce(self.prefix.as_slice(), self.frames),
+                                      &self.key) {
+      Some(frame) if frame.len() > 0 => frame,
+      _ => return Err(invalid("wrong bundle key, or the bundle is corrupt")),
+    };
+    self.frames += 1;
+    self.last = frame[0] == 1;
+    self.frame = frame;
+    self.pos = 1;
+    Ok(())
+  }
+}
+
+impl <R: Reader> Reader for SealedReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    while self.pos == self.frame.len() {
+      if self.last {
+        return Err(IoError{kind: EndOfFile, desc: "end of bundle", detail: None});
+      }
+      try!(self.read_frame());
+    }
+    let n = cmp::min(buf.len(), self.frame.len() - self.pos);
+    copy_memory(buf, self.frame.slice(self.pos, self.pos + n));
+    self.pos += n;
+    Ok(n)
+  }
+}
+
+
+/// Writes the entries of a snapshot to a bundle.
+pub struct BundleWriter<W> {
+  sealed: SealedWriter<W>,
+  // Whether the data of a file is being written:
+  in_file: bool,
+}
+
+impl <W: Writer> BundleWriter<W> {
+
+  /// Start a bundle with `info`, sealed with a key derived from `key`.
+  pub fn new(writer: W, key: &RepositoryKey, info: &BundleInfo) -> IoResult<BundleWriter<W>> {
+    let mut sealed = try!(SealedWriter::new(writer, key));
+    let json = json::encode(info);
+    try!(sealed.write_be_u32(json.len() as u32));
+    try!(sealed.write(json.as_bytes()));
+    Ok(BundleWriter{sealed: sealed, in_file: false})
+  }
+
+  /// Add a directory. `modified` is in seconds since the epoch.
+  pub fn add_dir(&mut self, path: &[u8], modified: u64) -> IoResult<()> {
+    self.add_record(b'd', path, modified)
+  }
+
+  /// Add a file, whose data follows with `add_data`.
+  pub fn add_file(&mut self, path: &[u8], modified: u64) -> IoResult<()> {
+    try!(self.add_record(b'f', path, modified));
+    self.in_file = true;
+    Ok(())
+  }
+
+  /// Add a piece of data to the file added last.
+  pub fn add_data(&mut self, data: &[u8]) -> IoResult<()> {
+    assert!(self.in_file);
+    if data.len() == 0 {
+      return Ok(());  // An empty piece ends the data.
+    }
+    try!(self.sealed.write_be_u32(data.len() as u32));
+    self.sealed.write(data)
+  }
+
+  /// End the bundle, and return the underlying writer.
+  pub fn finish(self) -> IoResult<W> {
+    let mut bundle = self;
+    try!(bundle.end_data());
+    try!(bundle.sealed.write_u8(b'e'));
+    bundle.sealed.finish()
+  }
+
+  fn add_record(&mut self, kind: u8, path: &[u8], modified: u64) -> IoResult<()> {
+    try!(self.end_data());
+    try!(self.sealed.write_u8(kind));
+    try!(self.sealed.write_be_u64(modified));
+    try!(self.sealed.write_be_u32(path.len() as u32));
+    self.sealed.write(path)
+  }
+
+  fn end_data(&mut self) -> IoResult<()> {
+    if !self.in_file {
+      return Ok(());
+    }
+    self.in_file = false;
+    self.sealed.write_be_u32(0)
+  }
+}
+
+
+/// Reads the entries of a bundle. Like `tar::TarReader`, the data of the current file is read
+/// through the `Reader` implementation. Headers of files have size 0, as it is not recorded.
+pub struct BundleReader<R> {
+  sealed: SealedReader<R>,
+  // Whether the data of a file is being read, and the bytes left of its current piece:
+  in_file: bool,
+  remaining: uint,
+  ended: bool,
+}
+
+impl <R: Reader> BundleReader<R> {
+
+  /// Open a bundle sealed with a key derived from `key`, and read its metadata.
+  pub fn open(reader: R, key: &RepositoryKey) -> IoResult<(BundleInfo, BundleReader<R>)> {
+    let mut sealed = try!(SealedReader::new(reader, key));
+    let size = try!(sealed.read_be_u32().map_err(truncated)) as uint;
+    let text = try!(sealed.read_exact(size).map_err(truncated));
+    let json = match str::from_utf8(text.as_slice()).and_then(|s| json::from_str(s).ok()) {
+      Some(json) => json,
+      None => return Err(invalid("invalid metadata")),
+    };
+    let info: BundleInfo = match Decodable::decode(&mut json::Decoder::new(json)) {
+      Ok(info) => info,
+      Err(_) => return Err(invalid("invalid metadata")),
+    };
+    Ok((info, BundleReader{sealed: sealed, in_file: false, remaining: 0, ended: false}))
+  }
+
+  fn skip_data(&mut self) -> IoResult<()> {
+    let mut buf = [0u8, ..4096];
+    loop {
+      match self.read(buf) {
+        Ok(_) => (),
+        Err(ref e) if e.kind == EndOfFile => return Ok(()),
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+impl <R: Reader> tar::EntryReader for BundleReader<R> {
+
+  fn next_header(&mut self) -> IoResult<Option<tar::Header>> {
+    try!(self.skip_data());
+    if self.ended {
+      return Ok(None);
+    }
+
+    let kind = match try!(self.sealed.read_u8().map_err(truncated)) {
+      b'd' => tar::Directory,
+      b'f' => tar::RegularFile,
+      b'e' => {
+        self.ended = true;
+        return Ok(None);
+      },
+      _ => return Err(invalid("unknown entry")),
+    };
+    let modified = try!(self.sealed.read_be_u64().map_err(truncated));
+    let size = try!(self.sealed.read_be_u32().map_err(truncated)) as uint;
+    let path = try!(self.sealed.read_exact(size).map_err(truncated));
+    self.in_file = kind == tar::RegularFile;
+    Ok(Some(tar::Header{path: path, kind: kind, size: 0,
+                        mode: if kind == tar::Directory { 0o755 } else { 0o644 },
+                        modified: modified}))
+  }
+}
+
+/// Reads the data of the current file.
+impl <R: Reader> Reader for BundleReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    while self.remaining == 0 {
+      if !self.in_file {
+        return Err(IoError{kind: EndOfFile, desc: "end of bundle entry", detail: None});
+      }
+      self.remaining = try!(self.sealed.read_be_u32().map_err(truncated)) as uint;
+      if self.remaining == 0 {
+        self.in_file = false;
+      }
+    }
+    let want = cmp::min(buf.len(), self.remaining);
+    let n = try!(self.sealed.read(buf.slice_to_mut(want)).map_err(truncated));
+    self.remaining -= n;
+    Ok(n)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use keys::{RepositoryKey};
+  use tar::{EntryReader, Directory, RegularFile};
+
+  use std::io::{MemReader, MemWriter};
+
+  fn info() -> BundleInfo {
+    BundleInfo{family: "home".to_string(), started: Some(1234), created: 5678,
+               index_key: None, chunk_key: Some("00ff".to_string())}
+  }
+
+  fn bundle(key: &RepositoryKey, big: &[u8]) -> Vec<u8> {
+    let mut bundle = BundleWriter::new(MemWriter::new(), key, &info()).unwrap();
+    bundle.add_dir(b"d", 10).unwrap();
+    bundle.add_file(b"d/a", 20).unwrap();
+    bundle.add_data(b"hello, ").unwrap();
+    bundle.add_data(b"world").unwrap();
+    bundle.add_file(b"d/big", 30).unwrap();
+    bundle.add_data(big).unwrap();
+    bundle.add_file(b"empty", 40).unwrap();
+    bundle.finish().unwrap().unwrap()
+  }
+
+  #[test]
+  fn entries_and_data() {
+    let key = RepositoryKey::new(b"secret");
+    let big = Vec::from_elem(3 << 20, 7u8);  // Spans several frames.
+    let (found, mut reader) = BundleReader::open(MemReader::new(bundle(&key, big.as_slice())),
+                                                 &key).unwrap();
+    assert_eq!(found, info());
+
+    let dir = reader.next_header().unwrap().unwrap();
+    assert_eq!(dir.path, b"d".into_vec());
+    assert_eq!(dir.kind, Directory);
+    assert_eq!(dir.modified, 10);
+
+    let file = reader.next_header().unwrap().unwrap();
+    assert_eq!(file.path, b"d/a".into_vec());
+    assert_eq!(file.kind, RegularFile);
+    assert_eq!(reader.read_to_end().unwrap(), b"hello, world".into_vec());
+
+    // The data of this file is skipped:
+    assert_eq!(reader.next_header().unwrap().unwrap().path, b"d/big".into_vec());
+
+    let empty = reader.next_header().unwrap().unwrap();
+    assert_eq!(empty.modified, 40);
+    assert_eq!(reader.read_to_end().unwrap(), Vec::new());
+
+    assert!(reader.next_header().unwrap().is_none());
+  }
+
+  #[test]
+  fn wrong_key() {
+    let data = bundle(&RepositoryKey::new(b"secret"), b"");
+    assert!(BundleReader::open(MemReader::new(data), &RepositoryKey::new(b"other")).is_err());
+  }
+
+  /// Whether all entries of `data` can be read.
+  fn reads_completely(data: Vec<u8>, key: &RepositoryKey) -> bool {
+    let mut reader = match BundleReader::open(MemReader::new(data), key) {
+      Ok((_, reader)) => reader,
+      Err(_) => return false,
+    };
+    loop {
+      match reader.next_header() {
+        Ok(Some(_)) => (),
+        Ok(None) => return true,
+        Err(_) => return false,
+      }
+    }
+  }
+
+  #[test]
+  fn truncated_and_modified() {
+    let key = RepositoryKey::new(b"secret");
+    let data = bundle(&key, Vec::from_elem(2 << 20, 7u8).as_slice());
+    assert!(reads_completely(data.clone(), &key));
+
+    // Cut off in the last frame:
+    assert!(!reads_completely(data.slice_to(data.len() - 100).into_vec(), &key));
+
+    let mut modified = data.clone();
+    *modified.get_mut(1000) ^= 1;
+    assert!(!reads_completely(modified, &key));
+  }
+}
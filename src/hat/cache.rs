@@ -0,0 +1,367 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that keeps the blobs read from another (slow, remote) backend in a local
+//! directory, so that repeated checkouts and verification passes do not download them again.
+//!
+//! The cache holds at most a given number of bytes; when it is full, the blobs that were read
+//! least recently are evicted. Blobs are only cached when they are read, not when they are
+//! stored. The cache is disposable: deleting its directory loses nothing but the cached copies.
+//!
+//! Each cached copy starts with the checksum of the blob, so that a copy that was damaged on the
+//! local disk is dropped and the blob read again, rather than handed out.
+
+use blob_store::{BackendProbe, BlobStoreBackend, StorageHint, blob_checksum};
+
+use serialize::hex::{FromHex, ToHex};
+use sodiumoxide::randombytes::{randombytes};
+
+use std::collections::hashmap::{HashMap};
+use std::collections::treemap::{TreeMap};
+use std::io::{File, UserDir};
+use std::io::fs::{mkdir_recursive, readdir, rename, stat, unlink};
+use std::sync::{Arc, Mutex};
+
+
+/// The length of the checksum that each cached copy starts with.
+static CHECKSUM_LEN: uint = 32;
+
+/// Which blobs are cached, and in what order they were last read.
+struct CacheIndex {
+  max_bytes: u64,
+  bytes: u64,
+
+  // The size of each cached blob, and when it was last read:
+  entries: HashMap<Vec<u8>, (u64, u64)>,
+  by_use: TreeMap<u64, Vec<u8>>,
+  next_use: u64,
+}
+
+impl CacheIndex {
+  fn new(max_bytes: u64) -> CacheIndex {
+    CacheIndex{max_bytes: max_bytes, bytes: 0, entries: HashMap::new(), by_use: TreeMap::new(),
+               next_use: 0}
+  }
+
+  /// Mark blob `name` as just read. Returns whether it is cached.
+  fn touch(&mut self, name: &[u8]) -> bool {
+    let used = self.next_use;
+    match self.entries.find_mut(&name.into_vec()) {
+      None => return false,
+      Some(entry) => {
+        let (size, last_used) = *entry;
+        self.by_use.remove(&last_used);
+        *entry = (size, used);
+      },
+    }
+    self.by_use.insert(used, name.into_vec());
+    self.next_use += 1;
+    true
+  }
+
+  /// Add blob `name` of `size` bytes as just read. Returns the blobs that were evicted to make
+  /// room for it; if it is larger than the whole cache, it is not added.
+  fn insert(&mut self, name: &[u8], size: u64) -> Vec<Vec<u8>> {
+    self.remove(name);
+    if size > self.max_bytes {
+      return vec![];
+    }
+    let mut evicted = vec![];
+    while self.bytes + size > self.max_bytes {
+      let oldest = match self.by_use.iter().next() {
+        Some((_, name)) => name.clone(),
+        None => break,
+      };
+      self.remove(oldest.as_slice());
+      evicted.push(oldest);
+    }
+    self.entries.insert(name.into_vec(), (size, self.next_use));
+    self.by_use.insert(self.next_use, name.into_vec());
+    self.next_use += 1;
+    self.bytes += size;
+    evicted
+  }
+
+  fn remove(&mut self, name: &[u8]) -> bool {
+    match self.entries.pop(&name.into_vec()) {
+      None => false,
+      Some((size, last_used)) => {
+        self.by_use.remove(&last_used);
+        self.bytes -= size;
+        true
+      },
+    }
+  }
+}
+
+#[deriving(Clone)]
+pub struct CachingBackend<B> {
+  backend: B,
+  dir: Path,
+  index: Arc<Mutex<CacheIndex>>,
+}
+
+impl <B: BlobStoreBackend> CachingBackend<B> {
+  /// Cache the blobs read from `backend` in `dir`, up to `max_bytes`. Blobs cached in `dir` by an
+  /// earlier run are used again, the most recently modified ones being kept the longest.
+  pub fn new(backend: B, dir: Path, max_bytes: u64) -> CachingBackend<B> {
+    let mut index = CacheIndex::new(max_bytes);
+    let mut found = vec![];
+    for path in readdir(&dir).unwrap_or(vec![]).into_iter() {
+      let name = match path.filename_str().and_then(|name| name.from_hex().ok()) {
+        Some(name) => name,
+        None => {
+          // Left over from an interrupted write:
+          let _ = unlink(&path);
+          continue;
+        },
+      };
+      match stat(&path) {
+        Ok(st) => found.push((st.modified, name, st.size)),
+        Err(_) => (),
+      }
+    }
+    found.sort();
+    for &(_, ref name, size) in found.iter() {
+      for evicted in index.insert(name.as_slice(), size).iter() {
+        let _ = unlink(&dir.join(evicted.to_hex()));
+      }
+    }
+    CachingBackend{backend: backend, dir: dir, index: Arc::new(Mutex::new(index))}
+  }
+
+  fn cached_path(&self, name: &[u8]) -> Path {
+    self.dir.join(name.to_hex())
+  }
+
+  /// The cached copy of blob `name`, if there is one and it is intact. A damaged copy is dropped.
+  fn read_cached(&self, name: &[u8]) -> Option<Vec<u8>> {
+    if !self.index.lock().touch(name) {
+      return None;
+    }
+    match File::open(&self.cached_path(name)).and_then(|mut f| f.read_to_end()) {
+      Ok(ref copy) if copy.len() >= CHECKSUM_LEN &&
+                      copy.slice_to(CHECKSUM_LEN) ==
+                      blob_checksum(copy.slice_from(CHECKSUM_LEN)).as_slice() => {
+        Some(copy.slice_from(CHECKSUM_LEN).to_vec())
+      },
+      _ => {
+        self.forget(name);
+        None
+      },
+    }
+  }
+
+  /// Keep a copy of blob `name`, evicting older blobs to make room. Failures only mean that the
+  /// blob is not cached.
+  fn write_cached(&self, name: &[u8], data: &[u8]) {
+    let size = (CHECKSUM_LEN + data.len()) as u64;
+    if size > self.index.lock().max_bytes {
+      return;
+    }
+    let path = self.cached_path(name);
+    // Each write has a part file of its own, so that concurrent reads of a blob do not mix:
+    let part = self.dir.join(format!("{}.{}.part", name.to_hex(),
+                                     randombytes(8).as_slice().to_hex()));
+    let written = mkdir_recursive(&self.dir, UserDir).and_then(|()| {
+      File::create(&part).and_then(|mut file| {
+        try!(file.write(blob_checksum(data).as_slice()));
+        file.write(data)
+      })
+    }).and_then(|()| rename(&part, &path));
+    if written.is_err() {
+      let _ = unlink(&part);
+      return;
+    }
+    let evicted = self.index.lock().insert(name, size);
+    for name in evicted.iter() {
+      let _ = unlink(&self.cached_path(name.as_slice()));
+    }
+  }
+
+  /// Drop the cached copy of blob `name`, e.g. because it is being replaced.
+  fn forget(&self, name: &[u8]) {
+    if self.index.lock().remove(name) {
+      let _ = unlink(&self.cached_path(name));
+    }
+  }
+}
+
+impl <B: BlobStoreBackend> BlobStoreBackend for CachingBackend<B> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.forget(name);
+    self.backend.store(name, data)
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    match self.read_cached(name) {
+      Some(data) => return Ok(data),
+      None => (),
+    }
+    let data = try!(self.backend.retrieve(name));
+    self.write_cached(name, data.as_slice());
+    Ok(data)
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    self.forget(name);
+    self.backend.store_with_hint(name, data, hint)
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    self.backend.set_storage_hint(name, hint)
+  }
+
+  fn supports_resume(&self) -> bool { self.backend.supports_resume() }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.backend.stored_length(name)
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.forget(name);
+    self.backend.append(name, data)
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.backend.stored_checksum(name)
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.forget(name);
+    self.backend.delete(name)
+  }
+
+  fn supports_retention(&self) -> bool { self.backend.supports_retention() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    self.backend.set_retention(name, until)
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    self.backend.list()
+  }
+
+  fn supports_listing(&self) -> bool { self.backend.supports_listing() }
+
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.backend.probe()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{CacheIndex};
+
+  use blob_store::{BlobStoreBackend, MemoryBackend};
+
+  use serialize::hex::{ToHex};
+
+  use std::io::{File, TempDir};
+  use std::sync::{Arc, Mutex};
+
+  /// Counts the blobs retrieved from a `MemoryBackend`.
+  #[deriving(Clone)]
+  struct CountingBackend {
+    backend: MemoryBackend,
+    retrieved: Arc<Mutex<uint>>,
+  }
+
+  impl BlobStoreBackend for CountingBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+      self.backend.store(name, data)
+    }
+
+    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+      *self.retrieved.lock() += 1;
+      self.backend.retrieve(name)
+    }
+  }
+
+  #[test]
+  fn index_evicts_least_recently_read() {
+    let mut index = CacheIndex::new(30);
+    assert_eq!(index.insert(b"a", 10), Vec::<Vec<u8>>::new());
+    assert_eq!(index.insert(b"b", 10), Vec::<Vec<u8>>::new());
+    assert_eq!(index.insert(b"c", 10), Vec::<Vec<u8>>::new());
+    assert!(index.touch(b"a"));
+    assert_eq!(index.insert(b"d", 15), vec![b"b".to_vec(), b"c".to_vec()]);
+    assert!(index.touch(b"a"));
+    assert!(!index.touch(b"b"));
+
+    // Blobs larger than the cache are not cached:
+    assert_eq!(index.insert(b"e", 31), Vec::<Vec<u8>>::new());
+    assert!(!index.touch(b"e"));
+    assert_eq!(index.bytes, 25);
+  }
+
+  #[test]
+  fn repeated_reads_come_from_the_cache() {
+    let dir = TempDir::new("hat-cache").unwrap();
+    let counting = CountingBackend{backend: MemoryBackend::new(),
+                                   retrieved: Arc::new(Mutex::new(0))};
+    let mut backend = CachingBackend::new(counting.clone(), dir.path().clone(), 1000);
+    backend.store(b"a", b"data").unwrap();
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+    assert_eq!(*counting.retrieved.lock(), 1);
+
+    // The cache survives a restart:
+    let mut backend = CachingBackend::new(counting.clone(), dir.path().clone(), 1000);
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+    assert_eq!(*counting.retrieved.lock(), 1);
+  }
+
+  #[test]
+  fn damaged_copies_are_read_again() {
+    let dir = TempDir::new("hat-cache").unwrap();
+    let counting = CountingBackend{backend: MemoryBackend::new(),
+                                   retrieved: Arc::new(Mutex::new(0))};
+    let mut backend = CachingBackend::new(counting.clone(), dir.path().clone(), 1000);
+    backend.store(b"a", b"data").unwrap();
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+
+    let path = dir.path().join(b"a".to_hex());
+    let mut copy = File::open(&path).read_to_end().unwrap();
+    let last = copy.len() - 1;
+    copy[last] ^= 1;
+    File::create(&path).write(copy.as_slice()).unwrap();
+
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+    assert_eq!(*counting.retrieved.lock(), 2);
+    // The copy read again replaces the damaged one:
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+    assert_eq!(*counting.retrieved.lock(), 2);
+  }
+
+  #[test]
+  fn writes_do_not_share_part_files() {
+    let dir = TempDir::new("hat-cache").unwrap();
+    let backend = CachingBackend::new(MemoryBackend::new(), dir.path().clone(), 1000);
+    let part = dir.path().join(format!("{}.part", b"a".to_hex()));
+    // A part file left by another writer is neither used nor removed:
+    File::create(&part).write(b"other").unwrap();
+    backend.write_cached(b"a", b"data");
+    assert_eq!(backend.read_cached(b"a"), Some(b"data".to_vec()));
+    assert_eq!(File::open(&part).read_to_end().unwrap(), b"other".to_vec());
+  }
+}
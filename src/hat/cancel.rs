@@ -0,0 +1,70 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Cancellation of long-running operations.
+//!
+//! A `CancelToken` is shared between the operations of a repository and whoever may want to stop
+//! them, e.g. the user interface of an application that embeds hat. Operations check the token
+//! between units of work (a file, a blob), so they stop soon after it is cancelled, leave the
+//! repository consistent, and return `Cancelled` instead of failing.
+
+use std::sync::Arc;
+use std::sync::atomic::{AtomicBool, SeqCst};
+
+
+#[deriving(Clone)]
+pub struct CancelToken {
+  cancelled: Arc<AtomicBool>,
+}
+
+/// The result of an operation that stopped because its `CancelToken` was cancelled.
+#[deriving(Clone, Show, PartialEq)]
+pub struct Cancelled;
+
+impl CancelToken {
+  pub fn new() -> CancelToken {
+    CancelToken{cancelled: Arc::new(AtomicBool::new(false))}
+  }
+
+  /// Ask the operations that use this token (or a clone of it) to stop. This cannot be undone.
+  pub fn cancel(&self) {
+    self.cancelled.store(true, SeqCst);
+  }
+
+  pub fn is_cancelled(&self) -> bool {
+    self.cancelled.load(SeqCst)
+  }
+
+  /// `Err(Cancelled)` if the token was cancelled, for operations that end here.
+  pub fn check(&self) -> Result<(), Cancelled> {
+    if self.is_cancelled() { Err(Cancelled) } else { Ok(()) }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn clones_share_cancellation() {
+    let token = CancelToken::new();
+    let clone = token.clone();
+    assert_eq!(clone.check(), Ok(()));
+
+    token.cancel();
+    assert!(clone.is_cancelled());
+    assert_eq!(clone.check(), Err(Cancelled));
+  }
+}
@@ -0,0 +1,433 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Splitting of file data into data-chunks.
+//!
+//! By default, files are split into chunks of a fixed size. With format-aware chunking, chunk
+//! boundaries of container files are aligned with the entries inside them (tar entry headers,
+//! SQLite pages and zip members), so that an entry that reappears in another container (or at
+//! another offset in the same container) splits into the same chunks and is deduplicated.
+
+use buffer_pool::{BufferPool};
+
+use std::cmp;
+use std::io;
+use std::io::{IoError, IoResult};
+use std::mem;
+use std::slice::bytes::{copy_memory};
+use std::u64;
+
+
+/// How file data is split into data-chunks.
+#[deriving(Clone)]
+pub struct ChunkerOptions {
+  /// The maximum size of a data-chunk.
+  pub chunk_size: uint,
+
+  /// Align chunk boundaries with the entries of recognized container formats.
+  pub format_aware: bool,
+}
+
+impl ChunkerOptions {
+  pub fn new(chunk_size: uint) -> ChunkerOptions {
+    ChunkerOptions{chunk_size: chunk_size, format_aware: false}
+  }
+}
+
+
+static TAR_BLOCK_SIZE: u64 = 512;
+static SQLITE_MAGIC: &'static [u8] = b"SQLite format 3\x00";
+static ZIP_LOCAL_HEADER_MAGIC: &'static [u8] = b"PK\x03\x04";
+
+#[deriving(Clone)]
+enum Format {
+  /// Fixed-size chunks.
+  PlainFormat,
+
+  /// Cut before each entry header. Holds the offset of the next entry header.
+  TarFormat(u64),
+
+  /// Cut at page boundaries. Holds the page size.
+  SqliteFormat(uint),
+
+  /// Cut before each local file header.
+  ZipFormat,
+}
+
+fn detect_format(start: &[u8]) -> Format {
+  if start.starts_with(SQLITE_MAGIC) && start.len() >= 18 {
+    let page_size = match ((start[16] as uint) << 8) | start[17] as uint {
+      1 => 65536,
+      n => n,
+    };
+    if page_size >= 512 && page_size & (page_size - 1) == 0 {
+      return SqliteFormat(page_size);
+    }
+  }
+  if start.len() >= TAR_BLOCK_SIZE as uint && start.slice(257, 262) == b"ustar" {
+    return TarFormat(0);
+  }
+  if start.starts_with(ZIP_LOCAL_HEADER_MAGIC) {
+    return ZipFormat;
+  }
+  PlainFormat
+}
+
+/// Parse the size field of a tar entry header (octal, or base-256 for large entries).
+fn tar_entry_size(header: &[u8]) -> Option<u64> {
+  let field = header.slice(124, 136);
+  if field[0] & 0x80 != 0 {
+    return Some(field.slice_from(1).iter().fold(0, |size, &b| (size << 8) | b as u64));
+  }
+
+  let mut size = 0u64;
+  let mut digits = 0u;
+  for &b in field.iter() {
+    match b as char {
+      ' ' if digits == 0 => continue,
+      '0'..'7' => {
+        size = size * 8 + (b - '0' as u8) as u64;
+        digits += 1;
+      },
+      ' ' | '\x00' => break,
+      _ => return None,
+    }
+  }
+  if digits > 0 { Some(size) } else { None }
+}
+
+/// The offset of the entry following the tar entry whose header starts at `offset`, or `None`
+/// at the end of the archive (or if the header cannot be parsed).
+fn tar_next_header(offset: u64, header: &[u8]) -> Option<u64> {
+  if header.len() < TAR_BLOCK_SIZE as uint || header.iter().all(|&b| b == 0) {
+    return None;
+  }
+  tar_entry_size(header).map(|size| {
+    let blocks = (size + TAR_BLOCK_SIZE - 1) / TAR_BLOCK_SIZE;
+    offset + TAR_BLOCK_SIZE * (1 + blocks)
+  })
+}
+
+
+/// Read up to `block_size` bytes into a buffer from `pool`, stopping early only at the end of the
+/// input or at an error.
+fn read_block<R: Reader>(reader: &mut R, pool: &BufferPool) -> (Vec<u8>, Option<IoError>) {
+  let block_size = pool.buffer_size();
+  let mut block = pool.get();
+  block.grow(block_size, 0u8);
+  let mut size = 0;
+  let mut error = None;
+  while size < block_size {
+    match reader.read(block.slice_from_mut(size)) {
+      Ok(n) => size += n,
+      Err(ref e) if e.kind == io::EndOfFile => break,
+      Err(e) => {
+        error = Some(e);
+        break;
+      },
+    }
+  }
+  block.truncate(size);
+  (block, error)
+}
+
+/// A `Reader` that reads ahead of its consumer in a separate task: the next block is read while
+/// the current one is processed. Read errors are passed on in order; the end of the input is
+/// reported as `EndOfFile`.
+///
+/// The first block is read right away, and no task is started if it holds all of the input.
+/// Blocks are buffers of `pool`, and are given back to it once they have been read.
+pub struct ReadAhead {
+  blocks: Receiver<IoResult<Vec<u8>>>,
+  current: Vec<u8>,
+  position: uint,
+  pool: BufferPool,
+}
+
+impl ReadAhead {
+  pub fn new<R: Reader + Send>(reader: R, pool: BufferPool) -> ReadAhead {
+    let block_size = pool.buffer_size();
+    // Blocks wait in the channel while the next one is read. There is room for the first block
+    // and an error, so that the sends below cannot block:
+    let (tx, rx) = sync_channel(2);
+    let mut reader = reader;
+
+    let (first, error) = read_block(&mut reader, &pool);
+    let more = error.is_none() && first.len() == block_size;
+    tx.send(Ok(first));
+    error.map(|e| tx.send(Err(e)));
+
+    if more {
+      let pool = pool.clone();
+      spawn(proc() {
+        let mut reader = reader;
+        loop {
+          let (block, error) = read_block(&mut reader, &pool);
+          let last = error.is_some() || block.len() < block_size;
+          // Stop early if the consumer is gone:
+          if tx.send_opt(Ok(block)).is_err() { break }
+          error.map(|e| tx.send_opt(Err(e)));
+          if last { break }
+        }
+      });
+    }
+    ReadAhead{blocks: rx, current: Vec::new(), position: 0, pool: pool}
+  }
+}
+
+impl Reader for ReadAhead {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    while self.position == self.current.len() {
+      match self.blocks.recv_opt() {
+        Ok(Ok(block)) => {
+          let used = mem::replace(&mut self.current, block);
+          self.pool.put(used);
+          self.position = 0;
+        },
+        Ok(Err(e)) => return Err(e),
+        Err(()) => return Err(io::standard_error(io::EndOfFile)),
+      }
+    }
+    let size = cmp::min(buf.len(), self.current.len() - self.position);
+    copy_memory(buf, self.current.slice(self.position, self.position + size));
+    self.position += size;
+    Ok(size)
+  }
+}
+
+
+/// Splits the data read from a `Reader` into data-chunks.
+///
+/// Every data-chunk is read in full, except at the end of the input. A read error also ends the
+/// input; it is passed to the error handler, if one is set.
+pub struct Chunker<R> {
+  reader: R,
+  options: ChunkerOptions,
+  format: Option<Format>,
+  on_error: Option<proc(IoError):Send>,
+  pool: Option<BufferPool>,
+
+  buffer: Vec<u8>,
+  offset: u64,
+  eof: bool,
+}
+
+impl <R: Reader> Chunker<R> {
+
+  pub fn new(reader: R, options: ChunkerOptions) -> Chunker<R> {
+    Chunker{reader: reader, options: options, format: None, on_error: None, pool: None,
+            buffer: Vec::new(), offset: 0, eof: false}
+  }
+
+  /// Read data-chunks into buffers taken from `pool`, whose buffers should be at least as large
+  /// as the chunk size. The receiver of the chunks is expected to give them back.
+  pub fn with_buffer_pool(self, pool: BufferPool) -> Chunker<R> {
+    let mut chunker = self;
+    chunker.buffer = pool.get();
+    chunker.pool = Some(pool);
+    chunker
+  }
+
+  /// Call `handler` with the error that ended the input, if reading fails.
+  pub fn on_error(self, handler: proc(IoError):Send) -> Chunker<R> {
+    let mut chunker = self;
+    chunker.on_error = Some(handler);
+    chunker
+  }
+
+  fn fill_buffer(&mut self) {
+    let chunk_size = self.options.chunk_size;
+    while !self.eof && self.buffer.len() < chunk_size {
+      // Read straight into the buffer, which becomes the next data-chunk:
+      let len = self.buffer.len();
+      self.buffer.grow(chunk_size - len, 0u8);
+      let result = self.reader.read(self.buffer.slice_from_mut(len));
+      let size = match result {
+        Ok(size) => size,
+        Err(ref e) if e.kind == io::EndOfFile => {
+          self.eof = true;
+          0
+        },
+        Err(e) => {
+          self.eof = true;
+          self.on_error.take().map(|handler| handler(e));
+          0
+        },
+      };
+      self.buffer.truncate(len + size);
+    }
+  }
+
+  /// The length of the next data-chunk, which starts at the beginning of the buffer.
+  fn cut(&mut self) -> uint {
+    let len = self.buffer.len();
+    match self.format.clone() {
+      None | Some(PlainFormat) => len,
+      Some(SqliteFormat(page_size)) => {
+        if len >= page_size { len - len % page_size } else { len }
+      },
+      Some(TarFormat(next_header)) => {
+        let mut next_header = next_header;
+        if next_header == self.offset {
+          next_header = tar_next_header(self.offset, self.buffer.as_slice())
+            .unwrap_or(u64::MAX);
+          self.format = Some(TarFormat(next_header));
+        }
+        if next_header > self.offset && next_header - self.offset < len as u64 {
+          (next_header - self.offset) as uint
+        } else { len }
+      },
+      Some(ZipFormat) => {
+        let magic_len = ZIP_LOCAL_HEADER_MAGIC.len();
+        range(1, len).find(|&i| {
+          i + magic_len <= len &&
+            self.buffer.slice(i, i + magic_len) == ZIP_LOCAL_HEADER_MAGIC
+        }).unwrap_or(len)
+      },
+    }
+  }
+}
+
+impl <R: Reader> Iterator<Vec<u8>> for Chunker<R> {
+  fn next(&mut self) -> Option<Vec<u8>> {
+    self.fill_buffer();
+    if self.buffer.len() == 0 {
+      return None;
+    }
+
+    if self.format.is_none() {
+      self.format = Some(if self.options.format_aware { detect_format(self.buffer.as_slice()) }
+                         else { PlainFormat });
+    }
+
+    let cut = self.cut();
+    // Only data left over after a format-aware cut is copied:
+    let mut rest = match self.pool {
+      Some(ref pool) => pool.get(),
+      None => Vec::with_capacity(self.options.chunk_size),
+    };
+    rest.push_all(self.buffer.slice_from(cut));
+    let mut chunk = mem::replace(&mut self.buffer, rest);
+    chunk.truncate(cut);
+    self.offset += cut as u64;
+    Some(chunk)
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use buffer_pool::{BufferPool};
+  use std::cmp;
+  use std::io;
+  use std::io::{IoResult, MemReader};
+
+  /// Returns its data in pieces of at most 7 bytes, then fails.
+  struct FailingReader {
+    data: Vec<u8>,
+  }
+
+  impl Reader for FailingReader {
+    fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+      if self.data.len() == 0 {
+        return Err(io::standard_error(io::OtherIoError));
+      }
+      let size = cmp::min(cmp::min(buf.len(), self.data.len()), 7);
+      buf.copy_from(self.data.slice_to(size));
+      self.data = self.data.slice_from(size).into_vec();
+      Ok(size)
+    }
+  }
+
+  fn chunk_lengths(data: Vec<u8>, chunk_size: uint, format_aware: bool) -> Vec<uint> {
+    let options = ChunkerOptions{chunk_size: chunk_size, format_aware: format_aware};
+    Chunker::new(MemReader::new(data), options).map(|chunk| chunk.len()).collect()
+  }
+
+  fn tar_header(size: uint) -> Vec<u8> {
+    let mut header = Vec::from_elem(512, 0u8);
+    for (i, &b) in b"ustar".iter().enumerate() {
+      *header.get_mut(257 + i) = b;
+    }
+    for (i, b) in format!("{:011o}", size).into_bytes().into_iter().enumerate() {
+      *header.get_mut(124 + i) = b;
+    }
+    header
+  }
+
+  #[test]
+  fn fixed_size() {
+    assert_eq!(chunk_lengths(Vec::from_elem(2500, 1u8), 1000, false), vec![1000, 1000, 500]);
+    assert_eq!(chunk_lengths(Vec::new(), 1000, false), vec![]);
+  }
+
+  #[test]
+  fn tar_entries() {
+    let mut archive = tar_header(100);
+    archive.push_all(Vec::from_elem(512, 1u8).as_slice());
+    archive.push_all(tar_header(1500).as_slice());
+    archive.push_all(Vec::from_elem(1536, 2u8).as_slice());
+    archive.push_all(Vec::from_elem(1024, 0u8).as_slice());
+
+    assert_eq!(chunk_lengths(archive.clone(), 1500, false), vec![1500, 1500, 1096]);
+    assert_eq!(chunk_lengths(archive, 1500, true), vec![1024, 1500, 548, 1024]);
+  }
+
+  #[test]
+  fn sqlite_pages() {
+    let mut db = b"SQLite format 3\x00\x04\x00".into_vec();
+    db.push_all(Vec::from_elem(4096 - db.len(), 0u8).as_slice());
+    db.push_all(Vec::from_elem(3 * 1024, 1u8).as_slice());
+    assert_eq!(chunk_lengths(db, 2500, true), vec![2048, 2048, 2048, 1024]);
+  }
+
+  #[test]
+  fn zip_members() {
+    let mut zip = b"PK\x03\x04first".into_vec();
+    zip.push_all(b"PK\x03\x04second");
+    assert_eq!(chunk_lengths(zip, 100, true), vec![9, 10]);
+  }
+
+  #[test]
+  fn read_errors_end_the_input() {
+    let (tx, rx) = channel();
+    let reader = FailingReader{data: Vec::from_elem(25, 1u8)};
+    let chunker = Chunker::new(reader, ChunkerOptions::new(10)).on_error(proc(e) {
+      tx.send(e.kind);
+    });
+    // Short reads are combined into complete chunks:
+    assert_eq!(chunker.map(|chunk| chunk.len()).collect::<Vec<uint>>(), vec![10, 10, 5]);
+    assert_eq!(rx.recv(), io::OtherIoError);
+  }
+
+  #[test]
+  fn read_ahead() {
+    let data = Vec::from_elem(2500, 1u8);
+    let reader = ReadAhead::new(MemReader::new(data.clone()), BufferPool::new(300, 4));
+    let chunks: Vec<Vec<u8>> = Chunker::new(reader, ChunkerOptions::new(1000)).collect();
+    assert_eq!(chunks.iter().map(|chunk| chunk.len()).collect::<Vec<uint>>(),
+               vec![1000, 1000, 500]);
+    assert_eq!(chunks.concat_vec(), data);
+
+    let (tx, rx) = channel();
+    let reader = ReadAhead::new(FailingReader{data: Vec::from_elem(12, 1u8)},
+                                BufferPool::new(100, 4));
+    let chunker = Chunker::new(reader, ChunkerOptions::new(10)).on_error(proc(e) {
+      tx.send(e.kind);
+    });
+    assert_eq!(chunker.map(|chunk| chunk.len()).collect::<Vec<uint>>(), vec![10, 2]);
+    assert_eq!(rx.recv(), io::OtherIoError);
+  }
+}
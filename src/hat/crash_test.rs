@@ -0,0 +1,92 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Crash injection, for testing that the commit protocol survives the process being killed.
+//!
+//! The steps of the commit protocol (reserving a blob, marking it in-air, storing it, committing
+//! it and flushing the indexes) are marked with `point()`. When enabled, each point exits the
+//! process at random, as if it had been killed. After such a crash, the repository must still
+//! pass its checks and accept new snapshots.
+//!
+//! Crash points are compiled out of builds with `ndebug`.
+
+use libc;
+
+
+/// The exit status of a process that was stopped at a crash point.
+pub static CRASH_EXIT_STATUS: int = 86;
+
+static mut CRASH_PROBABILITY: f64 = 0.0;
+
+/// Whether this build has crash points.
+#[cfg(not(ndebug))]
+pub fn supported() -> bool { true }
+
+#[cfg(ndebug)]
+pub fn supported() -> bool { false }
+
+/// Exit at each crash point with the given probability. Must be called before any worker tasks
+/// are started.
+pub fn enable(probability: f64) {
+  unsafe { CRASH_PROBABILITY = probability };
+}
+
+/// A point where the process may crash.
+#[cfg(not(ndebug))]
+pub fn point(name: &str) {
+  use std::rand::{Rng, task_rng};
+
+  let probability = unsafe { CRASH_PROBABILITY };
+  if probability > 0.0 && task_rng().gen::<f64>() < probability {
+    info!("Crash injected at {}.", name);
+    unsafe { libc::exit(CRASH_EXIT_STATUS as libc::c_int) };
+  }
+}
+
+#[cfg(ndebug)]
+#[inline]
+pub fn point(_name: &str) {}
+
+
+#[cfg(test)]
+#[cfg(not(ndebug))]
+mod tests {
+  use super::*;
+
+  use std::io::process::{Command, ExitStatus};
+  use std::os;
+
+  static CHILD_VARIABLE: &'static str = "HAT_CRASH_TEST_CHILD";
+
+  /// Run in a child process by `crash_points_exit`, which checks that it crashed.
+  #[test]
+  fn crash_child() {
+    if os::getenv(CHILD_VARIABLE).is_some() {
+      enable(1.0);
+      point("test");
+      fail!("The crash point did not exit.");
+    }
+  }
+
+  #[test]
+  fn crash_points_exit() {
+    // Without a probability, nothing crashes:
+    point("test");
+
+    let test_binary = os::self_exe_name().expect("the test binary is known");
+    let status = Command::new(test_binary).arg("crash_child").env(CHILD_VARIABLE, "1")
+      .status().unwrap();
+    assert_eq!(status, ExitStatus(CRASH_EXIT_STATUS));
+  }
+}
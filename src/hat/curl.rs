@@ -0,0 +1,262 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! HTTP requests through `curl`, for the blob store backends that talk HTTP.
+//!
+//! Options are passed to `curl` on its standard input, in its configuration file syntax, rather
+//! than on the command line, so that passwords and tokens do not show up in the process list.
+//!
+//! Failures that may pass, like a connection that could not be made or timed out, or an HTTP
+//! status of 429 or 5xx, are transient errors (see `blob_store::transient`).
+//!
+//! Backends authenticate with a fixed login of their own, or through a `RequestAuth` hook that
+//! is asked for every request, e.g. to sign it or to refresh an OAuth token.
+
+use blob_store::{transient};
+
+use std::fmt;
+use std::io::{File, TempDir, UserRead, UserWrite};
+use std::io::fs::{chmod};
+use std::io::process::{Command, ExitStatus, ProcessOutput};
+use std::str;
+use std::sync::{Arc};
+
+
+/// Exit codes of `curl` that mean the server could not be reached or did not answer in time:
+/// name resolution, connecting, timeouts, empty replies and failures to send or receive data.
+static TRANSIENT_EXIT_CODES: &'static [int] = &[5, 6, 7, 28, 52, 55, 56];
+
+/// Whether a request that got HTTP `status` may succeed if repeated.
+pub fn is_transient_status(status: uint) -> bool {
+  status == 429 || status >= 500
+}
+
+/// Quote `s` as a string of the `curl` configuration file syntax.
+pub fn quote(s: &str) -> String {
+  let mut quoted = String::from_str("\"");
+  for c in s.chars() {
+    match c {
+      '"' | '\\' => { quoted.push('\\'); quoted.push(c); },
+      '\n' => quoted.push_str("\\n"),
+      '\r' => quoted.push_str("\\r"),
+      '\t' => quoted.push_str("\\t"),
+      _ => quoted.push(c),
+    }
+  }
+  quoted.push('"');
+  quoted
+}
+
+/// A `header` option.
+pub fn header(name: &str, value: &str) -> String {
+  format!("header = {}", quote(format!("{}: {}", name, value).as_slice()))
+}
+
+/// Send one request. `options` are further `curl` options, e.g. from `header`. Returns the HTTP
+/// status and the response body.
+pub fn request(method: &str, url: &str, options: &[String]) -> Result<(uint, Vec<u8>), String> {
+  let mut config = vec![
+    "silent".to_string(), "show-error".to_string(),
+    format!("request = {}", quote(method)),
+    format!("url = {}", quote(url)),
+    // The status follows the body on the standard output:
+    "write-out = \"\\n%{http_code}\"".to_string(),
+  ];
+  config.push_all(options);
+
+  let mut process = match Command::new("curl").arg("--config").arg("-").spawn() {
+    Ok(process) => process,
+    Err(e) => return Err(format!("curl: {}", e)),
+  };
+  {
+    let mut stdin = process.stdin.take().expect("stdin is piped");
+    match stdin.write(config.connect("\n").as_bytes()) {
+      Ok(()) => (),
+      Err(e) => return Err(transient(format!("curl: {}", e))),
+    }
+  }
+  let out = match process.wait_with_output() {
+    Ok(out) => out,
+    Err(e) => return Err(transient(format!("curl: {}", e))),
+  };
+  if !out.status.success() {
+    let error = String::from_utf8_lossy(out.error.as_slice()).into_string();
+    let message = format!("{} {} failed ({}): {}", method, url, out.status,
+                          error.as_slice().trim());
+    return match out.status {
+      ExitStatus(code) if TRANSIENT_EXIT_CODES.contains(&code) => Err(transient(message)),
+      _ => Err(message),
+    };
+  }
+  let mut body = out.output;
+  let status = match body.iter().rposition(|&b| b == b'\n') {
+    Some(i) => {
+      let status = str::from_utf8(body.slice_from(i + 1)).and_then(from_str::<uint>);
+      body.truncate(i);
+      status
+    },
+    None => None,
+  };
+  match status {
+    Some(status) => Ok((status, body)),
+    None => Err(format!("{} {}: curl did not report an HTTP status", method, url)),
+  }
+}
+
+/// Send one request, and fail unless its status is 2xx. Returns the response body.
+pub fn expect_success(method: &str, url: &str, options: &[String]) -> Result<Vec<u8>, String> {
+  let (status, body) = try!(request(method, url, options));
+  if status < 200 || status >= 300 {
+    let message = format!("{} {} failed: HTTP status {}", method, url, status);
+    return Err(if is_transient_status(status) { transient(message) } else { message });
+  }
+  Ok(body)
+}
+
+/// Authentication of HTTP requests that a fixed login cannot do, such as request signing for an
+/// internal object store, or OAuth tokens that must be refreshed.
+pub trait RequestAuth {
+  /// The `curl` options (e.g. `header`s) that authenticate a `method` request of `url`.
+  fn options(&self, method: &str, url: &str) -> Result<Vec<String>, String>;
+}
+
+/// A `RequestAuth` as part of a backend configuration, shared between clones of the backend.
+#[deriving(Clone)]
+pub struct AuthHook {
+  auth: Arc<Box<RequestAuth + Send + Sync>>,
+}
+
+impl AuthHook {
+  pub fn new<A: RequestAuth + Send + Sync>(auth: A) -> AuthHook {
+    AuthHook{auth: Arc::new(box auth as Box<RequestAuth + Send + Sync>)}
+  }
+
+  pub fn options(&self, method: &str, url: &str) -> Result<Vec<String>, String> {
+    self.auth.options(method, url)
+  }
+}
+
+impl fmt::Show for AuthHook {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "AuthHook")
+  }
+}
+
+/// A `RequestAuth` that runs a command (with `sh -c`) for every request, with the method and URL
+/// as arguments. The command prints the headers to send, one `Name: value` per line.
+#[deriving(Clone)]
+pub struct CommandAuth {
+  command: String,
+}
+
+impl CommandAuth {
+  pub fn new(command: String) -> CommandAuth {
+    CommandAuth{command: command}
+  }
+}
+
+impl RequestAuth for CommandAuth {
+  fn options(&self, method: &str, url: &str) -> Result<Vec<String>, String> {
+    let script = format!("{} \"$@\"", self.command);
+    let out = Command::new("sh").arg("-c").arg(script.as_slice()).arg("hat-auth")
+                                .arg(method).arg(url).output();
+    let output = match out {
+      Ok(ProcessOutput{status, output, error}) => {
+        if !status.success() {
+          let error = String::from_utf8_lossy(error.as_slice()).into_string();
+          return Err(format!("'{}' failed ({}): {}", self.command, status,
+                             error.as_slice().trim()));
+        }
+        output
+      },
+      Err(e) => return Err(format!("could not run '{}': {}", self.command, e)),
+    };
+    let text = match str::from_utf8(output.as_slice()) {
+      Some(text) => text,
+      None => return Err(format!("'{}' printed headers that are not text", self.command)),
+    };
+    let mut options = vec![];
+    for line in text.lines() {
+      if line.trim().len() == 0 {
+        continue;
+      }
+      match line.find(':') {
+        Some(i) if i > 0 => {
+          options.push(header(line.slice_to(i).trim(), line.slice_from(i + 1).trim()));
+        },
+        _ => return Err(format!("'{}' printed '{}', which is not a header", self.command, line)),
+      }
+    }
+    Ok(options)
+  }
+}
+
+/// A local copy of data to upload (see `upload_option`), in a directory of its own that only the
+/// user can read. Both are removed again when dropped.
+pub struct SpoolFile {
+  pub path: Path,
+  _dir: TempDir,
+}
+
+impl SpoolFile {
+  pub fn create(data: &[u8]) -> Result<SpoolFile, String> {
+    let dir = try!(TempDir::new("hat-upload").map_err(|e| e.to_string()));
+    let spool = SpoolFile{path: dir.path().join("data"), _dir: dir};
+    try!(File::create(&spool.path).and_then(|mut f| {
+      try!(chmod(&spool.path, UserRead | UserWrite));
+      f.write(data)
+    }).map_err(|e| e.to_string()));
+    Ok(spool)
+  }
+
+  /// The option that uploads this file as the request body.
+  pub fn upload_option(&self) -> String {
+    format!("upload-file = {}", quote(self.path.display().to_string().as_slice()))
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn quoting() {
+    assert_eq!(quote("plain"), "\"plain\"".to_string());
+    assert_eq!(quote("pa\"ss\\word\n"), "\"pa\\\"ss\\\\word\\n\"".to_string());
+    assert_eq!(header("Depth", "1"), "header = \"Depth: 1\"".to_string());
+  }
+
+  #[test]
+  fn transient_statuses() {
+    assert!(is_transient_status(429));
+    assert!(is_transient_status(503));
+    assert!(!is_transient_status(404));
+    assert!(!is_transient_status(403));
+  }
+
+  #[test]
+  fn command_auth() {
+    let auth = AuthHook::new(CommandAuth::new(
+      "f() { echo \"X-Signature: $1 $2\"; echo; echo 'Authorization: Bearer t'; }; f".to_string()));
+    assert_eq!(auth.options("PUT", "https://example.com/blob/ab"),
+               Ok(vec![header("X-Signature", "PUT https://example.com/blob/ab"),
+                       header("Authorization", "Bearer t")]));
+
+    let auth = CommandAuth::new("echo not a header".to_string());
+    assert!(auth.options("GET", "https://example.com/").is_err());
+    let auth = CommandAuth::new("exit 1;".to_string());
+    assert!(auth.options("GET", "https://example.com/").is_err());
+  }
+}
@@ -0,0 +1,174 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Line-based unified diffs of file contents.
+
+use std::cmp;
+use std::str;
+
+
+/// Number of unchanged lines shown around each change.
+static CONTEXT: uint = 3;
+
+/// Upper bound on the size of the table used to compare the changed parts of two files.
+static MAX_TABLE_SIZE: uint = 4 * 1024 * 1024;
+
+
+#[deriving(PartialEq)]
+enum Op {
+  Keep,
+  Delete,
+  Insert,
+}
+
+/// Compute a shortest edit script from `old` to `new`. Each step carries the positions in `old`
+/// and `new` that it applies to. Returns `None` if the files are too large to compare.
+fn edit_script<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<(Op, uint, uint)>> {
+  // Only the part between a common prefix and a common suffix needs to be compared:
+  let mut prefix = 0;
+  while prefix < old.len() && prefix < new.len() && old[prefix] == new[prefix] {
+    prefix += 1;
+  }
+  let mut suffix = 0;
+  while suffix < old.len() - prefix && suffix < new.len() - prefix &&
+        old[old.len() - 1 - suffix] == new[new.len() - 1 - suffix] {
+    suffix += 1;
+  }
+
+  let old_mid = old.slice(prefix, old.len() - suffix);
+  let new_mid = new.slice(prefix, new.len() - suffix);
+  let (n, m) = (old_mid.len(), new_mid.len());
+  if (n + 1) * (m + 1) > MAX_TABLE_SIZE {
+    return None;
+  }
+
+  // lcs[i * (m + 1) + j] is the length of the longest common subsequence of old_mid[i..] and
+  // new_mid[j..].
+  let mut lcs = Vec::from_elem((n + 1) * (m + 1), 0u32);
+  for i in range(0, n).rev() {
+    for j in range(0, m).rev() {
+      let value = if old_mid[i] == new_mid[j] { lcs[(i + 1) * (m + 1) + j + 1] + 1 }
+                  else { cmp::max(lcs[(i + 1) * (m + 1) + j], lcs[i * (m + 1) + j + 1]) };
+      *lcs.get_mut(i * (m + 1) + j) = value;
+    }
+  }
+
+  let mut script = Vec::new();
+  for k in range(0, prefix) {
+    script.push((Keep, k, k));
+  }
+  let (mut i, mut j) = (0u, 0u);
+  while i < n || j < m {
+    if i < n && j < m && old_mid[i] == new_mid[j] {
+      script.push((Keep, prefix + i, prefix + j));
+      i += 1;
+      j += 1;
+    } else if j == m || (i < n && lcs[(i + 1) * (m + 1) + j] >= lcs[i * (m + 1) + j + 1]) {
+      script.push((Delete, prefix + i, prefix + j));
+      i += 1;
+    } else {
+      script.push((Insert, prefix + i, prefix + j));
+      j += 1;
+    }
+  }
+  for k in range(0, suffix) {
+    script.push((Keep, prefix + n + k, prefix + m + k));
+  }
+  Some(script)
+}
+
+fn is_text(data: &[u8]) -> bool {
+  !data.contains(&0) && str::from_utf8(data).is_some()
+}
+
+/// Describe how `new` differs from `old`, as a unified diff for text files. Binary files, and
+/// text files that are too large to compare, are only reported as differing.
+pub fn diff_contents(old_label: &str, new_label: &str, old: &[u8], new: &[u8]) -> String {
+  if !is_text(old) || !is_text(new) {
+    return format!("Binary files {} and {} differ\n", old_label, new_label);
+  }
+  let old_text = str::from_utf8(old).expect("is text");
+  let new_text = str::from_utf8(new).expect("is text");
+  let old_lines: Vec<&str> = old_text.split_terminator('\n').collect();
+  let new_lines: Vec<&str> = new_text.split_terminator('\n').collect();
+
+  let script = match edit_script(old_lines.as_slice(), new_lines.as_slice()) {
+    Some(script) => script,
+    None => return format!("Files {} and {} differ (too large to compare by line)\n",
+                           old_label, new_label),
+  };
+
+  let mut out = format!("--- {}\n+++ {}\n", old_label, new_label);
+  let mut k = 0;
+  while k < script.len() {
+    let (ref op, _, _) = script[k];
+    if *op == Keep {
+      k += 1;
+      continue;
+    }
+
+    // Grow the hunk until the changes are separated by more than twice the context:
+    let start = if k > CONTEXT { k - CONTEXT } else { 0 };
+    let mut last_change = k;
+    let mut end = k + 1;
+    while end < script.len() {
+      let (ref op, _, _) = script[end];
+      if *op != Keep {
+        last_change = end;
+      } else if end - last_change > 2 * CONTEXT {
+        break;
+      }
+      end += 1;
+    }
+    let stop = cmp::min(script.len(), last_change + CONTEXT + 1);
+
+    let hunk = script.slice(start, stop);
+    let old_count = hunk.iter().filter(|&&(ref op, _, _)| *op != Insert).count();
+    let new_count = hunk.iter().filter(|&&(ref op, _, _)| *op != Delete).count();
+    let &(_, old_start, new_start) = hunk.iter().next().expect("hunk is not empty");
+    out.push_str(format!("@@ -{},{} +{},{} @@\n",
+                         old_start + 1, old_count, new_start + 1, new_count).as_slice());
+    for &(ref op, i, j) in hunk.iter() {
+      match *op {
+        Keep => out.push_str(format!(" {}\n", old_lines[i]).as_slice()),
+        Delete => out.push_str(format!("-{}\n", old_lines[i]).as_slice()),
+        Insert => out.push_str(format!("+{}\n", new_lines[j]).as_slice()),
+      }
+    }
+
+    k = stop;
+  }
+  out
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn unified_diff() {
+    let old = b"a\nb\nc\nd\ne\nf\ng\nh\ni\nj\n";
+    let new = b"a\nb\nc\nd\nE\nf\ng\nh\ni\nj\nk\n";
+    assert_eq!(diff_contents("old", "new", old, new).as_slice(),
+               "--- old\n+++ new\n\
+                @@ -2,9 +2,10 @@\n b\n c\n d\n-e\n+E\n f\n g\n h\n i\n j\n+k\n");
+  }
+
+  #[test]
+  fn binary_files() {
+    assert_eq!(diff_contents("old", "new", b"a\x00", b"b\x00").as_slice(),
+               "Binary files old and new differ\n");
+  }
+}
@@ -0,0 +1,170 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that hands blobs to a user-supplied command, so that storage systems
+//! hat knows nothing about can be used without changing hat (like git's remote helpers).
+//!
+//! The command is run with `sh -c` once per request, with the request and the hex name of the
+//! blob appended as arguments:
+//!
+//! - `store NAME`: store the blob read from standard input. Exit only once it is durable.
+//! - `retrieve NAME`: write the blob to standard output.
+//! - `delete NAME`: remove the blob.
+//! - `list`: write the names of all blobs to standard output, one per line.
+//!
+//! A request fails when the command exits with a non-zero status; its standard error is then
+//! shown as the error message. Exit status 75 (`EX_TEMPFAIL`) marks the failure as transient,
+//! so that the request is retried. A command that stores blobs elsewhere than a local disk should
+//! store under a temporary name and rename, so an interrupted `store` never leaves part of a
+//! blob under its real name.
+
+use blob_store::{BlobStoreBackend, transient};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::io::process::{Command, ExitStatus, ProcessOutput};
+use std::str;
+
+
+/// The exit status of a command whose request may succeed if repeated (from sysexits.h).
+static EX_TEMPFAIL: int = 75;
+
+#[deriving(Clone)]
+pub struct ExecBackend {
+  command: String,
+}
+
+impl ExecBackend {
+  pub fn new(command: String) -> ExecBackend {
+    ExecBackend{command: command}
+  }
+
+  /// Run the command with `args`, feeding it `input`. Returns the standard output.
+  ///
+  /// The command may exit without reading all of its input (e.g. when it fails early), so an
+  /// error writing the input is only reported once the command has been waited for, and only if
+  /// the command itself succeeded.
+  fn run(&self, args: &[&str], input: &[u8]) -> Result<Vec<u8>, String> {
+    let script = format!("{} \"$@\"", self.command);
+    let mut command = Command::new("sh");
+    command.arg("-c").arg(script.as_slice()).arg("hat-exec");
+    for arg in args.iter() {
+      command.arg(*arg);
+    }
+    let request = args.connect(" ");
+
+    let mut process = match command.spawn() {
+      Ok(process) => process,
+      Err(e) => return Err(format!("could not run '{}': {}", self.command, e)),
+    };
+    // Closing stdin, as the pipe is dropped here, tells the command that its input is complete:
+    let written = process.stdin.take().expect("stdin is piped").write(input);
+    match process.wait_with_output() {
+      Err(e) => Err(format!("'{} {}': {}", self.command, request, e)),
+      Ok(ProcessOutput{status, output, error}) => {
+        if status.success() {
+          return match written {
+            Ok(()) => Ok(output),
+            Err(e) => Err(format!("'{} {}': {}", self.command, request, e)),
+          };
+        }
+        let error = String::from_utf8_lossy(error.as_slice()).into_string();
+        let message = format!("'{} {}' failed ({}): {}", self.command, request, status,
+                              error.as_slice().trim());
+        match status {
+          ExitStatus(EX_TEMPFAIL) => Err(transient(message)),
+          _ => Err(message),
+        }
+      },
+    }
+  }
+}
+
+impl BlobStoreBackend for ExecBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.run(["store", name.to_hex().as_slice()], data).map(|_| ())
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    self.run(["retrieve", name.to_hex().as_slice()], [])
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.run(["delete", name.to_hex().as_slice()], []).map(|_| ())
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let out = try!(self.run(["list"], []));
+    let text = match str::from_utf8(out.as_slice()) {
+      Some(text) => text,
+      None => return Err(format!("'{} list' wrote names that are not text", self.command)),
+    };
+    Ok(text.lines().filter_map(|line| {
+      let name = line.trim();
+      if name.len() == 0 { None } else { name.from_hex().ok() }
+    }).collect())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use blob_store::{BlobStoreBackend, is_transient};
+
+  use std::io::{TempDir};
+
+  /// A command that keeps blobs as files in `dir`.
+  fn dir_command(dir: &Path) -> String {
+    format!("f() {{ case $1 in store) cat > {0}/$2;; retrieve) cat {0}/$2;; \
+             delete) rm {0}/$2;; list) ls {0};; esac; }}; f", dir.display())
+  }
+
+  #[test]
+  fn blobs_go_through_the_command() {
+    let dir = TempDir::new("hat-exec").unwrap();
+    let mut backend = ExecBackend::new(dir_command(dir.path()));
+
+    backend.store([1, 2], b"first").unwrap();
+    backend.store([3], b"second").unwrap();
+    assert_eq!(backend.retrieve([1, 2]), Ok(b"first".into_vec()));
+    assert_eq!(backend.list(), Ok(vec![vec![1, 2], vec![3]]));
+
+    backend.delete([1, 2]).unwrap();
+    assert_eq!(backend.list(), Ok(vec![vec![3]]));
+    let error = backend.retrieve([1, 2]).unwrap_err();
+    assert!(error.as_slice().contains("retrieve 0102' failed"), "{}", error);
+  }
+
+  #[test]
+  fn failed_requests_are_errors() {
+    let mut backend = ExecBackend::new("exit 3;".to_string());
+    assert!(!is_transient(backend.store([1], b"data").unwrap_err().as_slice()));
+    assert!(backend.list().is_err());
+
+    let mut backend = ExecBackend::new("exit 75;".to_string());
+    assert!(is_transient(backend.store([1], b"data").unwrap_err().as_slice()));
+
+    // A blob larger than the pipe buffer cannot be written to a command that does not read it;
+    // the exit status still decides the error:
+    let blob = Vec::from_elem(1024 * 1024, 7u8);
+    assert!(is_transient(backend.store([1], blob.as_slice()).unwrap_err().as_slice()));
+    let mut backend = ExecBackend::new("exit 0;".to_string());
+    assert!(backend.store([1], blob.as_slice()).is_err());
+  }
+}
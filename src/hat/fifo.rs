@@ -0,0 +1,137 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Reading named pipes (FIFOs) without hanging.
+//!
+//! Opening a named pipe for reading blocks until some process opens it for writing, and reading
+//! it blocks until that process writes; a snapshot that reads one would wait forever if no
+//! process ever does. `FifoReader` opens the pipe without blocking, and gives up with a
+//! `TimedOut` error when no data arrives for a while.
+
+use libc::{c_int, c_short, c_ulong, c_void, size_t};
+use libc::funcs::posix88::fcntl;
+use libc::funcs::posix88::unistd::{close, read};
+use libc::consts::os::posix88::{O_RDONLY, O_NONBLOCK, EAGAIN, EINTR};
+
+use std::io;
+use std::io::{IoError, IoResult, Reader};
+use std::os;
+
+
+static POLLIN: c_short = 0x1;
+
+#[repr(C)]
+struct PollFd {
+  fd: c_int,
+  events: c_short,
+  revents: c_short,
+}
+
+pub struct FifoReader {
+  fd: c_int,
+  timeout_ms: u64,
+}
+
+impl FifoReader {
+  /// Open the named pipe at `path` for reading. Reads fail with `TimedOut` if no data arrives
+  /// within `timeout_ms` milliseconds; they reach the end of the data once the writer is done.
+  pub fn open(path: &Path, timeout_ms: u64) -> IoResult<FifoReader> {
+    let fd = path.with_c_str(|c_str| unsafe { fcntl::open(c_str, O_RDONLY | O_NONBLOCK, 0) });
+    if fd < 0 {
+      return Err(IoError::last_error());
+    }
+    Ok(FifoReader{fd: fd, timeout_ms: timeout_ms})
+  }
+
+  /// Wait until there is data (or the end of it) to read. Returns whether there is.
+  fn wait(&self) -> IoResult<bool> {
+    extern {
+      fn poll(fds: *mut PollFd, nfds: c_ulong, timeout: c_int) -> c_int;
+    }
+    let mut pollfd = PollFd{fd: self.fd, events: POLLIN, revents: 0};
+    loop {
+      match unsafe { poll(&mut pollfd, 1, self.timeout_ms as c_int) } {
+        0 => return Ok(false),
+        n if n > 0 => return Ok(true),
+        _ if os::errno() == EINTR as int => continue,
+        _ => return Err(IoError::last_error()),
+      }
+    }
+  }
+}
+
+impl Reader for FifoReader {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    loop {
+      if !try!(self.wait()) {
+        return Err(IoError{kind: io::TimedOut,
+                           desc: "no data arrived from the named pipe in time",
+                           detail: None});
+      }
+      let n = unsafe { read(self.fd, buf.as_mut_ptr() as *mut c_void, buf.len() as size_t) };
+      if n > 0 {
+        return Ok(n as uint);
+      } else if n == 0 {
+        return Err(io::standard_error(io::EndOfFile));
+      }
+      let errno = os::errno();
+      if errno != EAGAIN as int && errno != EINTR as int {
+        return Err(IoError::last_error());
+      }
+    }
+  }
+}
+
+impl Drop for FifoReader {
+  fn drop(&mut self) {
+    unsafe { close(self.fd) };
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use libc::funcs::posix88::stat_::{mkfifo};
+
+  use std::io;
+  use std::io::{File, TempDir};
+
+  fn make_fifo(dir: &TempDir) -> Path {
+    let path = dir.path().join("fifo");
+    assert_eq!(path.with_c_str(|c_str| unsafe { mkfifo(c_str, 0o600) }), 0);
+    path
+  }
+
+  #[test]
+  fn reads_what_is_written() {
+    let dir = TempDir::new("hat-fifo").unwrap();
+    let path = make_fifo(&dir);
+    let mut reader = FifoReader::open(&path, 5000).unwrap();
+    let writer_path = path.clone();
+    spawn(proc() {
+      File::create(&writer_path).and_then(|mut f| f.write(b"hello")).unwrap();
+    });
+    assert_eq!(reader.read_to_end().unwrap(), b"hello".to_vec());
+  }
+
+  #[test]
+  fn times_out_without_writer() {
+    let dir = TempDir::new("hat-fifo").unwrap();
+    let path = make_fifo(&dir);
+    let mut reader = FifoReader::open(&path, 50).unwrap();
+    assert_eq!(reader.read_to_end().unwrap_err().kind, io::TimedOut);
+  }
+}
@@ -0,0 +1,175 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Content fingerprints of snapshots and of local directory trees.
+//!
+//! A fingerprint only depends on file names, the directory structure and file contents, so the
+//! fingerprint of a snapshot can be recomputed from a restored copy of it by anyone.
+
+use chunker::{Chunker, ChunkerOptions};
+
+use hash_index::{Hash};
+use hash_tree::{HashOnlyBackend, SimpleHashTreeWriter};
+
+use serialize::hex::{ToHex};
+
+use std::ascii::{StrAsciiExt};
+use std::io::{File, IoResult, TypeDirectory, TypeFile};
+use std::io::fs::{lstat, readdir};
+
+
+/// Number of bytes of a fingerprint that are shown to users.
+static PRINTABLE_BYTES: uint = 16;
+
+
+/// Collects the entries of a single directory and computes its fingerprint.
+pub struct Manifest {
+  lines: Vec<(Vec<u8>, String)>,
+}
+
+impl Manifest {
+
+  pub fn new() -> Manifest {
+    Manifest{lines: Vec::new()}
+  }
+
+  /// Add a file by its name and the top hash of its data.
+  pub fn add_file(&mut self, name: Vec<u8>, data_hash: &[u8]) {
+    let line = format!("f {} {}\n", name.as_slice().to_hex(), data_hash.to_hex());
+    self.lines.push((name, line));
+  }
+
+  /// Add a sub-directory by its name and fingerprint.
+  pub fn add_dir(&mut self, name: Vec<u8>, fingerprint: &Hash) {
+    let line = format!("d {} {}\n", name.as_slice().to_hex(),
+                       fingerprint.bytes.as_slice().to_hex());
+    self.lines.push((name, line));
+  }
+
+  /// The fingerprint of the directory. Entries are ordered by name, so the order in which they
+  /// were added does not matter.
+  pub fn fingerprint(self) -> Hash {
+    let mut lines = self.lines;
+    lines.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+
+    let mut manifest = Vec::new();
+    for (_, line) in lines.into_iter() {
+      manifest.push_all(line.as_bytes());
+    }
+    Hash::new(manifest.as_slice())
+  }
+}
+
+
+/// The short, printable form of a fingerprint.
+pub fn to_printable(fingerprint: &Hash) -> String {
+  fingerprint.bytes.slice_to(PRINTABLE_BYTES).to_hex()
+}
+
+/// Check a fingerprint against a (possibly shortened) printable fingerprint.
+pub fn matches_printable(fingerprint: &Hash, printable: &str) -> bool {
+  let printable = printable.trim().to_ascii_lower();
+  printable.len() >= PRINTABLE_BYTES * 2 &&
+    fingerprint.bytes.as_slice().to_hex().as_slice().starts_with(printable.as_slice())
+}
+
+
+/// Compute the top hash of a file's data, exactly as a snapshot would.
+pub fn file_data_hash(path: &Path, chunker: &ChunkerOptions) -> IoResult<Hash> {
+  let file = try!(File::open(path));
+  let mut tree = SimpleHashTreeWriter::new(8, HashOnlyBackend);
+  for chunk in Chunker::new(file, chunker.clone()) {
+    tree.append(chunk);
+  }
+  let (hash, _) = tree.hash();
+  Ok(hash)
+}
+
+/// Compute the fingerprint of a local directory tree (e.g. a restored snapshot), with files split
+/// into data-chunks as given by `chunker`. Symbolic links are ignored, as they are not part of
+/// snapshots.
+pub fn fingerprint_of_dir(dir: &Path, chunker: &ChunkerOptions) -> IoResult<Hash> {
+  let mut manifest = Manifest::new();
+  for path in try!(readdir(dir)).into_iter() {
+    let name = path.filename().expect("directory entry has a name").into_vec();
+    let stat = try!(lstat(&path));
+    if stat.kind == TypeDirectory {
+      manifest.add_dir(name, &try!(fingerprint_of_dir(&path, chunker)));
+    } else if stat.kind == TypeFile {
+      manifest.add_file(name, try!(file_data_hash(&path, chunker)).bytes.as_slice());
+    }
+  }
+  Ok(manifest.fingerprint())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use chunker::{ChunkerOptions};
+  use hash_index::{Hash};
+
+  use std::ascii::{StrAsciiExt};
+  use std::io::{File, TempDir, UserDir};
+  use std::io::fs::{mkdir, symlink};
+
+  fn manifest(entries: &[(&str, u8)]) -> Hash {
+    let mut manifest = Manifest::new();
+    for &(name, hash) in entries.iter() {
+      manifest.add_file(name.as_bytes().into_vec(), [hash]);
+    }
+    manifest.fingerprint()
+  }
+
+  #[test]
+  fn entries_in_any_order() {
+    assert_eq!(manifest([("a", 1), ("b", 2)]), manifest([("b", 2), ("a", 1)]));
+    assert!(manifest([("a", 1), ("b", 2)]) != manifest([("a", 2), ("b", 1)]));
+
+    // A directory is not a file with the same hash:
+    let file = manifest([("a", 1)]);
+    let mut dir = Manifest::new();
+    dir.add_dir(b"a".into_vec(), &Hash{bytes: vec![1]});
+    assert!(dir.fingerprint() != file);
+  }
+
+  #[test]
+  fn printable_fingerprints() {
+    let fingerprint = manifest([("a", 1)]);
+    let printable = to_printable(&fingerprint);
+    assert_eq!(printable.len(), 32);
+    assert!(matches_printable(&fingerprint, printable.as_slice()));
+    assert!(matches_printable(&fingerprint, printable.as_slice().to_ascii_upper().as_slice()));
+    // Too short to be trusted:
+    assert!(!matches_printable(&fingerprint, printable.as_slice().slice_to(31)));
+    assert!(!matches_printable(&manifest([("a", 2)]), printable.as_slice()));
+  }
+
+  #[test]
+  fn fingerprints_of_dirs() {
+    let chunker = ChunkerOptions::new(1024);
+    let dir = TempDir::new("hat-fingerprint").unwrap();
+    File::create(&dir.path().join("a")).write(b"file a").unwrap();
+    mkdir(&dir.path().join("sub"), UserDir).unwrap();
+    let before = fingerprint_of_dir(dir.path(), &chunker).unwrap();
+
+    // Symbolic links are not part of snapshots:
+    symlink(&dir.path().join("a"), &dir.path().join("link")).unwrap();
+    assert_eq!(fingerprint_of_dir(dir.path(), &chunker).unwrap(), before);
+
+    File::create(&dir.path().join("sub").join("b")).write(b"file b").unwrap();
+    assert!(fingerprint_of_dir(dir.path(), &chunker).unwrap() != before);
+  }
+}
@@ -0,0 +1,113 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Line-based search of file contents, fed one data-chunk at a time.
+
+use regex::{Regex};
+
+
+pub enum Pattern {
+  /// Match lines containing this string.
+  LiteralPattern(String),
+
+  /// Match lines matching this regular expression.
+  RegexPattern(Regex),
+}
+
+impl Pattern {
+  fn is_match(&self, line: &str) -> bool {
+    match *self {
+      LiteralPattern(ref literal) => line.contains(literal.as_slice()),
+      RegexPattern(ref re) => re.is_match(line),
+    }
+  }
+}
+
+
+/// Searches the lines of a single file. Lines may span several data-chunks.
+pub struct LineSearch<'a> {
+  pattern: &'a Pattern,
+  partial_line: Vec<u8>,
+  line_no: uint,
+
+  /// Whether the file looks binary (contains a NUL byte).
+  pub binary: bool,
+
+  /// Line numbers (starting at 1) and contents of the matching lines.
+  pub matches: Vec<(uint, String)>,
+}
+
+impl <'a> LineSearch<'a> {
+
+  pub fn new(pattern: &'a Pattern) -> LineSearch<'a> {
+    LineSearch{pattern: pattern, partial_line: Vec::new(), line_no: 0,
+               binary: false, matches: Vec::new()}
+  }
+
+  fn end_line(&mut self) {
+    self.line_no += 1;
+    let line = String::from_utf8_lossy(self.partial_line.as_slice()).into_string();
+    if self.pattern.is_match(line.as_slice()) {
+      self.matches.push((self.line_no, line));
+    }
+    self.partial_line.clear();
+  }
+
+  /// Search the next data-chunk of the file.
+  pub fn feed(&mut self, chunk: &[u8]) {
+    for &byte in chunk.iter() {
+      if byte == 0 { self.binary = true }
+      if byte == '\n' as u8 {
+        self.end_line();
+      } else {
+        self.partial_line.push(byte);
+      }
+    }
+  }
+
+  /// Search the last line of the file, if it does not end with a newline.
+  pub fn finish(&mut self) {
+    if self.partial_line.len() > 0 {
+      self.end_line();
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use regex::{Regex};
+
+  #[test]
+  fn lines_across_chunks() {
+    let pattern = LiteralPattern("needle".to_string());
+    let mut search = LineSearch::new(&pattern);
+    search.feed(b"hay\nhay ne");
+    search.feed(b"edle hay\nhay\nneedle");
+    search.finish();
+    assert!(!search.binary);
+    assert_eq!(search.matches, vec![(2, "hay needle hay".to_string()), (4, "needle".to_string())]);
+  }
+
+  #[test]
+  fn regex_and_binary() {
+    let pattern = RegexPattern(Regex::new("^a+b$").unwrap());
+    let mut search = LineSearch::new(&pattern);
+    search.feed(b"aab\n\x00ab\nab x\n");
+    search.finish();
+    assert!(search.binary);
+    assert_eq!(search.matches, vec![(1, "aab".to_string())]);
+  }
+}
@@ -14,12 +14,15 @@
 
 //! Local state for known hashes and their external location (blob reference).
 
+use std::collections::hashmap::{HashMap, HashSet};
+use std::mem;
 use std::time::duration::{Duration};
 use serialize::hex::{ToHex};
 
 use callback_container::{CallbackContainer};
 use cumulative_counter::{CumulativeCounter};
 use unique_priority_queue::{UniquePriorityQueue};
+use keys::{unlock_database};
 use process::{Process, MsgHandler};
 
 use sqlite3::database::{Database};
@@ -104,7 +107,36 @@ pub enum Msg {
   CallAfterHashIsComitted(Hash, proc():Send),
 
   /// Flush the hash index to clear internal buffers and commit the underlying database.
+  /// Returns `FlushOK` with whether every reserved `Hash` is now durably committed.
   Flush,
+
+  /// Flush the hash index and run maintenance on the underlying storage (for sqlite, `VACUUM` and
+  /// `ANALYZE`).
+  /// Returns `MaintenanceOK`.
+  Maintenance,
+
+  /// Check the underlying storage for corruption and verify that every committed
+  /// persistent reference points into one of the given (committed) blobs. The function is used to
+  /// extract the blob name from a persistent reference (`Ok(None)` if no blob is needed).
+  /// Returns `SelfCheckResult` with the problems found (empty if none).
+  SelfCheck(HashSet<Vec<u8>>, fn(&[u8]) -> Result<Option<Vec<u8>>, String>),
+
+  /// Flush the hash index and list the names of the blobs that committed entries reference.
+  /// The function extracts the blob name from a persistent reference (as for `SelfCheck`).
+  /// Returns `ReferencedBlobs`.
+  ListReferencedBlobs(fn(&[u8]) -> Result<Option<Vec<u8>>, String>),
+
+  /// Flush the hash index and list the hash and persistent reference of each committed entry
+  /// that is stored in one of the given blobs. The function extracts the blob name from a
+  /// persistent reference (as for `SelfCheck`).
+  /// Returns `Chunks`.
+  ListChunksIn(HashSet<Vec<u8>>, fn(&[u8]) -> Result<Option<Vec<u8>>, String>),
+
+  /// Flush the hash index, merge duplicate entries, remove the entries that do not point into
+  /// one of the given (committed) blobs, and rewrite the underlying storage compactly. The
+  /// function extracts the blob name from a persistent reference (as for `SelfCheck`).
+  /// Returns `Compacted` with the number of duplicates merged and of entries removed.
+  Compact(HashSet<Vec<u8>>, fn(&[u8]) -> Result<Option<Vec<u8>>, String>),
 }
 
 pub enum Reply {
@@ -117,67 +149,192 @@ pub enum Reply {
 
   ReserveOK,
   CommitOK,
+  FlushOK(bool),
   CallbackRegistered,
+  MaintenanceOK,
+  SelfCheckResult(Vec<String>),
+  ReferencedBlobs(HashSet<Vec<u8>>),
+  Chunks(Vec<(Hash, Vec<u8>)>),
+  Compacted(uint, uint),
 
   Retry,
 }
 
 
-#[deriving(Clone)]
-struct QueueEntry {
-  id: i64,
-  level: i64,
-  payload: Option<Vec<u8>>,
-  persistent_ref: Option<Vec<u8>>,
+/// Persistence engine behind the hash index.
+///
+/// Only committed entries reach the storage. Changes are buffered until `commit()` makes them
+/// durable.
+pub trait HashIndexStorage {
+  /// The largest entry id in use (`0` if the index is empty).
+  fn max_id(&mut self) -> i64;
+
+  /// Locate a stored entry and its id.
+  fn locate(&mut self, hash: &Hash) -> Option<(i64, HashEntry)>;
+
+  /// Store committed entries. Entries are given in increasing hash order, so that they touch the
+  /// pages of an index on the hash in order; their ids are not ordered. An entry whose hash is
+  /// already stored (a duplicate stored by a sparse index) must be kept as well, so that the data
+  /// it references is not lost; `locate` may return either entry.
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>);
+
+  /// Durably commit all changes made so far.
+  fn commit(&mut self);
+
+  /// Perform housekeeping, like reclaiming unused space.
+  fn maintenance(&mut self);
+
+  /// Check the stored data for corruption. Returns a description of each problem found.
+  fn integrity_check(&mut self) -> Vec<String>;
+
+  /// Call `f` with the hash and persistent reference of every stored entry.
+  fn for_each_persistent_ref(&mut self, f: |&[u8], &[u8]|);
+
+  /// Drop duplicates that reference the same data as the entry they duplicate, and entries whose
+  /// persistent reference `keep` rejects (e.g. because their blob was deleted). An entry that is
+  /// dropped is replaced by one of its duplicates, if any is kept. Returns the number of
+  /// duplicates merged and the number of entries removed; each dropped entry or duplicate is
+  /// counted once, and a duplicate that replaces a removed entry is not counted.
+  fn compact(&mut self, keep: |&[u8]| -> bool) -> (uint, uint);
 }
 
-pub struct HashIndex {
-  dbh: Database,
 
-  id_counter: CumulativeCounter<i64>,
+/// The most shards a hash index can have: each is a database attached to the same connection, and
+/// sqlite attaches at most 10.
+pub static MAX_SHARDS: uint = 10;
 
-  queue: UniquePriorityQueue<i64, Vec<u8>, QueueEntry>,
+/// Check the number of shards given for a new hash index.
+pub fn check_shards(shards: uint) -> Result<(), String> {
+  if shards == 0 || shards > MAX_SHARDS {
+    return Err(format!("A hash index has from 1 to {} shards, not {}.", MAX_SHARDS, shards));
+  }
+  Ok(())
+}
 
-  callbacks: CallbackContainer<Vec<u8>>,
+/// Pick the shard for a hash. Hashes are uniformly distributed, so the first byte will do.
+fn shard_of(hash_bytes: &[u8], shards: uint) -> uint {
+  assert!(hash_bytes.len() > 0);
+  hash_bytes[0] as uint % shards
+}
 
-  flush_timer: PeriodicTimer,
 
+/// Whether a hash lies in the hot part of a sparse index with `buckets` buckets. The second byte
+/// is used, so that the hot part is spread evenly across shards.
+fn is_hot(hash_bytes: &[u8], buckets: uint) -> bool {
+  buckets <= 1 || hash_bytes.len() < 2 || hash_bytes[1] as uint % buckets == 0
 }
 
-impl HashIndex {
 
-  pub fn new(path: String) -> HashIndex {
-    let mut hi = match open(path.as_slice()) {
-      Ok(dbh) => {
-        HashIndex{dbh: dbh,
-                  id_counter: CumulativeCounter::new(0i64),
-                  queue: UniquePriorityQueue::new(),
-                  callbacks: CallbackContainer::new(),
-                  flush_timer: PeriodicTimer::new(Duration::seconds(10)),
-        }
-      },
-      Err(err) => fail!(err.to_string()),
+/// The default `HashIndexStorage`, backed by sqlite. The index can be sharded by hash prefix across
+/// several database files.
+pub struct SqliteHashIndexStorage {
+  dbh: Database,
+  shards: uint,
+}
+
+impl SqliteHashIndexStorage {
+
+  /// Open the hash index stored at `path`. The number of shards is fixed when the index is
+  /// created and is otherwise read from the index itself. Fails if the index cannot be opened
+  /// (see `open`).
+  pub fn new(path: String, key: Option<Vec<u8>>, shards: Option<uint>) -> SqliteHashIndexStorage {
+    match SqliteHashIndexStorage::open(path, key, shards) {
+      Ok(storage) => storage,
+      Err(e) => fail!(e),
+    }
+  }
+
+  /// Open the hash index stored at `path`, or return an error if it cannot be opened with this
+  /// key or number of shards (see `check_shards`); an existing index cannot be resharded.
+  pub fn open(path: String, key: Option<Vec<u8>>, shards: Option<uint>)
+              -> Result<SqliteHashIndexStorage, String> {
+    match shards {
+      Some(shards) => try!(check_shards(shards)),
+      None => (),
+    }
+    let mut storage = match open(path.as_slice()) {
+      Ok(dbh) => SqliteHashIndexStorage{dbh: dbh, shards: 1},
+      Err(err) => return Err(err.to_string()),
     };
-    hi.exec_or_die("CREATE TABLE IF NOT EXISTS
-                  hash_index (id        INTEGER PRIMARY KEY,
-                              hash      BLOB,
-                              height    INTEGER,
-                              payload   BLOB,
-                              blob_ref  BLOB)");
+    match key {
+      Some(ref key) => try!(unlock_database(&mut storage.dbh, key.as_slice())),
+      None => (),
+    }
 
-    hi.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
-                  HashIndex_UniqueHash
-                  ON hash_index(hash)");
+    try!(storage.initialize_shards(path.as_slice(), key, shards));
 
-    hi.exec_or_die("BEGIN");
+    for table in storage.tables().iter() {
+      storage.exec_or_die(format!("CREATE TABLE IF NOT EXISTS
+                    {} (id        INTEGER PRIMARY KEY,
+                        hash      BLOB,
+                        height    INTEGER,
+                        payload   BLOB,
+                        blob_ref  BLOB)", table).as_slice());
+    }
+    storage.exec_or_die("CREATE TABLE IF NOT EXISTS
+                         hash_index_duplicates (hash      BLOB,
+                                                blob_ref  BLOB)");
+
+    for schema in storage.schemas().iter() {
+      storage.exec_or_die(format!("CREATE UNIQUE INDEX IF NOT EXISTS
+                    {}HashIndex_UniqueHash
+                    ON hash_index(hash)", schema).as_slice());
+    }
 
-    hi.refresh_id_counter();
-    hi
+    storage.exec_or_die("BEGIN");
+    Ok(storage)
   }
 
-  #[cfg(test)]
-  pub fn new_for_testing() -> HashIndex {
-    HashIndex::new(":memory:".to_string())
+  /// Read the number of shards of the index, or record `shards` (which has been checked) for a
+  /// new one, and attach the shards.
+  fn initialize_shards(&mut self, path: &str, key: Option<Vec<u8>>, shards: Option<uint>)
+                       -> Result<(), String> {
+    self.exec_or_die("CREATE TABLE IF NOT EXISTS hash_index_meta (shards INTEGER)");
+
+    let stored = self.select1("SELECT shards FROM hash_index_meta").map(|mut c| c.get_int(0));
+    self.shards = match (stored, shards) {
+      (Some(stored), Some(wanted)) if stored as uint != wanted => {
+        return Err(format!("The hash index was created with {} shard(s); resharding to {} is \
+                            not supported.", stored, wanted));
+      },
+      (Some(stored), _) => stored as uint,
+      (None, wanted) => {
+        let wanted = wanted.unwrap_or(1);
+        self.exec_or_die(format!("INSERT INTO hash_index_meta (shards) VALUES ({})",
+                                 wanted).as_slice());
+        wanted
+      },
+    };
+    try!(check_shards(self.shards).map_err(|e| format!("The hash index is corrupt: {}", e)));
+
+    if self.shards > 1 {
+      // Shards are attached to this connection, which keeps commits atomic across all of them.
+      for i in range(0, self.shards) {
+        let shard_path = if path == ":memory:" { path.to_string() }
+                         else { format!("{}.shard{}", path, i).replace("'", "''") };
+        let key_clause = match key {
+          Some(ref key) => format!(" KEY \"x'{}'\"", key.as_slice().to_hex()),
+          None => "".to_string(),
+        };
+        self.exec_or_die(format!("ATTACH DATABASE '{}' AS shard{}{}",
+                                 shard_path, i, key_clause).as_slice());
+      }
+    }
+    Ok(())
+  }
+
+  /// The schema prefixes of all shards (empty for an unsharded index).
+  fn schemas(&self) -> Vec<String> {
+    if self.shards == 1 { vec!("".to_string()) }
+    else { Vec::from_fn(self.shards, |i| format!("shard{}.", i)) }
+  }
+
+  fn tables(&self) -> Vec<String> {
+    self.schemas().into_iter().map(|schema| format!("{}hash_index", schema)).collect()
+  }
+
+  fn table_for(&self, hash_bytes: &[u8]) -> String {
+    self.tables().swap_remove(shard_of(hash_bytes, self.shards)).expect("shard exists")
   }
 
   fn exec_or_die(&mut self, sql: &str) {
@@ -202,12 +359,31 @@ impl HashIndex {
     if cursor.step() == SQLITE_ROW { Some(cursor) } else { None }
   }
 
-  fn index_locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
+  /// The number of rows changed by the last statement.
+  fn changes(&mut self) -> uint {
+    self.select1("SELECT changes()").expect("changes").get_int(0) as uint
+  }
+}
+
+impl HashIndexStorage for SqliteHashIndexStorage {
+
+  fn max_id(&mut self) -> i64 {
+    let mut id = 0;
+    for table in self.tables().iter() {
+      let max = self.select1(format!("SELECT MAX(id) FROM {}", table).as_slice())
+        .expect("id").get_int(0);
+      if max > id { id = max }
+    }
+    id as i64
+  }
+
+  fn locate(&mut self, hash: &Hash) -> Option<(i64, HashEntry)> {
     assert!(hash.bytes.len() > 0);
 
+    let table = self.table_for(hash.bytes.as_slice());
     let result_opt = self.select1(format!(
-      "SELECT id, height, payload, blob_ref FROM hash_index WHERE hash=x'{}'",
-      hash.bytes.as_slice().to_hex()
+      "SELECT id, height, payload, blob_ref FROM {} WHERE hash=x'{}'",
+      table, hash.bytes.as_slice().to_hex()
     ).as_slice());
     result_opt.map(|result| {
       let mut result = result;
@@ -215,21 +391,361 @@ impl HashIndex {
       let level = result.get_int(1) as i64;
       let payload = result.get_blob(2).unwrap_or([]).into_vec();
       let persistent_ref = result.get_blob(3).unwrap_or([]).into_vec();
-      QueueEntry{id: id, level: level,
-                 payload: if payload.len() == 0 { None }
-                          else {Some(payload) },
-                 persistent_ref: Some(persistent_ref)
-      } })
+      (id, HashEntry{hash: hash.clone(), level: level,
+                     payload: if payload.len() == 0 { None }
+                              else {Some(payload) },
+                     persistent_ref: Some(persistent_ref)
+      }) })
   }
 
-  fn locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>) {
+    let shards = self.shards;
+    let tables = self.tables();
+    let mut insert_stms = Vec::new();
+    for table in tables.iter() {
+      insert_stms.push(self.dbh.prepare(format!(
+        "INSERT OR IGNORE INTO {} (id, hash, height, payload, blob_ref) VALUES (?, ?, ?, ?, ?)",
+        table).as_slice(), &None).unwrap());
+    }
+    let mut changes_stm = self.dbh.prepare("SELECT changes()", &None).unwrap();
+    let mut duplicate_stm = self.dbh.prepare(
+      "INSERT INTO hash_index_duplicates (hash, blob_ref) VALUES (?, ?)", &None).unwrap();
+
+    for (id, entry) in entries.into_iter() {
+      let HashEntry{hash, level, payload, persistent_ref} = entry;
+      let insert_stm = insert_stms.get_mut(shard_of(hash.bytes.as_slice(), shards));
+
+      let payload = payload.unwrap_or_else(|| b"".into_vec());
+      let persistent_ref = persistent_ref.expect("hash was comitted");
+
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(id)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash.bytes.clone())));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(level)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(payload)));
+      assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(persistent_ref.clone())));
+
+      assert_eq!(SQLITE_DONE, insert_stm.step());
+
+      assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
+      assert_eq!(SQLITE_OK, insert_stm.reset());
+
+      // The hash was already stored, so keep the duplicate's reference on the side:
+      assert_eq!(SQLITE_ROW, changes_stm.step());
+      let inserted = changes_stm.get_int(0) > 0;
+      assert_eq!(SQLITE_OK, changes_stm.reset());
+      if !inserted {
+        assert_eq!(SQLITE_OK, duplicate_stm.bind_param(1, &Blob(hash.bytes)));
+        assert_eq!(SQLITE_OK, duplicate_stm.bind_param(2, &Blob(persistent_ref)));
+        assert_eq!(SQLITE_DONE, duplicate_stm.step());
+        assert_eq!(SQLITE_OK, duplicate_stm.clear_bindings());
+        assert_eq!(SQLITE_OK, duplicate_stm.reset());
+      }
+    }
+  }
+
+  fn commit(&mut self) {
+    self.exec_or_die("COMMIT; BEGIN");
+  }
+
+  fn maintenance(&mut self) {
+    // VACUUM cannot run inside a transaction. It only compacts the main database file, while
+    // ANALYZE covers all attached shards.
+    self.exec_or_die("COMMIT; VACUUM; ANALYZE; BEGIN");
+  }
+
+  fn integrity_check(&mut self) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut cursor = self.prepare_or_die("PRAGMA integrity_check");
+    while cursor.step() == SQLITE_ROW {
+      match cursor.get_text(0) {
+        Some(msg) if msg != "ok" => problems.push(format!("hash_index: {}", msg)),
+        _ => (),
+      }
+    }
+    problems
+  }
+
+  fn for_each_persistent_ref(&mut self, f: |&[u8], &[u8]|) {
+    let mut tables = self.tables();
+    tables.push("hash_index_duplicates".to_string());
+    for table in tables.iter() {
+      let mut cursor = self.prepare_or_die(format!("SELECT hash, blob_ref FROM {}",
+                                                   table).as_slice());
+      while cursor.step() == SQLITE_ROW {
+        f(cursor.get_blob(0).unwrap_or([]), cursor.get_blob(1).unwrap_or([]));
+      }
+    }
+  }
+
+  fn compact(&mut self, keep: |&[u8]| -> bool) -> (uint, uint) {
+    let tables = self.tables();
+    let mut merged = 0;
+    let mut removed = 0;
+
+    // Duplicates that reference the same data as their entry, or as another duplicate:
+    for table in tables.iter() {
+      self.exec_or_die(format!(
+        "DELETE FROM hash_index_duplicates WHERE EXISTS (
+           SELECT 1 FROM {} AS entry WHERE entry.hash = hash_index_duplicates.hash
+                                        AND entry.blob_ref = hash_index_duplicates.blob_ref)",
+        table).as_slice());
+      merged += self.changes();
+    }
+    self.exec_or_die("DELETE FROM hash_index_duplicates WHERE rowid NOT IN (
+                        SELECT MIN(rowid) FROM hash_index_duplicates GROUP BY hash, blob_ref)");
+    merged += self.changes();
+
+    // Duplicates that reference deleted data:
+    let mut dropped = Vec::new();
+    {
+      let mut cursor = self.prepare_or_die("SELECT rowid, blob_ref FROM hash_index_duplicates");
+      while cursor.step() == SQLITE_ROW {
+        if !keep(cursor.get_blob(1).unwrap_or([])) {
+          dropped.push(cursor.get_int(0));
+        }
+      }
+    }
+    for rowid in dropped.iter() {
+      self.exec_or_die(format!("DELETE FROM hash_index_duplicates WHERE rowid = {}",
+                               rowid).as_slice());
+      removed += 1;
+    }
+
+    // Entries that reference deleted data, which are replaced by a duplicate if one is left:
+    for table in tables.iter() {
+      let mut dropped = Vec::new();
+      {
+        let mut cursor = self.prepare_or_die(format!("SELECT id, hash, blob_ref FROM {}",
+                                                     table).as_slice());
+        while cursor.step() == SQLITE_ROW {
+          if !keep(cursor.get_blob(2).unwrap_or([])) {
+            dropped.push((cursor.get_int(0), cursor.get_blob(1).unwrap_or([]).to_hex()));
+          }
+        }
+      }
+      for &(id, ref hash) in dropped.iter() {
+        let duplicate = self.select1(format!(
+          "SELECT rowid FROM hash_index_duplicates WHERE hash = x'{}' LIMIT 1", hash).as_slice())
+          .map(|mut cursor| cursor.get_int(0));
+        match duplicate {
+          Some(rowid) => {
+            self.exec_or_die(format!(
+              "UPDATE {} SET blob_ref = (SELECT blob_ref FROM hash_index_duplicates
+                                         WHERE rowid = {})
+               WHERE id = {}", table, rowid, id).as_slice());
+            self.exec_or_die(format!("DELETE FROM hash_index_duplicates WHERE rowid = {}",
+                                     rowid).as_slice());
+          },
+          None => self.exec_or_die(format!("DELETE FROM {} WHERE id = {}", table,
+                                           id).as_slice()),
+        }
+        removed += 1;
+      }
+    }
+
+    (merged, removed)
+  }
+}
+
+
+/// A `HashIndexStorage` that keeps its entries in memory, e.g. for tests. Nothing is persisted.
+pub struct MemoryHashIndexStorage {
+  entries: HashMap<Vec<u8>, (i64, HashEntry)>,
+
+  // Entries whose hash was already stored (see `insert_batch`):
+  duplicates: Vec<HashEntry>,
+}
+
+impl MemoryHashIndexStorage {
+  pub fn new() -> MemoryHashIndexStorage {
+    MemoryHashIndexStorage{entries: HashMap::new(), duplicates: Vec::new()}
+  }
+}
+
+fn stored_ref<'a>(entry: &'a HashEntry) -> &'a [u8] {
+  entry.persistent_ref.as_ref().expect("hash was comitted").as_slice()
+}
+
+impl HashIndexStorage for MemoryHashIndexStorage {
+
+  fn max_id(&mut self) -> i64 {
+    self.entries.values().map(|&(id, _)| id).max().unwrap_or(0)
+  }
+
+  fn locate(&mut self, hash: &Hash) -> Option<(i64, HashEntry)> {
+    self.entries.find(&hash.bytes).map(|&(id, ref entry)| (id, entry.clone()))
+  }
+
+  fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>) {
+    for (id, mut entry) in entries.into_iter() {
+      assert!(entry.persistent_ref.is_some(), "hash was comitted");
+      // As with sqlite, an empty payload reads back as none:
+      if entry.payload.as_ref().map_or(false, |payload| payload.len() == 0) {
+        entry.payload = None;
+      }
+      if self.entries.contains_key(&entry.hash.bytes) {
+        self.duplicates.push(entry);
+      } else {
+        self.entries.insert(entry.hash.bytes.clone(), (id, entry));
+      }
+    }
+  }
+
+  fn commit(&mut self) {}
+
+  fn maintenance(&mut self) {}
+
+  fn integrity_check(&mut self) -> Vec<String> { vec![] }
+
+  fn for_each_persistent_ref(&mut self, f: |&[u8], &[u8]|) {
+    for &(_, ref entry) in self.entries.values() {
+      f(entry.hash.bytes.as_slice(), stored_ref(entry));
+    }
+    for duplicate in self.duplicates.iter() {
+      f(duplicate.hash.bytes.as_slice(), stored_ref(duplicate));
+    }
+  }
+
+  fn compact(&mut self, keep: |&[u8]| -> bool) -> (uint, uint) {
+    let mut merged = 0;
+    let mut removed = 0;
+
+    // Duplicates that reference the same data as their entry, or as another duplicate:
+    let mut distinct: Vec<HashEntry> = Vec::new();
+    for duplicate in mem::replace(&mut self.duplicates, Vec::new()).into_iter() {
+      let same_as_entry = match self.entries.find(&duplicate.hash.bytes) {
+        Some(&(_, ref entry)) => entry.persistent_ref == duplicate.persistent_ref,
+        None => false,
+      };
+      if same_as_entry || distinct.iter().any(|other| other.hash == duplicate.hash &&
+                                                      other.persistent_ref ==
+                                                      duplicate.persistent_ref) {
+        merged += 1;
+      } else {
+        distinct.push(duplicate);
+      }
+    }
+
+    // Duplicates that reference deleted data:
+    for duplicate in distinct.into_iter() {
+      if keep(stored_ref(&duplicate)) {
+        self.duplicates.push(duplicate);
+      } else {
+        removed += 1;
+      }
+    }
+
+    // Entries that reference deleted data, which are replaced by a duplicate if one is left:
+    let mut dropped = Vec::new();
+    for (hash, &(_, ref entry)) in self.entries.iter() {
+      if !keep(stored_ref(entry)) {
+        dropped.push(hash.clone());
+      }
+    }
+    for hash in dropped.into_iter() {
+      let (id, mut entry) = self.entries.pop(&hash).expect("entry");
+      match self.duplicates.iter().position(|duplicate| duplicate.hash.bytes == hash) {
+        Some(i) => {
+          entry.persistent_ref = self.duplicates.remove(i).expect("duplicate").persistent_ref;
+          self.entries.insert(hash, (id, entry));
+        },
+        None => (),
+      }
+      removed += 1;
+    }
+
+    (merged, removed)
+  }
+}
+
+
+/// The number of committed entries that are kept in memory before they are written to storage.
+/// They are written in one batch, sorted by hash, when there are this many or at the next flush.
+static INSERT_BATCH_SIZE: uint = 50000;
+
+#[deriving(Clone)]
+struct QueueEntry {
+  id: i64,
+  level: i64,
+  payload: Option<Vec<u8>>,
+  persistent_ref: Option<Vec<u8>>,
+}
+
+pub struct HashIndex {
+  storage: Box<HashIndexStorage>,
+
+  id_counter: CumulativeCounter<i64>,
+
+  queue: UniquePriorityQueue<i64, Vec<u8>, QueueEntry>,
+
+  callbacks: CallbackContainer<Vec<u8>>,
+
+  // Committed entries that are not written to storage yet, by hash:
+  pending: HashMap<Vec<u8>, QueueEntry>,
+
+  flush_timer: PeriodicTimer,
+
+  /// The number of buckets of a sparse index (`1` if the index is not sparse). See `new`.
+  sparse_buckets: uint,
+}
+
+impl HashIndex {
+
+  /// Open the sqlite hash index stored at `path` (see `SqliteHashIndexStorage::new`).
+  ///
+  /// With `sparse_buckets` set to N, the index is sparse: hashes are split into N buckets by
+  /// prefix, and only chunks in one of them are looked up in the stored index before they are
+  /// stored. Other chunks are only deduplicated against chunks that are still being stored, so
+  /// some data may be stored twice, but only a fraction of the index is read (and cached) while
+  /// taking a snapshot. This suits machines with little memory or slow disks.
+  pub fn new(path: String, key: Option<Vec<u8>>, shards: Option<uint>,
+             sparse_buckets: Option<uint>) -> HashIndex {
+    let mut hi = HashIndex::with_storage(box SqliteHashIndexStorage::new(path, key, shards));
+    hi.sparse_buckets = sparse_buckets.unwrap_or(1);
+    assert!(hi.sparse_buckets > 0);
+    hi
+  }
+
+  /// Create a hash index that persists committed entries through `storage`.
+  pub fn with_storage(storage: Box<HashIndexStorage>) -> HashIndex {
+    let mut hi = HashIndex{storage: storage,
+                           id_counter: CumulativeCounter::new(0i64),
+                           queue: UniquePriorityQueue::new(),
+                           callbacks: CallbackContainer::new(),
+                           pending: HashMap::new(),
+                           flush_timer: PeriodicTimer::new(Duration::seconds(10)),
+                           sparse_buckets: 1,
+    };
+    hi.refresh_id_counter();
+    hi
+  }
+
+  #[cfg(test)]
+  pub fn new_for_testing() -> HashIndex {
+    HashIndex::new(":memory:".to_string(), None, None, None)
+  }
+
+  fn index_locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
+    self.storage.locate(hash).map(|(id, entry)| {
+      QueueEntry{id: id, level: entry.level,
+                 payload: entry.payload,
+                 persistent_ref: entry.persistent_ref}
+    })
+  }
+
+  /// Find an entry that is being committed, or was committed but is not written yet.
+  fn locate_unwritten(&mut self, hash: &Hash) -> Option<QueueEntry> {
     let result_opt = self.queue.find_value_of_key(&hash.bytes);
-    result_opt.map(|x| x).or_else(|| self.index_locate(hash))
+    result_opt.map(|x| x).or_else(|| self.pending.find(&hash.bytes).map(|x| x.clone()))
+  }
+
+  fn locate(&mut self, hash: &Hash) -> Option<QueueEntry> {
+    self.locate_unwritten(hash).or_else(|| self.index_locate(hash))
   }
 
   fn refresh_id_counter(&mut self) {
-    let id = self.select1("SELECT MAX(id) FROM hash_index").expect("id").get_int(0);
-    self.id_counter = CumulativeCounter::new(id as i64);
+    let id = self.storage.max_id();
+    self.id_counter = CumulativeCounter::new(id);
   }
 
   fn next_id(&mut self) -> i64 {
@@ -276,6 +792,10 @@ impl HashIndex {
 
     if self.queue.find_value_of_key(&hash.bytes).is_some() {
       self.callbacks.add(hash.bytes.clone(), callback);
+    } else if self.pending.contains_key(&hash.bytes) {
+      // Committed, but only safe once written at the next flush:
+      self.callbacks.add(hash.bytes.clone(), callback);
+      self.callbacks.allow_flush_of(hash.bytes.clone());
     } else if self.locate(hash).is_some() {
       // Hash was already committed
       callback();
@@ -288,36 +808,36 @@ impl HashIndex {
   }
 
   fn insert_completed_in_order(&mut self) {
-    let mut insert_stm = self.dbh.prepare(
-      "INSERT INTO hash_index (id, hash, height, payload, blob_ref) VALUES (?, ?, ?, ?, ?)",
-      &None).unwrap();
-
     loop {
       match self.queue.pop_min_if_complete() {
         None => break,
         Some((id, hash_bytes, queue_entry)) => {
           assert_eq!(id, queue_entry.id);
-
-          let child_refs_opt = queue_entry.payload;
-          let payload = child_refs_opt.unwrap_or_else(|| b"".into_vec());
-          let level = queue_entry.level;
-          let persistent_ref = queue_entry.persistent_ref.expect("hash was comitted");
-
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(1, &Integer64(id)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(2, &Blob(hash_bytes.clone())));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(3, &Integer64(level)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(4, &Blob(payload)));
-          assert_eq!(SQLITE_OK, insert_stm.bind_param(5, &Blob(persistent_ref)));
-
-          assert_eq!(SQLITE_DONE, insert_stm.step());
-
-          assert_eq!(SQLITE_OK, insert_stm.clear_bindings());
-          assert_eq!(SQLITE_OK, insert_stm.reset());
-
-          self.callbacks.allow_flush_of(hash_bytes);
+          // The callbacks run at the next flush, which writes the entry first:
+          self.callbacks.allow_flush_of(hash_bytes.clone());
+          self.pending.insert(hash_bytes, queue_entry);
         },
       }
     }
+
+    if self.pending.len() >= INSERT_BATCH_SIZE {
+      self.write_pending();
+    }
+  }
+
+  /// Write the committed entries to storage, sorted by hash: hashes are random, so entries in id
+  /// order would update the pages of the hash index in random order.
+  fn write_pending(&mut self) {
+    if self.pending.len() == 0 {
+      return;
+    }
+    let pending = mem::replace(&mut self.pending, HashMap::new());
+    let mut entries: Vec<(i64, HashEntry)> = pending.into_iter().map(|(hash_bytes, qe)| {
+      (qe.id, HashEntry{hash: Hash{bytes: hash_bytes}, level: qe.level, payload: qe.payload,
+                        persistent_ref: qe.persistent_ref})
+    }).collect();
+    entries.sort_by(|&(_, ref a), &(_, ref b)| a.hash.bytes.cmp(&b.hash.bytes));
+    self.storage.insert_batch(entries);
   }
 
   fn commit(&mut self, hash: &Hash, blob_ref: &Vec<u8>) {
@@ -341,11 +861,53 @@ impl HashIndex {
 
   fn flush(&mut self) {
     // Callbacks assume their data is safe, so commit before calling them
-    self.exec_or_die("COMMIT; BEGIN");
+    self.write_pending();
+    self.storage.commit();
 
     // Run ready callbacks
     self.callbacks.flush();
   }
+
+  fn maintenance(&mut self) {
+    self.flush();
+    self.storage.maintenance();
+  }
+
+  fn self_check(&mut self, blobs: &HashSet<Vec<u8>>,
+                blob_name_of: fn(&[u8]) -> Result<Option<Vec<u8>>, String>) -> Vec<String> {
+    let mut problems = self.storage.integrity_check();
+
+    self.storage.for_each_persistent_ref(|hash, persistent_ref| {
+      match blob_name_of(persistent_ref) {
+        Err(e) => {
+          problems.push(format!("hash_index: unreadable blob reference for hash {}: {}",
+                                hash.to_hex(), e));
+        },
+        Ok(Some(ref name)) if !blobs.contains(name) => {
+          problems.push(format!("hash_index: hash {} references uncommitted blob {}",
+                                hash.to_hex(), name.as_slice().to_hex()));
+        },
+        Ok(_) => (),
+      }
+    });
+
+    problems
+  }
+
+  fn compact(&mut self, blobs: &HashSet<Vec<u8>>,
+             blob_name_of: fn(&[u8]) -> Result<Option<Vec<u8>>, String>) -> (uint, uint) {
+    self.flush();
+    let counts = self.storage.compact(|persistent_ref| {
+      match blob_name_of(persistent_ref) {
+        Ok(Some(ref name)) => blobs.contains(name),
+        // Entries without data, and references we cannot read, are left alone:
+        _ => true,
+      }
+    });
+    self.storage.commit();
+    self.storage.maintenance();
+    counts
+  }
 }
 
 // #[unsafe_desctructor]
@@ -395,10 +957,14 @@ impl MsgHandler<Msg, Reply> for HashIndex {
         // To avoid unused IO, we store entries in-memory until committed to persistent storage.
         // This allows us to continue after a crash without needing to scan through and delete
         // uncommitted entries.
-        return reply(match self.locate(&hash_entry.hash) {
-          Some(_) => HashKnown,
-          None => { self.reserve(hash_entry); ReserveOK },
-        });
+        // In a sparse index, only hot hashes are looked up in storage:
+        let known = if is_hot(hash_entry.hash.bytes.as_slice(), self.sparse_buckets) {
+          self.locate(&hash_entry.hash).is_some()
+        } else {
+          self.locate_unwritten(&hash_entry.hash).is_some()
+        };
+        return reply(if known { HashKnown }
+                     else { self.reserve(hash_entry); ReserveOK });
       },
 
       UpdateReserved(hash_entry) => {
@@ -424,8 +990,284 @@ impl MsgHandler<Msg, Reply> for HashIndex {
 
       Flush => {
         self.flush();
-        return reply(CommitOK);
-      }
+        return reply(FlushOK(self.queue.len() == 0));
+      },
+
+      Maintenance => {
+        self.maintenance();
+        return reply(MaintenanceOK);
+      },
+
+      SelfCheck(blobs, blob_name_of) => {
+        return reply(SelfCheckResult(self.self_check(&blobs, blob_name_of)));
+      },
+
+      ListReferencedBlobs(blob_name_of) => {
+        self.flush();
+        let mut names = HashSet::new();
+        self.storage.for_each_persistent_ref(|_, persistent_ref| {
+          match blob_name_of(persistent_ref) {
+            Ok(Some(name)) => { names.insert(name); },
+            _ => (),
+          }
+        });
+        return reply(ReferencedBlobs(names));
+      },
+
+      ListChunksIn(blobs, blob_name_of) => {
+        self.flush();
+        let mut chunks = Vec::new();
+        self.storage.for_each_persistent_ref(|hash, persistent_ref| {
+          match blob_name_of(persistent_ref) {
+            Ok(Some(ref name)) if blobs.contains(name) => {
+              chunks.push((Hash{bytes: hash.into_vec()}, persistent_ref.into_vec()));
+            },
+            _ => (),
+          }
+        });
+        return reply(Chunks(chunks));
+      },
+
+      Compact(blobs, blob_name_of) => {
+        let (merged, removed) = self.compact(&blobs, blob_name_of);
+        return reply(Compacted(merged, removed));
+      },
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{is_hot};
+
+  use process::{MsgHandler};
+  use std::io::{TempDir};
+  use std::sync::{Arc, Mutex};
+
+  fn index_path(dir: &TempDir) -> String {
+    dir.path().join("hash_index.sqlite3").as_str().unwrap().to_string()
+  }
+
+  fn entry(hash: u8, persistent_ref: &str) -> HashEntry {
+    HashEntry{hash: Hash{bytes: vec![hash]}, level: 0, payload: None,
+              persistent_ref: Some(persistent_ref.as_bytes().to_vec())}
+  }
+
+  fn persistent_ref<S: HashIndexStorage>(storage: &mut S, hash: u8) -> Option<Vec<u8>> {
+    storage.locate(&Hash{bytes: vec![hash]}).and_then(|(_, entry)| entry.persistent_ref)
+  }
+
+  fn check_compact<S: HashIndexStorage>(storage: &mut S) {
+    storage.insert_batch(vec![(1, entry(1, "a")), (2, entry(2, "gone")),
+                              (3, entry(3, "gone")), (4, entry(4, "a"))]);
+    // Duplicates, as stored by a sparse index:
+    storage.insert_batch(vec![(5, entry(1, "a")), (6, entry(1, "b")), (7, entry(2, "b")),
+                              (8, entry(4, "gone"))]);
+    storage.insert_batch(vec![(9, entry(1, "b"))]);
+
+    // Merged: the duplicate of 1 that matches its entry, and the second duplicate "b" of 1.
+    // Removed: entry 2 (replaced by its duplicate), entry 3 and the duplicate of 4.
+    assert_eq!(storage.compact(|persistent_ref| persistent_ref != b"gone"), (2, 3));
+    assert_eq!(persistent_ref(storage, 1), Some(b"a".to_vec()));
+    assert_eq!(persistent_ref(storage, 2), Some(b"b".to_vec()));
+    assert_eq!(persistent_ref(storage, 3), None);
+    assert_eq!(persistent_ref(storage, 4), Some(b"a".to_vec()));
+
+    // Nothing is left to merge or remove:
+    assert_eq!(storage.compact(|persistent_ref| persistent_ref != b"gone"), (0, 0));
+  }
+
+  #[test]
+  fn compact_counts_each_entry_once() {
+    let dir = TempDir::new("hash-index").unwrap();
+    check_compact(&mut SqliteHashIndexStorage::open(index_path(&dir), None, Some(2)).unwrap());
+  }
+
+  #[test]
+  fn compact_in_memory() {
+    check_compact(&mut MemoryHashIndexStorage::new());
+  }
+
+  #[test]
+  fn hash_index_in_memory() {
+    let mut storage = MemoryHashIndexStorage::new();
+    storage.insert_batch(vec![(7, entry(1, "a"))]);
+    let mut ids = vec![];
+    storage.for_each_persistent_ref(|hash, persistent_ref| {
+      ids.push((hash.to_vec(), persistent_ref.to_vec()));
+    });
+    assert_eq!(ids, vec![(vec![1], b"a".to_vec())]);
+
+    // Committed entries are found by a hash index on top of the storage:
+    let mut index = HashIndex::with_storage(box storage);
+    assert_eq!(index.storage.max_id(), 7);
+    assert!(index.index_locate(&Hash{bytes: vec![1]}).is_some());
+    assert!(index.index_locate(&Hash{bytes: vec![2]}).is_none());
+  }
+
+  fn send(index: &mut HashIndex, msg: Msg) -> Reply {
+    let mut result = None;
+    index.handle(msg, |reply| result = Some(reply));
+    result.expect("a reply")
+  }
+
+  /// Reserve `hash` in `index`. Returns whether the hash was known.
+  fn reserve(index: &mut HashIndex, hash: Vec<u8>) -> bool {
+    match send(index, Reserve(HashEntry{hash: Hash{bytes: hash}, level: 0, payload: None,
+                                        persistent_ref: None})) {
+      HashKnown => true,
+      ReserveOK => false,
+      _ => fail!("unexpected reply"),
+    }
+  }
+
+  #[test]
+  fn sparse_index() {
+    assert!(is_hot([7, 8], 4));
+    assert!(!is_hot([8, 7], 4));
+    // Without buckets, every hash is hot:
+    assert!(is_hot([8, 7], 1));
+
+    let mut storage = MemoryHashIndexStorage::new();
+    let hot = vec![2, 0];
+    let cold = vec![3, 1];
+    storage.insert_batch(vec![(2, HashEntry{hash: Hash{bytes: hot.clone()}, level: 0,
+                                            payload: None, persistent_ref: Some(b"a".to_vec())}),
+                              (3, HashEntry{hash: Hash{bytes: cold.clone()}, level: 0,
+                                            payload: None, persistent_ref: Some(b"a".to_vec())})]);
+    let mut index = HashIndex::with_storage(box storage);
+    index.sparse_buckets = 4;
+
+    // Only hot hashes are looked up in storage, so the cold one is stored again:
+    assert!(reserve(&mut index, hot.clone()));
+    assert!(!reserve(&mut index, cold.clone()));
+    // Hashes that are still being stored are known either way:
+    assert!(reserve(&mut index, cold.clone()));
+    assert!(!reserve(&mut index, vec![4, 1]));
+    assert!(reserve(&mut index, vec![4, 1]));
+  }
+
+  /// Memory storage that records the hashes of each batch inserted.
+  struct RecordingStorage {
+    storage: MemoryHashIndexStorage,
+    batches: Arc<Mutex<Vec<Vec<Vec<u8>>>>>,
+  }
+
+  impl HashIndexStorage for RecordingStorage {
+    fn max_id(&mut self) -> i64 { self.storage.max_id() }
+
+    fn locate(&mut self, hash: &Hash) -> Option<(i64, HashEntry)> { self.storage.locate(hash) }
+
+    fn insert_batch(&mut self, entries: Vec<(i64, HashEntry)>) {
+      self.batches.lock().push(entries.iter().map(|&(_, ref e)| e.hash.bytes.clone()).collect());
+      self.storage.insert_batch(entries)
+    }
+
+    fn commit(&mut self) { self.storage.commit() }
+
+    fn maintenance(&mut self) { self.storage.maintenance() }
+
+    fn integrity_check(&mut self) -> Vec<String> { self.storage.integrity_check() }
+
+    fn for_each_persistent_ref(&mut self, f: |&[u8], &[u8]|) {
+      self.storage.for_each_persistent_ref(f)
+    }
+
+    fn compact(&mut self, keep: |&[u8]| -> bool) -> (uint, uint) { self.storage.compact(keep) }
+  }
+
+  #[test]
+  fn committed_entries_are_written_sorted_at_flush() {
+    let batches = Arc::new(Mutex::new(Vec::new()));
+    let mut index = HashIndex::with_storage(
+      box RecordingStorage{storage: MemoryHashIndexStorage::new(), batches: batches.clone()});
+    for hash in [3u8, 1, 2].iter() {
+      assert!(!reserve(&mut index, vec![*hash]));
+      send(&mut index, Commit(Hash{bytes: vec![*hash]}, vec![*hash]));
+    }
+
+    // Committed entries are known before they are written:
+    assert!(batches.lock().is_empty());
+    assert!(reserve(&mut index, vec![1]));
+    let (tx, rx) = channel();
+    let callback = proc() { tx.send(()) };
+    match send(&mut index, CallAfterHashIsComitted(Hash{bytes: vec![1]}, callback)) {
+      CallbackRegistered => (),
+      _ => fail!("unexpected reply"),
+    }
+    assert!(rx.try_recv().is_err());
+
+    // They are written at the next flush, in one batch sorted by hash:
+    match send(&mut index, Flush) {
+      FlushOK(true) => (),
+      _ => fail!("unexpected reply"),
+    }
+    assert_eq!(*batches.lock(), vec![vec![vec![1], vec![2], vec![3]]]);
+    assert!(rx.try_recv().is_ok());
+    assert_eq!(index.storage.locate(&Hash{bytes: vec![2]}).map(|(_, e)| e.persistent_ref),
+               Some(Some(vec![2])));
+  }
+
+  fn blob_name(persistent_ref: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    if persistent_ref == b"bad" {
+      Err("unreadable".to_string())
+    } else {
+      Ok(Some(persistent_ref.to_vec()))
+    }
+  }
+
+  #[test]
+  fn self_check() {
+    let mut storage = MemoryHashIndexStorage::new();
+    storage.insert_batch(vec![(1, entry(1, "a")), (2, entry(2, "gone")), (3, entry(3, "bad"))]);
+    let mut index = HashIndex::with_storage(box storage);
+
+    let blobs = vec![b"a".to_vec()].into_iter().collect();
+    let mut problems = index.self_check(&blobs, blob_name);
+    problems.sort();
+    assert_eq!(problems, vec![
+      "hash_index: hash 02 references uncommitted blob 676f6e65".to_string(),
+      "hash_index: unreadable blob reference for hash 03: unreadable".to_string()]);
+  }
+
+  #[test]
+  fn zero_shards() {
+    let dir = TempDir::new("hash-index").unwrap();
+    assert!(SqliteHashIndexStorage::open(index_path(&dir), None, Some(0)).is_err());
+    assert!(SqliteHashIndexStorage::open(index_path(&dir), None, Some(MAX_SHARDS + 1)).is_err());
+    // Nothing was recorded, so the index can still be created:
+    let storage = SqliteHashIndexStorage::open(index_path(&dir), None, Some(2)).unwrap();
+    assert_eq!(storage.schemas().len(), 2);
+  }
+
+  #[test]
+  fn one_shard() {
+    let dir = TempDir::new("hash-index").unwrap();
+    let storage = SqliteHashIndexStorage::open(index_path(&dir), None, Some(1)).unwrap();
+    assert_eq!(storage.tables(), vec!["hash_index".to_string()]);
+    assert!(!dir.path().join("hash_index.sqlite3.shard0").exists());
+  }
+
+  #[test]
+  fn many_shards() {
+    let dir = TempDir::new("hash-index").unwrap();
+    {
+      let storage = SqliteHashIndexStorage::open(index_path(&dir), None, Some(MAX_SHARDS))
+        .unwrap();
+      assert_eq!(storage.tables().len(), MAX_SHARDS);
+      assert_eq!(storage.table_for([12, 0]), "shard2.hash_index".to_string());
+    }
+    for i in range(0, MAX_SHARDS) {
+      assert!(dir.path().join(format!("hash_index.sqlite3.shard{}", i)).exists());
+    }
+
+    // The number of shards is read from the index, which cannot be resharded:
+    {
+      let storage = SqliteHashIndexStorage::open(index_path(&dir), None, None).unwrap();
+      assert_eq!(storage.tables().len(), MAX_SHARDS);
     }
+    assert!(SqliteHashIndexStorage::open(index_path(&dir), None, Some(2)).is_err());
   }
 }
@@ -0,0 +1,118 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Parallel hashing of data-chunks.
+
+use hash_index::{Hash};
+
+use std::sync::{Arc, Mutex};
+
+
+/// A pool of tasks that hash data-chunks.
+#[deriving(Clone)]
+pub struct HashPool {
+  jobs: SyncSender<(Vec<u8>, Sender<(Hash, Vec<u8>)>)>,
+  workers: uint,
+}
+
+impl HashPool {
+
+  /// Start `workers` hashing tasks. They stop once the pool (and all its clones) are dropped.
+  pub fn new(workers: uint) -> HashPool {
+    let (jobs, job_rx) = sync_channel(workers);
+    let job_rx = Arc::new(Mutex::new(job_rx));
+    for _ in range(0, workers) {
+      let job_rx = job_rx.clone();
+      spawn(proc() {
+        loop {
+          let job = job_rx.lock().recv_opt();
+          match job {
+            Ok((chunk, reply)) => {
+              let hash = Hash::new(chunk.as_slice());
+              let _ = reply.send_opt((hash, chunk));
+            },
+            Err(()) => break,
+          }
+        }
+      });
+    }
+    HashPool{jobs: jobs, workers: workers}
+  }
+
+  pub fn workers(&self) -> uint {
+    self.workers
+  }
+
+  /// Hash `chunks` in parallel. Returns each chunk with its hash, in the given order.
+  pub fn hash_all(&self, chunks: Vec<Vec<u8>>) -> Vec<(Hash, Vec<u8>)> {
+    let replies: Vec<Receiver<(Hash, Vec<u8>)>> = chunks.into_iter().map(|chunk| {
+      let (reply, reply_rx) = channel();
+      self.jobs.send((chunk, reply));
+      reply_rx
+    }).collect();
+    replies.into_iter().map(|reply_rx| reply_rx.recv()).collect()
+  }
+}
+
+
+/// Pairs the data-chunks of an iterator with their hashes. With a pool, as many chunks as there
+/// are workers are read ahead and hashed in parallel.
+pub struct HashedChunks<IT> {
+  chunks: IT,
+  pool: Option<HashPool>,
+  ready: Vec<(Hash, Vec<u8>)>,  // In reverse order.
+}
+
+impl <IT: Iterator<Vec<u8>>> HashedChunks<IT> {
+  pub fn new(chunks: IT, pool: Option<HashPool>) -> HashedChunks<IT> {
+    HashedChunks{chunks: chunks, pool: pool, ready: Vec::new()}
+  }
+}
+
+impl <IT: Iterator<Vec<u8>>> Iterator<(Hash, Vec<u8>)> for HashedChunks<IT> {
+  fn next(&mut self) -> Option<(Hash, Vec<u8>)> {
+    if self.ready.len() == 0 {
+      match self.pool {
+        Some(ref pool) if pool.workers() > 1 => {
+          let batch: Vec<Vec<u8>> = self.chunks.by_ref().take(pool.workers()).collect();
+          self.ready = pool.hash_all(batch);
+          self.ready.reverse();
+        },
+        _ => return self.chunks.next().map(|chunk| (Hash::new(chunk.as_slice()), chunk)),
+      }
+    }
+    self.ready.pop()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use hash_index::{Hash};
+
+  #[test]
+  fn hashes_in_order() {
+    let chunks: Vec<Vec<u8>> = range(0u8, 10).map(|i| Vec::from_elem(100, i)).collect();
+    for pool in vec![None, Some(HashPool::new(1)), Some(HashPool::new(3))].into_iter() {
+      let hashed: Vec<(Hash, Vec<u8>)> =
+        HashedChunks::new(chunks.clone().into_iter(), pool).collect();
+      assert_eq!(hashed.len(), chunks.len());
+      for (&(ref hash, ref chunk), expected) in hashed.iter().zip(chunks.iter()) {
+        assert_eq!(chunk, expected);
+        assert_eq!(*hash, Hash::new(expected.as_slice()));
+      }
+    }
+  }
+}
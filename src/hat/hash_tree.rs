@@ -64,6 +64,27 @@ pub trait HashTreeBackend {
 
   fn insert_chunk(&mut self, Hash, i64, Option<Vec<u8>>, Vec<u8>) -> Vec<u8>;
 
+  /// The length of the data-chunk `hash`, stored at `persistent_ref`. Backends that can tell the
+  /// length from the reference should do so without fetching the chunk.
+  fn fetch_chunk_length(&mut self, hash: Hash, _persistent_ref: &[u8]) -> Option<uint> {
+    self.fetch_chunk(hash).map(|chunk| chunk.len())
+  }
+
+}
+
+
+/// A backend that stores nothing. Useful for computing the top hash of data without storing it.
+#[deriving(Clone)]
+pub struct HashOnlyBackend;
+
+impl HashTreeBackend for HashOnlyBackend {
+  fn fetch_chunk(&mut self, _hash: Hash) -> Option<Vec<u8>> { None }
+  fn fetch_payload(&mut self, _hash: Hash) -> Option<Vec<u8>> { None }
+  fn fetch_persistent_ref(&mut self, _hash: Hash) -> Option<Vec<u8>> { None }
+  fn insert_chunk(&mut self, _hash: Hash, _level: i64, _payload: Option<Vec<u8>>,
+                  _chunk: Vec<u8>) -> Vec<u8> {
+    Vec::new()
+  }
 }
 
 
@@ -78,6 +99,21 @@ fn hash_refs_from_bytes(bytes: &[u8]) -> Option<Vec<HashRef>> {
   })
 }
 
+/// The hashes of `refs`, concatenated. A tree node is identified by the hash of these.
+fn concat_hashes(refs: Vec<HashRef>) -> Vec<u8> {
+  let mut hashes = Vec::new();
+  for hashref in refs.into_iter() {
+    hashes.extend(hashref.hash.into_iter());
+  }
+  hashes
+}
+
+/// The hash that identifies the tree node stored as `data`, or `None` if `data` is not a tree
+/// node (but a data-block, which is identified by `Hash::new(data)`).
+pub fn node_hash(data: &[u8]) -> Option<Hash> {
+  hash_refs_from_bytes(data).map(|refs| Hash::new(concat_hashes(refs).as_slice()))
+}
+
 
 /// A simple implementation of a hash-tree stream writer.
 ///
@@ -126,6 +162,13 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     self.append_at(0, hash, chunk, None);
   }
 
+  /// Append a data-block whose hash is already known, i.e. `hash` must be
+  /// `Hash::new(chunk.as_slice())`. Backends that only compute hashes (see `HashOnlyBackend`)
+  /// ignore the data, so an empty `chunk` can be given to them.
+  pub fn append_hashed(&mut self, hash: Hash, chunk: Vec<u8>) {
+    self.append_at(0, hash, chunk, None);
+  }
+
   fn append_at(&mut self, level: uint, hash: Hash, data: Vec<u8>, metadata: Option<Vec<u8>>) {
     let persistent_ref = self.backend.insert_chunk(hash.clone(), level as i64, metadata, data);
     let hash_ref = HashRef::new(hash.bytes, persistent_ref);
@@ -157,13 +200,7 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeWriter<B> {
     let data = hash_refs_to_bytes(&level_v);
 
     // The hashes for this level is stored as metadata for future use:
-    let metadata_bytes = {
-      let mut metadata = Vec::new();
-      for hashref in level_v.into_iter() {
-        metadata.extend(hashref.hash.into_iter());
-      }
-      metadata
-    };
+    let metadata_bytes = concat_hashes(level_v);
 
     let hash = Hash::new(metadata_bytes.as_slice());
     self.append_at(level + 1, hash, data, Some(metadata_bytes));
@@ -260,6 +297,18 @@ impl <B: HashTreeBackend + Clone> SimpleHashTreeReader<B> {
     }
   }
 
+  /// Estimates the number of bytes left to read from the stored lengths of the pending nodes.
+  /// Nodes below an inner node are not fetched, so the size of a deep tree is underestimated.
+  pub fn estimated_size(&mut self) -> u64 {
+    let mut size = 0u64;
+    for child in self.stack.iter() {
+      let hash = Hash{bytes: child.hash.clone()};
+      size += self.backend.fetch_chunk_length(hash, child.persistent_ref.as_slice())
+        .unwrap_or(0) as u64;
+    }
+    size
+  }
+
   fn extract(&mut self) -> Option<Vec<u8>> {
     while self.stack.len() > 0 {
       let child = self.stack.pop().expect("len() > 0");
@@ -293,6 +342,82 @@ impl <B: HashTreeBackend + Clone> Iterator<Vec<u8>> for SimpleHashTreeReader<B>
 }
 
 
+/// The position and hash of a data-chunk within the data of a hash-tree.
+#[deriving(Clone, Show, PartialEq)]
+pub struct ChunkInfo {
+  pub offset: u64,
+  pub length: u64,
+  pub hash: Hash,
+}
+
+/// An iterator over the data-chunks of a hash-tree that yields their positions and hashes, but
+/// not their data. Only the tree's inner nodes are fetched, so specific regions of a large file
+/// can be verified or transferred without reading all of it.
+pub struct ChunkInfoIterator<B> {
+  backend: B,
+  stack: Vec<HashRef>,
+  offset: u64,
+}
+
+impl <B: HashTreeBackend + Clone> ChunkInfoIterator<B> {
+
+  /// List the data-chunks of the hash tree defined by `root_hash` and `root_ref`.
+  pub fn new(backend: B, root_hash: Hash, root_ref: Vec<u8>) -> ChunkInfoIterator<B> {
+    let stack = if root_hash.bytes.len() == 0 { Vec::new() }
+                else { vec![HashRef::new(root_hash.bytes, root_ref)] };
+    ChunkInfoIterator{backend: backend, stack: stack, offset: 0}
+  }
+}
+
+impl <B: HashTreeBackend + Clone> Iterator<ChunkInfo> for ChunkInfoIterator<B> {
+  fn next(&mut self) -> Option<ChunkInfo> {
+    while self.stack.len() > 0 {
+      let node = self.stack.pop().expect("len() > 0");
+      let hash = Hash{bytes: node.hash};
+
+      // Only inner nodes carry a payload (the hashes of their children):
+      if self.backend.fetch_payload(hash.clone()).is_some() {
+        let data = self.backend.fetch_chunk(hash).expect("Invalid hash ref");
+        let mut childs = hash_refs_from_bytes(data.as_slice()).expect("Invalid tree node");
+        childs.reverse();
+        self.stack.extend(childs.into_iter());
+        continue;
+      }
+
+      let length = self.backend.fetch_chunk_length(hash.clone(), node.persistent_ref.as_slice())
+        .expect("Invalid hash ref") as u64;
+      let info = ChunkInfo{offset: self.offset, length: length, hash: hash};
+      self.offset += length;
+      return Some(info);
+    }
+
+    None
+  }
+}
+
+
+/// The persistent references of all nodes of the hash tree defined by `root_hash` and `root_ref`,
+/// inner nodes included, e.g. to find the blobs that hold a file. Only inner nodes are fetched.
+pub fn persistent_refs<B: HashTreeBackend>(backend: &mut B, root_hash: Hash, root_ref: Vec<u8>)
+                                          -> Vec<Vec<u8>> {
+  if root_hash.bytes.len() == 0 {
+    return Vec::new();
+  }
+  let mut refs = Vec::new();
+  let mut stack = vec![HashRef::new(root_hash.bytes, root_ref)];
+  while stack.len() > 0 {
+    let node = stack.pop().expect("len() > 0");
+    let hash = Hash{bytes: node.hash};
+    if backend.fetch_payload(hash.clone()).is_some() {
+      let data = backend.fetch_chunk(hash).expect("Invalid hash ref");
+      stack.extend(hash_refs_from_bytes(data.as_slice()).expect("Invalid tree node").into_iter());
+    }
+    refs.push(node.persistent_ref);
+  }
+  refs
+}
+
+
 #[cfg(test)]
 mod tests {
   use super::*;
@@ -447,6 +572,44 @@ mod tests {
     };
   }
 
+  #[test]
+  fn chunk_infos() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
+
+    let chunks: Vec<Vec<u8>> = range(0u, 20).map(|i| Vec::from_elem(i + 1, i as u8)).collect();
+    for chunk in chunks.iter() {
+      ht.append(chunk.clone());
+    }
+    let (hash, hash_ref) = ht.hash();
+
+    let mut offset = 0;
+    let mut expected = Vec::new();
+    for chunk in chunks.iter() {
+      expected.push(ChunkInfo{offset: offset, length: chunk.len() as u64,
+                              hash: Hash::new(chunk.as_slice())});
+      offset += chunk.len() as u64;
+    }
+    assert_eq!(ChunkInfoIterator::new(backend, hash, hash_ref).collect::<Vec<ChunkInfo>>(),
+               expected);
+  }
+
+  #[test]
+  fn all_persistent_refs() {
+    let backend = MemoryBackend::new();
+    let mut ht = SimpleHashTreeWriter::new(4, backend.clone());
+    for i in range(0u8, 20) {
+      ht.append(Vec::from_elem(i as uint + 1, i));
+    }
+    let (hash, hash_ref) = ht.hash();
+
+    // Every stored node, leaf or inner, is listed once:
+    let refs = persistent_refs(&mut backend.clone(), hash, hash_ref);
+    let stored: HashSet<Vec<u8>> = backend.chunks.lock().keys().map(|k| k.clone()).collect();
+    assert_eq!(refs.len(), stored.len());
+    assert!(refs.iter().all(|r| stored.contains(r)));
+  }
+
   #[test]
   fn identity_implicit_flush() {
     let order = 8;
@@ -511,6 +674,26 @@ mod tests {
     }
   }
 
+  #[test]
+  fn append_hashed_builds_the_same_tree() {
+    let chunks: Vec<Vec<u8>> = range(0u8, 20).map(|i| vec![i, i + 1]).collect();
+
+    let mut plain = SimpleHashTreeWriter::new(4, MemoryBackend::new());
+    let mut hashed = SimpleHashTreeWriter::new(4, MemoryBackend::new());
+    // Only hashes are needed to compute the top hash:
+    let mut hash_only = SimpleHashTreeWriter::new(4, HashOnlyBackend);
+    for chunk in chunks.iter() {
+      plain.append(chunk.clone());
+      hashed.append_hashed(Hash::new(chunk.as_slice()), chunk.clone());
+      hash_only.append_hashed(Hash::new(chunk.as_slice()), Vec::new());
+    }
+
+    let (top, top_ref) = plain.hash();
+    assert_eq!(hashed.hash(), (top.clone(), top_ref));
+    let (hash_only_top, _) = hash_only.hash();
+    assert_eq!(hash_only_top, top);
+  }
+
 
   #[bench]
   fn append_unknown_16x128_kb(bench: &mut Bencher) {
@@ -17,23 +17,65 @@
 use process::{Process};
 
 use blob_index::{BlobIndex, BlobIndexProcess};
-use blob_store::{BlobStore, BlobStoreBackend};
-
-use hash_index::{HashIndex, HashIndexProcess};
+use blob_index;
+use blob_store::{BackendProbe, BlobID, BlobStore, BlobStoreBackend, StorageHint, TieredBackend};
+use blob_store;
+use buffer_pool::{BufferPool};
+use bundle;
+use cancel::{CancelToken, Cancelled};
+
+use chunker::{Chunker, ChunkerOptions, ReadAhead};
+use crash_test;
+
+use diff;
+use fifo::{FifoReader};
+use fingerprint::{Manifest};
+use grep::{LineSearch, Pattern};
+
+use hash_index::{Hash, HashIndex, HashIndexProcess};
+use hash_index;
 use hash_tree;
 
-use key_index::{KeyIndex, KeyEntry};
+use key_index::{KeyIndex, KeyIndexProcess, KeyEntry};
+use key_index;
+
+use keys::{RepositoryKey, BlobCipher, ChunkCipher};
 
-use key_store::{KeyStore, KeyStoreProcess};
+use key_store::{KeyStore, KeyStoreProcess, HashStoreBackend};
 use key_store;
 
 use listdir;
-
+use manifest::{SnapshotManifest};
+use nofollow;
+use output;
+use reflink::{ReflinkTable};
+use tar;
+
+use serialize::{json, Decodable};
+use serialize::hex::{ToHex};
+
+use libc::{c_int};
+use libc::funcs::posix88::fcntl;
+use libc::funcs::posix88::stat_::{fstat};
+use libc::funcs::posix88::unistd::{close};
+use libc::consts::os::posix88::{O_RDWR, O_CREAT, O_EXCL, ENOENT, EEXIST, EWOULDBLOCK};
+use libc::types::os::arch::posix01::{stat};
+
+use std::any::{Any, AnyRefExt};
+use std::cmp;
+use std::collections::hashmap::{HashMap, HashSet};
+use std::collections::treemap::{TreeMap};
 use std::io;
-use std::io::{Reader, IoResult, UserDir,
-              TypeDirectory, TypeSymlink, TypeFile, FileStat};
-use std::io::fs::{lstat, File, mkdir_recursive};
+use std::io::{Reader, Writer, IoResult, UserDir, SeekSet, ChanReader, EndOfFile,
+              Truncate, ReadWrite, TempDir,
+              TypeDirectory, TypeSymlink, TypeFile, TypeNamedPipe, FileStat};
+use std::io::util::{NullReader};
+use std::io::fs::{lstat, readdir, unlink, File, mkdir_recursive};
+use std::io::process::{Command, InheritFd};
+use std::mem;
+use std::os;
 use std::sync;
+use std::task;
 
 use time;
 
@@ -41,11 +83,18 @@ use time;
 
 pub struct Hat<B> {
   repository_root: Path,
+  key: Option<RepositoryKey>,
+  chunk_cipher: Option<ChunkCipher>,
+  blob_encryption: bool,
   blob_index: BlobIndexProcess,
   hash_index: HashIndexProcess,
 
   backend: B,
   max_blob_size: uint,
+  workers: PipelineWorkers,
+  cancel: CancelToken,
+
+  _in_use: InUseMarker,
 }
 
 fn concat_filename(a: &Path, b: String) -> String {
@@ -62,316 +111,3398 @@ fn hash_index_name(root: &Path) -> String {
   concat_filename(root, "hash_index.sqlite3".to_string())
 }
 
-impl <B: BlobStoreBackend + Clone + Send> Hat<B> {
-  pub fn open_repository(repository_root: &Path, backend: B, max_blob_size: uint)
-                        -> Option<Hat<B>> {
-    repository_root.as_str().map(|_| {
-      let blob_index_path = blob_index_name(repository_root);
-      let hash_index_path = hash_index_name(repository_root);
-      let biP = Process::new(proc() { BlobIndex::new(blob_index_path) });
-      let hiP = Process::new(proc() { HashIndex::new(hash_index_path) });
-      Hat{repository_root: repository_root.clone(),
-                hash_index: hiP,
-                blob_index: biP,
-                backend: backend.clone(),
-                max_blob_size: max_blob_size,
-      }
-    })
-  }
+/// Maintenance tasks that can be scheduled in the repository: "maintenance" (database
+/// maintenance of all indexes), "check" (integrity check of all indexes) and "compact"
+/// (compaction of the hash index, see `Hat::compact_hash_index`).
+pub static MAINTENANCE_TASKS: &'static [&'static str] = &["maintenance", "check", "compact"];
 
-  pub fn open_family(&self, name: String) -> Option<Family<B>> {
-    // We setup a standard pipeline of processes:
-    // KeyStore -> KeyIndex
-    //          -> HashIndex
-    //          -> BlobStore -> BlobIndex
+/// Size of the data-chunks that files are split into, unless a family overrides it.
+pub static DEFAULT_CHUNK_SIZE: uint = 128 * 1024;
 
-    let local_blob_index = self.blob_index.clone();
-    let local_backend = self.backend.clone();
-    let local_max_blob_size = self.max_blob_size;
-    let bsP = Process::new(proc() {
-      BlobStore::new(local_blob_index, local_backend, local_max_blob_size) });
+/// The number of unused chunk buffers that a family keeps for reuse.
+static BUFFER_POOL_SIZE: uint = 64;
 
-    let local_hash_index = self.hash_index.clone();
-    let local_hash_index2 = self.hash_index.clone();
-    let local_bsP = bsP.clone();
 
-    let key_index_path = concat_filename(&self.repository_root, name.clone());
-    let kiP = Process::new(proc() { KeyIndex::new(key_index_path) });
+/// The number of tasks working in each stage of the snapshot pipeline. Scanning directories and
+/// reading files is configured per snapshot (see `SnapshotOptions`).
+#[deriving(Clone, Show)]
+pub struct PipelineWorkers {
+  /// Tasks hashing data-chunks.
+  pub hash: uint,
 
-    let ksP = Process::new(proc() { KeyStore::new(kiP, local_hash_index2, bsP) });
+  /// Tasks uploading full blobs to the backend.
+  pub upload: uint,
+}
 
-    Some(Family{name: name,
-                key_store: ksP})
+impl PipelineWorkers {
+  /// One hashing task per CPU, and two uploads in flight so the next blob is filled while the
+  /// previous one is stored.
+  pub fn auto() -> PipelineWorkers {
+    PipelineWorkers{hash: cmp::max(1, os::num_cpus()), upload: 2}
   }
 }
 
 
+/// Per-family overrides of repository defaults. Overrides are recorded in the family's key index
+/// and stay in effect for later snapshots of the family.
+#[deriving(Clone)]
+pub struct FamilySettings {
+  /// The size that data-chunks are combined into before being stored in a blob.
+  pub max_blob_size: Option<uint>,
 
-struct FileEntry {
-  name: Vec<u8>,
+  /// When given, the blob size is adapted to the latency and throughput of the backend, between
+  /// this size and `max_blob_size`.
+  pub min_blob_size: Option<uint>,
 
-  parent_id: Option<Vec<u8>>,
+  /// The size of the data-chunks that files are split into.
+  pub chunk_size: Option<uint>,
 
-  stat: FileStat,
-  full_path: Path,
+  /// Whether chunk boundaries of container files are aligned with their entries.
+  pub format_aware_chunking: Option<bool>,
+
+  /// What to snapshot when no directory is given (see `Family::defaults`).
+  pub defaults: Option<FamilyDefaults>,
 }
 
-impl FileEntry {
-  fn new(full_path: Path,
-         parent: Option<Vec<u8>>) -> Result<FileEntry, io::IoError> {
-    let filename_opt = full_path.filename();
-    if filename_opt.is_some() {
-      lstat(&full_path).map(|st| {
-        FileEntry{
-          name: filename_opt.unwrap().into_vec(),
-          parent_id: parent.clone(),
-          stat: st,
-          full_path: full_path.clone()}
-      })
-    }
-    else { Err(io::IoError{kind: io::OtherIoError,
-                           desc: "Could not parse filename.",
-                           detail: None }) }
+impl FamilySettings {
+  pub fn new() -> FamilySettings {
+    FamilySettings{max_blob_size: None, min_blob_size: None, chunk_size: None,
+                   format_aware_chunking: None, defaults: None}
   }
+}
 
-  fn file_iterator(&self) -> IoResult<FileIterator> {
-    FileIterator::new(&self.full_path)
-  }
+/// How a family is snapshotted by default. Like the other `FamilySettings`, the defaults are
+/// recorded in the family's key index, so every client of the repository takes the same
+/// snapshot; together with the recorded chunking overrides, a snapshot needs no options.
+#[deriving(Clone, Show, PartialEq, Encodable, Decodable)]
+pub struct FamilyDefaults {
+  /// The directory to snapshot.
+  pub root: Option<String>,
 
-  fn is_directory(&self) -> bool { self.stat.kind == TypeDirectory }
-  fn is_symlink(&self) -> bool { self.stat.kind == TypeSymlink }
-  fn is_file(&self) -> bool { self.stat.kind == TypeFile }
+  /// Paths to leave out (see `SnapshotOptions::excludes`).
+  pub excludes: Vec<String>,
+
+  /// Take a snapshot when the last one started this many days ago (see `Hat::due_snapshots`),
+  /// or never if `0`.
+  pub snapshot_interval_days: u64,
 }
 
-impl Clone for FileEntry {
-  fn clone(&self) -> FileEntry {
-    FileEntry{
-      name:self.name.clone(), parent_id:self.parent_id.clone(),
-      stat: FileStat{
-        size: self.stat.size,
-        kind: self.stat.kind,
-        perm: self.stat.perm,
-        created: self.stat.created,
-        modified: self.stat.modified,
-        accessed: self.stat.accessed,
-        unstable: self.stat.unstable,
-      },
-      full_path:self.full_path.clone()}
+impl FamilyDefaults {
+  pub fn new() -> FamilyDefaults {
+    FamilyDefaults{root: None, excludes: Vec::new(), snapshot_interval_days: 0}
   }
 }
 
-impl KeyEntry<FileEntry> for FileEntry {
-  fn name(&self) -> Vec<u8> {
-    self.name.clone()
-  }
-  fn id(&self) -> Option<Vec<u8>> {
-    Some(format!("d{:u}i{:u}",
-                 self.stat.unstable.device,
-                 self.stat.unstable.inode).as_bytes().into_vec())
-  }
-  fn parent_id(&self) -> Option<Vec<u8>> {
-    self.parent_id.clone()
+fn family_setting(index: &KeyIndexProcess<FileEntry>, name: &str, value: Option<uint>)
+                  -> Option<uint> {
+  match value {
+    Some(value) => {
+      index.send_reply(key_index::StoreSetting(name.to_string(), value as i64));
+      Some(value)
+    },
+    None => match index.send_reply(key_index::FetchSetting(name.to_string())) {
+      key_index::Setting(value) => value.map(|v| v as uint),
+      _ => fail!("Unexpected reply from key index."),
+    },
   }
+}
 
-  fn size(&self) -> Option<u64> {
-    Some(self.stat.size)
+/// Record `defaults` in the family's key index if given, and return the recorded defaults.
+fn family_defaults(index: &KeyIndexProcess<FileEntry>, defaults: Option<FamilyDefaults>)
+                   -> FamilyDefaults {
+  match defaults {
+    Some(defaults) => {
+      index.send_reply(key_index::StoreTextSetting("defaults".to_string(),
+                                                   json::encode(&defaults)));
+      defaults
+    },
+    None => match index.send_reply(key_index::FetchTextSetting("defaults".to_string())) {
+      key_index::TextSetting(Some(text)) => {
+        let decoded = json::from_str(text.as_slice()).ok().and_then(|json| {
+          Decodable::decode(&mut json::Decoder::new(json)).ok()
+        });
+        decoded.expect("The family defaults are corrupt.")
+      },
+      key_index::TextSetting(None) => FamilyDefaults::new(),
+      _ => fail!("Unexpected reply from key index."),
+    },
   }
+}
 
-  fn created(&self) -> Option<u64> {
-    Some(self.stat.created)
-  }
-  fn modified(&self) -> Option<u64> {
-    Some(self.stat.modified)
-  }
-  fn accessed(&self) -> Option<u64> {
-    Some(self.stat.accessed)
-  }
 
-  fn permissions(&self) -> Option<u64> {
-    None
-  }
-  fn user_id(&self) -> Option<u64> {
-    None
-  }
-  fn group_id(&self) -> Option<u64> {
-    None
-  }
-  fn with_id(&self, id: Vec<u8>) -> FileEntry {
-    assert_eq!(Some(id), self.id());
-    self.clone()
-  }
+fn spool_dir(root: &Path) -> Path {
+  let mut dir = root.clone();
+  dir.push("spool");
+  dir
 }
 
-struct FileIterator {
-  file: File
+/// Where the keyring of the repository at `root` is kept (see `keyring::Keyring`). It has a
+/// directory of its own, since the files at the top of the repository are families.
+pub fn keyring_path(root: &Path) -> Path {
+  root.join("keys").join("keyring")
 }
 
-impl FileIterator {
-  fn new(path: &Path) -> IoResult<FileIterator> {
-    match File::open(path) {
-      Ok(f) => Ok(FileIterator{file: f}),
-      Err(e) => Err(e),
+
+static IN_USE_MARKER: &'static str = "in_use";
+
+static LOCK_EX: c_int = 2;
+static LOCK_NB: c_int = 4;
+
+/// A file that exists, and is locked, while the repository is open. If it is found unlocked when
+/// opening the repository, the previous process using it did not shut down cleanly; if it is
+/// locked, another process is using the repository.
+struct InUseMarker {
+  path: Path,
+  fd: c_int,
+}
+
+impl InUseMarker {
+  /// Create and lock the marker. Returns whether it already existed, or an error if another
+  /// process holds it or it cannot be created (e.g. in a read-only repository).
+  fn create(root: &Path) -> Result<(InUseMarker, bool), String> {
+    extern {
+      fn flock(fd: c_int, operation: c_int) -> c_int;
+    }
+    let path = root.join(IN_USE_MARKER);
+    let error = |what: &str| format!("Could not {} '{}': {}", what, path.display(),
+                                     io::IoError::last_error());
+    loop {
+      let mut existed = true;
+      let mut fd = path.with_c_str(|c_str| unsafe { fcntl::open(c_str, O_RDWR, 0) });
+      if fd < 0 && os::errno() == ENOENT as int {
+        existed = false;
+        fd = path.with_c_str(|c_str| unsafe {
+          fcntl::open(c_str, O_RDWR | O_CREAT | O_EXCL, 0o600)
+        });
+        // Another process created it first:
+        if fd < 0 && os::errno() == EEXIST as int { continue }
+      }
+      if fd < 0 {
+        return Err(error("open"));
+      }
+      if unsafe { flock(fd, LOCK_EX | LOCK_NB) } != 0 {
+        let in_use = os::errno() == EWOULDBLOCK as int;
+        let message = error("lock");
+        unsafe { close(fd) };
+        return Err(if in_use {
+          "The repository is in use by another process.".to_string()
+        } else { message });
+      }
+      // The process that held the marker may have removed it after we opened it:
+      if is_same_file(fd, &path) {
+        return Ok((InUseMarker{path: path.clone(), fd: fd}, existed));
+      }
+      unsafe { close(fd) };
     }
   }
 }
 
-impl Iterator<Vec<u8>> for FileIterator {
-  fn next(&mut self) -> Option<Vec<u8>> {
-    let mut buf = Vec::from_elem(128*1024, 0u8);
-    match self.file.read(buf.as_mut_slice()) {
-      Err(_) => None,
-      Ok(size) => Some(buf.slice_to(size).into_vec()),
-    }
+/// Whether `fd` is the open file at `path`.
+fn is_same_file(fd: c_int, path: &Path) -> bool {
+  let mut st: stat = unsafe { mem::zeroed() };
+  if unsafe { fstat(fd, &mut st) } != 0 {
+    return false;
   }
+  lstat(path).map(|path_st| path_st.unstable.inode == st.st_ino as u64).unwrap_or(false)
 }
 
+impl Drop for InUseMarker {
+  fn drop(&mut self) {
+    // Removed while still locked, so that no other process takes it for a crashed one's:
+    let _ = unlink(&self.path);
+    unsafe { close(self.fd) };
+  }
+}
 
-#[deriving(Clone)]
-struct InsertPathHandler<B:'static> {
-  count: sync::Arc<sync::Mutex<uint>>,
-  last_print: sync::Arc<sync::Mutex<time::Timespec>>,
-  my_last_print: time::Timespec,
 
-  key_store: KeyStoreProcess<FileEntry, FileIterator, B>,
+/// What was done to bring the indexes back in agreement after an unclean shutdown.
+pub struct RecoveryReport {
+  /// In-air blobs whose upload was finished from a local copy.
+  pub resumed: uint,
+
+  /// In-air blobs that could not be resumed, and were forgotten.
+  pub rolled_back: uint,
+
+  /// Committed blobs that turned out to be referenced by the hash index.
+  pub indexed: uint,
+
+  /// Committed blobs whose hash entries were lost, so that nothing references them.
+  pub orphaned: uint,
 }
 
-impl <B> InsertPathHandler<B> {
-  pub fn new(key_store: KeyStoreProcess<FileEntry, FileIterator, B>)
-             -> InsertPathHandler<B> {
-    InsertPathHandler{
-      count: sync::Arc::new(sync::Mutex::new(0)),
-      last_print: sync::Arc::new(sync::Mutex::new(time::now().to_timespec())),
-      my_last_print: time::now().to_timespec(),
-      key_store: key_store,
-    }
+/// Forget in-air blobs that could not be resumed, and sort the committed, but unindexed, blobs
+/// into those that the hash index references and those that it does not. Hash entries that were
+/// not committed never reached the hash index, so they need no rollback.
+fn recover_indexes(blob_index: &BlobIndexProcess, hash_index: &HashIndexProcess,
+                   report: &mut RecoveryReport) {
+  let in_air = match blob_index.send_reply(blob_index::ListInAir) {
+    blob_index::InAirBlobs(blobs) => blobs,
+    _ => fail!("Unexpected reply from blob index."),
+  };
+  for blob in in_air.into_iter() {
+    blob_index.send_reply(blob_index::RollBack(blob));
+    report.rolled_back += 1;
   }
+
+  let unindexed = match blob_index.send_reply(blob_index::ListUnindexed) {
+    blob_index::UnindexedBlobs(blobs) => blobs,
+    _ => fail!("Unexpected reply from blob index."),
+  };
+  if unindexed.len() == 0 { return }
+
+  let referenced = match hash_index.send_reply(
+    hash_index::ListReferencedBlobs(BlobID::blob_name_of)) {
+    hash_index::ReferencedBlobs(names) => names,
+    _ => fail!("Unexpected reply from hash index."),
+  };
+  let (indexed, orphaned) = unindexed.partition(|blob| referenced.contains(&blob.name));
+  report.indexed = indexed.len();
+  report.orphaned = orphaned.len();
+  blob_index.send_reply(blob_index::Indexed(indexed));
+  blob_index.send_reply(blob_index::Orphaned(orphaned));
 }
 
-impl <B: BlobStoreBackend + Clone + Send> listdir::PathHandler<Option<Vec<u8>>>
-  for InsertPathHandler<B> {
-  fn handle_path(&mut self, parent: Option<Vec<u8>>, path: Path) -> Option<Option<Vec<u8>>> {
-    let count = {
-      let mut guarded_count = self.count.lock();
-      *guarded_count += 1;
-      *guarded_count
-    };
+/// Which part of the stored data `verify_data` downloads and re-hashes. Each run continues with
+/// the blobs that were verified least recently, so that repeated runs cover all of the data.
+pub enum DataSubset {
+  /// This percentage of the blobs (at least one blob).
+  PercentOfBlobs(uint),
 
-    if self.my_last_print.sec <= time::now().to_timespec().sec - 1 {
-      let mut guarded_last_print = self.last_print.lock();
-      let now = time::now().to_timespec();
-      if guarded_last_print.sec <= now.sec - 1 {
-        println!("#{}: {}", count, path.display());
-        *guarded_last_print = now;
-      }
-      self.my_last_print = now;
-    }
+  /// As many blobs as fit in this number of downloaded bytes (at least one blob).
+  ByteBudget(u64),
+}
 
-    let fileEntry_opt = FileEntry::new(path.clone(), parent);
-    match fileEntry_opt {
-      Err(e) => {
-        println!("Skipping '{}': {}", path.display(), e.to_string());
-      },
-      Ok(fileEntry) => {
-        if fileEntry.is_symlink() {
-          return None;
-        }
-        let is_directory = fileEntry.is_directory();
-        let local_root = path;
-        let local_fileEntry = fileEntry.clone();
-        let create_file_it = proc() {
-          match local_fileEntry.file_iterator() {
-            Err(e) => {println!("Skipping '{}': {}", local_root.display(), e.to_string());
-                       None},
-            Ok(it) => { Some(it) }
-          }
-        };
-        let create_file_it_opt = if is_directory { None }
-                                 else { Some(create_file_it) };
+/// The outcome of `verify_data`.
+pub struct DataVerifyReport {
+  /// A description of each problem found.
+  pub problems: Vec<String>,
 
-        match self.key_store.send_reply(
-          key_store::Insert(fileEntry, create_file_it_opt))
-        {
-          key_store::Id(id) => {
-            if is_directory { return Some(Some(id)) }
-          },
-          _ => fail!("Unexpected reply from key store."),
-        }
-      }
-    }
+  /// The number of blobs downloaded.
+  pub blobs: uint,
 
-    return None;
-  }
+  /// The number of data-chunks re-hashed.
+  pub chunks: uint,
+
+  /// The number of bytes downloaded.
+  pub bytes: u64,
+
+  /// Whether verification stopped early because it was cancelled. The blobs verified until then
+  /// are recorded, so the next run continues with the others.
+  pub cancelled: bool,
 }
 
+/// The number of blobs whose chunks are looked up in the hash index at once.
+static VERIFY_BATCH: uint = 256;
 
-fn try_a_few_times_then_fail(f: || -> bool, msg: &str) {
-  for i in range(1u, 5) {
-    if f() { return }
+/// Whether the byte budget of `verify_data` (if any) is used up.
+fn spent(report: &DataVerifyReport, budget: Option<u64>) -> bool {
+  budget.map(|bytes| report.blobs > 0 && report.bytes >= bytes).unwrap_or(false)
+}
+
+/// Re-hash the chunks stored in `blob` (named `name`), decrypting them with `cipher` if the
+/// repository is encrypted. Returns whether all of them are intact.
+fn verify_chunks(name: &[u8], blob: &[u8], chunks: &[(Hash, Vec<u8>)],
+                 cipher: &Option<ChunkCipher>, report: &mut DataVerifyReport) -> bool {
+  let mut intact = true;
+  for &(ref hash, ref persistent_ref) in chunks.iter() {
+    report.chunks += 1;
+    let id = BlobID::from_bytes(persistent_ref.clone());
+    let stored = match id.chunk_of(blob) {
+      Some(sealed) => match *cipher {
+        Some(ref cipher) => match cipher.open(hash.bytes.as_slice(), sealed) {
+          Some(chunk) => Some(chunk),
+          None => {
+            intact = false;
+            report.problems.push(format!("chunk {} in blob {}: cannot be decrypted",
+                                         hash.bytes.as_slice().to_hex(), name.to_hex()));
+            continue;
+          },
+        },
+        None => Some(sealed.into_vec()),
+      },
+      None => None,
+    };
+    match stored.as_ref().map(|chunk| chunk.as_slice()) {
+      // Tree nodes are identified by the hashes of their children, not by their data:
+      Some(chunk) if Hash::new(chunk) == *hash ||
+        hash_tree::node_hash(chunk).as_ref() == Some(hash) => (),
+      Some(_) => {
+        intact = false;
+        report.problems.push(format!("chunk {} in blob {}: data does not match its hash",
+                                     hash.bytes.as_slice().to_hex(), name.to_hex()));
+      },
+      None => {
+        intact = false;
+        report.problems.push(format!("chunk {} in blob {}: lies beyond the end of the blob",
+                                     hash.bytes.as_slice().to_hex(), name.to_hex()));
+      },
+    }
   }
-  fail!(msg.to_string());
+  intact
 }
 
+impl <B: BlobStoreBackend + Clone + Send> Hat<B> {
+  /// Open the repository in `repository_root`. If a `key` is given, the local indexes are
+  /// encrypted with keys derived from it. The hash index of a new repository is sharded across
+  /// `hash_index_shards` files (existing repositories keep their number of shards). If
+  /// `sparse_index` is given, the hash index is used sparsely (see `HashIndex::new`). Fails if
+  /// another process has the repository open.
+  ///
+  /// Nothing is written to the backend, unless uploads interrupted by a crash are resumed; see
+  /// `probe_backend` to check that it can be written to.
+  pub fn open_repository(repository_root: &Path, backend: B, max_blob_size: uint,
+                         key: Option<RepositoryKey>, hash_index_shards: Option<uint>,
+                         sparse_index: Option<uint>)
+                        -> Result<Hat<B>, String> {
+    if repository_root.as_str().is_none() {
+      return Err("the repository path is not valid UTF-8".to_string());
+    }
+    match hash_index_shards {
+      Some(shards) => try!(hash_index::check_shards(shards)),
+      None => (),
+    }
+    let (in_use, unclean) = try!(InUseMarker::create(repository_root));
+    let blob_index_path = blob_index_name(repository_root);
+    let hash_index_path = hash_index_name(repository_root);
+    let blob_index_key = key.as_ref().map(|k| k.derive("blob_index"));
+    let hash_index_key = key.as_ref().map(|k| k.derive("hash_index"));
+    let biP = Process::new_named("BlobIndex", proc() {
+      BlobIndex::new(blob_index_path, blob_index_key) });
+    let hiP = Process::new_named("HashIndex", proc() {
+      HashIndex::new(hash_index_path, hash_index_key, hash_index_shards, sparse_index)
+    });
+
+    // Finish uploading blobs that were interrupted by a crash:
+    let mut recovery = BlobStore::new(biP.clone(), backend.clone(), max_blob_size,
+                                      Some(spool_dir(repository_root)));
+    let resumed = recovery.resume_uploads();
+    if resumed > 0 {
+      info!("Resumed the upload of {} interrupted blob(s).", resumed);
+    }
+
+    if unclean {
+      info!("The repository was not closed cleanly; recovering indexes...");
+      let mut report = RecoveryReport{resumed: resumed, rolled_back: 0, indexed: 0,
+                                      orphaned: 0};
+      recover_indexes(&biP, &hiP, &mut report);
+      info!("Recovered: {} blob(s) resumed, {} in-air blob(s) rolled back, {} committed \
+             blob(s) confirmed, {} orphaned blob(s).",
+            report.resumed, report.rolled_back, report.indexed, report.orphaned);
+    }
 
-struct Family<B> {
-  name: String,
-  key_store: KeyStoreProcess<FileEntry, FileIterator, B>,
-}
+    Ok(Hat{repository_root: repository_root.clone(),
+           key: key.clone(),
+           chunk_cipher: None,
+           blob_encryption: false,
+           hash_index: hiP,
+           blob_index: biP,
+           backend: backend.clone(),
+           max_blob_size: max_blob_size,
+           workers: PipelineWorkers::auto(),
+           cancel: CancelToken::new(),
+           _in_use: in_use})
+  }
 
-impl <B: BlobStoreBackend + Clone + Send> Family<B> {
+  /// Check that the backend can be written to (see `BlobStoreBackend::probe`), so that a backend
+  /// that is unreachable or read-only is reported before a snapshot rather than halfway through
+  /// it. This writes a small probe blob, which is left behind on backends that cannot delete
+  /// blobs. Failures are not retried.
+  pub fn probe_backend(&self) -> Result<BackendProbe, String> {
+    let probe = try!(self.backend.clone().probe().map_err(|e| {
+      format!("the blob store is not usable: {}", e)
+    }));
+    detail!("Blob store: {} ms to read a blob, {} bytes free, deleting {}, listing {}.",
+            probe.latency_ms,
+            probe.free_bytes.map(|bytes| bytes.to_string()).unwrap_or("unknown".to_string()),
+            if probe.supports_delete { "supported" } else { "not supported" },
+            if probe.supports_listing { "supported" } else { "not supported" });
+    Ok(probe)
+  }
 
-  pub fn snapshot_dir(&self, dir: Path) {
-    let mut handler = InsertPathHandler::new(self.key_store.clone());
-    listdir::iterate_recursively((Path::new(dir.clone()), None), &mut handler);
+  /// Set the number of hashing and upload tasks of families opened after this call.
+  pub fn set_pipeline_workers(&mut self, workers: PipelineWorkers) {
+    self.workers = workers;
   }
 
-  pub fn flush(&self) {
-    self.key_store.send_reply(key_store::Flush);
+  /// Stop long-running operations (`verify_data`, and the snapshots and checkouts of families
+  /// opened after this call) when `token` is cancelled (see `cancel`).
+  pub fn set_cancel_token(&mut self, token: CancelToken) {
+    self.cancel = token;
   }
 
-  pub fn checkout_in_dir(&self, output_dir: &mut Path, dir_id: Option<Vec<u8>>) {
+  /// Encrypt the data-chunks of this repository with a key derived from `key` (see
+  /// `keys::ChunkCipher`). Encryption is a per-repository switch: it is turned on by the first
+  /// client to give a key, which must happen before any data is stored, and afterwards every
+  /// client must give the same key.
+  pub fn set_chunk_key(&mut self, key: &RepositoryKey) -> Result<(), String> {
+    let cipher = ChunkCipher::new(key);
+    match self.repository_setting("chunk_encryption".to_string()) {
+      Some(check) if check == cipher.check_value() => (),
+      Some(_) => return Err("Wrong chunk key for this repository.".to_string()),
+      None => {
+        let stored = match self.blob_index.send_reply(blob_index::ListVerificationOrder) {
+          blob_index::BlobNames(names) => names.len(),
+          _ => fail!("Unexpected reply from blob index."),
+        };
+        if stored > 0 {
+          return Err("The repository already stores unencrypted data; chunk encryption can \
+                      only be turned on for a new repository.".to_string());
+        }
+        self.blob_index.send_reply(blob_index::StoreSetting("chunk_encryption".to_string(),
+                                                            cipher.check_value()));
+      },
+    }
+    self.chunk_cipher = Some(cipher);
+    Ok(())
+  }
 
-    fn put_chunks<B: hash_tree::HashTreeBackend + Clone>(
-      fd: &mut File, tree: hash_tree::ReaderResult<B>)
-    {
-      let mut it = match tree {
-        hash_tree::NoData => fail!("Trying to read data where none exist."),
-        hash_tree::SingleBlock(chunk) => {
-          try_a_few_times_then_fail(|| fd.write(chunk.as_slice()).is_ok(),
-                                    "Could not write chunk.");
-          return;
-        },
-        hash_tree::Tree(it) => it,
-      };
-      // We have a tree
-      for chunk in it {
-        try_a_few_times_then_fail(|| fd.write(chunk.as_slice()).is_ok(), "Could not write chunk.");
-      }
+  /// Record that the backend encrypts blobs with `cipher` (see `blob_store::EncryptedBackend`).
+  /// Like chunk encryption, this must be turned on before any data is stored, and the same key
+  /// must be used from then on.
+  pub fn set_blob_cipher(&mut self, cipher: &BlobCipher) -> Result<(), String> {
+    match self.repository_setting("blob_encryption".to_string()) {
+      Some(check) if check == cipher.check_value() => (),
+      Some(_) => return Err("Wrong blob key for this repository.".to_string()),
+      None => {
+        let stored = match self.blob_index.send_reply(blob_index::ListVerificationOrder) {
+          blob_index::BlobNames(names) => names.len(),
+          _ => fail!("Unexpected reply from blob index."),
+        };
+        if stored > 0 {
+          return Err("The repository already stores unencrypted blobs; blob encryption can \
+                      only be turned on for a new repository.".to_string());
+        }
+        self.blob_index.send_reply(blob_index::StoreSetting("blob_encryption".to_string(),
+                                                            cipher.check_value()));
+      },
     }
+    self.blob_encryption = true;
+    Ok(())
+  }
 
-    // create output_dir
-    mkdir_recursive(output_dir, UserDir).unwrap();
+  /// Check that every key the repository was encrypted with has been set.
+  pub fn check_keys(&self) -> Result<(), String> {
+    if self.chunk_cipher.is_none() &&
+       self.repository_setting("chunk_encryption".to_string()).is_some() {
+      return Err("The data of this repository is encrypted; a chunk key is needed.".to_string());
+    }
+    if !self.blob_encryption &&
+       self.repository_setting("blob_encryption".to_string()).is_some() {
+      return Err("The blobs of this repository are encrypted; a blob key is needed.".to_string());
+    }
+    Ok(())
+  }
 
-    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
-      key_store::ListResult(ls) => ls,
-      _ => fail!("Unexpected result from key store."),
-    };
+  pub fn open_family(&self, name: String) -> Option<Family<B>> {
+    self.open_family_with_settings(name, FamilySettings::new())
+  }
+
+  /// Open a family, recording any overrides given in `settings`. Settings that are not given are
+  /// taken from earlier overrides, or else from the repository defaults.
+  pub fn open_family_with_settings(&self, name: String, settings: FamilySettings)
+                                   -> Option<Family<B>> {
+    // We setup a standard pipeline of processes:
+    // KeyStore -> KeyIndex
+    //          -> HashIndex
+    //          -> BlobStore -> BlobIndex
+
+    match self.check_keys() {
+      Ok(()) => (),
+      Err(e) => fail!(e),
+    }
 
-    for (id, name, _, _, _, hash, _, data_res) in listing.move_iter() {
+    let key_index_path = concat_filename(&self.repository_root, name.clone());
+    let key_index_key = self.key.as_ref().map(|k| k.derive("key_index"));
+    let kiP = Process::new_named("KeyIndex", proc() {
+      KeyIndex::new(key_index_path, key_index_key) });
 
-      output_dir.push(name);
+    let max_blob_size = family_setting(&kiP, "max_blob_size", settings.max_blob_size);
+    let min_blob_size = family_setting(&kiP, "min_blob_size", settings.min_blob_size);
+    let chunk_size = family_setting(&kiP, "chunk_size", settings.chunk_size);
+    let format_aware = family_setting(&kiP, "format_aware_chunking",
+                                      settings.format_aware_chunking.map(|b| b as uint));
+    let defaults = family_defaults(&kiP, settings.defaults);
 
-      if hash.len() == 0 {
-        // This is a directory, recurse!
-        self.checkout_in_dir(output_dir, Some(id));
-      } else {
-        // This is a file, write it
-        let mut fd = File::create(output_dir).unwrap();
-        put_chunks(&mut fd, data_res);
-        try_a_few_times_then_fail(|| fd.flush().is_ok(), "Could not flush file.")
+    let chunk_size = chunk_size.unwrap_or(DEFAULT_CHUNK_SIZE);
+    let buffer_pool = BufferPool::new(chunk_size, BUFFER_POOL_SIZE);
+
+    let local_blob_index = self.blob_index.clone();
+    let local_backend = self.backend.clone();
+    let local_max_blob_size = max_blob_size.unwrap_or(self.max_blob_size);
+    let local_spool_dir = spool_dir(&self.repository_root);
+    let local_buffer_pool = buffer_pool.clone();
+    let upload_workers = self.workers.upload;
+    let bsP = Process::new_named("BlobStore", proc() {
+      let mut bs = BlobStore::new(local_blob_index, local_backend, local_max_blob_size,
+                                  Some(local_spool_dir));
+      bs.recycle_buffers(local_buffer_pool);
+      bs.set_upload_workers(upload_workers);
+      match min_blob_size {
+        Some(min) if min < local_max_blob_size => bs.adapt_blob_size(min, local_max_blob_size),
+        _ => (),
       }
+      bs });
+
+    let local_hash_index = self.hash_index.clone();
+
+    let hash_workers = self.workers.hash;
+    let chunk_cipher = self.chunk_cipher.clone();
+    let ksP = Process::new_named("KeyStore", proc() {
+      let mut ks = KeyStore::new(kiP, local_hash_index, bsP);
+      ks.set_hash_workers(hash_workers);
+      chunk_cipher.map(|cipher| ks.set_chunk_cipher(cipher));
+      ks });
+
+    Some(Family{name: name,
+                chunker: ChunkerOptions{chunk_size: chunk_size,
+                                        format_aware: format_aware == Some(1)},
+                buffer_pool: buffer_pool,
+                defaults: defaults,
+                key_store: ksP,
+                failures: sync::Arc::new(sync::Mutex::new(Vec::new())),
+                cancel: self.cancel.clone()})
+  }
 
-      output_dir.pop();
+  /// Create the family `new_name` as a copy of the family `name`. The new family shares all data
+  /// with the original (only the key index is copied), and the two evolve independently.
+  pub fn fork_family(&self, name: String, new_name: String) -> Result<(), String> {
+    let families = self.list_families();
+    if !families.contains(&name) {
+      return Err(format!("Family '{}' does not exist.", name));
+    }
+    if families.contains(&new_name) {
+      return Err(format!("Family '{}' already exists.", new_name));
     }
 
+    let source_path = concat_filename(&self.repository_root, name);
+    let key_index_path = concat_filename(&self.repository_root, new_name);
+    let key_index_key = self.key.as_ref().map(|k| k.derive("key_index"));
+
+    // Opening the source brings its schema up to date:
+    drop(KeyIndex::new(source_path.clone(), key_index_key.clone()));
+    let kiP: KeyIndexProcess<FileEntry> =
+      Process::new(proc() { KeyIndex::new(key_index_path, key_index_key) });
+    kiP.send_reply(key_index::ImportFrom(source_path));
+    Ok(())
+  }
+
+  /// List the names of the families (snapshots) in this repository: the files at its top, other
+  /// than the repository-wide indexes. Anything else is kept in directories (e.g. the spool).
+  pub fn list_families(&self) -> Vec<String> {
+    let paths = readdir(&self.repository_root).unwrap_or(Vec::new());
+    let mut names: Vec<String> = paths.into_iter().filter(|path| path.is_file()).filter_map(|path| {
+      path.filename_str().map(|name| name.to_string())
+    }).filter(|name| {
+      // Skip the repository-wide indexes (and their shards), sqlite journals and the marker:
+      !name.as_slice().contains(".sqlite3") && !name.as_slice().ends_with("-journal") &&
+        name.as_slice() != IN_USE_MARKER
+    }).collect();
+    names.sort();
+    names
+  }
+
+  /// Run database maintenance on the repository-wide indexes (blob index and hash index).
+  pub fn maintenance(&self) {
+    self.blob_index.send_reply(blob_index::Maintenance);
+    self.hash_index.send_reply(hash_index::Maintenance);
+  }
+
+  /// Check the repository-wide indexes for local corruption, including that every hash refers to
+  /// a committed blob. Returns a description of each problem found.
+  pub fn check(&self) -> Vec<String> {
+    let mut problems = match self.blob_index.send_reply(blob_index::SelfCheck) {
+      blob_index::SelfCheckResult(problems) => problems,
+      _ => fail!("Unexpected reply from blob index."),
+    };
+
+    let committed = match self.blob_index.send_reply(blob_index::ListCommitted) {
+      blob_index::BlobNames(names) => names.into_iter().collect(),
+      _ => fail!("Unexpected reply from blob index."),
+    };
+
+    match self.hash_index.send_reply(hash_index::SelfCheck(committed, BlobID::blob_name_of)) {
+      hash_index::SelfCheckResult(more) => problems.extend(more.into_iter()),
+      _ => fail!("Unexpected reply from hash index."),
+    }
+
+    problems
+  }
+
+  /// Merge duplicate entries of the hash index, remove the entries that point into blobs that are
+  /// no longer committed (e.g. deleted ones), and rewrite the index compactly; otherwise, the
+  /// hash index only ever grows. Returns the number of duplicates merged and of entries removed.
+  pub fn compact_hash_index(&self) -> (uint, uint) {
+    let committed = match self.blob_index.send_reply(blob_index::ListCommitted) {
+      blob_index::BlobNames(names) => names.into_iter().collect(),
+      _ => fail!("Unexpected reply from blob index."),
+    };
+    match self.hash_index.send_reply(hash_index::Compact(committed, BlobID::blob_name_of)) {
+      hash_index::Compacted(merged, removed) => (merged, removed),
+      _ => fail!("Unexpected reply from hash index."),
+    }
+  }
+
+  /// Compare the checksum recorded for each committed blob with the one the backend keeps,
+  /// without downloading any data. Returns a description of each mismatch, and the number of
+  /// blobs that could not be verified because the backend keeps no checksum for them.
+  ///
+  /// With blob encryption the backend only knows checksums of the sealed data, which cannot be
+  /// compared with the recorded ones; this is reported as an error rather than as unverified.
+  pub fn verify_blobs(&self) -> Result<(Vec<String>, uint), String> {
+    if self.blob_encryption {
+      return Err("The blobs of this repository are encrypted; the backend only keeps checksums \
+                  of the sealed data. Use `verify` to download and check the blobs instead."
+                 .to_string());
+    }
+    let checksums = match self.blob_index.send_reply(blob_index::ListChecksums) {
+      blob_index::BlobChecksums(checksums) => checksums,
+      _ => fail!("Unexpected reply from blob index."),
+    };
+
+    let mut backend = self.backend.clone();
+    let mut problems = Vec::new();
+    let mut unverified = 0;
+    for (name, recorded) in checksums.into_iter() {
+      match backend.stored_checksum(name.as_slice()) {
+        Ok(Some(ref stored)) if *stored == recorded => (),
+        Ok(Some(stored)) => problems.push(format!(
+          "blob {}: backend has checksum {}, expected {}", name.as_slice().to_hex(),
+          stored.as_slice().to_hex(), recorded.as_slice().to_hex())),
+        Ok(None) => unverified += 1,
+        Err(e) => problems.push(format!("blob {}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    Ok((problems, unverified))
+  }
+
+  /// Download a subset of the committed blobs and re-hash the data-chunks stored in them. Blobs
+  /// that turn out intact are recorded as verified, so that the next run continues with others.
+  pub fn verify_data(&self, subset: DataSubset) -> DataVerifyReport {
+    let order = match self.blob_index.send_reply(blob_index::ListVerificationOrder) {
+      blob_index::BlobNames(names) => names,
+      _ => fail!("Unexpected reply from blob index."),
+    };
+    let (limit, budget) = match subset {
+      PercentOfBlobs(percent) => {
+        (cmp::max(1, (order.len() * cmp::min(percent, 100) + 99) / 100), None)
+      },
+      ByteBudget(bytes) => (order.len(), Some(bytes)),
+    };
+
+    let mut backend = self.backend.clone();
+    let mut report = DataVerifyReport{problems: Vec::new(), blobs: 0, chunks: 0, bytes: 0,
+                                      cancelled: false};
+    for batch in order.slice_to(cmp::min(limit, order.len())).chunks(VERIFY_BATCH) {
+      let names: HashSet<Vec<u8>> = batch.iter().map(|name| name.clone()).collect();
+      let chunks = match self.hash_index.send_reply(
+        hash_index::ListChunksIn(names, BlobID::blob_name_of)) {
+        hash_index::Chunks(chunks) => chunks,
+        _ => fail!("Unexpected reply from hash index."),
+      };
+      let mut chunks_by_blob: HashMap<Vec<u8>, Vec<(Hash, Vec<u8>)>> = HashMap::new();
+      for (hash, persistent_ref) in chunks.into_iter() {
+        match BlobID::blob_name_of(persistent_ref.as_slice()) {
+          Ok(Some(name)) => chunks_by_blob.find_or_insert(name, Vec::new())
+                                          .push((hash, persistent_ref)),
+          _ => (),
+        }
+      }
+
+      let mut verified = Vec::new();
+      for name in batch.iter() {
+        report.cancelled = self.cancel.is_cancelled();
+        if spent(&report, budget) || report.cancelled { break }
+        let blob = match backend.retrieve(name.as_slice()) {
+          Ok(blob) => blob,
+          Err(e) => {
+            report.problems.push(format!("blob {}: {}", name.as_slice().to_hex(), e));
+            continue;
+          },
+        };
+        report.blobs += 1;
+        report.bytes += blob.len() as u64;
+        let intact = match chunks_by_blob.find(name) {
+          Some(chunks) => verify_chunks(name.as_slice(), blob.as_slice(), chunks.as_slice(),
+                                        &self.chunk_cipher, &mut report),
+          None => true,
+        };
+        if intact {
+          verified.push(name.clone());
+        }
+      }
+      self.blob_index.send_reply(blob_index::Verified(verified, time::get_time().sec));
+      if spent(&report, budget) || report.cancelled { break }
+    }
+    report
+  }
+
+  fn repository_setting(&self, name: String) -> Option<i64> {
+    match self.blob_index.send_reply(blob_index::FetchSetting(name)) {
+      blob_index::Setting(value) => value,
+      _ => fail!("Unexpected reply from blob index."),
+    }
+  }
+
+  /// Schedule a maintenance task (see `MAINTENANCE_TASKS`) to be due every `days` days, or never
+  /// if `days` is `0`. The schedule is stored in the repository, so it applies to every client.
+  pub fn schedule_maintenance(&self, task: &str, days: u64) -> Result<(), String> {
+    if !MAINTENANCE_TASKS.iter().any(|&t| t == task) {
+      return Err(format!("Unknown maintenance task '{}'.", task));
+    }
+    self.blob_index.send_reply(blob_index::StoreSetting(format!("{}_interval_days", task),
+                                                        days as i64));
+    Ok(())
+  }
+
+  /// List each maintenance task with its interval in days (`0` if not scheduled) and the time
+  /// it last ran (in seconds since the epoch, if ever).
+  pub fn maintenance_schedule(&self) -> Vec<(&'static str, u64, Option<u64>)> {
+    MAINTENANCE_TASKS.iter().map(|&task| {
+      let days = self.repository_setting(format!("{}_interval_days", task)).unwrap_or(0);
+      let last_run = self.repository_setting(format!("{}_last_run", task));
+      (task, days as u64, last_run.map(|t| t as u64))
+    }).collect()
+  }
+
+  /// Run the scheduled maintenance tasks that are due, and record when they ran. Returns the
+  /// tasks that ran, and the problems found by checks.
+  pub fn run_due_maintenance(&self) -> (Vec<&'static str>, Vec<String>) {
+    let now = time::get_time().sec as u64;
+    let mut ran = Vec::new();
+    let mut problems = Vec::new();
+    for (task, days, last_run) in self.maintenance_schedule().into_iter() {
+      let due = days > 0 && last_run.map_or(true, |t| t + days * 24 * 60 * 60 <= now);
+      if !due { continue }
+
+      let families: Vec<Family<B>> = self.list_families().into_iter().filter_map(|name| {
+        self.open_family(name)
+      }).collect();
+      match task {
+        "maintenance" => {
+          self.maintenance();
+          for family in families.iter() { family.maintenance() }
+        },
+        "check" => {
+          problems.extend(self.check().into_iter());
+          for family in families.iter() { problems.extend(family.check().into_iter()) }
+        },
+        "compact" => {
+          let (merged, removed) = self.compact_hash_index();
+          info!("Compacted the hash index: {} duplicate(s) merged, {} entry(s) removed.",
+                merged, removed);
+        },
+        _ => unreachable!(),
+      }
+      self.blob_index.send_reply(blob_index::StoreSetting(format!("{}_last_run", task),
+                                                          now as i64));
+      ran.push(task);
+    }
+    (ran, problems)
+  }
+
+  /// The families whose default snapshot interval (see `FamilyDefaults`) has passed since their
+  /// last snapshot started, or that have an interval but no snapshot yet.
+  pub fn due_snapshots(&self) -> Vec<String> {
+    let now = time::get_time().sec as u64;
+    self.list_families().into_iter().filter(|name| {
+      let family = match self.open_family(name.clone()) {
+        Some(family) => family,
+        None => return false,
+      };
+      let days = family.defaults().snapshot_interval_days;
+      let last = family.list_snapshots().last().map(|&(_, started)| started);
+      days > 0 && last.map_or(true, |t| t + days * 24 * 60 * 60 <= now)
+    }).collect()
+  }
+
+  /// The names of the blobs used by snapshots that started less than `days` days ago, and of
+  /// those used by older snapshots. A blob can be used by both.
+  fn blobs_by_age(&self, days: u64) -> (HashSet<Vec<u8>>, HashSet<Vec<u8>>) {
+    let now = time::get_time().sec as u64;
+    let age = days * 24 * 60 * 60;
+    let cutoff = if now > age { now - age } else { 0 };
+
+    let mut recent = HashSet::new();
+    let mut old = HashSet::new();
+    let mut recent_seen = HashSet::new();
+    let mut old_seen = HashSet::new();
+    for name in self.list_families().into_iter() {
+      let family = match self.open_family(name.clone()) {
+        Some(family) => family,
+        None => fail!(format!("Could not open family '{}'", name)),
+      };
+      for (id, started) in family.list_snapshots().into_iter() {
+        if started >= cutoff {
+          family.snapshot_blobs(id, &mut recent, &mut recent_seen);
+        } else {
+          family.snapshot_blobs(id, &mut old, &mut old_seen);
+        }
+      }
+    }
+    (recent, old)
+  }
+
+  /// Give the blobs that are only used by snapshots that started more than `days` days ago the
+  /// storage hint `hint`, so that the backend can store them for less (e.g. in a cheaper S3
+  /// storage class). Hinted blobs that recent snapshots use again (through deduplication) are
+  /// given the default hint again. The hints are recorded in the blob index.
+  pub fn hint_old_blobs(&self, days: u64, hint: StorageHint) -> StorageHintReport {
+    let (recent, old) = self.blobs_by_age(days);
+    let hinted: HashMap<Vec<u8>, i64> = match self.blob_index.send_reply(
+      blob_index::ListStorageHints) {
+      blob_index::BlobHints(hints) => hints.into_iter().collect(),
+      _ => fail!("Unexpected reply from blob index."),
+    };
+
+    let mut backend = self.backend.clone();
+    let mut report = StorageHintReport{hinted: 0, restored: 0, errors: Vec::new()};
+
+    let mut to_hint = Vec::new();
+    for name in old.iter() {
+      if recent.contains(name) || hinted.find(name) == Some(&hint.code()) { continue }
+      match backend.set_storage_hint(name.as_slice(), hint.clone()) {
+        Ok(()) => to_hint.push(name.clone()),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    report.hinted = to_hint.len();
+    self.blob_index.send_reply(blob_index::StorageHinted(to_hint, hint.code()));
+
+    let mut to_restore = Vec::new();
+    for name in recent.iter() {
+      if !hinted.contains_key(name) { continue }
+      match backend.set_storage_hint(name.as_slice(), blob_store::FrequentAccess) {
+        Ok(()) => to_restore.push(name.clone()),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    report.restored = to_restore.len();
+    self.blob_index.send_reply(blob_index::StorageHinted(to_restore,
+                                                         blob_store::FrequentAccess.code()));
+
+    report
+  }
+
+  /// Keep the blobs used by snapshots that started less than `days` days ago from being deleted
+  /// for another `days` days, on backends that support retention (e.g. S3 Object Lock), so that
+  /// not even a client with the backend's credentials can delete recent snapshots. Old blobs
+  /// that recent snapshots use again (through deduplication) are locked too.
+  ///
+  /// Retention only grows: running this again extends the lock of blobs that are still used.
+  pub fn lock_recent_blobs(&self, days: u64) -> RetentionReport {
+    let mut report = RetentionReport{locked: 0, until: 0, errors: Vec::new()};
+    let mut backend = self.backend.clone();
+    if !backend.supports_retention() {
+      report.errors.push("The blob store does not support retention.".to_string());
+      return report;
+    }
+    let (recent, _) = self.blobs_by_age(days);
+    report.until = time::get_time().sec as u64 + days * 24 * 60 * 60;
+    for name in recent.iter() {
+      match backend.set_retention(name.as_slice(), report.until) {
+        Ok(()) => report.locked += 1,
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    report
+  }
+}
+
+/// The outcome of `hint_old_blobs`.
+pub struct StorageHintReport {
+  /// The number of blobs given the storage hint for old data.
+  pub hinted: uint,
+
+  /// The number of blobs given the default hint again, because recent snapshots use them again.
+  pub restored: uint,
+
+  /// A description of each blob whose hint could not be changed.
+  pub errors: Vec<String>,
+}
+
+/// The outcome of `lock_recent_blobs`.
+pub struct RetentionReport {
+  /// The number of blobs locked.
+  pub locked: uint,
+
+  /// Until when the blobs are locked, in seconds since the epoch.
+  pub until: u64,
+
+  /// A description of each blob that could not be locked.
+  pub errors: Vec<String>,
+}
+
+/// The outcome of `archive_old_blobs`.
+pub struct ArchiveReport {
+  /// The number of blobs moved to the archive backend.
+  pub archived: uint,
+
+  /// The number of archived blobs moved back to the primary backend, because recent snapshots
+  /// use them again.
+  pub recalled: uint,
+
+  /// A description of each blob that could not be moved.
+  pub errors: Vec<String>,
+}
+
+impl <P: BlobStoreBackend + Clone + Send, A: BlobStoreBackend + Clone + Send>
+  Hat<TieredBackend<P, A>> {
+
+  /// Read the blobs that the blob index records as archived from the archive backend. This must
+  /// be done before anything is read from the repository.
+  pub fn load_archive_locations(&self) {
+    match self.blob_index.send_reply(blob_index::ListArchived) {
+      blob_index::BlobNames(names) => self.backend.mark_archived(names, true),
+      _ => fail!("Unexpected reply from blob index."),
+    }
+  }
+
+  /// Move the blobs that are only used by snapshots that started more than `days` days ago to
+  /// the archive backend, so that recent snapshots stay on the primary backend. Archived blobs
+  /// that recent snapshots use again (through deduplication) are moved back.
+  ///
+  /// A blob is only deleted from its old location once the blob index records the new one.
+  pub fn archive_old_blobs(&self, days: u64) -> ArchiveReport {
+    let (recent, old) = self.blobs_by_age(days);
+
+    let mut backend = self.backend.clone();
+    let mut report = ArchiveReport{archived: 0, recalled: 0, errors: Vec::new()};
+
+    let mut to_archive = Vec::new();
+    for name in old.iter() {
+      if recent.contains(name) || backend.is_archived(name.as_slice()) { continue }
+      match backend.copy_to_archive(name.as_slice()) {
+        Ok(()) => to_archive.push(name.clone()),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    self.blob_index.send_reply(blob_index::Archived(to_archive.clone(), true));
+    backend.mark_archived(to_archive.clone(), true);
+    report.archived = to_archive.len();
+    for name in to_archive.iter() {
+      match backend.delete_from_primary(name.as_slice()) {
+        Ok(()) => (),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+
+    let mut to_recall = Vec::new();
+    for name in recent.iter() {
+      if !backend.is_archived(name.as_slice()) { continue }
+      match backend.copy_to_primary(name.as_slice()) {
+        Ok(()) => to_recall.push(name.clone()),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+    self.blob_index.send_reply(blob_index::Archived(to_recall.clone(), false));
+    backend.mark_archived(to_recall.clone(), false);
+    report.recalled = to_recall.len();
+    for name in to_recall.iter() {
+      match backend.delete_from_archive(name.as_slice()) {
+        Ok(()) => (),
+        Err(e) => report.errors.push(format!("{}: {}", name.as_slice().to_hex(), e)),
+      }
+    }
+
+    report
+  }
+}
+
+
+
+struct FileEntry {
+  name: Vec<u8>,
+
+  parent_id: Option<Vec<u8>>,
+
+  stat: FileStat,
+  full_path: Path,
+
+  // Whether `stat.size` is the size of the data; the size of streamed command output is not
+  // known in advance.
+  size_known: bool,
+}
+
+impl FileEntry {
+  fn new(full_path: Path,
+         parent: Option<Vec<u8>>) -> Result<FileEntry, io::IoError> {
+    let filename_opt = full_path.filename();
+    if filename_opt.is_some() {
+      lstat(&full_path).map(|st| {
+        FileEntry{
+          name: filename_opt.unwrap().into_vec(),
+          parent_id: parent.clone(),
+          stat: st,
+          full_path: full_path.clone(),
+          size_known: true}
+      })
+    }
+    else { Err(io::IoError{kind: io::OtherIoError,
+                           desc: "Could not parse filename.",
+                           detail: None }) }
+  }
+
+  /// An entry of a tar archive (see `Family::snapshot_tar`). Archive entries have no inode, so
+  /// one is derived from the entry's path, which keeps its ID stable across imported snapshots.
+  fn from_tar(header: &tar::Header, name: Vec<u8>, parent: Option<Vec<u8>>) -> FileEntry {
+    let digest = Hash::new(header.path.as_slice()).bytes;
+    let inode = digest.iter().take(8).fold(0u64, |n, &b| (n << 8) | b as u64);
+    let millis = header.modified * 1000;
+    FileEntry{
+      name: name,
+      parent_id: parent,
+      stat: FileStat{
+        size: header.size,
+        kind: if header.kind == tar::Directory { TypeDirectory } else { TypeFile },
+        perm: io::FilePermission::from_bits_truncate(header.mode),
+        created: millis,
+        modified: millis,
+        accessed: millis,
+        unstable: io::UnstableFileStat{device: 0, inode: inode, rdev: 0, nlink: 1, uid: 0,
+                                       gid: 0, blksize: 0, blocks: 0, flags: 0, gen: 0},
+      },
+      full_path: Path::new(header.path.clone()),
+      size_known: true}
+  }
+
+  /// The data-chunks of the entry. Special files are classified by `special_files`: named pipes
+  /// are read with a timeout if it says so, and otherwise special files have no data.
+  fn file_iterator(&self, options: ChunkerOptions, pool: BufferPool,
+                   special_files: &SpecialFilePolicy) -> IoResult<Chunker<ReadAhead>> {
+    let reader = match (self.stat.kind, special_files) {
+      (TypeFile, _) => ReadAhead::new(try!(File::open(&self.full_path)), pool.clone()),
+      (TypeNamedPipe, &ReadFifos(timeout_ms)) => {
+        ReadAhead::new(try!(FifoReader::open(&self.full_path, timeout_ms)), pool.clone())
+      },
+      _ => ReadAhead::new(NullReader, pool.clone()),
+    };
+    Ok(Chunker::new(reader, options).with_buffer_pool(pool))
+  }
+
+  fn has_nodump_flag(&self) -> bool { listdir::has_nodump_flag(&self.full_path, &self.stat) }
+
+  fn is_directory(&self) -> bool { self.stat.kind == TypeDirectory }
+  fn is_symlink(&self) -> bool { self.stat.kind == TypeSymlink }
+  fn is_file(&self) -> bool { self.stat.kind == TypeFile }
+
+  /// Whether the entry is a named pipe, socket or device, rather than a file, directory or
+  /// symbolic link.
+  fn is_special(&self) -> bool { !self.is_directory() && !self.is_symlink() && !self.is_file() }
+}
+
+impl Clone for FileEntry {
+  fn clone(&self) -> FileEntry {
+    FileEntry{
+      name:self.name.clone(), parent_id:self.parent_id.clone(),
+      stat: FileStat{
+        size: self.stat.size,
+        kind: self.stat.kind,
+        perm: self.stat.perm,
+        created: self.stat.created,
+        modified: self.stat.modified,
+        accessed: self.stat.accessed,
+        unstable: self.stat.unstable,
+      },
+      full_path:self.full_path.clone(),
+      size_known: self.size_known}
+  }
+}
+
+impl KeyEntry<FileEntry> for FileEntry {
+  fn name(&self) -> Vec<u8> {
+    self.name.clone()
+  }
+  fn id(&self) -> Option<Vec<u8>> {
+    Some(format!("d{:u}i{:u}",
+                 self.stat.unstable.device,
+                 self.stat.unstable.inode).as_bytes().into_vec())
+  }
+  fn parent_id(&self) -> Option<Vec<u8>> {
+    self.parent_id.clone()
+  }
+
+  fn size(&self) -> Option<u64> {
+    if self.size_known { Some(self.stat.size) } else { None }
+  }
+
+  fn created(&self) -> Option<u64> {
+    Some(self.stat.created)
+  }
+  fn modified(&self) -> Option<u64> {
+    Some(self.stat.modified)
+  }
+  fn accessed(&self) -> Option<u64> {
+    Some(self.stat.accessed)
+  }
+
+  fn permissions(&self) -> Option<u64> {
+    None
+  }
+  fn user_id(&self) -> Option<u64> {
+    None
+  }
+  fn group_id(&self) -> Option<u64> {
+    None
+  }
+  fn with_id(&self, id: Vec<u8>) -> FileEntry {
+    assert_eq!(Some(id), self.id());
+    self.clone()
+  }
+}
+
+/// A file that a snapshot could not read, in full or at all.
+#[deriving(Clone, Encodable)]
+pub struct FileFailure {
+  pub path: String,
+  pub error: String,
+}
+
+fn record_failure(failures: &sync::Arc<sync::Mutex<Vec<FileFailure>>>, path: &Path,
+                  error: String) {
+  failures.lock().push(FileFailure{path: path.display().to_string(), error: error});
+}
+
+/// Files of at least this size report how far they have been read.
+static PROGRESS_FILE_SIZE: u64 = 256 * 1024 * 1024;
+
+/// The number of seconds between progress reports for a large file.
+static PROGRESS_INTERVAL: i64 = 10;
+
+/// How far a file being snapshotted has been read.
+struct ReadProgress {
+  path: Path,
+  size: u64,
+  bytes_read: u64,
+  last_print: time::Timespec,
+}
+
+impl ReadProgress {
+  fn new(path: Path, size: u64, now: time::Timespec) -> ReadProgress {
+    ReadProgress{path: path, size: size, bytes_read: 0, last_print: now}
+  }
+
+  /// Count `bytes` more as read at `now`. Returns the progress to report, if it is due.
+  fn read(&mut self, bytes: u64, now: time::Timespec) -> Option<String> {
+    self.bytes_read += bytes;
+    if self.size < PROGRESS_FILE_SIZE || now.sec - self.last_print.sec < PROGRESS_INTERVAL {
+      return None;
+    }
+    self.last_print = now;
+    Some(format!("'{}': read {} of {} bytes ({}%)", self.path.display(), self.bytes_read,
+                 self.size, self.bytes_read * 100 / self.size))
+  }
+}
+
+/// The data-chunks of a file being snapshotted. Large files report their progress as they are
+/// read, so that a long-running snapshot of a single file does not appear to hang.
+struct FileIterator {
+  chunks: Chunker<ReadAhead>,
+  progress: ReadProgress,
+}
+
+impl FileIterator {
+  fn new(chunks: Chunker<ReadAhead>, path: Path, size: u64) -> FileIterator {
+    FileIterator{chunks: chunks,
+                 progress: ReadProgress::new(path, size, time::now().to_timespec())}
+  }
+}
+
+impl Iterator<Vec<u8>> for FileIterator {
+  fn next(&mut self) -> Option<Vec<u8>> {
+    let chunk = self.chunks.next();
+    let bytes = chunk.as_ref().map(|c| c.len() as u64).unwrap_or(0);
+    match self.progress.read(bytes, time::now().to_timespec()) {
+      Some(report) => info!("{}", report),
+      None => (),
+    }
+    chunk
+  }
+}
+
+
+/// Options controlling which paths are picked up by a snapshot.
+#[deriving(Clone)]
+pub struct SnapshotOptions {
+  /// Skip the contents of directories tagged with a valid `CACHEDIR.TAG` file.
+  pub skip_tagged_cache_dirs: bool,
+
+  /// Skip the contents of the user's XDG cache directory.
+  pub skip_xdg_cache_dir: bool,
+
+  /// Skip the contents of hat repositories, including the one being written to, which would
+  /// otherwise grow with every snapshot that contains it.
+  pub skip_repositories: bool,
+
+  /// Skip files and directories marked with the filesystem "nodump" flag.
+  pub honor_nodump: bool,
+
+  /// The number of tasks listing directories and reading files in parallel.
+  pub workers: uint,
+
+  /// Only record the files whose data is already stored, and store nothing (see `seed_dir`).
+  pub seed_only: bool,
+
+  /// The expected size of the snapshot (see `Family::estimate_dir`), to report progress as a
+  /// percentage.
+  pub expected: Option<SizeEstimate>,
+
+  /// The files of an earlier snapshot, e.g. the one that `dir` was restored from (see
+  /// `Family::reference_files`). Files that it holds at the same path and with the same
+  /// modification time are recorded with its data, without being read.
+  pub reference: Option<sync::Arc<ReferenceFiles>>,
+
+  /// What to store of named pipes, sockets and devices.
+  pub special_files: SpecialFilePolicy,
+
+  /// Paths to leave out, with everything below them. Relative paths are relative to the
+  /// directory being snapshotted.
+  pub excludes: Vec<Path>,
+
+  /// The files that the snapshot must capture. The files inserted are recorded in it, for
+  /// `Family::flush_checked` to check the snapshot against it.
+  pub manifest: Option<sync::Arc<SnapshotManifest>>,
+
+  /// Files whose data is hashed and uploaded before that of the other files, so that the most
+  /// important data is stored soonest: paths relative to the directory being snapshotted, with
+  /// everything below them, or `*SUFFIX` for the files whose name ends in SUFFIX (`*.sqlite`).
+  pub priority_paths: Vec<String>,
+
+  /// Also store the files modified at or after this time (in milliseconds) first.
+  pub priority_since: Option<u64>,
+}
+
+/// What a snapshot stores of special files (named pipes, sockets and devices). Their contents
+/// are not files: reading a named pipe waits for a writer that may never come, and reading a
+/// device can go on forever.
+#[deriving(Clone, PartialEq, Show)]
+pub enum SpecialFilePolicy {
+  /// Record the entry and its metadata, without data. It is restored as an empty file.
+  RecordSpecialFiles,
+
+  /// Read named pipes as files, until their writer is done or no data has arrived for the
+  /// given number of milliseconds. Other special files are recorded as with
+  /// `RecordSpecialFiles`.
+  ReadFifos(u64),
+}
+
+impl SnapshotOptions {
+  pub fn new() -> SnapshotOptions {
+    SnapshotOptions{skip_tagged_cache_dirs: true,
+                    skip_xdg_cache_dir: false,
+                    skip_repositories: true,
+                    honor_nodump: false,
+                    workers: 5,
+                    seed_only: false,
+                    expected: None,
+                    reference: None,
+                    special_files: RecordSpecialFiles,
+                    excludes: Vec::new(),
+                    manifest: None,
+                    priority_paths: Vec::new(),
+                    priority_since: None}
+  }
+
+  /// Whether `path`, found in a snapshot of `root`, is one of the `excludes`.
+  fn is_excluded(&self, root: &Path, path: &Path) -> bool {
+    self.excludes.iter().any(|exclude| root.join(exclude) == *path)
+  }
+
+  /// Whether some files are stored before the others (see `priority_paths`).
+  fn has_priorities(&self) -> bool {
+    self.priority_paths.len() > 0 || self.priority_since.is_some()
+  }
+
+  /// Whether the file at `path`, found in a snapshot of `root` and last modified at `modified`,
+  /// is stored before the others.
+  fn is_priority(&self, root: &Path, path: &Path, modified: u64) -> bool {
+    if self.priority_since.map_or(false, |since| modified >= since) {
+      return true;
+    }
+    self.priority_paths.iter().any(|pattern| {
+      if pattern.as_slice().starts_with("*") {
+        let suffix = pattern.as_slice().slice_from(1).as_bytes();
+        path.filename().map_or(false, |name| name.ends_with(suffix))
+      } else {
+        root.join(pattern.as_slice()).is_ancestor_of(path)
+      }
+    })
+  }
+}
+
+/// The files of a snapshot by path, with their modification time, data hash and persistent
+/// reference (as listed by `key_store::ListDir`).
+pub struct ReferenceFiles {
+  files: HashMap<Vec<u8>, (u64, Vec<u8>, Vec<u8>)>,
+}
+
+impl ReferenceFiles {
+  pub fn len(&self) -> uint {
+    self.files.len()
+  }
+
+  /// The data hash and persistent reference of the file at `path`, if it was last modified at
+  /// `modified`.
+  fn unchanged(&self, path: &[u8], modified: u64) -> Option<(Vec<u8>, Vec<u8>)> {
+    match self.files.find(&path.into_vec()) {
+      Some(&(recorded, ref hash, ref persistent_ref)) if recorded == modified => {
+        Some((hash.clone(), persistent_ref.clone()))
+      },
+      _ => None,
+    }
+  }
+}
+
+/// Why the contents of `dir` are left out of a snapshot with `options`, if they are.
+fn excluded_dir_reason(options: &SnapshotOptions, xdg_cache_dir: &Option<Path>, dir: &Path)
+                       -> Option<&'static str> {
+  if xdg_cache_dir.as_ref() == Some(dir) {
+    return Some("cache directory");
+  }
+  if options.skip_tagged_cache_dirs && listdir::is_tagged_cache_dir(dir) {
+    return Some("tagged cache directory");
+  }
+  if options.skip_repositories && listdir::is_hat_repository_dir(dir) {
+    return Some("hat repository");
+  }
+  None
+}
+
+/// The outcome of `Family::seed_dir`.
+#[deriving(Clone, Show)]
+pub struct SeedCounts {
+  /// Files whose data was already stored, and that were recorded.
+  pub seeded: uint,
+
+  /// Files whose data is not stored yet, or that could not be read.
+  pub missing: uint,
+}
+
+/// The outcome of `Family::estimate_dir`.
+#[deriving(Clone, Show)]
+pub struct SizeEstimate {
+  /// Files and directories that a snapshot would record.
+  pub entries: uint,
+
+  /// The total size of those files.
+  pub bytes: u64,
+
+  /// Files changed since the family's last snapshot started (all files, if there is none). Only
+  /// their data can be new, so their size bounds the data to upload.
+  pub changed_files: uint,
+  pub changed_bytes: u64,
+}
+
+/// Counts what a snapshot would record, with the same exclusions, without reading any data.
+#[deriving(Clone)]
+struct EstimatePathHandler {
+  estimate: sync::Arc<sync::Mutex<SizeEstimate>>,
+  root: Path,
+  options: SnapshotOptions,
+  xdg_cache_dir: Option<Path>,
+
+  // The start of the last snapshot, in milliseconds since the epoch:
+  since: Option<u64>,
+}
+
+impl listdir::PathHandler<()> for EstimatePathHandler {
+  fn handle_path(&mut self, _: (), path: Path) -> Option<()> {
+    if self.options.is_excluded(&self.root, &path) {
+      return None;
+    }
+    let entry = match FileEntry::new(path.clone(), None) {
+      Ok(ref entry) if entry.is_symlink() => return None,
+      Ok(ref entry) if self.options.honor_nodump && entry.has_nodump_flag() => return None,
+      Ok(entry) => entry,
+      Err(_) => return None,
+    };
+
+    if entry.is_directory() {
+      self.estimate.lock().entries += 1;
+      let excluded = excluded_dir_reason(&self.options, &self.xdg_cache_dir, &path).is_some();
+      return if excluded { None } else { Some(()) };
+    }
+
+    let changed = self.since.map(|since| {
+      cmp::max(entry.stat.modified, entry.stat.created) >= since
+    }).unwrap_or(true);
+    let mut estimate = self.estimate.lock();
+    estimate.entries += 1;
+    estimate.bytes += entry.stat.size;
+    if changed {
+      estimate.changed_files += 1;
+      estimate.changed_bytes += entry.stat.size;
+    }
+    None
+  }
+}
+
+
+/// The number of blocks of a file's data that `Family::insert_streamed` reads ahead of the key
+/// store.
+static STREAM_PIPE_BLOCKS: uint = 4;
+
+/// The size of the blocks that `Family::insert_streamed` reads file data in.
+static STREAM_BLOCK_SIZE: uint = 64 * 1024;
+
+
+/// Files up to this size are inserted in batches, rather than one message at a time.
+static BATCH_FILE_SIZE: u64 = 64 * 1024;
+
+/// The largest number of files inserted in one batch.
+static BATCH_LEN: uint = 256;
+
+struct InsertPathHandler<B:'static> {
+  count: sync::Arc<sync::Mutex<uint>>,
+  last_print: sync::Arc<sync::Mutex<time::Timespec>>,
+  my_last_print: time::Timespec,
+  failures: sync::Arc<sync::Mutex<Vec<FileFailure>>>,
+  seed_counts: sync::Arc<sync::Mutex<SeedCounts>>,
+  reused: sync::Arc<sync::Mutex<uint>>,
+
+  // The directory being inserted, to find its files in `options.reference`:
+  root: Path,
+  options: SnapshotOptions,
+  xdg_cache_dir: Option<Path>,
+  chunker: ChunkerOptions,
+  buffer_pool: BufferPool,
+
+  key_store: KeyStoreProcess<FileEntry, FileIterator, B>,
+  cancel: CancelToken,
+
+  // Small files of the current directory that are waiting to be inserted:
+  batch: Vec<(FileEntry, Option<proc():Send -> Option<FileIterator>>)>,
+}
+
+impl <B: Clone> Clone for InsertPathHandler<B> {
+  fn clone(&self) -> InsertPathHandler<B> {
+    InsertPathHandler{
+      count: self.count.clone(),
+      last_print: self.last_print.clone(),
+      my_last_print: self.my_last_print,
+      failures: self.failures.clone(),
+      seed_counts: self.seed_counts.clone(),
+      reused: self.reused.clone(),
+      root: self.root.clone(),
+      options: self.options.clone(),
+      xdg_cache_dir: self.xdg_cache_dir.clone(),
+      chunker: self.chunker.clone(),
+      buffer_pool: self.buffer_pool.clone(),
+      key_store: self.key_store.clone(),
+      cancel: self.cancel.clone(),
+      batch: Vec::new(),  // Pending files belong to the original.
+    }
+  }
+}
+
+impl <B> InsertPathHandler<B> {
+  pub fn new(key_store: KeyStoreProcess<FileEntry, FileIterator, B>, root: Path,
+             options: SnapshotOptions, chunker: ChunkerOptions, buffer_pool: BufferPool,
+             failures: sync::Arc<sync::Mutex<Vec<FileFailure>>>) -> InsertPathHandler<B> {
+    let xdg_cache_dir = if options.skip_xdg_cache_dir { listdir::xdg_cache_dir() }
+                        else { None };
+    InsertPathHandler{
+      count: sync::Arc::new(sync::Mutex::new(0)),
+      last_print: sync::Arc::new(sync::Mutex::new(time::now().to_timespec())),
+      my_last_print: time::now().to_timespec(),
+      failures: failures,
+      seed_counts: sync::Arc::new(sync::Mutex::new(SeedCounts{seeded: 0, missing: 0})),
+      reused: sync::Arc::new(sync::Mutex::new(0)),
+      root: root,
+      options: options,
+      xdg_cache_dir: xdg_cache_dir,
+      chunker: chunker,
+      buffer_pool: buffer_pool,
+      key_store: key_store,
+      cancel: CancelToken::new(),
+      batch: Vec::new(),
+    }
+  }
+
+  /// Decide whether the contents of a directory should be left out of the snapshot.
+  /// The directory entry itself is still recorded, but nothing inside it, not even its
+  /// `CACHEDIR.TAG`.
+  fn skip_dir_contents(&self, dir: &Path) -> bool {
+    match excluded_dir_reason(&self.options, &self.xdg_cache_dir, dir) {
+      Some("hat repository") => {
+        warning!("Warning: skipping the contents of hat repository '{}'", dir.display());
+        true
+      },
+      Some(reason) => {
+        info!("Skipping {} '{}'", reason, dir.display());
+        true
+      },
+      None => false,
+    }
+  }
+
+  /// The data of `entry` in `options.reference`, if it is unchanged there.
+  fn reference_data(&self, entry: &FileEntry) -> Option<(Vec<u8>, Vec<u8>)> {
+    let reference = match self.options.reference {
+      Some(ref reference) => reference,
+      None => return None,
+    };
+    entry.full_path.path_relative_from(&self.root).and_then(|relative| {
+      reference.unchanged(relative.as_vec(), entry.stat.modified)
+    })
+  }
+}
+
+impl <B: BlobStoreBackend + Clone + Send> InsertPathHandler<B> {
+  /// Insert the pending small files.
+  fn flush_batch(&mut self) {
+    if self.batch.len() == 0 {
+      return;
+    }
+    let batch = mem::replace(&mut self.batch, Vec::new());
+    // Only files without priority are batched, so these wait for the others:
+    let insert = if self.options.has_priorities() { key_store::InsertLater(batch) }
+                 else { key_store::InsertBatch(batch) };
+    match self.key_store.send_reply(insert) {
+      key_store::Ids(_) => (),
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+}
+
+impl <B: BlobStoreBackend + Clone + Send> listdir::PathHandler<Option<Vec<u8>>>
+  for InsertPathHandler<B> {
+  fn end_dir(&mut self) {
+    self.flush_batch();
+  }
+
+  fn handle_path(&mut self, parent: Option<Vec<u8>>, path: Path) -> Option<Option<Vec<u8>>> {
+    // Once cancelled, the remaining entries are left out and no directory is entered:
+    if self.cancel.is_cancelled() {
+      return None;
+    }
+    if self.options.is_excluded(&self.root, &path) {
+      detail!("Skipping '{}': excluded", path.display());
+      return None;
+    }
+
+    let count = {
+      let mut guarded_count = self.count.lock();
+      *guarded_count += 1;
+      *guarded_count
+    };
+
+    if output::verbosity() == output::Verbose {
+      detail!("#{}: {}", count, path.display());
+    } else if self.my_last_print.sec <= time::now().to_timespec().sec - 1 {
+      let mut guarded_last_print = self.last_print.lock();
+      let now = time::now().to_timespec();
+      if guarded_last_print.sec <= now.sec - 1 {
+        match self.options.expected {
+          Some(ref expected) if expected.entries > 0 => {
+            info!("#{} ({}%): {}", count, cmp::min(100, count * 100 / expected.entries),
+                  path.display());
+          },
+          _ => info!("#{}: {}", count, path.display()),
+        }
+        *guarded_last_print = now;
+      }
+      self.my_last_print = now;
+    }
+
+    let fileEntry_opt = FileEntry::new(path.clone(), parent);
+    match fileEntry_opt {
+      Err(e) => {
+        warning!("Skipping '{}': {}", path.display(), e.to_string());
+        record_failure(&self.failures, &path, e.to_string());
+      },
+      Ok(fileEntry) => {
+        if fileEntry.is_symlink() {
+          return None;
+        }
+        if self.options.honor_nodump && fileEntry.has_nodump_flag() {
+          detail!("Skipping '{}': marked as nodump", path.display());
+          return None;
+        }
+        let mut fileEntry = fileEntry;
+        match (&self.options.manifest, path.path_relative_from(&self.root)) {
+          (&Some(ref manifest), Some(ref relative)) if !fileEntry.is_directory() => {
+            manifest.saw(relative.as_vec(), fileEntry.stat.size);
+          },
+          _ => (),
+        }
+        if fileEntry.is_special() {
+          detail!("Recording special file '{}'", path.display());
+          // The data of a named pipe has no size up front:
+          fileEntry.size_known = false;
+        }
+        let is_directory = fileEntry.is_directory();
+        let descend = is_directory && !self.skip_dir_contents(&path);
+        let urgent = !is_directory && self.options.has_priorities() &&
+                     self.options.is_priority(&self.root, &path, fileEntry.stat.modified);
+        if urgent {
+          detail!("Storing '{}' first", path.display());
+        }
+        let local_root = path;
+        let local_fileEntry = fileEntry.clone();
+        let chunker = self.chunker.clone();
+        let buffer_pool = self.buffer_pool.clone();
+        let failures = self.failures.clone();
+        let special_files = self.options.special_files.clone();
+        let create_file_it = proc() {
+          match local_fileEntry.file_iterator(chunker, buffer_pool, &special_files) {
+            Err(e) => {warning!("Skipping '{}': {}", local_root.display(), e.to_string());
+                       record_failure(&failures, &local_root, e.to_string());
+                       None},
+            Ok(it) => {
+              let path = local_root.clone();
+              let it = it.on_error(proc(e) {
+                warning!("Error reading '{}': {}; only the data before the error is stored",
+                         path.display(), e.to_string());
+                record_failure(&failures, &path, e.to_string());
+              });
+              Some(FileIterator::new(it, local_root, local_fileEntry.stat.size))
+            }
+          }
+        };
+        let create_file_it_opt = if is_directory { None }
+                                 else { Some(create_file_it) };
+
+        if self.options.seed_only && !is_directory {
+          let seeded = match self.key_store.send_reply(
+            key_store::Seed(fileEntry, create_file_it_opt))
+          {
+            key_store::Id(_) => true,
+            key_store::NotStored => false,
+            _ => fail!("Unexpected reply from key store."),
+          };
+          let mut counts = self.seed_counts.lock();
+          if seeded { counts.seeded += 1 } else { counts.missing += 1 }
+          return None;
+        }
+
+        if !is_directory && !fileEntry.is_special() {
+          match self.reference_data(&fileEntry) {
+            Some((hash, persistent_ref)) => {
+              match self.key_store.send_reply(
+                key_store::InsertStored(fileEntry, hash, persistent_ref))
+              {
+                key_store::Id(_) => *self.reused.lock() += 1,
+                _ => fail!("Unexpected reply from key store."),
+              }
+              return None;
+            },
+            None => (),
+          }
+        }
+
+        if !is_directory && !urgent && fileEntry.stat.size <= BATCH_FILE_SIZE {
+          self.batch.push((fileEntry, create_file_it_opt));
+          if self.batch.len() >= BATCH_LEN {
+            self.flush_batch();
+          }
+          return None;
+        }
+
+        if !is_directory && !urgent && self.options.has_priorities() {
+          match self.key_store.send_reply(
+            key_store::InsertLater(vec![(fileEntry, create_file_it_opt)]))
+          {
+            key_store::Ids(_) => return None,
+            _ => fail!("Unexpected reply from key store."),
+          }
+        }
+
+        match self.key_store.send_reply(
+          key_store::Insert(fileEntry, create_file_it_opt))
+        {
+          key_store::Id(id) => {
+            if descend { return Some(Some(id)) }
+          },
+          _ => fail!("Unexpected reply from key store."),
+        }
+      }
+    }
+
+    return None;
+  }
+}
+
+
+fn try_a_few_times_then_fail(f: || -> bool, msg: &str) {
+  for i in range(1u, 5) {
+    if f() { return }
+  }
+  fail!(msg.to_string());
+}
+
+
+/// Options controlling how a snapshot is written back to the local filesystem.
+#[deriving(Clone)]
+pub struct CheckoutOptions {
+  /// Restore repeated data-chunks as reflinks to their first restored copy, if the target
+  /// filesystem supports it (e.g. btrfs or XFS).
+  pub reflink: bool,
+
+  /// Number of files whose data-chunks are fetched in parallel.
+  pub fetch_workers: uint,
+
+  /// The order in which files are restored.
+  pub order: RestoreOrder,
+
+  /// Leave out this many leading components of each path, like tar's `--strip-components`.
+  /// Entries with no more components than this are not restored themselves.
+  pub strip_components: uint,
+
+  /// Restore the entries below a snapshot path (given as its components) to a destination
+  /// instead, relative to the checkout directory. When there is any mapping, only the mapped
+  /// subtrees are restored; the longest matching prefix wins.
+  pub path_map: Vec<(Vec<Vec<u8>>, Path)>,
+}
+
+impl CheckoutOptions {
+  pub fn new() -> CheckoutOptions {
+    CheckoutOptions{reflink: false, fetch_workers: 4, order: PathOrder, strip_components: 0,
+                    path_map: Vec::new()}
+  }
+
+  /// Map the snapshot path `from` (e.g. `home/alice`) to `to`.
+  pub fn map_path(&mut self, from: &str, to: Path) {
+    let prefix = from.split('/').filter(|c| *c != "" && *c != ".")
+                     .map(|c| c.as_bytes().into_vec()).collect();
+    self.path_map.push((prefix, to));
+  }
+
+  /// Where the entry at `source` (its path components in the snapshot) is restored to when
+  /// checking out into `root`, or `None` if it is not restored.
+  pub fn destination(&self, root: &Path, source: &[Vec<u8>]) -> Option<Path> {
+    let mut best: Option<&(Vec<Vec<u8>>, Path)> = None;
+    for mapping in self.path_map.iter() {
+      let &(ref prefix, _) = mapping;
+      let longer = best.map(|&(ref other, _)| prefix.len() > other.len()).unwrap_or(true);
+      if longer && source.starts_with(prefix.as_slice()) {
+        best = Some(mapping);
+      }
+    }
+    let (mut path, rest) = match best {
+      Some(&(ref prefix, ref to)) => (root.join(to.as_vec()), source.slice_from(prefix.len())),
+      None if self.path_map.len() > 0 => return None,
+      None if self.strip_components > 0 && source.len() <= self.strip_components => return None,
+      None => (root.clone(), source.slice_from(self.strip_components)),
+    };
+    for component in rest.iter() {
+      path.push(component.as_slice());
+    }
+    Some(path)
+  }
+
+  /// Whether anything below the directory `source` is restored.
+  fn restores_below(&self, source: &[Vec<u8>]) -> bool {
+    self.path_map.len() == 0 || self.path_map.iter().any(|&(ref prefix, _)| {
+      source.starts_with(prefix.as_slice()) || prefix.as_slice().starts_with(source)
+    })
+  }
+}
+
+/// The order in which a checkout restores files. Any order but `PathOrder` lists the whole
+/// snapshot before the first file is restored.
+#[deriving(Clone, PartialEq, Show)]
+pub enum RestoreOrder {
+  /// Directory by directory, in listing order.
+  PathOrder,
+
+  /// Smallest files first, to repopulate as many files as possible early on.
+  SmallestFirst,
+
+  /// Grouped by the blob that holds each file. The chunk reads of all files are planned ahead,
+  /// so that a blob shared by several files is fetched from the backend once.
+  BlobOrder,
+}
+
+impl RestoreOrder {
+  pub fn from_name(name: &str) -> Option<RestoreOrder> {
+    match name {
+      "path" => Some(PathOrder),
+      "smallest" => Some(SmallestFirst),
+      "blob" => Some(BlobOrder),
+      _ => None,
+    }
+  }
+}
+
+
+/// A file to restore, handed from the listing stage to the fetch stage of a checkout.
+struct FetchJob<B> {
+  file_no: uint,
+  path: Path,
+  data: hash_tree::ReaderResult<HashStoreBackend<B>>,
+
+  // The recorded access and modification times, in milliseconds since the epoch:
+  times: (u64, u64),
+}
+
+/// Instructions for the write stage of a checkout. Files are identified by their `file_no`.
+enum WriteMsg {
+  /// Create the file at the given path, replacing whatever file was there.
+  OpenFile(uint, Path),
+
+  /// Append a data-chunk to an open file.
+  WriteChunk(uint, Vec<u8>),
+
+  /// All data-chunks of the file have been written. Give it the access and modification times.
+  CloseFile(uint, (u64, u64)),
+}
+
+/// The message of a task that failed with `cause`.
+fn failure_message(cause: &Any) -> String {
+  match cause.downcast_ref::<String>() {
+    Some(message) => message.clone(),
+    None => cause.downcast_ref::<&'static str>().map(|message| message.to_string())
+                 .unwrap_or("unknown failure".to_string()),
+  }
+}
+
+/// Fetch stage: read the data-chunks of each file and pass them on, in order, to the write stage.
+/// Several fetchers run in parallel. Reading ahead of the writer keeps the blob cache warm.
+fn fetch_files<B: BlobStoreBackend + Clone + Send>(
+  jobs: sync::Arc<sync::Mutex<Receiver<FetchJob<B>>>>, out: SyncSender<WriteMsg>)
+{
+  loop {
+    let job = match jobs.lock().recv_opt() {
+      Ok(job) => job,
+      Err(()) => return,  // All files have been handed out.
+    };
+    let FetchJob{file_no, path, data, times} = job;
+
+    out.send(OpenFile(file_no, path));
+    match data {
+      hash_tree::NoData => fail!("Trying to read data where none exist."),
+      hash_tree::SingleBlock(chunk) => out.send(WriteChunk(file_no, chunk)),
+      hash_tree::Tree(it) => {
+        let mut it = it;
+        for chunk in it {
+          out.send(WriteChunk(file_no, chunk));
+        }
+      },
+    }
+    out.send(CloseFile(file_no, times));
+  }
+}
+
+fn put_chunk(fd: &mut nofollow::NewFile, path: &Path, offset: u64, chunk: Vec<u8>,
+             reflinks: &mut Option<ReflinkTable>)
+{
+  let len = chunk.len() as u64;
+  match *reflinks {
+    Some(ref mut table) if len > 0 => {
+      let hash = Hash::new(chunk.as_slice()).bytes;
+      match table.try_clone(&hash, path, offset) {
+        Some((start, end)) => {
+          // Write what was not cloned around the cloned blocks:
+          let (start, end) = (start as uint, end as uint);
+          try_a_few_times_then_fail(|| fd.write(chunk.slice_to(start)).is_ok(),
+                                    "Could not write chunk.");
+          try_a_few_times_then_fail(|| fd.seek(offset as i64 + end as i64, SeekSet).is_ok(),
+                                    "Could not seek file.");
+          try_a_few_times_then_fail(|| fd.write(chunk.slice_from(end)).is_ok(),
+                                    "Could not write chunk.");
+          return;
+        },
+        None => table.remember(hash, path.clone(), offset, len),
+      }
+    },
+    _ => (),
+  }
+  try_a_few_times_then_fail(|| fd.write(chunk.as_slice()).is_ok(), "Could not write chunk.");
+}
+
+/// Write stage: write data-chunks to their files below `root`. Returns the number of files
+/// completed, or why a file could not be created.
+///
+/// Files and their directories are created without following symbolic links (see `nofollow`),
+/// so that a link put in their place after they were listed cannot redirect the writes.
+fn write_files(root: Path, msgs: Receiver<WriteMsg>, reflinks: Option<ReflinkTable>)
+               -> Result<uint, String> {
+  let mut reflinks = reflinks;
+  let mut open_files: HashMap<uint, (nofollow::NewFile, Path, u64)> = HashMap::new();
+  let mut completed = 0u;
+
+  for msg in msgs.iter() {
+    match msg {
+      OpenFile(file_no, path) => {
+        let fd = match nofollow::create_file(&root, &path) {
+          Ok(fd) => fd,
+          Err(e) => return Err(format!("Could not create '{}': {}", path.display(), e)),
+        };
+        open_files.insert(file_no, (fd, path, 0));
+      },
+      WriteChunk(file_no, chunk) => {
+        let entry = open_files.find_mut(&file_no).expect("file is open");
+        let (ref mut fd, ref path, ref mut offset) = *entry;
+        let len = chunk.len() as u64;
+        put_chunk(fd, path, *offset, chunk, &mut reflinks);
+        *offset += len;
+      },
+      CloseFile(file_no, (accessed, modified)) => {
+        let (mut fd, path, _) = open_files.pop(&file_no).expect("file is open");
+        try_a_few_times_then_fail(|| fd.flush().is_ok(), "Could not flush file.");
+        // Entries from archives may have no recorded times:
+        if modified > 0 {
+          let accessed = if accessed > 0 { accessed } else { modified };
+          fd.set_times(accessed, modified).unwrap_or_else(|e| {
+            warning!("Could not set the times of '{}': {}", path.display(), e);
+          });
+        }
+        drop(fd);
+        completed += 1;
+      },
+    }
+  }
+
+  Ok(completed)
+}
+
+
+/// A difference between the snapshots of two families.
+pub enum SnapshotChange {
+  /// The path only exists in the second snapshot.
+  Added(Vec<u8>),
+
+  /// The path only exists in the first snapshot.
+  Removed(Vec<u8>),
+
+  /// The file's data differs. If content diffs were requested, a description of how.
+  Modified(Vec<u8>, Option<String>),
+}
+
+/// Whether `name` is safe to restore below a directory: a single path component that cannot
+/// refer to the directory itself, its parent, or a location outside it.
+fn is_safe_name(name: &[u8]) -> bool {
+  name.len() > 0 && name != b"." && name != b".." && !name.contains(&('/' as u8)) &&
+    !name.contains(&0)
+}
+
+fn join_path(prefix: &[u8], name: &[u8]) -> Vec<u8> {
+  let mut path = prefix.into_vec();
+  if path.len() > 0 { path.push('/' as u8) }
+  path.push_all(name);
+  path
+}
+
+fn read_all<B: hash_tree::HashTreeBackend + Clone>(data: hash_tree::ReaderResult<B>) -> Vec<u8> {
+  match data {
+    hash_tree::NoData => Vec::new(),
+    hash_tree::SingleBlock(chunk) => chunk,
+    hash_tree::Tree(it) => {
+      let mut it = it;
+      let mut all = Vec::new();
+      for chunk in it {
+        all.push_all(chunk.as_slice());
+      }
+      all
+    },
+  }
+}
+
+/// Files are spooled to a temporary file beyond this size while they are added to a tar archive
+/// (see `TarSpool`).
+static TAR_MEMORY_LIMIT: uint = 16 * 1024 * 1024;
+
+/// The data of a file to be added to a tar archive, whose header needs the size of the data up
+/// front. The data is kept in memory, or in a temporary file in `dir` once it grows large.
+struct TarSpool {
+  dir: Path,
+  chunks: Vec<Vec<u8>>,
+  size: u64,
+  file: Option<File>,
+}
+
+impl TarSpool {
+  fn new(dir: Path) -> TarSpool {
+    TarSpool{dir: dir, chunks: Vec::new(), size: 0, file: None}
+  }
+
+  fn push(&mut self, chunk: Vec<u8>) -> IoResult<()> {
+    self.size += chunk.len() as u64;
+    if self.file.is_none() && self.size > TAR_MEMORY_LIMIT as u64 {
+      let mut file = try!(File::open_mode(&self.dir.join("data"), Truncate, ReadWrite));
+      for spooled in self.chunks.iter() {
+        try!(file.write(spooled.as_slice()));
+      }
+      self.chunks.clear();
+      self.file = Some(file);
+    }
+    match self.file {
+      Some(ref mut file) => file.write(chunk.as_slice()),
+      None => {
+        self.chunks.push(chunk);
+        Ok(())
+      },
+    }
+  }
+
+  /// Add the data to `tar` as the file at `path`.
+  fn add_to<W: Writer>(self, tar: &mut tar::TarWriter<W>, path: &[u8], modified: u64)
+                       -> IoResult<()> {
+    try!(tar.add_file(path, self.size, 0o644, modified));
+    match self.file {
+      None => {
+        for chunk in self.chunks.iter() {
+          try!(tar.write(chunk.as_slice()));
+        }
+      },
+      Some(file) => {
+        let mut file = file;
+        try!(file.seek(0, SeekSet));
+        let mut block = Vec::from_elem(STREAM_BLOCK_SIZE, 0u8);
+        loop {
+          match file.read(block.as_mut_slice()) {
+            Ok(n) => try!(tar.write(block.slice_to(n))),
+            Err(ref e) if e.kind == EndOfFile => break,
+            Err(e) => return Err(e),
+          }
+        }
+      },
+    }
+    Ok(())
+  }
+}
+
+
+pub struct Family<B> {
+  name: String,
+  chunker: ChunkerOptions,
+  buffer_pool: BufferPool,
+  defaults: FamilyDefaults,
+  key_store: KeyStoreProcess<FileEntry, FileIterator, B>,
+  failures: sync::Arc<sync::Mutex<Vec<FileFailure>>>,
+  cancel: CancelToken,
+}
+
+impl <B: BlobStoreBackend + Clone + Send> Family<B> {
+
+  /// How the family is snapshotted by default (see `FamilySettings::defaults`).
+  pub fn defaults(&self) -> &FamilyDefaults {
+    &self.defaults
+  }
+
+  /// Take a new snapshot of `dir`. Earlier snapshots of the family are kept. If the snapshot is
+  /// cancelled, it stops listing `dir`; the data read so far is still stored by `flush`, so the
+  /// next snapshot need not read it again, but the snapshot itself is not published.
+  pub fn snapshot_dir(&self, dir: Path, options: SnapshotOptions) -> Result<(), Cancelled> {
+    match self.key_store.send_reply(key_store::BeginSnapshot) {
+      key_store::SnapshotId(_) => (),
+      _ => fail!("Unexpected reply from key store."),
+    }
+    let workers = options.workers;
+    let has_reference = options.reference.is_some();
+    let mut handler = InsertPathHandler::new(self.key_store.clone(), dir.clone(), options,
+                                             self.chunker.clone(), self.buffer_pool.clone(),
+                                             self.failures.clone());
+    handler.cancel = self.cancel.clone();
+    listdir::iterate_recursively((Path::new(dir.clone()), None), &mut handler, workers);
+    crash_test::point("snapshot: listed files");
+    if has_reference {
+      info!("{} file(s) unchanged since the reference snapshot were not read.",
+            *handler.reused.lock());
+    }
+    self.cancel.check()
+  }
+
+  /// Count the files and bytes that a snapshot of `dir` with `options` would record, without
+  /// reading any file data. This is quick compared to the snapshot itself, and gives it a total
+  /// to report progress against (see `SnapshotOptions::expected`).
+  pub fn estimate_dir(&self, dir: Path, options: SnapshotOptions) -> SizeEstimate {
+    let since = match self.key_store.send_reply(key_store::ListSnapshots) {
+      key_store::Snapshots(snapshots) => snapshots.last().map(|&(_, started)| started * 1000),
+      _ => fail!("Unexpected reply from key store."),
+    };
+    let xdg_cache_dir = if options.skip_xdg_cache_dir { listdir::xdg_cache_dir() }
+                        else { None };
+    let workers = options.workers;
+    let estimate = sync::Arc::new(sync::Mutex::new(
+      SizeEstimate{entries: 0, bytes: 0, changed_files: 0, changed_bytes: 0}));
+    let mut handler = EstimatePathHandler{estimate: estimate.clone(), root: dir.clone(),
+                                          options: options, xdg_cache_dir: xdg_cache_dir,
+                                          since: since};
+    listdir::iterate_recursively((dir, ()), &mut handler, workers);
+    let result = estimate.lock().clone();
+    result
+  }
+
+  /// Seed the family from `dir`, e.g. a copy of the data restored on a new machine: record the
+  /// files whose data is already stored in the repository, and store nothing. A later snapshot of
+  /// `dir` skips the recorded files while they are unchanged, and only reads the others. The seed
+  /// is a snapshot of its own, published by `flush`.
+  pub fn seed_dir(&self, dir: Path, options: SnapshotOptions) -> SeedCounts {
+    match self.key_store.send_reply(key_store::BeginSnapshot) {
+      key_store::SnapshotId(_) => (),
+      _ => fail!("Unexpected reply from key store."),
+    }
+    let workers = options.workers;
+    let mut seed_options = options;
+    seed_options.seed_only = true;
+    let mut handler = InsertPathHandler::new(self.key_store.clone(), dir.clone(), seed_options,
+                                             self.chunker.clone(), self.buffer_pool.clone(),
+                                             self.failures.clone());
+    listdir::iterate_recursively((Path::new(dir.clone()), None), &mut handler, workers);
+    let counts = handler.seed_counts.lock().clone();
+    counts
+  }
+
+  /// Take a new snapshot of the directories and regular files in a tar archive, as if it was
+  /// unpacked into a directory. File data is streamed from `archive` into the key store, so the
+  /// archive can be read from a pipe. Other entries are skipped, like symlinks by `snapshot_dir`.
+  /// If `started` is given, it is recorded as the snapshot's start time. Returns the number of
+  /// entries inserted; as with `snapshot_dir`, the snapshot is published by `flush`.
+  pub fn snapshot_tar<R: Reader>(&self, archive: R, started: Option<u64>) -> IoResult<uint> {
+    self.snapshot_entries(&mut tar::TarReader::new(archive), started, true)
+  }
+
+  /// Take a new snapshot of the entries of a bundle, as `snapshot_tar` does for a tar archive.
+  /// Bundles do not record file sizes, so the files are stored without one.
+  pub fn snapshot_bundle<R: Reader>(&self, bundle: &mut bundle::BundleReader<R>,
+                                    started: Option<u64>) -> IoResult<uint> {
+    self.snapshot_entries(bundle, started, false)
+  }
+
+  fn snapshot_entries<E: tar::EntryReader>(&self, entries: &mut E, started: Option<u64>,
+                                           sizes_known: bool) -> IoResult<uint> {
+    let begin = match started {
+      Some(started) => key_store::BeginSnapshotAt(started),
+      None => key_store::BeginSnapshot,
+    };
+    match self.key_store.send_reply(begin) {
+      key_store::SnapshotId(_) => (),
+      _ => fail!("Unexpected reply from key store."),
+    }
+
+    let mut dirs = HashMap::new();  // The IDs of the directories inserted so far, by path.
+    let mut count = 0;
+    loop {
+      let header = match try!(entries.next_header()) {
+        Some(header) => header,
+        None => break,
+      };
+      if header.path.len() == 0 || header.kind == tar::Other {
+        continue;
+      }
+      let (dir, name) = match header.path.iter().rposition(|&b| b == b'/') {
+        Some(i) => (header.path.slice_to(i), header.path.slice_from(i + 1)),
+        None => (b"", header.path.as_slice()),
+      };
+      let parent = self.tar_dir_id(dir, header.modified, &mut dirs);
+      let mut entry = FileEntry::from_tar(&header, name.into_vec(), parent);
+      entry.size_known = sizes_known;
+      count += 1;
+
+      if header.kind == tar::Directory {
+        let id = self.insert_entry(entry, None);
+        dirs.insert(header.path.clone(), id);
+        continue;
+      }
+
+      // Data that is left unread is skipped by `next_header`:
+      try!(self.insert_streamed(entry, entries));
+    }
+    Ok(count)
+  }
+
+  /// Add the output of `command` (run with `sh -c`) to the snapshot in progress, as a file at
+  /// `path` relative to the root of the snapshot (e.g. `databases/mail.sql`). Missing parent
+  /// directories are added as for a tar archive, so `path` should be outside the snapshotted
+  /// tree. The output is streamed into the key store and never written to disk. If the command
+  /// fails, the output it produced is kept and the failure is recorded (see `failed_files`).
+  pub fn snapshot_command(&self, path: &str, command: &str) {
+    let path_bytes = path.trim_chars('/').as_bytes().into_vec();
+    let failure_path = Path::new(path_bytes.clone());
+    if path_bytes.len() == 0 {
+      warning!("Skipping '{}': not a valid path for command output", path);
+      return record_failure(&self.failures, &failure_path, "invalid path".to_string());
+    }
+    let (dir, name) = match path_bytes.iter().rposition(|&b| b == b'/') {
+      Some(i) => (path_bytes.slice_to(i), path_bytes.slice_from(i + 1)),
+      None => (b"", path_bytes.as_slice()),
+    };
+
+    let mut process = match Command::new("sh").arg("-c").arg(command)
+                                              .stdin(InheritFd(0)).stderr(InheritFd(2))
+                                              .spawn() {
+      Ok(process) => process,
+      Err(e) => {
+        warning!("Skipping '{}': could not run '{}': {}", path, command, e);
+        return record_failure(&self.failures, &failure_path, e.to_string());
+      },
+    };
+
+    let now = time::get_time().sec as u64;
+    let parent = self.tar_dir_id(dir, now, &mut HashMap::new());
+    let header = tar::Header{path: path_bytes.clone(), kind: tar::RegularFile, size: 0,
+                             mode: 0o644, modified: now};
+    let mut entry = FileEntry::from_tar(&header, name.into_vec(), parent);
+    entry.size_known = false;
+
+    let streamed = {
+      let mut output = process.stdout.take().expect("stdout is piped");
+      self.insert_streamed(entry, &mut output)
+    };
+    let error = match (streamed, process.wait()) {
+      (Err(e), _) => Some(format!("could not read the output of '{}': {}", command, e)),
+      (_, Err(e)) => Some(format!("'{}': {}", command, e)),
+      (_, Ok(ref status)) if !status.success() => Some(format!("'{}' failed ({})", command,
+                                                               status)),
+      _ => None,
+    };
+    error.map(|e| {
+      warning!("Error in '{}': {}; only the output before the error is stored", path, e);
+      record_failure(&self.failures, &failure_path, e);
+    });
+  }
+
+  /// Insert `entry` with the data read from `data`, streaming it into the key store. If the key
+  /// store already knows the entry from an earlier snapshot, it drops the receiving end, and the
+  /// rest of the data is left unread.
+  fn insert_streamed<R: Reader>(&self, entry: FileEntry, data: &mut R) -> IoResult<()> {
+    let (data_tx, data_rx) = sync_channel(STREAM_PIPE_BLOCKS);
+    let chunker = self.chunker.clone();
+    let pool = self.buffer_pool.clone();
+    let path = entry.full_path.clone();
+    let size = entry.stat.size;
+    self.insert_entry(entry, Some(proc() {
+      let reader = ReadAhead::new(ChanReader::new(data_rx), pool.clone());
+      Some(FileIterator::new(Chunker::new(reader, chunker).with_buffer_pool(pool), path, size))
+    }));
+
+    loop {
+      let mut block = Vec::from_elem(STREAM_BLOCK_SIZE, 0u8);
+      match data.read(block.as_mut_slice()) {
+        Ok(n) => {
+          block.truncate(n);
+          if data_tx.send_opt(block).is_err() { break }
+        },
+        Err(ref e) if e.kind == EndOfFile => break,
+        Err(e) => return Err(e),
+      }
+    }
+    Ok(())
+  }
+
+  /// The ID of the directory at `path` in a tar archive being snapshotted, inserting it (and its
+  /// parents) if the archive did not list it before its contents.
+  fn tar_dir_id(&self, path: &[u8], modified: u64, dirs: &mut HashMap<Vec<u8>, Vec<u8>>)
+                -> Option<Vec<u8>> {
+    if path.len() == 0 {
+      return None;
+    }
+    match dirs.find(&path.into_vec()) {
+      Some(id) => return Some(id.clone()),
+      None => (),
+    }
+    let (dir, name) = match path.iter().rposition(|&b| b == b'/') {
+      Some(i) => (path.slice_to(i), path.slice_from(i + 1)),
+      None => (b"", path),
+    };
+    let parent = self.tar_dir_id(dir, modified, dirs);
+    let header = tar::Header{path: path.into_vec(), kind: tar::Directory, size: 0, mode: 0o755,
+                             modified: modified};
+    let id = self.insert_entry(FileEntry::from_tar(&header, name.into_vec(), parent), None);
+    dirs.insert(path.into_vec(), id.clone());
+    Some(id)
+  }
+
+  fn insert_entry(&self, entry: FileEntry,
+                  data: Option<proc():Send -> Option<FileIterator>>) -> Vec<u8> {
+    match self.key_store.send_reply(key_store::Insert(entry, data)) {
+      key_store::Id(id) => id,
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// Make the snapshot in progress durable and then visible, in two phases: first all blobs,
+  /// hashes and entries are flushed, then the snapshot is published in a single transaction. If
+  /// the process stops in between, the snapshot is discarded and the previous one stays latest.
+  /// A cancelled snapshot is only flushed, and discarded in the same way.
+  pub fn flush(&self) {
+    self.key_store.send_reply(key_store::Flush);
+    crash_test::point("snapshot: prepared");
+    if !self.cancel.is_cancelled() {
+      self.key_store.send_reply(key_store::PublishSnapshot);
+    }
+  }
+
+  /// Flush the snapshot as `flush` does, but only publish it if it captured every file of
+  /// `manifest` with the listed size and data (see `SnapshotOptions::manifest`). Returns the
+  /// problems found otherwise; the data stored is kept, as for a cancelled snapshot.
+  pub fn flush_checked(&self, manifest: &SnapshotManifest) -> Result<(), Vec<String>> {
+    self.key_store.send_reply(key_store::Flush);
+    crash_test::point("snapshot: prepared");
+    let mut files = HashMap::new();
+    self.reference_files_rec(b"", None, &mut files);
+    let hashes: HashMap<Vec<u8>, Vec<u8>> =
+      files.into_iter().map(|(path, (_, hash, _))| (path, hash)).collect();
+    let problems = manifest.check(&hashes);
+    if problems.len() > 0 {
+      return Err(problems);
+    }
+    if !self.cancel.is_cancelled() {
+      self.key_store.send_reply(key_store::PublishSnapshot);
+    }
+    Ok(())
+  }
+
+  /// The files that snapshots of this family could not read, in full or at all. Files are read
+  /// in the background, so the list is only complete after `flush`.
+  pub fn failed_files(&self) -> Vec<FileFailure> {
+    self.failures.lock().clone()
+  }
+
+  /// List the snapshots of this family by ID and start time, oldest first.
+  pub fn list_snapshots(&self) -> Vec<(i64, u64)> {
+    match self.key_store.send_reply(key_store::ListSnapshots) {
+      key_store::Snapshots(snapshots) => snapshots,
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// Work on the earlier snapshot `id` (for listing and checkout) instead of the latest one.
+  pub fn select_snapshot(&self, id: i64) {
+    self.key_store.send_reply(key_store::SelectSnapshot(id));
+  }
+
+  /// Add the names of the blobs that hold the data of snapshot `id` to `blobs`. Files whose data
+  /// hash is in `seen` are skipped, and the data hashes of the other files are added to it. This
+  /// selects the snapshot (see `select_snapshot`).
+  pub fn snapshot_blobs(&self, id: i64, blobs: &mut HashSet<Vec<u8>>,
+                        seen: &mut HashSet<Vec<u8>>) {
+    self.select_snapshot(id);
+    self.snapshot_blobs_rec(None, blobs, seen);
+  }
+
+  fn snapshot_blobs_rec(&self, dir_id: Option<Vec<u8>>, blobs: &mut HashSet<Vec<u8>>,
+                        seen: &mut HashSet<Vec<u8>>) {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    for (id, _, _, _, _, hash, persistent_ref, _) in listing.into_iter() {
+      if hash.len() == 0 {
+        self.snapshot_blobs_rec(Some(id), blobs, seen);
+        continue;
+      }
+      if !seen.insert(hash.clone()) { continue }
+      match self.key_store.send_reply(key_store::ListBlobs(hash, persistent_ref)) {
+        key_store::BlobNames(names) => blobs.extend(names.into_iter()),
+        _ => fail!("Unexpected result from key store."),
+      }
+    }
+  }
+
+  /// Make the earlier snapshot `id` the family's latest snapshot again, by publishing a copy of
+  /// it. The snapshots taken since are kept, and can still be selected. Returns the ID of the
+  /// copy.
+  pub fn roll_back_to(&self, id: i64) -> Result<i64, String> {
+    match self.key_store.send_reply(key_store::RollBackTo(id)) {
+      key_store::SnapshotId(copy) => Ok(copy),
+      key_store::NotStored => Err(format!("there is no snapshot {}", id)),
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// How many bytes inserted so far were new versus deduplicated.
+  pub fn byte_counts(&self) -> key_store::ByteCounts {
+    match self.key_store.send_reply(key_store::FetchByteCounts) {
+      key_store::ByteCountsResult(counts) => counts,
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// Run database maintenance on this family's key index.
+  pub fn maintenance(&self) {
+    self.key_store.send_reply(key_store::Maintenance);
+  }
+
+  /// Check this family's key index for local corruption, including that every data hash is known.
+  /// Returns a description of each problem found.
+  pub fn check(&self) -> Vec<String> {
+    match self.key_store.send_reply(key_store::SelfCheck) {
+      key_store::SelfCheckResult(problems) => problems,
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// List how the snapshot of `other` differs from this family's snapshot. With `content`, the
+  /// data of modified files is compared as well.
+  pub fn diff(&self, other: &Family<B>, content: bool) -> Vec<SnapshotChange> {
+    let mut changes = Vec::new();
+    self.diff_rec(other, b"", None, None, content, &mut changes);
+    changes
+  }
+
+  /// The files of this family's snapshot (see `select_snapshot`), to take a snapshot of a copy
+  /// of it with (see `SnapshotOptions::reference`).
+  pub fn reference_files(&self) -> ReferenceFiles {
+    let mut files = HashMap::new();
+    self.reference_files_rec(b"", None, &mut files);
+    ReferenceFiles{files: files}
+  }
+
+  fn reference_files_rec(&self, prefix: &[u8], dir_id: Option<Vec<u8>>,
+                         files: &mut HashMap<Vec<u8>, (u64, Vec<u8>, Vec<u8>)>) {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    for (id, name, _, modified, _, hash, persistent_ref, _) in listing.into_iter() {
+      let path = join_path(prefix, name.as_slice());
+      if hash.len() == 0 {
+        self.reference_files_rec(path.as_slice(), Some(id), files);
+      } else {
+        files.insert(path, (modified, hash, persistent_ref));
+      }
+    }
+  }
+
+  fn list_by_name(&self, dir_id: Option<Vec<u8>>)
+                  -> TreeMap<Vec<u8>, (Vec<u8>, Vec<u8>,
+                                       hash_tree::ReaderResult<HashStoreBackend<B>>)> {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    let mut by_name = TreeMap::new();
+    for (id, name, _, _, _, hash, _, data_res) in listing.into_iter() {
+      by_name.insert(name, (id, hash, data_res));
+    }
+    by_name
+  }
+
+  fn diff_rec(&self, other: &Family<B>, prefix: &[u8],
+              dir_id: Option<Vec<u8>>, other_dir_id: Option<Vec<u8>>,
+              content: bool, changes: &mut Vec<SnapshotChange>) {
+    let mine = self.list_by_name(dir_id);
+    let mut theirs = other.list_by_name(other_dir_id);
+
+    for (name, (id, hash, data)) in mine.into_iter() {
+      let path = join_path(prefix, name.as_slice());
+      match theirs.pop(&name) {
+        None => changes.push(Removed(path)),
+        Some((other_id, other_hash, other_data)) => {
+          let (is_dir, other_is_dir) = (hash.len() == 0, other_hash.len() == 0);
+          if is_dir && other_is_dir {
+            self.diff_rec(other, path.as_slice(), Some(id), Some(other_id), content, changes);
+          } else if is_dir != other_is_dir {
+            changes.push(Removed(path.clone()));
+            changes.push(Added(path));
+          } else if hash != other_hash {
+            let description = if content {
+              let label = String::from_utf8_lossy(path.as_slice()).into_string();
+              Some(diff::diff_contents(format!("a/{}", label).as_slice(),
+                                       format!("b/{}", label).as_slice(),
+                                       read_all(data).as_slice(),
+                                       read_all(other_data).as_slice()))
+            } else { None };
+            changes.push(Modified(path, description));
+          }
+        },
+      }
+    }
+
+    for (name, _) in theirs.into_iter() {
+      changes.push(Added(join_path(prefix, name.as_slice())));
+    }
+  }
+
+  /// Search the lines of every file in this family's snapshot for `pattern`, streaming each file
+  /// chunk by chunk. Only files whose path starts with `path_prefix` are read. Returns the path,
+  /// whether the file looks binary, and the matching lines of each file with a match.
+  pub fn grep(&self, pattern: &Pattern, path_prefix: &[u8])
+              -> Vec<(Vec<u8>, bool, Vec<(uint, String)>)> {
+    let mut found = Vec::new();
+    self.grep_rec(pattern, path_prefix, b"", None, &mut found);
+    found
+  }
+
+  fn grep_rec(&self, pattern: &Pattern, path_prefix: &[u8], prefix: &[u8],
+              dir_id: Option<Vec<u8>>, found: &mut Vec<(Vec<u8>, bool, Vec<(uint, String)>)>) {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    for (id, name, _, _, _, hash, _, data_res) in listing.into_iter() {
+      let path = join_path(prefix, name.as_slice());
+      if hash.len() == 0 {
+        // Only descend into directories that may contain matching paths:
+        if path_prefix.starts_with(path.as_slice()) || path.as_slice().starts_with(path_prefix) {
+          self.grep_rec(pattern, path_prefix, path.as_slice(), Some(id), found);
+        }
+        continue;
+      }
+      if !path.as_slice().starts_with(path_prefix) {
+        continue;
+      }
+
+      let mut search = LineSearch::new(pattern);
+      match data_res {
+        hash_tree::NoData => (),
+        hash_tree::SingleBlock(chunk) => search.feed(chunk.as_slice()),
+        hash_tree::Tree(it) => {
+          let mut it = it;
+          for chunk in it {
+            search.feed(chunk.as_slice());
+          }
+        },
+      }
+      search.finish();
+
+      if search.matches.len() > 0 {
+        found.push((path, search.binary, search.matches));
+      }
+    }
+  }
+
+  /// Write the entries of this family's snapshot (see `select_snapshot`) to `bundle`, and
+  /// return how many there were.
+  pub fn export_bundle<W: Writer>(&self, bundle: &mut bundle::BundleWriter<W>)
+                                  -> IoResult<uint> {
+    let mut count = 0;
+    try!(self.export_bundle_rec(bundle, b"", None, &mut count));
+    Ok(count)
+  }
+
+  fn export_bundle_rec<W: Writer>(&self, bundle: &mut bundle::BundleWriter<W>, prefix: &[u8],
+                                  dir_id: Option<Vec<u8>>, count: &mut uint) -> IoResult<()> {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    for (id, name, _, modified, _, hash, _, data_res) in listing.into_iter() {
+      let path = join_path(prefix, name.as_slice());
+      *count += 1;
+      if hash.len() == 0 {
+        try!(bundle.add_dir(path.as_slice(), modified / 1000));
+        try!(self.export_bundle_rec(bundle, path.as_slice(), Some(id), count));
+        continue;
+      }
+
+      try!(bundle.add_file(path.as_slice(), modified / 1000));
+      match data_res {
+        hash_tree::NoData => (),
+        hash_tree::SingleBlock(chunk) => try!(bundle.add_data(chunk.as_slice())),
+        hash_tree::Tree(it) => {
+          let mut it = it;
+          for chunk in it {
+            try!(bundle.add_data(chunk.as_slice()));
+          }
+        },
+      }
+    }
+    Ok(())
+  }
+
+  /// Write the snapshot to `tar` as a tar archive, e.g. to restore it on a host where hat is not
+  /// installed. Modes are not recorded in snapshots, so files get mode 0644 and directories 0755.
+  /// Returns the number of entries written.
+  pub fn export_tar<W: Writer>(&self, tar: &mut tar::TarWriter<W>) -> IoResult<uint> {
+    let spool_dir = try!(TempDir::new("hat-tar"));
+    let mut count = 0;
+    try!(self.export_tar_rec(tar, spool_dir.path(), b"", None, &mut count));
+    Ok(count)
+  }
+
+  fn export_tar_rec<W: Writer>(&self, tar: &mut tar::TarWriter<W>, spool_dir: &Path,
+                               prefix: &[u8], dir_id: Option<Vec<u8>>, count: &mut uint)
+                               -> IoResult<()> {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    for (id, name, _, modified, _, hash, _, data_res) in listing.into_iter() {
+      let path = join_path(prefix, name.as_slice());
+      *count += 1;
+      if hash.len() == 0 {
+        try!(tar.add_dir(path.as_slice(), 0o755, modified / 1000));
+        try!(self.export_tar_rec(tar, spool_dir, path.as_slice(), Some(id), count));
+        continue;
+      }
+
+      let mut spool = TarSpool::new(spool_dir.clone());
+      match data_res {
+        hash_tree::NoData => (),
+        hash_tree::SingleBlock(chunk) => try!(spool.push(chunk)),
+        hash_tree::Tree(it) => {
+          let mut it = it;
+          for chunk in it {
+            try!(spool.push(chunk));
+          }
+        },
+      }
+      try!(spool.add_to(tar, path.as_slice(), modified / 1000));
+    }
+    Ok(())
+  }
+
+  /// The content fingerprint of this family's snapshot (see `fingerprint`).
+  pub fn fingerprint(&self) -> Hash {
+    self.fingerprint_rec(None)
+  }
+
+  fn fingerprint_rec(&self, dir_id: Option<Vec<u8>>) -> Hash {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    let mut manifest = Manifest::new();
+    for (id, name, _, _, _, hash, _, _) in listing.into_iter() {
+      if hash.len() == 0 {
+        manifest.add_dir(name, &self.fingerprint_rec(Some(id)));
+      } else {
+        manifest.add_file(name, hash.as_slice());
+      }
+    }
+    manifest.fingerprint()
+  }
+
+  /// Restore the directory `dir_id` (or the root) into `output_dir`. Files get their recorded
+  /// modification times, so that a later snapshot of the copy can tell which of them were
+  /// changed (see `SnapshotOptions::reference`).
+  ///
+  /// The checkout runs as a pipeline: this task lists directories and creates them, a pool of
+  /// fetchers reads the data-chunks of files in parallel, and a single writer writes them to disk.
+  /// If the checkout is cancelled, no more files are queued, and the files already queued are
+  /// restored in full before it returns. If a fetcher or the writer fails, the checkout fails
+  /// with its cause once the other tasks of the pipeline have stopped.
+  pub fn checkout_in_dir(&self, output_dir: &mut Path, dir_id: Option<Vec<u8>>,
+                         options: &CheckoutOptions) -> Result<(), Cancelled> {
+    let workers = cmp::max(1, options.fetch_workers);
+    let reflinks = if options.reflink { Some(ReflinkTable::new()) } else { None };
+
+    // The bounded channels keep the fetchers from reading too far ahead of the writer. The writer
+    // reports the number of files it completed on the done channel, and any task of the pipeline
+    // that fails reports why.
+    let (write_tx, write_rx) = sync_channel(workers * 16);
+    let (done_tx, done_rx) = channel();
+    let writer_done = done_tx.clone();
+    let root = output_dir.clone();
+    spawn(proc() {
+      match task::try(proc() { write_files(root, write_rx, reflinks) }) {
+        Ok(result) => writer_done.send(result),
+        Err(cause) => writer_done.send(Err(failure_message(&*cause))),
+      }
+    });
+
+    let (job_tx, job_rx) = sync_channel(workers);
+    let job_rx = sync::Arc::new(sync::Mutex::new(job_rx));
+    for _ in range(0, workers) {
+      let jobs = job_rx.clone();
+      let out = write_tx.clone();
+      let fetcher_done = done_tx.clone();
+      spawn(proc() {
+        match task::try(proc() { fetch_files(jobs, out) }) {
+          Ok(()) => (),
+          Err(cause) => fetcher_done.send(Err(failure_message(&*cause))),
+        }
+      });
+    }
+    drop(write_tx);
+    drop(done_tx);
+
+    let mut file_count = 0u;
+    let mut deferred = Vec::new();
+    let mut reads = Vec::new();
+    // The checkout directory itself is given by the user, and may be reached through links:
+    match mkdir_recursive(&*output_dir, UserDir) {
+      Ok(()) => (),
+      Err(e) => fail!("Could not create '{}': {}", output_dir.display(), e),
+    }
+    let root = options.destination(&*output_dir, []);
+    match root {
+      Some(ref path) => match nofollow::create_dir_all(&*output_dir, path) {
+        Ok(()) => (),
+        Err(e) => fail!("Could not create '{}': {}", path.display(), e),
+      },
+      None => (),
+    }
+    self.list_files_rec(&*output_dir, dir_id, &mut Vec::new(), root, options, &job_tx,
+                        &mut deferred, &mut reads, &mut file_count);
+
+    // Fetch each blob once for all the files that read from it:
+    if options.order == BlobOrder {
+      self.plan_reads(reads);
+    }
+
+    // The sort is stable, so files with equal keys stay in path order:
+    deferred.sort_by(|&(ref a, _), &(ref b, _)| a.cmp(b));
+    for (_, job) in deferred.move_iter() {
+      if self.cancel.is_cancelled() { break }
+      let mut job = job;
+      job.file_no = file_count;
+      // The fetchers have all failed if they are gone; that is reported below.
+      if job_tx.send_opt(job).is_err() { break }
+      file_count += 1;
+    }
+    drop(job_tx);
+
+    // The done channel closes once the writer and all fetchers have stopped.
+    let mut completed = 0u;
+    let mut failures = Vec::new();
+    for done in done_rx.iter() {
+      match done {
+        Ok(count) => completed = count,
+        Err(failure) => failures.push(failure),
+      }
+    }
+    if options.order == BlobOrder {
+      self.plan_reads(Vec::new());
+    }
+    if failures.len() > 0 {
+      fail!("Checkout failed: {}", failures.connect("; "));
+    }
+    if completed != file_count {
+      fail!("Checkout incomplete: restored {} of {} files.", completed, file_count);
+    }
+    self.cancel.check()
+  }
+
+  fn plan_reads(&self, reads: Vec<Vec<u8>>) {
+    match self.key_store.send_reply(key_store::PlanReads(reads)) {
+      key_store::PlanOK => (),
+      _ => fail!("Unexpected reply from key store."),
+    }
+  }
+
+  /// Listing stage: create the directories below `dir_id` and queue their files for fetching.
+  /// Unless the restore order is `PathOrder`, files are instead added to `deferred` with their
+  /// sort key; in `BlobOrder`, the blob of each chunk read is added to `reads`. `source` holds
+  /// the components of the directory's path in the snapshot, and `dir_dest` where the directory
+  /// itself was restored (if it was).
+  fn list_files_rec(&self, output_dir: &Path, dir_id: Option<Vec<u8>>,
+                    source: &mut Vec<Vec<u8>>, dir_dest: Option<Path>, options: &CheckoutOptions,
+                    jobs: &SyncSender<FetchJob<B>>,
+                    deferred: &mut Vec<((u64, Vec<u8>), FetchJob<B>)>,
+                    reads: &mut Vec<Vec<u8>>, file_count: &mut uint) {
+    let listing = match self.key_store.send_reply(key_store::ListDir(dir_id)) {
+      key_store::ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+
+    for (id, name, _, modified, accessed, hash, persistent_ref, data_res) in listing.move_iter() {
+      if self.cancel.is_cancelled() {
+        return;
+      }
+      if !is_safe_name(name.as_slice()) {
+        warning!("Skipping unsafe name {} in {}", String::from_utf8_lossy(name.as_slice()),
+                 dir_dest.as_ref().unwrap_or(output_dir).display());
+        continue;
+      }
+
+      source.push(name);
+      let dest = options.destination(output_dir, source.as_slice());
+
+      // Never write through a symbolic link already present in the output directory; it could
+      // point anywhere (checkout itself does not create symbolic links):
+      let blocked = dest.as_ref().map(|path| {
+        lstat(path).map(|st| st.kind == TypeSymlink).unwrap_or(false)
+      }).unwrap_or(false);
+      if blocked {
+        warning!("Skipping {}: a symbolic link is in the way", dest.unwrap().display());
+        source.pop();
+        continue;
+      }
+
+      if hash.len() == 0 {
+        // This is a directory, recurse!
+        if options.restores_below(source.as_slice()) {
+          let created = dest.as_ref().map(|path| {
+            nofollow::create_dir_all(output_dir, path).map_err(|e| {
+              warning!("Skipping {}: {}", path.display(), e);
+            })
+          }).unwrap_or(Ok(()));
+          if created.is_err() {
+            source.pop();
+            continue;
+          }
+          self.list_files_rec(output_dir, Some(id), source, dest, options, jobs, deferred,
+                              reads, file_count);
+        }
+      } else if dest.is_some() {
+        // This is a file, queue it. The writer creates its directory if that was not restored
+        // itself, because it was stripped away or the file is mapped (see `write_files`):
+        let path = dest.unwrap();
+        let mut job = FetchJob{file_no: *file_count, path: path, data: data_res,
+                               times: (accessed, modified)};
+        match options.order {
+          PathOrder => {
+            // The fetchers have all failed if they are gone (see `checkout_in_dir`).
+            if jobs.send_opt(job).is_ok() {
+              *file_count += 1;
+            }
+          },
+          SmallestFirst => {
+            let size = match job.data {
+              hash_tree::NoData => 0,
+              hash_tree::SingleBlock(ref data) => data.len() as u64,
+              hash_tree::Tree(ref mut reader) => reader.estimated_size(),
+            };
+            deferred.push(((size, Vec::new()), job));
+          },
+          BlobOrder => {
+            let blob_name = BlobID::blob_name_of(persistent_ref.as_slice()).ok()
+              .and_then(|name| name).unwrap_or(Vec::new());
+            match job.data {
+              hash_tree::Tree(_) => {
+                match self.key_store.send_reply(key_store::ListBlobs(hash, persistent_ref)) {
+                  key_store::BlobNames(names) => reads.push_all(names.as_slice()),
+                  _ => fail!("Unexpected reply from key store."),
+                }
+              },
+              _ => (),
+            }
+            deferred.push(((0, blob_name), job));
+          },
+        }
+      }
+
+      source.pop();
+    }
+
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{InUseMarker, IN_USE_MARKER, ReadProgress, PROGRESS_FILE_SIZE, PROGRESS_INTERVAL,
+              failure_message, is_safe_name, write_files, OpenFile, WriteChunk, CloseFile};
+
+  use blob_store::{BlobStoreBackend, MemoryBackend};
+  use chunker::{ChunkerOptions};
+  use fingerprint;
+  use keyring::{Keyring};
+  use keys::{BlobCipher, RepositoryKey};
+
+  use std::io::{File, TempDir, UserDir};
+  use std::io;
+  use std::io::fs::{chmod, mkdir, symlink};
+  use std::io::timer;
+  use std::task;
+  use std::time::duration::{Duration};
+  use time;
+
+  fn open_repository(dir: &TempDir) -> Hat<MemoryBackend> {
+    Hat::open_repository(dir.path(), MemoryBackend::new(), 1024 * 1024, None, None, None)
+      .unwrap()
+  }
+
+  /// A family with a snapshot of the files `a` and `sub/b`.
+  fn snapshot_family(hat: &Hat<MemoryBackend>) -> Family<MemoryBackend> {
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(b"file a").unwrap();
+    mkdir(&data_dir.path().join("sub"), UserDir).unwrap();
+    File::create(&data_dir.path().join("sub").join("b")).write(b"file b").unwrap();
+
+    let family = hat.open_family("documents".to_string()).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+    family
+  }
+
+  fn read(path: &Path) -> Vec<u8> {
+    File::open(path).read_to_end().unwrap()
+  }
+
+  #[test]
+  fn families_skip_the_keyring() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    hat.open_family("documents".to_string()).unwrap();
+
+    let path = keyring_path(dir.path());
+    let mut keyring = Keyring::new();
+    keyring.add("alice", b"passphrase", b"secret").unwrap();
+    keyring.save(&path).unwrap();
+    File::create(&path.with_extension("tmp")).unwrap();
+
+    assert_eq!(hat.list_families(), vec!["documents".to_string()]);
+  }
+
+  #[test]
+  fn verify_data_of_a_multi_level_tree() {
+    // 100 different chunks of 64 bytes: the hash tree over them (of order 8) has 13 nodes on the
+    // first level, 2 on the second and the root on the third.
+    let data: Vec<u8> = range(0u, 6400).map(|i| ((i / 64) ^ (i % 64)) as u8).collect();
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("file")).write(data.as_slice()).unwrap();
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let mut settings = FamilySettings::new();
+    settings.chunk_size = Some(64);
+    let family = hat.open_family_with_settings("documents".to_string(), settings).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+
+    let report = hat.verify_data(PercentOfBlobs(100));
+    assert_eq!(report.problems, Vec::<String>::new());
+    assert!(report.chunks >= 100 + 13 + 2 + 1);
+  }
+
+  #[test]
+  fn check_a_consistent_repository() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+    assert_eq!(hat.check(), Vec::<String>::new());
+    assert_eq!(family.check(), Vec::<String>::new());
+  }
+
+  #[test]
+  fn verify_blobs_against_backend_checksums() {
+    let data: Vec<u8> = range(0u, 20000).map(|i| (i % 251) as u8).collect();
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(data.as_slice()).unwrap();
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let mut backend = MemoryBackend::new();
+    let hat = Hat::open_repository(dir.path(), backend.clone(), 1024 * 1024, None, None, None)
+      .unwrap();
+    let family = hat.open_family("documents".to_string()).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+    assert_eq!(hat.verify_blobs(), Ok((vec![], 0)));
+
+    let names = backend.list().unwrap();
+    backend.delete(names[0].as_slice()).unwrap();
+    backend.store(names[0].as_slice(), b"damaged").unwrap();
+    let (problems, unverified) = hat.verify_blobs().unwrap();
+    assert_eq!(unverified, 0);
+    assert_eq!(problems.len(), 1);
+    assert!(problems[0].as_slice().contains("backend has checksum"), "{}", problems[0]);
+  }
+
+  #[test]
+  fn encrypted_blobs_need_their_key() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let cipher = BlobCipher::new(&RepositoryKey::new(b"blob key"));
+    {
+      let mut hat = open_repository(&dir);
+      hat.set_blob_cipher(&cipher).unwrap();
+      assert_eq!(hat.check_keys(), Ok(()));
+    }
+
+    let mut hat = open_repository(&dir);
+    assert!(hat.check_keys().is_err());
+
+    hat.set_blob_cipher(&cipher).unwrap();
+    assert_eq!(hat.check_keys(), Ok(()));
+    // The backend cannot check sealed blobs against the recorded checksums.
+    assert!(hat.verify_blobs().is_err());
+  }
+
+  #[test]
+  fn unsafe_names() {
+    assert!(is_safe_name(b"file"));
+    assert!(is_safe_name(b"..file"));
+    assert!(!is_safe_name(b""));
+    assert!(!is_safe_name(b"."));
+    assert!(!is_safe_name(b".."));
+    assert!(!is_safe_name(b"a/b"));
+    assert!(!is_safe_name(b"/etc"));
+    assert!(!is_safe_name(b"a\0b"));
+  }
+
+  #[test]
+  fn checkout_skips_symbolic_links() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+
+    // Links in the checkout directory to a file and a directory outside it:
+    let outside = TempDir::new("hat-outside").unwrap();
+    File::create(&outside.path().join("a")).write(b"outside").unwrap();
+    let out = TempDir::new("hat-checkout").unwrap();
+    symlink(&outside.path().join("a"), &out.path().join("a")).unwrap();
+    symlink(outside.path(), &out.path().join("sub")).unwrap();
+
+    family.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert_eq!(read(&outside.path().join("a")), b"outside".into_vec());
+    assert!(!outside.path().join("b").exists());
+
+    // Links put in place after the files were listed, before the writer gets to them:
+    let late = TempDir::new("hat-checkout").unwrap();
+    let (tx, rx) = channel();
+    tx.send(OpenFile(0, late.path().join("a")));
+    symlink(&outside.path().join("a"), &late.path().join("a")).unwrap();
+    tx.send(WriteChunk(0, b"file a".into_vec()));
+    tx.send(CloseFile(0, (0, 0)));
+    tx.send(OpenFile(1, late.path().join("sub").join("b")));
+    symlink(outside.path(), &late.path().join("sub")).unwrap();
+    drop(tx);
+    assert!(write_files(late.path().clone(), rx, None).is_err());
+    assert_eq!(read(&outside.path().join("a")), b"outside".into_vec());
+    assert_eq!(read(&late.path().join("a")), b"file a".into_vec());
+    assert!(!outside.path().join("b").exists());
+  }
+
+  #[test]
+  fn checkout_destinations() {
+    let root = Path::new("/restore");
+    let source = |path: &str| -> Vec<Vec<u8>> {
+      path.split('/').map(|c| c.as_bytes().into_vec()).collect()
+    };
+
+    let mut options = CheckoutOptions::new();
+    options.strip_components = 1;
+    assert_eq!(options.destination(&root, []), None);
+    assert_eq!(options.destination(&root, source("home").as_slice()), None);
+    assert_eq!(options.destination(&root, source("home/alice/notes").as_slice()),
+               Some(Path::new("/restore/alice/notes")));
+
+    let mut options = CheckoutOptions::new();
+    options.map_path("home/", Path::new("users"));
+    options.map_path("./home/alice", Path::new("alice"));
+    assert_eq!(options.destination(&root, source("etc/passwd").as_slice()), None);
+    assert_eq!(options.destination(&root, source("home/bob/notes").as_slice()),
+               Some(Path::new("/restore/users/bob/notes")));
+    assert_eq!(options.destination(&root, source("home/alice/notes").as_slice()),
+               Some(Path::new("/restore/alice/notes")));
+  }
+
+  #[test]
+  fn checkout_with_stripped_and_mapped_paths() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    let mut options = CheckoutOptions::new();
+    options.strip_components = 1;
+    family.checkout_in_dir(&mut out.path().clone(), None, &options).unwrap();
+    assert_eq!(read(&out.path().join("b")), b"file b".into_vec());
+    assert!(!out.path().join("a").exists());
+    assert!(!out.path().join("sub").exists());
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    let mut options = CheckoutOptions::new();
+    options.map_path("sub", Path::new("mapped/here"));
+    family.checkout_in_dir(&mut out.path().clone(), None, &options).unwrap();
+    assert_eq!(read(&out.path().join("mapped/here/b")), b"file b".into_vec());
+    assert!(!out.path().join("a").exists());
+    assert!(!out.path().join("sub").exists());
+  }
+
+  #[test]
+  fn checkout_with_reflinks() {
+    // Two files of the same chunks, which are not aligned to filesystem blocks in the second.
+    // Whether the filesystem of the checkout can clone blocks or not, both are restored in full.
+    let chunks: Vec<u8> = range(0u, 20000).map(|i| ((i / 64) ^ (i % 64)) as u8).collect();
+    let mut shifted = b"unaligned".into_vec();
+    shifted.push_all(chunks.as_slice());
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(chunks.as_slice()).unwrap();
+    File::create(&data_dir.path().join("b")).write(shifted.as_slice()).unwrap();
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let mut settings = FamilySettings::new();
+    settings.chunk_size = Some(8192);
+    let family = hat.open_family_with_settings("documents".to_string(), settings).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    let mut options = CheckoutOptions::new();
+    options.reflink = true;
+    options.fetch_workers = 1;
+    family.checkout_in_dir(&mut out.path().clone(), None, &options).unwrap();
+    assert_eq!(read(&out.path().join("a")), chunks);
+    assert_eq!(read(&out.path().join("b")), shifted);
+  }
+
+  #[test]
+  fn checkout_reports_why_fetching_failed() {
+    let failed = task::try(proc() {
+      let data: Vec<u8> = range(0u, 20000).map(|i| (i % 251) as u8).collect();
+      let data_dir = TempDir::new("hat-data").unwrap();
+      File::create(&data_dir.path().join("a")).write(data.as_slice()).unwrap();
+
+      let dir = TempDir::new("hat-repository").unwrap();
+      let mut backend = MemoryBackend::new();
+      let hat = Hat::open_repository(dir.path(), backend.clone(), 1024 * 1024, None, None, None)
+        .unwrap();
+      let family = hat.open_family("documents".to_string()).unwrap();
+      family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+      family.flush();
+
+      // The data of the file is lost:
+      for name in backend.list().unwrap().iter() {
+        backend.delete(name.as_slice()).unwrap();
+      }
+      let out = TempDir::new("hat-checkout").unwrap();
+      family.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    });
+    let message = failure_message(&*failed.unwrap_err());
+    assert!(message.as_slice().starts_with("Checkout failed: "), "{}", message);
+  }
+
+  #[test]
+  fn maintenance_keeps_snapshots() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+
+    // VACUUM needs the open transactions of the indexes to be committed first:
+    hat.maintenance();
+    family.maintenance();
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    family.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert_eq!(read(&out.path().join("sub").join("b")), b"file b".into_vec());
+  }
+
+  #[test]
+  fn fingerprint_of_a_restored_snapshot() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    family.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    let chunker = ChunkerOptions::new(DEFAULT_CHUNK_SIZE);
+    let restored = fingerprint::fingerprint_of_dir(out.path(), &chunker).unwrap();
+    assert_eq!(restored, family.fingerprint());
+  }
+
+  #[test]
+  fn family_settings_are_remembered() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let mut settings = FamilySettings::new();
+    settings.chunk_size = Some(64);
+    {
+      let family = hat.open_family_with_settings("documents".to_string(), settings).unwrap();
+      assert_eq!(family.chunker.chunk_size, 64);
+      // Commits the key index, and with it the settings:
+      family.maintenance();
+    }
+
+    let family = hat.open_family("documents".to_string()).unwrap();
+    assert_eq!(family.chunker.chunk_size, 64);
+    let other = hat.open_family("photos".to_string()).unwrap();
+    assert_eq!(other.chunker.chunk_size, DEFAULT_CHUNK_SIZE);
+  }
+
+  #[test]
+  fn forked_families_share_snapshots() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = snapshot_family(&hat);
+
+    assert!(hat.fork_family("missing".to_string(), "copy".to_string()).is_err());
+    assert!(hat.fork_family("documents".to_string(), "documents".to_string()).is_err());
+    hat.fork_family("documents".to_string(), "copy".to_string()).unwrap();
+    let mut families = hat.list_families();
+    families.sort();
+    assert_eq!(families, vec!["copy".to_string(), "documents".to_string()]);
+
+    let copy = hat.open_family("copy".to_string()).unwrap();
+    assert_eq!(copy.fingerprint(), family.fingerprint());
+    let out = TempDir::new("hat-checkout").unwrap();
+    copy.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert_eq!(read(&out.path().join("sub").join("b")), b"file b".into_vec());
+  }
+
+  #[test]
+  fn scheduled_maintenance() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    assert!(hat.schedule_maintenance("defragment", 1).is_err());
+    hat.schedule_maintenance("check", 7).unwrap();
+    assert_eq!(hat.maintenance_schedule(), vec![("maintenance", 0, None), ("check", 7, None)]);
+
+    // Only scheduled tasks run, and then not again until their interval has passed:
+    assert_eq!(hat.run_due_maintenance(), (vec!["check"], vec![]));
+    assert_eq!(hat.run_due_maintenance(), (vec![], vec![]));
+    let (task, _, last_run) = hat.maintenance_schedule()[1];
+    assert_eq!(task, "check");
+    assert!(last_run.is_some());
+  }
+
+  #[test]
+  fn checkout_in_every_order() {
+    // Files of several sizes, spread over blobs of 4 KiB, the largest of many data-chunks:
+    let data_dir = TempDir::new("hat-data").unwrap();
+    let sizes = [10000u, 100, 3000, 1, 700];
+    for (i, &size) in sizes.iter().enumerate() {
+      let data: Vec<u8> = range(0, size).map(|j| ((j / 64) ^ i) as u8).collect();
+      File::create(&data_dir.path().join(format!("f{}", i))).write(data.as_slice()).unwrap();
+    }
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = Hat::open_repository(dir.path(), MemoryBackend::new(), 4096, None, None, None)
+      .unwrap();
+    let mut settings = FamilySettings::new();
+    settings.chunk_size = Some(512);
+    let family = hat.open_family_with_settings("documents".to_string(), settings).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+
+    for name in ["path", "smallest", "blob"].iter() {
+      let out = TempDir::new("hat-checkout").unwrap();
+      let mut options = CheckoutOptions::new();
+      options.order = RestoreOrder::from_name(*name).unwrap();
+      family.checkout_in_dir(&mut out.path().clone(), None, &options).unwrap();
+      for i in range(0, sizes.len()) {
+        let name = format!("f{}", i);
+        assert_eq!(read(&out.path().join(name.as_slice())),
+                   read(&data_dir.path().join(name.as_slice())));
+      }
+    }
+    assert_eq!(RestoreOrder::from_name("random"), None);
+  }
+
+  #[test]
+  fn progress_of_large_files() {
+    let at = |sec: i64| time::Timespec::new(sec, 0);
+    let mut large = ReadProgress::new(Path::new("/large"), PROGRESS_FILE_SIZE * 2, at(0));
+    assert_eq!(large.read(PROGRESS_FILE_SIZE, at(1)), None);
+    assert_eq!(large.read(0, at(PROGRESS_INTERVAL)),
+               Some(format!("'/large': read {} of {} bytes (50%)", PROGRESS_FILE_SIZE,
+                            PROGRESS_FILE_SIZE * 2)));
+    // Not again until another interval has passed:
+    assert_eq!(large.read(1, at(PROGRESS_INTERVAL + 1)), None);
+
+    let mut small = ReadProgress::new(Path::new("/small"), PROGRESS_FILE_SIZE - 1, at(0));
+    assert_eq!(small.read(PROGRESS_FILE_SIZE - 1, at(PROGRESS_INTERVAL)), None);
+  }
+
+  #[test]
+  fn unreadable_files_are_reported() {
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("readable")).write(b"data").unwrap();
+    let unreadable = data_dir.path().join("unreadable");
+    File::create(&unreadable).write(b"secret").unwrap();
+    chmod(&unreadable, io::UserWrite).unwrap();
+    if File::open(&unreadable).is_ok() {
+      return;  // Running with privileges that read any file.
+    }
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = hat.open_family("documents".to_string()).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+
+    let failed: Vec<String> = family.failed_files().into_iter().map(|f| f.path).collect();
+    assert_eq!(failed, vec![unreadable.display().to_string()]);
+  }
+
+  #[test]
+  fn repositories_are_skipped() {
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(b"file a").unwrap();
+    let repository_root = data_dir.path().join("repo");
+    mkdir(&repository_root, UserDir).unwrap();
+    let hat = Hat::open_repository(&repository_root, MemoryBackend::new(), 1024 * 1024,
+                                   None, None, None).unwrap();
+
+    let skipped = hat.open_family("skipped".to_string()).unwrap();
+    skipped.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    skipped.flush();
+    let out = TempDir::new("hat-checkout").unwrap();
+    skipped.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert_eq!(read(&out.path().join("a")), b"file a".into_vec());
+    // The directory itself is still recorded:
+    assert!(out.path().join("repo").is_dir());
+    assert!(!out.path().join("repo").join("blob_index.sqlite3").exists());
+
+    let mut options = SnapshotOptions::new();
+    options.skip_repositories = false;
+    let included = hat.open_family("included".to_string()).unwrap();
+    included.snapshot_dir(data_dir.path().clone(), options).unwrap();
+    included.flush();
+    let out = TempDir::new("hat-checkout").unwrap();
+    included.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert!(out.path().join("repo").join("blob_index.sqlite3").exists());
+  }
+
+  #[test]
+  fn command_output_is_stored() {
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(b"file a").unwrap();
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = hat.open_family("documents".to_string()).unwrap();
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.snapshot_command("/databases/dump.sql", "printf 'dump'");
+    // The output before a failure is kept:
+    family.snapshot_command("partial", "printf 'half'; exit 3");
+    family.flush();
+
+    let failed: Vec<String> = family.failed_files().into_iter().map(|f| f.path).collect();
+    assert_eq!(failed, vec!["partial".to_string()]);
+
+    let out = TempDir::new("hat-checkout").unwrap();
+    family.checkout_in_dir(&mut out.path().clone(), None, &CheckoutOptions::new()).unwrap();
+    assert_eq!(read(&out.path().join("a")), b"file a".into_vec());
+    assert_eq!(read(&out.path().join("databases").join("dump.sql")), b"dump".into_vec());
+    assert_eq!(read(&out.path().join("partial")), b"half".into_vec());
+  }
+
+  #[test]
+  fn estimate_counts_changed_files() {
+    let data_dir = TempDir::new("hat-data").unwrap();
+    File::create(&data_dir.path().join("a")).write(b"file a").unwrap();
+    mkdir(&data_dir.path().join("sub"), UserDir).unwrap();
+    File::create(&data_dir.path().join("sub").join("b")).write(b"file b").unwrap();
+
+    let dir = TempDir::new("hat-repository").unwrap();
+    let hat = open_repository(&dir);
+    let family = hat.open_family("documents".to_string()).unwrap();
+    // Without an earlier snapshot, every file counts as changed:
+    let estimate = family.estimate_dir(data_dir.path().clone(), SnapshotOptions::new());
+    assert_eq!((estimate.entries, estimate.bytes), (3, 12));
+    assert_eq!((estimate.changed_files, estimate.changed_bytes), (2, 12));
+
+    // Start the snapshot in a later second than the files were written:
+    timer::sleep(Duration::milliseconds(1100));
+    family.snapshot_dir(data_dir.path().clone(), SnapshotOptions::new()).unwrap();
+    family.flush();
+    File::create(&data_dir.path().join("c")).write(b"new").unwrap();
+
+    let estimate = family.estimate_dir(data_dir.path().clone(), SnapshotOptions::new());
+    assert_eq!((estimate.entries, estimate.bytes), (4, 15));
+    assert_eq!((estimate.changed_files, estimate.changed_bytes), (1, 3));
+  }
+
+  #[test]
+  fn clean_open() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    {
+      let (_marker, unclean) = InUseMarker::create(dir.path()).unwrap();
+      assert!(!unclean);
+      assert!(dir.path().join(IN_USE_MARKER).exists());
+    }
+    assert!(!dir.path().join(IN_USE_MARKER).exists());
+    let (_marker, unclean) = InUseMarker::create(dir.path()).unwrap();
+    assert!(!unclean);
+  }
+
+  #[test]
+  fn unclean_open() {
+    // A process that died left its marker behind, unlocked:
+    let dir = TempDir::new("hat-repository").unwrap();
+    File::create(&dir.path().join(IN_USE_MARKER)).unwrap();
+    let (_marker, unclean) = InUseMarker::create(dir.path()).unwrap();
+    assert!(unclean);
+  }
+
+  #[test]
+  fn concurrent_open() {
+    let dir = TempDir::new("hat-repository").unwrap();
+    let (_marker, _) = InUseMarker::create(dir.path()).unwrap();
+    assert!(InUseMarker::create(dir.path()).is_err());
+    // The marker of the process that has the repository open stays:
+    assert!(dir.path().join(IN_USE_MARKER).exists());
   }
 }
@@ -13,11 +13,23 @@
 // limitations under the License.
 
 //! Local state for keys in the snapshot in progress (the "index").
+//!
+//! The index keeps the entries of every snapshot of a family. A new snapshot starts out empty;
+//! entries that are unchanged since an earlier snapshot are carried over when they are looked up,
+//! so the listing of each snapshot stays complete, while files that have disappeared are left
+//! behind in the older snapshots.
+//!
+//! A new snapshot stays invisible until it is published, which happens in a single transaction
+//! once everything it refers to is durable. A snapshot that was never published (because the
+//! process stopped before) is discarded when the next snapshot begins.
 
+use std::collections::hashmap::{HashMap};
+use std::collections::treemap::{TreeMap};
 use std::time::duration::{Duration};
 
 use periodic_timer::{PeriodicTimer};
 use sodiumoxide::randombytes::{randombytes};
+use keys::{unlock_database};
 use process::{Process, MsgHandler};
 use sqlite3::database::{Database};
 
@@ -25,6 +37,8 @@ use sqlite3::cursor::{Cursor};
 use sqlite3::types::{SQLITE_ROW, SQLITE_DONE};
 use sqlite3::{open};
 
+use time;
+
 use serialize::hex::{ToHex};
 
 
@@ -58,6 +72,10 @@ pub enum Msg<KeyEntryT> {
   /// Returns either `Id` with the found entry ID or `Notfound`.
   LookupExact(KeyEntryT),
 
+  /// Look up each entry as with `LookupExact`, and insert the ones that are not found.
+  /// Returns `Ids` with the ID of each entry, and whether it was inserted.
+  InsertBatch(Vec<KeyEntryT>),
+
   /// Update the `payload` and `persistent_ref` of an entry.
   /// Returns `UpdateOK`.
   UpdateDataHash(KeyEntryT, Option<Vec<u8>>, Option<Vec<u8>>),
@@ -68,58 +86,247 @@ pub enum Msg<KeyEntryT> {
 
   /// Flush this key index.
   Flush,
+
+  /// Run maintenance on the underlying storage (for sqlite, `VACUUM` and `ANALYZE`).
+  /// Returns `MaintenanceOK`.
+  Maintenance,
+
+  /// Check the underlying storage for corruption.
+  /// Returns `SelfCheckResult` with the problems found (empty if none).
+  SelfCheck,
+
+  /// List the distinct data hashes referenced by entries in the index, along with their
+  /// persistent references.
+  /// Returns `DataHashes`.
+  ListDataHashes,
+
+  /// Look up a family setting (see `StoreSetting`).
+  /// Returns `Setting`.
+  FetchSetting(String),
+
+  /// Record a family setting, like an override of the repository's blob size.
+  /// Returns `UpdateOK`.
+  StoreSetting(String, i64),
+
+  /// Look up a textual family setting (see `StoreTextSetting`).
+  /// Returns `TextSetting`.
+  FetchTextSetting(String),
+
+  /// Record a textual family setting, like the family's snapshot defaults.
+  /// Returns `UpdateOK`.
+  StoreTextSetting(String, String),
+
+  /// Copy all entries and settings from the key index stored at the given path. The data of the
+  /// entries is shared by hash, so no data is copied.
+  /// Returns `UpdateOK`.
+  ImportFrom(String),
+
+  /// Start a new snapshot. Entries are inserted into (and listed from) the new snapshot from now
+  /// on. Until this is sent, the index works on its latest published snapshot.
+  /// Returns `SnapshotId` with the ID of the new snapshot.
+  BeginSnapshot,
+
+  /// Start a new snapshot as with `BeginSnapshot`, recording the given start time (in seconds
+  /// since the epoch) instead of the current time, e.g. for a snapshot imported from elsewhere.
+  /// Returns `SnapshotId` with the ID of the new snapshot.
+  BeginSnapshotAt(u64),
+
+  /// Durably mark the current snapshot as complete, making it the latest snapshot.
+  /// Returns `UpdateOK`.
+  PublishSnapshot,
+
+  /// Work on an earlier snapshot, e.g. to list its entries.
+  /// Returns `UpdateOK`.
+  SelectSnapshot(i64),
+
+  /// List the published snapshots in the index, oldest first.
+  /// Returns `Snapshots` with the ID and start time (in seconds since the epoch) of each.
+  ListSnapshots,
+
+  /// Publish a copy of an earlier snapshot as the latest one, e.g. to supersede snapshots of a
+  /// corrupted tree. The snapshots in between are kept.
+  /// Returns `SnapshotId` with the ID of the copy, or `NotFound` if there is no such snapshot.
+  RollBackTo(i64),
 }
 
 pub enum Reply {
   Id(Vec<u8>),
+  Ids(Vec<(Vec<u8>, bool)>),
   NotFound,
   UpdateOK,
   ListResult(Vec<(Vec<u8>, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>)>),
   FlushOK,
+  MaintenanceOK,
+  SelfCheckResult(Vec<String>),
+  DataHashes(Vec<(Vec<u8>, Vec<u8>)>),
+  Setting(Option<i64>),
+  TextSetting(Option<String>),
+  SnapshotId(i64),
+  Snapshots(Vec<(i64, u64)>),
 }
 
 
-pub struct KeyIndex {
-  path: String,
-  dbh: Database,
-  flush_timer: PeriodicTimer,
+/// Persistence engine behind the key index.
+///
+/// Entries are addressed by their `parent` and `id` within the current snapshot; the root level
+/// has an empty parent. Changes are buffered until `flush()` makes them durable.
+pub trait KeyIndexStorage {
+  /// Insert (or replace) an entry.
+  fn insert(&mut self, id: &[u8], parent: &[u8], name: &[u8], created: u64, accessed: u64);
+
+  /// Find an entry under `parent` with matching timestamps, either by `id` or (if `id` is `None`)
+  /// by `name`. An entry found in an earlier snapshot is carried over into the current one.
+  /// Returns the entry's ID if it exists.
+  fn lookup(&mut self, parent: &[u8], id: Option<&[u8]>, name: &[u8],
+            created: u64, modified: u64, accessed: u64) -> Option<Vec<u8>>;
+
+  /// Set (or clear) the data hash and persistent reference of an entry. If `modified` is given,
+  /// the entry is only updated if it was not modified after that time.
+  fn update_data_hash(&mut self, parent: &[u8], id: &[u8],
+                      data: Option<(Vec<u8>, Vec<u8>)>, modified: Option<u64>);
+
+  /// List all entries under `parent`.
+  fn list_dir(&mut self, parent: &[u8])
+              -> Vec<(Vec<u8>, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>)>;
+
+  /// Durably commit all changes made so far.
+  fn flush(&mut self);
+
+  /// Perform housekeeping, like reclaiming unused space.
+  fn maintenance(&mut self);
+
+  /// Check the stored data for corruption. Returns a description of each problem found.
+  fn integrity_check(&mut self) -> Vec<String>;
+
+  /// List the distinct data hashes referenced by entries, along with their persistent references.
+  fn list_data_hashes(&mut self) -> Vec<(Vec<u8>, Vec<u8>)>;
+
+  /// Look up a named setting of the family.
+  fn setting(&mut self, name: &str) -> Option<i64>;
+
+  /// Record a named setting of the family.
+  fn set_setting(&mut self, name: &str, value: i64);
+
+  /// Look up a named textual setting of the family.
+  fn text_setting(&mut self, name: &str) -> Option<String>;
+
+  /// Record a named textual setting of the family.
+  fn set_text_setting(&mut self, name: &str, value: &str);
+
+  /// Copy all entries, snapshots and settings from another key index of the same kind, stored at
+  /// `path`.
+  fn import_from(&mut self, path: &str);
+
+  /// Start a new, empty snapshot and make it the current one, discarding any snapshot that was
+  /// never published. Returns its ID.
+  fn begin_snapshot(&mut self, started: u64) -> i64;
+
+  /// Atomically commit all changes and mark the current snapshot as published.
+  fn publish_snapshot(&mut self);
+
+  /// Make an existing snapshot the current one.
+  fn select_snapshot(&mut self, snapshot: i64);
+
+  /// List the ID and start time of each published snapshot, oldest first.
+  fn list_snapshots(&mut self) -> Vec<(i64, u64)>;
+
+  /// Publish a new snapshot holding the entries of the published snapshot `snapshot`, and make
+  /// it the current one. Returns its ID, or `None` if there is no such snapshot.
+  fn copy_snapshot(&mut self, snapshot: i64, started: u64) -> Option<i64>;
 }
 
 
-impl KeyIndex {
-  pub fn new(path: String) -> KeyIndex {
-    let mut ki = match open(path.as_slice()) {
-      Ok(dbh) => {
-        KeyIndex{path: path,
-                 dbh: dbh,
-                 flush_timer: PeriodicTimer::new(Duration::seconds(5))}
-      },
+/// The default `KeyIndexStorage`, backed by sqlite.
+pub struct SqliteKeyIndexStorage {
+  dbh: Database,
+  key: Option<Vec<u8>>,
+  snapshot: i64,
+}
+
+impl SqliteKeyIndexStorage {
+  pub fn new(path: String, key: Option<Vec<u8>>) -> SqliteKeyIndexStorage {
+    let mut storage = match open(path.as_slice()) {
+      Ok(dbh) => SqliteKeyIndexStorage{dbh: dbh, key: key.clone(), snapshot: 0},
       Err(err) => fail!(err.to_string()),
     };
-    ki.exec_or_die("CREATE TABLE IF NOT EXISTS
-                  key_index (id     BLOB PRIMARY KEY,
+    match key {
+      Some(ref key) => match unlock_database(&mut storage.dbh, key.as_slice()) {
+        Ok(()) => (),
+        Err(e) => fail!(e),
+      },
+      None => (),
+    }
+    storage.exec_or_die("CREATE TABLE IF NOT EXISTS
+                  snapshots (id        INTEGER PRIMARY KEY,
+                             started   UINT8,
+                             published UINT8)");
+    // Snapshots from before publication was tracked are complete:
+    if !storage.has_column("snapshots", "published") {
+      storage.exec_or_die("ALTER TABLE snapshots ADD COLUMN published UINT8 DEFAULT 1");
+    }
+
+    // Key indexes from before snapshot history hold a single snapshot:
+    let unversioned = storage.has_column("key_index", "id") &&
+                      !storage.has_column("key_index", "snapshot");
+    if unversioned {
+      storage.exec_or_die("ALTER TABLE key_index RENAME TO key_index_unversioned");
+    }
+
+    storage.exec_or_die("CREATE TABLE IF NOT EXISTS
+                  key_index (snapshot INTEGER,
+                             id     BLOB,
                              parent BLOB,
                              name   BLOB,
                              created UINT8,
                              modified UINT8,
                              accessed UINT8,
                              hash BLOB,
-                             persistent_ref BLOB
+                             persistent_ref BLOB,
+                             PRIMARY KEY (snapshot, id)
                             );");
+    storage.exec_or_die("CREATE INDEX IF NOT EXISTS
+                  KeyIndex_ParentSnapshot ON key_index(parent, snapshot)");
+
+    if unversioned {
+      storage.exec_or_die("INSERT INTO key_index SELECT 1, * FROM key_index_unversioned;
+                           DROP TABLE key_index_unversioned;
+                           INSERT INTO snapshots (id, started, published) VALUES (1, 0, 1)");
+    }
+
+    storage.exec_or_die("CREATE TABLE IF NOT EXISTS
+                  family_settings (name  TEXT PRIMARY KEY,
+                                   value INTEGER)");
+    storage.exec_or_die("CREATE TABLE IF NOT EXISTS
+                  family_text_settings (name  TEXT PRIMARY KEY,
+                                        value TEXT)");
 
     if cfg!(test) {
-      ki.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
-                    KeyIndex_UniqueParentName
-                    ON key_index(parent, name)");
+      storage.exec_or_die("CREATE UNIQUE INDEX IF NOT EXISTS
+                    KeyIndex_UniqueSnapshotParentName
+                    ON key_index(snapshot, parent, name)");
     }
 
-    ki.exec_or_die("BEGIN");
-    ki
+    storage.snapshot = storage.latest_published_snapshot();
+    storage.exec_or_die("BEGIN");
+    storage
   }
 
-  #[cfg(test)]
-  pub fn new_for_testing() -> KeyIndex {
-    KeyIndex::new(":memory:".to_string())
+  fn has_column(&mut self, table: &str, column: &str) -> bool {
+    let mut cursor = self.prepare_or_die(format!("PRAGMA table_info({})", table).as_slice());
+    while cursor.step() == SQLITE_ROW {
+      match cursor.get_text(1) {
+        Some(name) if name == column => return true,
+        _ => (),
+      }
+    }
+    false
+  }
+
+  fn latest_published_snapshot(&mut self) -> i64 {
+    let mut cursor = self.prepare_or_die(
+      "SELECT IFNULL(MAX(id), 0) FROM snapshots WHERE published=1");
+    assert!(cursor.step() == SQLITE_ROW);
+    cursor.get_int(0) as i64
   }
 
   fn exec_or_die(&mut self, sql: &str) {
@@ -136,6 +343,458 @@ impl KeyIndex {
       Err(x) => fail!("sqlite error: {} ({:?})", self.dbh.get_errmsg(), x),
     }
   }
+}
+
+impl Drop for SqliteKeyIndexStorage {
+  fn drop(&mut self) {
+    self.exec_or_die("COMMIT");
+  }
+}
+
+impl KeyIndexStorage for SqliteKeyIndexStorage {
+
+  fn insert(&mut self, id: &[u8], parent: &[u8], name: &[u8], created: u64, accessed: u64) {
+    self.exec_or_die(format!(
+      "INSERT OR REPLACE INTO key_index (snapshot, id, parent, name, created, accessed)
+       VALUES ({}, x'{:s}', x'{:s}', x'{:s}', {:u}, {:u})",
+      self.snapshot, id.to_hex(), parent.to_hex(), name.to_hex(), created, accessed).as_slice());
+  }
+
+  fn lookup(&mut self, parent: &[u8], id: Option<&[u8]>, name: &[u8],
+            created: u64, modified: u64, accessed: u64) -> Option<Vec<u8>> {
+    let key_clause = match id {
+      Some(id) => format!("id=x'{:s}'", id.to_hex()),
+      None => format!("name=x'{:s}'", name.to_hex()),
+    };
+    let current = self.snapshot;
+    let found = {
+      let mut cursor = self.prepare_or_die(format!(
+        "SELECT snapshot, id FROM key_index
+          WHERE snapshot<={} AND parent=x'{:s}' AND {}
+          AND created={:u} AND modified={:u} AND accessed={:u}
+          ORDER BY snapshot DESC LIMIT 1",
+        current, parent.to_hex(), key_clause, created, modified, accessed).as_slice());
+      if cursor.step() == SQLITE_ROW {
+        let res = (cursor.get_int(0) as i64, cursor.get_blob(1).expect("id").into_vec());
+        assert!(cursor.step() == SQLITE_DONE);
+        Some(res)
+      } else {
+        None
+      }
+    };
+
+    found.map(|(snapshot, id)| {
+      if snapshot < current {
+        self.exec_or_die(format!(
+          "INSERT OR REPLACE INTO key_index
+           SELECT {}, id, parent, name, created, modified, accessed, hash, persistent_ref
+             FROM key_index WHERE snapshot={} AND id=x'{:s}'",
+          current, snapshot, id.as_slice().to_hex()).as_slice());
+      }
+      id
+    })
+  }
+
+  fn update_data_hash(&mut self, parent: &[u8], id: &[u8],
+                      data: Option<(Vec<u8>, Vec<u8>)>, modified: Option<u64>) {
+    match (data, modified) {
+      (Some((hash, persistent_ref)), Some(modified)) => {
+        self.exec_or_die(format!(
+          "UPDATE key_index SET hash=x'{:s}', persistent_ref=x'{:s}'
+                                            , modified={:u}
+            WHERE snapshot={} AND parent=x'{:s}' AND id=x'{:s}' AND IFNULL(modified,0)<={:u}",
+          hash.as_slice().to_hex(), persistent_ref.as_slice().to_hex(),
+          modified, self.snapshot, parent.to_hex(), id.to_hex(), modified).as_slice());
+      },
+      (Some((hash, persistent_ref)), None) => {
+        self.exec_or_die(format!(
+          "UPDATE key_index SET hash=x'{:s}', persistent_ref=x'{:s}'
+           WHERE snapshot={} AND parent=x'{:s}' AND id=x'{:s}'",
+          hash.as_slice().to_hex(), persistent_ref.as_slice().to_hex(),
+          self.snapshot, parent.to_hex(), id.to_hex()).as_slice());
+      },
+      (None, Some(modified)) => {
+        self.exec_or_die(format!(
+          "UPDATE key_index SET hash=NULL, persistent_ref=NULL
+                                         , modified={:u}
+            WHERE snapshot={} AND parent=x'{:s}' AND id=x'{:s}' AND IFNULL(modified, 0)<={:u}",
+          modified, self.snapshot, parent.to_hex(), id.to_hex(), modified).as_slice());
+      },
+      (None, None) => {
+        self.exec_or_die(format!(
+          "UPDATE key_index SET hash=NULL, persistent_ref=NULL
+           WHERE snapshot={} AND parent=x'{:s}' AND id=x'{:s}'",
+          self.snapshot, parent.to_hex(), id.to_hex()).as_slice());
+      },
+    }
+  }
+
+  fn list_dir(&mut self, parent: &[u8])
+              -> Vec<(Vec<u8>, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>)> {
+    let mut listing = Vec::new();
+
+    let mut cursor = self.prepare_or_die(format!(
+       "SELECT id, name, created, modified, accessed, hash, persistent_ref
+        FROM key_index
+        WHERE snapshot={} AND parent=x'{:s}'", self.snapshot, parent.to_hex()).as_slice());
+
+    // TODO(jos): replace get_int with something that understands uint64
+    while cursor.step() == SQLITE_ROW {
+      let id = cursor.get_blob(0).expect("id").into_vec();
+      let name = cursor.get_blob(1).expect("name").into_vec();
+      let created = cursor.get_int(2);
+      let modified = cursor.get_int(3);
+      let accessed = cursor.get_int(4);
+      let hash = cursor.get_blob(5).unwrap_or([]).into_vec();
+      let persistent_ref = cursor.get_blob(6).unwrap_or([]).into_vec();
+
+      listing.push((id, name,
+                    created as u64, modified as u64, accessed as u64,
+                    hash, persistent_ref));
+    }
+
+    listing
+  }
+
+  fn flush(&mut self) {
+    self.exec_or_die("COMMIT; BEGIN");
+  }
+
+  fn maintenance(&mut self) {
+    // VACUUM cannot run inside a transaction.
+    self.exec_or_die("COMMIT; VACUUM; ANALYZE; BEGIN");
+  }
+
+  fn integrity_check(&mut self) -> Vec<String> {
+    let mut problems = Vec::new();
+    let mut cursor = self.prepare_or_die("PRAGMA integrity_check");
+    while cursor.step() == SQLITE_ROW {
+      match cursor.get_text(0) {
+        Some(msg) if msg != "ok" => problems.push(format!("key_index: {}", msg)),
+        _ => (),
+      }
+    }
+    problems
+  }
+
+  fn list_data_hashes(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut hashes = Vec::new();
+    let mut cursor = self.prepare_or_die(
+      "SELECT DISTINCT hash, persistent_ref FROM key_index
+        WHERE hash IS NOT NULL AND LENGTH(hash) > 0");
+    while cursor.step() == SQLITE_ROW {
+      hashes.push((cursor.get_blob(0).expect("hash").into_vec(),
+                   cursor.get_blob(1).unwrap_or([]).into_vec()));
+    }
+    hashes
+  }
+
+  fn setting(&mut self, name: &str) -> Option<i64> {
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT value FROM family_settings WHERE name='{}'", name.replace("'", "''")).as_slice());
+    if cursor.step() == SQLITE_ROW { Some(cursor.get_int(0) as i64) } else { None }
+  }
+
+  fn set_setting(&mut self, name: &str, value: i64) {
+    self.exec_or_die(format!(
+      "INSERT OR REPLACE INTO family_settings (name, value) VALUES ('{}', {})",
+      name.replace("'", "''"), value).as_slice());
+  }
+
+  fn text_setting(&mut self, name: &str) -> Option<String> {
+    let mut cursor = self.prepare_or_die(format!(
+      "SELECT value FROM family_text_settings WHERE name='{}'",
+      name.replace("'", "''")).as_slice());
+    if cursor.step() == SQLITE_ROW { cursor.get_text(0).map(|value| value.to_string()) }
+    else { None }
+  }
+
+  fn set_text_setting(&mut self, name: &str, value: &str) {
+    self.exec_or_die(format!(
+      "INSERT OR REPLACE INTO family_text_settings (name, value) VALUES ('{}', '{}')",
+      name.replace("'", "''"), value.replace("'", "''")).as_slice());
+  }
+
+  fn import_from(&mut self, path: &str) {
+    // All key indexes of a repository share the same key.
+    let key_clause = match self.key {
+      Some(ref key) => format!(" KEY \"x'{}'\"", key.as_slice().to_hex()),
+      None => "".to_string(),
+    };
+    // ATTACH cannot run inside a transaction.
+    self.exec_or_die(format!("COMMIT; ATTACH DATABASE '{}' AS source{}; BEGIN",
+                             path.replace("'", "''"), key_clause).as_slice());
+    self.exec_or_die("INSERT OR REPLACE INTO key_index SELECT * FROM source.key_index;
+                      INSERT OR IGNORE INTO snapshots SELECT * FROM source.snapshots;
+                      INSERT OR IGNORE INTO family_settings SELECT * FROM source.family_settings;
+                      INSERT OR IGNORE INTO family_text_settings
+                        SELECT * FROM source.family_text_settings");
+    self.exec_or_die("COMMIT; DETACH DATABASE source; BEGIN");
+    self.snapshot = self.latest_published_snapshot();
+  }
+
+  fn begin_snapshot(&mut self, started: u64) -> i64 {
+    self.exec_or_die("DELETE FROM key_index
+                       WHERE snapshot IN (SELECT id FROM snapshots WHERE published=0);
+                      DELETE FROM snapshots WHERE published=0");
+    self.snapshot = self.latest_published_snapshot() + 1;
+    self.exec_or_die(format!(
+      "INSERT INTO snapshots (id, started, published) VALUES ({}, {:u}, 0)",
+      self.snapshot, started).as_slice());
+    self.snapshot
+  }
+
+  fn publish_snapshot(&mut self) {
+    self.exec_or_die(format!("UPDATE snapshots SET published=1 WHERE id={};
+                              COMMIT; BEGIN", self.snapshot).as_slice());
+  }
+
+  fn select_snapshot(&mut self, snapshot: i64) {
+    self.snapshot = snapshot;
+  }
+
+  fn list_snapshots(&mut self) -> Vec<(i64, u64)> {
+    let mut snapshots = Vec::new();
+    let mut cursor = self.prepare_or_die(
+      "SELECT id, started FROM snapshots WHERE published=1 ORDER BY id");
+    while cursor.step() == SQLITE_ROW {
+      snapshots.push((cursor.get_int(0) as i64, cursor.get_int(1) as u64));
+    }
+    snapshots
+  }
+
+  fn copy_snapshot(&mut self, snapshot: i64, started: u64) -> Option<i64> {
+    if !self.list_snapshots().iter().any(|&(id, _)| id == snapshot) {
+      return None;
+    }
+    let copy = self.begin_snapshot(started);
+    self.exec_or_die(format!(
+      "INSERT INTO key_index
+       SELECT {}, id, parent, name, created, modified, accessed, hash, persistent_ref
+         FROM key_index WHERE snapshot={}", copy, snapshot).as_slice());
+    self.publish_snapshot();
+    Some(copy)
+  }
+}
+
+
+/// An entry as recorded by `MemoryKeyIndexStorage`.
+#[deriving(Clone)]
+struct MemoryKey {
+  parent: Vec<u8>,
+  name: Vec<u8>,
+  created: u64,
+  modified: Option<u64>,
+  accessed: u64,
+  data: Option<(Vec<u8>, Vec<u8>)>,
+}
+
+/// A `KeyIndexStorage` that keeps its entries in memory, e.g. for tests. Nothing is persisted,
+/// so there is nothing to import from either.
+pub struct MemoryKeyIndexStorage {
+  // Entries by snapshot and ID:
+  keys: TreeMap<(i64, Vec<u8>), MemoryKey>,
+
+  // The start time of each snapshot, and whether it is published:
+  snapshots: TreeMap<i64, (u64, bool)>,
+
+  settings: HashMap<String, i64>,
+  text_settings: HashMap<String, String>,
+  snapshot: i64,
+}
+
+impl MemoryKeyIndexStorage {
+  pub fn new() -> MemoryKeyIndexStorage {
+    MemoryKeyIndexStorage{keys: TreeMap::new(), snapshots: TreeMap::new(),
+                          settings: HashMap::new(), text_settings: HashMap::new(), snapshot: 0}
+  }
+
+  fn latest_published_snapshot(&self) -> i64 {
+    self.snapshots.iter().filter(|&(_, &(_, published))| published)
+                         .map(|(&id, _)| id).max().unwrap_or(0)
+  }
+}
+
+impl KeyIndexStorage for MemoryKeyIndexStorage {
+
+  fn insert(&mut self, id: &[u8], parent: &[u8], name: &[u8], created: u64, accessed: u64) {
+    self.keys.insert((self.snapshot, id.to_vec()),
+                     MemoryKey{parent: parent.to_vec(), name: name.to_vec(), created: created,
+                               modified: None, accessed: accessed, data: None});
+  }
+
+  fn lookup(&mut self, parent: &[u8], id: Option<&[u8]>, name: &[u8],
+            created: u64, modified: u64, accessed: u64) -> Option<Vec<u8>> {
+    let current = self.snapshot;
+    let mut found: Option<(i64, Vec<u8>)> = None;
+    for (&(snapshot, ref key_id), key) in self.keys.iter() {
+      let same_key = match id {
+        Some(id) => key_id.as_slice() == id,
+        None => key.name.as_slice() == name,
+      };
+      let matches = same_key && snapshot <= current && key.parent.as_slice() == parent &&
+                    key.created == created && key.modified == Some(modified) &&
+                    key.accessed == accessed;
+      if matches && found.as_ref().map_or(true, |&(latest, _)| snapshot > latest) {
+        found = Some((snapshot, key_id.clone()));
+      }
+    }
+
+    found.map(|(snapshot, id)| {
+      if snapshot < current {
+        let key = self.keys.find(&(snapshot, id.clone())).expect("key").clone();
+        self.keys.insert((current, id.clone()), key);
+      }
+      id
+    })
+  }
+
+  fn update_data_hash(&mut self, parent: &[u8], id: &[u8],
+                      data: Option<(Vec<u8>, Vec<u8>)>, modified: Option<u64>) {
+    let key = match self.keys.find_mut(&(self.snapshot, id.to_vec())) {
+      Some(key) => key,
+      None => return,
+    };
+    if key.parent.as_slice() != parent { return }
+    match modified {
+      Some(modified) => {
+        if key.modified.unwrap_or(0) > modified { return }
+        key.modified = Some(modified);
+      },
+      None => (),
+    }
+    key.data = data;
+  }
+
+  fn list_dir(&mut self, parent: &[u8])
+              -> Vec<(Vec<u8>, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>)> {
+    let mut listing = Vec::new();
+    for (&(snapshot, ref id), key) in self.keys.iter() {
+      if snapshot != self.snapshot || key.parent.as_slice() != parent { continue }
+      let (hash, persistent_ref) = key.data.clone().unwrap_or((vec![], vec![]));
+      listing.push((id.clone(), key.name.clone(), key.created, key.modified.unwrap_or(0),
+                    key.accessed, hash, persistent_ref));
+    }
+    listing
+  }
+
+  fn flush(&mut self) {}
+
+  fn maintenance(&mut self) {}
+
+  fn integrity_check(&mut self) -> Vec<String> { vec![] }
+
+  fn list_data_hashes(&mut self) -> Vec<(Vec<u8>, Vec<u8>)> {
+    let mut hashes = Vec::new();
+    for (_, key) in self.keys.iter() {
+      match key.data {
+        Some((ref hash, ref persistent_ref)) if hash.len() > 0 => {
+          hashes.push((hash.clone(), persistent_ref.clone()));
+        },
+        _ => (),
+      }
+    }
+    hashes.sort();
+    hashes.dedup();
+    hashes
+  }
+
+  fn setting(&mut self, name: &str) -> Option<i64> {
+    self.settings.find(&name.to_string()).map(|&value| value)
+  }
+
+  fn set_setting(&mut self, name: &str, value: i64) {
+    self.settings.insert(name.to_string(), value);
+  }
+
+  fn text_setting(&mut self, name: &str) -> Option<String> {
+    self.text_settings.find(&name.to_string()).map(|value| value.clone())
+  }
+
+  fn set_text_setting(&mut self, name: &str, value: &str) {
+    self.text_settings.insert(name.to_string(), value.to_string());
+  }
+
+  fn import_from(&mut self, path: &str) {
+    fail!("An in-memory key index cannot import the key index at '{}'.", path);
+  }
+
+  fn begin_snapshot(&mut self, started: u64) -> i64 {
+    let unpublished: Vec<i64> = self.snapshots.iter().filter(|&(_, &(_, published))| !published)
+                                              .map(|(&id, _)| id).collect();
+    let discarded: Vec<(i64, Vec<u8>)> = self.keys.iter().map(|(key, _)| key.clone())
+      .filter(|&(snapshot, _)| unpublished.contains(&snapshot)).collect();
+    for key in discarded.iter() {
+      self.keys.remove(key);
+    }
+    for snapshot in unpublished.iter() {
+      self.snapshots.remove(snapshot);
+    }
+
+    self.snapshot = self.latest_published_snapshot() + 1;
+    self.snapshots.insert(self.snapshot, (started, false));
+    self.snapshot
+  }
+
+  fn publish_snapshot(&mut self) {
+    let snapshot = self.snapshot;
+    match self.snapshots.find_mut(&snapshot) {
+      Some(state) => {
+        let (started, _) = *state;
+        *state = (started, true);
+      },
+      None => (),
+    }
+  }
+
+  fn select_snapshot(&mut self, snapshot: i64) {
+    self.snapshot = snapshot;
+  }
+
+  fn list_snapshots(&mut self) -> Vec<(i64, u64)> {
+    self.snapshots.iter().filter(|&(_, &(_, published))| published)
+                         .map(|(&id, &(started, _))| (id, started)).collect()
+  }
+
+  fn copy_snapshot(&mut self, snapshot: i64, started: u64) -> Option<i64> {
+    if !self.list_snapshots().iter().any(|&(id, _)| id == snapshot) {
+      return None;
+    }
+    let copy = self.begin_snapshot(started);
+    let copied: Vec<(Vec<u8>, MemoryKey)> = self.keys.iter()
+      .filter(|&(&(from, _), _)| from == snapshot)
+      .map(|(&(_, ref id), key)| (id.clone(), key.clone())).collect();
+    for (id, key) in copied.into_iter() {
+      self.keys.insert((copy, id), key);
+    }
+    self.publish_snapshot();
+    Some(copy)
+  }
+}
+
+
+pub struct KeyIndex {
+  storage: Box<KeyIndexStorage>,
+  flush_timer: PeriodicTimer,
+}
+
+
+impl KeyIndex {
+  /// Open the sqlite key index stored at `path`.
+  pub fn new(path: String, key: Option<Vec<u8>>) -> KeyIndex {
+    KeyIndex::with_storage(box SqliteKeyIndexStorage::new(path, key))
+  }
+
+  /// Create a key index that persists its entries through `storage`.
+  pub fn with_storage(storage: Box<KeyIndexStorage>) -> KeyIndex {
+    KeyIndex{storage: storage,
+             flush_timer: PeriodicTimer::new(Duration::seconds(5))}
+  }
+
+  #[cfg(test)]
+  pub fn new_for_testing() -> KeyIndex {
+    KeyIndex::new(":memory:".to_string(), None)
+  }
 
   pub fn maybe_flush(&mut self) {
     if self.flush_timer.did_fire() {
@@ -144,19 +803,31 @@ impl KeyIndex {
   }
 
   pub fn flush(&mut self) {
-    self.exec_or_die("COMMIT; BEGIN");
+    self.storage.flush();
   }
-}
 
-impl Clone for KeyIndex {
-  fn clone(&self) -> KeyIndex {
-    KeyIndex::new(self.path.clone())
+  pub fn maintenance(&mut self) {
+    self.storage.maintenance();
   }
-}
 
-impl Drop for KeyIndex {
-  fn drop(&mut self) {
-    self.exec_or_die("COMMIT");
+  fn insert<A: KeyEntry<A>>(&mut self, entry: &A) -> Vec<u8> {
+    let parent = entry.parent_id().unwrap_or(b"".into_vec());
+    let id = entry.id().unwrap_or_else(|| randombytes(16));
+
+    self.storage.insert(id.as_slice(), parent.as_slice(), entry.name().as_slice(),
+                        entry.created().unwrap_or(0),
+                        entry.accessed().unwrap_or(0));
+    id
+  }
+
+  fn lookup_exact<A: KeyEntry<A>>(&mut self, entry: &A) -> Option<Vec<u8>> {
+    let parent = entry.parent_id().unwrap_or(b"".into_vec());
+    let id = entry.id();
+    self.storage.lookup(parent.as_slice(), id.as_ref().map(|id| id.as_slice()),
+                        entry.name().as_slice(),
+                        entry.created().unwrap_or(0),
+                        entry.modified().unwrap_or(0),
+                        entry.accessed().unwrap_or(0))
   }
 }
 
@@ -165,52 +836,25 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
     match msg {
 
       Insert(entry) => {
-        let parent = entry.parent_id().unwrap_or(b"".into_vec());
-        let id = entry.id().unwrap_or_else(|| randombytes(16));
-
-        self.exec_or_die(format!(
-          "INSERT OR REPLACE INTO key_index (id, parent, name, created, accessed)
-           VALUES (x'{:s}', x'{:s}', x'{:s}', {:u}, {:u})",
-          id.as_slice().to_hex(), parent.as_slice().to_hex(), entry.name().as_slice().to_hex(),
-          entry.created().unwrap_or(0),
-          entry.accessed().unwrap_or(0)).as_slice());
-
-        return reply(Id(id));
+        return reply(Id(self.insert(&entry)));
       },
 
       LookupExact(entry) => {
-        let parent = entry.parent_id().unwrap_or(b"".into_vec());
-        let mut cursor = match entry.id() {
-          Some(id) => {
-            self.prepare_or_die(format!(
-              "SELECT id FROM key_index
-                WHERE parent=x'{:s}' AND id=x'{:s}'
-                AND created={:u} AND modified={:u} AND accessed={:u}
-                LIMIT 1",
-              parent.as_slice().to_hex(), id.as_slice().to_hex(),
-              entry.created().unwrap_or(0),
-              entry.modified().unwrap_or(0),
-              entry.accessed().unwrap_or(0)).as_slice())
-          },
-          None => {
-            self.prepare_or_die(format!(
-              "SELECT id FROM key_index
-                WHERE parent=x'{:s}' AND name=x'{:s}'
-                AND created={:u} AND modified={:u} AND accessed={:u}
-                LIMIT 1",
-              parent.as_slice().to_hex(), entry.name().as_slice().to_hex(),
-              entry.created().unwrap_or(0),
-              entry.modified().unwrap_or(0),
-              entry.accessed().unwrap_or(0)).as_slice())
-          }
-        };
-        if cursor.step() == SQLITE_ROW {
-          let res = Id(cursor.get_blob(0).expect("id").into_vec());
-          assert!(cursor.step() == SQLITE_DONE);
-          return reply(res);
-            } else {
-          return reply(NotFound);
+        return reply(match self.lookup_exact(&entry) {
+          Some(id) => Id(id),
+          None => NotFound,
+        });
+      },
+
+      InsertBatch(entries) => {
+        let mut ids = Vec::with_capacity(entries.len());
+        for entry in entries.iter() {
+          ids.push(match self.lookup_exact(entry) {
+            Some(id) => (id, false),
+            None => (self.insert(entry), true),
+          });
         }
+        return reply(Ids(ids));
       },
 
       UpdateDataHash(entry, hash_opt, persistent_ref_opt) => {
@@ -218,45 +862,12 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
 
         assert!(hash_opt.is_some() == persistent_ref_opt.is_some());
 
-        if hash_opt.is_some() && persistent_ref_opt.is_some() {
-          match entry.modified() {
-            Some(modified) => {
-              self.exec_or_die(format!(
-                "UPDATE key_index SET hash=x'{:s}', persistent_ref=x'{:s}'
-                                                  , modified={:u}
-                  WHERE parent=x'{:s}' AND id=x'{:s}' AND IFNULL(modified,0)<={:u}",
-                hash_opt.unwrap().as_slice().to_hex(),
-                persistent_ref_opt.unwrap().as_slice().to_hex(),
-                modified, parent.as_slice().to_hex(), entry.id().unwrap().as_slice().to_hex(),
-                modified).as_slice());
-            },
-            None => {
-              self.exec_or_die(format!(
-                "UPDATE key_index SET hash=x'{:s}', persistent_ref=x'{:s}'
-                 WHERE parent=x'{:s}' AND id=x'{:s}'",
-                hash_opt.unwrap().as_slice().to_hex(),
-                persistent_ref_opt.unwrap().as_slice().to_hex(),
-                parent.as_slice().to_hex(), entry.id().unwrap().as_slice().to_hex()).as_slice());
-            }
-          }
-        } else {
-          match entry.modified() {
-            Some(modified) => {
-              self.exec_or_die(format!(
-                "UPDATE key_index SET hash=NULL, persistent_ref=NULL
-                                               , modified={:u}
-                  WHERE parent=x'{:s}' AND id=x'{:s}' AND IFNULL(modified, 0)<={:u}",
-                modified, parent.as_slice().to_hex(), entry.id().unwrap().as_slice().to_hex(),
-                modified).as_slice());
-            },
-            None => {
-              self.exec_or_die(format!(
-                "UPDATE key_index SET hash=NULL, persistent_ref=NULL
-                 WHERE parent=x'{:s}' AND id=x'{:s}'",
-                parent.as_slice().to_hex(), entry.id().unwrap().as_slice().to_hex()).as_slice());
-            }
-          }
-        }
+        let data = match (hash_opt, persistent_ref_opt) {
+          (Some(hash), Some(persistent_ref)) => Some((hash, persistent_ref)),
+          _ => None,
+        };
+        self.storage.update_data_hash(parent.as_slice(), entry.id().unwrap().as_slice(),
+                                      data, entry.modified());
 
         self.maybe_flush();
         return reply(UpdateOK);
@@ -267,31 +878,76 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
         return reply(FlushOK);
       },
 
+      Maintenance => {
+        self.maintenance();
+        return reply(MaintenanceOK);
+      },
+
+      SelfCheck => {
+        return reply(SelfCheckResult(self.storage.integrity_check()));
+      },
+
+      ListDataHashes => {
+        return reply(DataHashes(self.storage.list_data_hashes()));
+      },
+
+      FetchSetting(name) => {
+        return reply(Setting(self.storage.setting(name.as_slice())));
+      },
+
+      StoreSetting(name, value) => {
+        self.storage.set_setting(name.as_slice(), value);
+        return reply(UpdateOK);
+      },
+
+      FetchTextSetting(name) => {
+        return reply(TextSetting(self.storage.text_setting(name.as_slice())));
+      },
+
+      StoreTextSetting(name, value) => {
+        self.storage.set_text_setting(name.as_slice(), value.as_slice());
+        return reply(UpdateOK);
+      },
+
+      ImportFrom(path) => {
+        self.storage.import_from(path.as_slice());
+        return reply(UpdateOK);
+      },
+
       ListDir(parent) => {
-        let mut listing = Vec::new();
         let parent = parent.unwrap_or(b"".into_vec());
+        return reply(ListResult(self.storage.list_dir(parent.as_slice())));
+      },
 
-        let mut cursor = self.prepare_or_die(format!(
-           "SELECT id, name, created, modified, accessed, hash, persistent_ref
-            FROM key_index
-            WHERE parent=x'{:s}'", parent.as_slice().to_hex()).as_slice());
-
-        // TODO(jos): replace get_int with something that understands uint64
-        while cursor.step() == SQLITE_ROW {
-          let id = cursor.get_blob(0).expect("id").into_vec();
-          let name = cursor.get_blob(1).expect("name").into_vec();
-          let created = cursor.get_int(2);
-          let modified = cursor.get_int(3);
-          let accessed = cursor.get_int(4);
-          let hash = cursor.get_blob(5).unwrap_or([]).into_vec();
-          let persistent_ref = cursor.get_blob(6).unwrap_or([]).into_vec();
-
-          listing.push((id, name,
-                        created as u64, modified as u64, accessed as u64,
-                        hash, persistent_ref));
-        }
+      BeginSnapshot => {
+        let started = time::get_time().sec as u64;
+        return reply(SnapshotId(self.storage.begin_snapshot(started)));
+      },
+
+      BeginSnapshotAt(started) => {
+        return reply(SnapshotId(self.storage.begin_snapshot(started)));
+      },
+
+      PublishSnapshot => {
+        self.storage.publish_snapshot();
+        return reply(UpdateOK);
+      },
+
+      SelectSnapshot(snapshot) => {
+        self.storage.select_snapshot(snapshot);
+        return reply(UpdateOK);
+      },
 
-        return reply(ListResult(listing));
+      ListSnapshots => {
+        return reply(Snapshots(self.storage.list_snapshots()));
+      },
+
+      RollBackTo(snapshot) => {
+        let started = time::get_time().sec as u64;
+        return reply(match self.storage.copy_snapshot(snapshot, started) {
+          Some(copy) => SnapshotId(copy),
+          None => NotFound,
+        });
       },
     }
   }
@@ -301,6 +957,7 @@ impl <A: KeyEntry<A>> MsgHandler<Msg<A>, Reply> for KeyIndex {
 #[cfg(test)]
 mod tests {
   use super::*;
+  use process::{Process};
 
   struct TestEntry {
     id: Option<Vec<u8>>,
@@ -309,7 +966,7 @@ mod tests {
   }
   impl KeyEntry<TestEntry> for TestEntry {
     fn id(&self) -> Option<Vec<u8>> {
-      None
+      self.id.clone()
     }
     fn parent_id(&self) -> Option<Vec<u8>>{
       self.parent.clone()
@@ -326,7 +983,7 @@ mod tests {
       None
     }
     fn modified(&self) -> Option<u64> {
-      None
+      Some(0)
     }
     fn accessed(&self) -> Option<u64> {
       None
@@ -348,4 +1005,160 @@ mod tests {
     }
   }
 
+  fn entry(name: &str) -> TestEntry {
+    TestEntry{id: None, parent: None, name: name.as_bytes().into_vec()}
+  }
+
+  /// Insert an entry and record its modification time, as a snapshot does; only entries with a
+  /// modification time are found by `LookupExact`.
+  fn insert(kiP: &KeyIndexProcess<TestEntry>, name: &str) {
+    let id = match kiP.send_reply(Insert(entry(name))) {
+      Id(id) => id,
+      _ => fail!("Unexpected result from key index."),
+    };
+    kiP.send_reply(UpdateDataHash(entry(name).with_id(id), None, None));
+  }
+
+  fn list_names(kiP: &KeyIndexProcess<TestEntry>) -> Vec<Vec<u8>> {
+    let mut names: Vec<Vec<u8>> = match kiP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls.into_iter().map(|(_, name, _, _, _, _, _)| name).collect(),
+      _ => fail!("Unexpected result from key index."),
+    };
+    names.sort();
+    names
+  }
+
+  fn in_memory() -> KeyIndexProcess<TestEntry> {
+    Process::new(proc() { KeyIndex::with_storage(box MemoryKeyIndexStorage::new()) })
+  }
+
+  #[test]
+  fn snapshot_history() {
+    check_snapshot_history(Process::new(proc() { KeyIndex::new_for_testing() }));
+  }
+
+  #[test]
+  fn snapshot_history_in_memory() {
+    check_snapshot_history(in_memory());
+  }
+
+  fn check_snapshot_history(kiP: KeyIndexProcess<TestEntry>) {
+
+    let first = match kiP.send_reply(BeginSnapshot) {
+      SnapshotId(id) => id,
+      _ => fail!("Unexpected result from key index."),
+    };
+    insert(&kiP, "a");
+    insert(&kiP, "b");
+    kiP.send_reply(PublishSnapshot);
+
+    // Only "a" is still present in the second snapshot:
+    kiP.send_reply(BeginSnapshot);
+    assert_eq!(list_names(&kiP), vec![]);
+    match kiP.send_reply(LookupExact(entry("a"))) {
+      Id(_) => (),
+      _ => fail!("Entry of the first snapshot was not found."),
+    }
+    assert_eq!(list_names(&kiP), vec![b"a".into_vec()]);
+
+    match kiP.send_reply(ListSnapshots) {
+      Snapshots(snapshots) => assert_eq!(snapshots.len(), 1),
+      _ => fail!("Unexpected result from key index."),
+    }
+    kiP.send_reply(PublishSnapshot);
+    match kiP.send_reply(ListSnapshots) {
+      Snapshots(snapshots) => assert_eq!(snapshots.len(), 2),
+      _ => fail!("Unexpected result from key index."),
+    }
+
+    kiP.send_reply(SelectSnapshot(first));
+    assert_eq!(list_names(&kiP), vec![b"a".into_vec(), b"b".into_vec()]);
+  }
+
+  #[test]
+  fn text_settings() {
+    let kiP: KeyIndexProcess<TestEntry> = Process::new(proc() { KeyIndex::new_for_testing() });
+    match kiP.send_reply(FetchTextSetting("root".to_string())) {
+      TextSetting(value) => assert_eq!(value, None),
+      _ => fail!("Unexpected result from key index."),
+    }
+    kiP.send_reply(StoreTextSetting("root".to_string(), "/home/o'brien".to_string()));
+    kiP.send_reply(StoreTextSetting("root".to_string(), "123".to_string()));
+    match kiP.send_reply(FetchTextSetting("root".to_string())) {
+      TextSetting(value) => assert_eq!(value, Some("123".to_string())),
+      _ => fail!("Unexpected result from key index."),
+    }
+  }
+
+  #[test]
+  fn unpublished_snapshot_is_discarded() {
+    let kiP: KeyIndexProcess<TestEntry> = Process::new(proc() { KeyIndex::new_for_testing() });
+    kiP.send_reply(BeginSnapshot);
+    insert(&kiP, "a");
+    kiP.send_reply(PublishSnapshot);
+
+    // A snapshot that is never published:
+    kiP.send_reply(BeginSnapshot);
+    insert(&kiP, "b");
+
+    let third = match kiP.send_reply(BeginSnapshot) {
+      SnapshotId(id) => id,
+      _ => fail!("Unexpected result from key index."),
+    };
+    assert_eq!(third, 2);
+    match kiP.send_reply(LookupExact(entry("b"))) {
+      NotFound => (),
+      _ => fail!("Entry of the unpublished snapshot was found."),
+    }
+    match kiP.send_reply(ListSnapshots) {
+      Snapshots(snapshots) => assert_eq!(snapshots.len(), 1),
+      _ => fail!("Unexpected result from key index."),
+    }
+  }
+
+  #[test]
+  fn roll_back_to_an_earlier_snapshot() {
+    check_roll_back(Process::new(proc() { KeyIndex::new_for_testing() }));
+  }
+
+  #[test]
+  fn roll_back_in_memory() {
+    check_roll_back(in_memory());
+  }
+
+  fn check_roll_back(kiP: KeyIndexProcess<TestEntry>) {
+    kiP.send_reply(BeginSnapshot);
+    insert(&kiP, "good");
+    kiP.send_reply(PublishSnapshot);
+    kiP.send_reply(BeginSnapshot);
+    insert(&kiP, "corrupt");
+    kiP.send_reply(PublishSnapshot);
+
+    let copy = match kiP.send_reply(RollBackTo(1)) {
+      SnapshotId(id) => id,
+      _ => fail!("Unexpected result from key index."),
+    };
+    assert_eq!(copy, 3);
+    assert_eq!(list_names(&kiP), vec![b"good".into_vec()]);
+
+    // The copy is the latest snapshot, and the one it supersedes is kept:
+    match kiP.send_reply(ListSnapshots) {
+      Snapshots(snapshots) => {
+        assert_eq!(snapshots.iter().map(|&(id, _)| id).collect::<Vec<i64>>(), vec![1, 2, 3]);
+      },
+      _ => fail!("Unexpected result from key index."),
+    }
+    kiP.send_reply(BeginSnapshot);
+    match kiP.send_reply(LookupExact(entry("good"))) {
+      Id(_) => (),
+      _ => fail!("Entry of the copied snapshot was not found."),
+    }
+    kiP.send_reply(SelectSnapshot(2));
+    assert_eq!(list_names(&kiP), vec![b"corrupt".into_vec()]);
+
+    match kiP.send_reply(RollBackTo(7)) {
+      NotFound => (),
+      _ => fail!("Rolled back to a snapshot that does not exist."),
+    }
+  }
 }
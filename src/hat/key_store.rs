@@ -15,20 +15,58 @@
 //! External API for creating and manipulating snapshots.
 
 use blob_store;
-use hash_tree::{SimpleHashTreeWriter, HashTreeBackend,
-                SimpleHashTreeReader, ReaderResult};
+use crash_test;
+use hash_tree::{SimpleHashTreeWriter, HashTreeBackend, HashOnlyBackend,
+                SimpleHashTreeReader, ReaderResult, SingleBlock};
 use hash_index;
+use hash_tree;
+use hash_pool::{HashPool, HashedChunks};
 
 use process::{Process, MsgHandler};
 
 use key_index::{KeyIndexProcess, KeyEntry};
 use key_index;
+use keys::{ChunkCipher, SEAL_OVERHEAD};
+
+use serialize::hex::{ToHex};
+
+use std::mem;
+use std::sync;
 
 
 #[cfg(test)]
 use key_index::{KeyIndex};
 
 
+/// Files with less data than this are stored inline in the key index, instead of in blobs.
+static INLINE_THRESHOLD: uint = 256;
+
+/// First byte of a persistent reference that holds inline data. Blob references never start with
+/// this byte (they are JSON objects).
+static INLINE_TAG: u8 = 0;
+
+/// Files with at most this much data are read in full and looked up by their top hash, before
+/// their data-chunks are processed one by one.
+static WHOLE_FILE_LOOKUP_LIMIT: uint = 64 * 1024 * 1024;
+
+/// The most keys whose data waits to be stored (see `InsertLater`) before it is stored anyway.
+static DEFERRED_KEYS: uint = 256;
+
+fn inline_ref(data: &[u8]) -> Vec<u8> {
+  let mut persistent_ref = vec![INLINE_TAG];
+  persistent_ref.push_all(data);
+  persistent_ref
+}
+
+fn inline_data_of<'a>(persistent_ref: &'a [u8]) -> Option<&'a [u8]> {
+  if persistent_ref.len() > 0 && persistent_ref[0] == INLINE_TAG {
+    Some(persistent_ref.slice_from(1))
+  } else {
+    None
+  }
+}
+
+
 pub type KeyStoreProcess<KE, IT, B> = Process<Msg<KE, IT>, Reply<B>, KeyStore<KE, IT, B>>;
 
 // Public structs
@@ -40,6 +78,29 @@ pub enum Msg<KE, IT> {
   /// Returns `Id` with the new entry ID.
   Insert(KE, Option<proc():Send -> Option<IT>>),
 
+  /// Insert many keys at once, as if by `Insert`. This saves a round-trip to the key index per
+  /// key, which dominates when inserting many small files.
+  /// Returns `Ids` with the entry IDs, in order.
+  InsertBatch(Vec<(KE, Option<proc():Send -> Option<IT>>)>),
+
+  /// Insert many keys at once, as by `InsertBatch`, but store their data after the data of keys
+  /// inserted by `Insert` and `InsertBatch` in the meantime, so that more important data is
+  /// hashed and uploaded first. The deferred data is stored once `DEFERRED_KEYS` keys are
+  /// waiting, and before any request other than an insert is handled (so that it is flushed).
+  /// Returns `Ids` with the entry IDs, in order.
+  InsertLater(Vec<(KE, Option<proc():Send -> Option<IT>>)>),
+
+  /// Insert a key as with `Insert`, but only if its data (if any) is already stored, e.g. because
+  /// an identical file was inserted before. The data is read and hashed, but nothing is stored.
+  /// Returns `Id` with the entry ID, or `NotStored` if the key was not inserted.
+  Seed(KE, Option<proc():Send -> Option<IT>>),
+
+  /// Insert a key as with `Insert`, but with the data hash and persistent reference of data that
+  /// is already stored, e.g. as listed by `ListDir` for a file of another family's snapshot.
+  /// Nothing is read or stored.
+  /// Returns `Id` with the entry ID.
+  InsertStored(KE, Vec<u8>, Vec<u8>),
+
   /// List a "directory" (aka. a `level`) in the index.
   /// Returns `ListResult` with all the entries under the given parent.
   ListDir(Option<Vec<u8>>),
@@ -47,19 +108,100 @@ pub enum Msg<KE, IT> {
   /// Flush this key store and its dependencies.
   /// Returns `FlushOK`.
   Flush,
+
+  /// Run database maintenance on the key index.
+  /// Returns `MaintenanceOK`.
+  Maintenance,
+
+  /// Check the key index for corruption and verify that every data hash it references is known
+  /// by the hash index.
+  /// Returns `SelfCheckResult` with the problems found (empty if none).
+  SelfCheck,
+
+  /// Report how many bytes of data inserted so far were new and how many were deduplicated.
+  /// Returns `ByteCountsResult`.
+  FetchByteCounts,
+
+  /// Start a new snapshot in the key index (see `key_index::BeginSnapshot`).
+  /// Returns `SnapshotId` with the ID of the new snapshot.
+  BeginSnapshot,
+
+  /// Start a new snapshot with the given start time (see `key_index::BeginSnapshotAt`).
+  /// Returns `SnapshotId` with the ID of the new snapshot.
+  BeginSnapshotAt(u64),
+
+  /// Publish the current snapshot (see `key_index::PublishSnapshot`). Everything it refers to
+  /// must be durable, so this should follow a `Flush`.
+  /// Returns `PublishOK`.
+  PublishSnapshot,
+
+  /// List and check out entries of an earlier snapshot.
+  /// Returns `SelectOK`.
+  SelectSnapshot(i64),
+
+  /// List the snapshots in the key index, oldest first.
+  /// Returns `Snapshots` with the ID and start time of each.
+  ListSnapshots,
+
+  /// Publish a copy of an earlier snapshot as the latest one (see `key_index::RollBackTo`).
+  /// Returns `SnapshotId` with the ID of the copy, or `NotStored` if there is no such snapshot.
+  RollBackTo(i64),
+
+  /// List the blobs holding the data of an entry, given its data hash and persistent reference
+  /// (as listed by `ListDir`). This includes the blobs of the hash tree's inner nodes.
+  /// Returns `BlobNames`.
+  ListBlobs(Vec<u8>, Vec<u8>),
+
+  /// Announce the chunk reads of an upcoming restore to the blob store, as one blob name per
+  /// read (see `blob_store::PlanReads`).
+  /// Returns `PlanOK`.
+  PlanReads(Vec<Vec<u8>>),
 }
 
 pub enum Reply<B> {
   Id(Vec<u8>),
+  Ids(Vec<Vec<u8>>),
+  NotStored,
   ListResult(Vec<(Vec<u8>, Vec<u8>, u64, u64, u64, Vec<u8>, Vec<u8>,
                   ReaderResult<HashStoreBackend<B>>)>),
   FlushOK,
+  MaintenanceOK,
+  SelfCheckResult(Vec<String>),
+  ByteCountsResult(ByteCounts),
+  SnapshotId(i64),
+  PublishOK,
+  SelectOK,
+  Snapshots(Vec<(i64, u64)>),
+  BlobNames(Vec<Vec<u8>>),
+  PlanOK,
+}
+
+/// Accounting of inserted data bytes (excluding hash tree meta-data).
+#[deriving(Clone, Show, PartialEq)]
+pub struct ByteCounts {
+  /// Bytes of data-chunks that were not known before, and are stored in new blobs.
+  pub new_bytes: u64,
+
+  /// Bytes of data-chunks that were already known by the hash index.
+  pub dedup_bytes: u64,
+}
+
+impl ByteCounts {
+  pub fn new() -> ByteCounts {
+    ByteCounts{new_bytes: 0, dedup_bytes: 0}
+  }
 }
 
 pub struct KeyStore<KE, IT, B> {
   index: KeyIndexProcess<KE>,
   hash_index: hash_index::HashIndexProcess,
   blob_store: blob_store::BlobStoreProcess<B>,
+  byte_counts: sync::Arc<sync::Mutex<ByteCounts>>,
+  hash_pool: Option<HashPool>,
+  chunk_cipher: Option<ChunkCipher>,
+
+  // Inserted keys whose data is still to be stored (see `InsertLater`):
+  deferred: Vec<(KE, Vec<u8>, Option<proc():Send -> Option<IT>>)>,
 }
 
 // Implementations
@@ -68,7 +210,21 @@ impl <KE: KeyEntry<KE> + Send, IT: Iterator<Vec<u8>>, B: blob_store::BlobStoreBa
   pub fn new(index: KeyIndexProcess<KE>,
              hash_index: hash_index::HashIndexProcess,
              blob_store: blob_store::BlobStoreProcess<B>) -> KeyStore<KE, IT, B> {
-    KeyStore{index: index, hash_index: hash_index, blob_store: blob_store}
+    KeyStore{index: index, hash_index: hash_index, blob_store: blob_store,
+             byte_counts: sync::Arc::new(sync::Mutex::new(ByteCounts::new())),
+             hash_pool: None, chunk_cipher: None, deferred: Vec::new()}
+  }
+
+  /// Encrypt data-chunks with `cipher` before they are stored, and decrypt them when they are
+  /// read back. Every chunk of the repository must be encrypted with the same cipher.
+  pub fn set_chunk_cipher(&mut self, cipher: ChunkCipher) {
+    self.chunk_cipher = Some(cipher);
+  }
+
+  /// Hash data-chunks in `workers` parallel tasks. With a single worker, chunks are hashed by
+  /// the key store itself.
+  pub fn set_hash_workers(&mut self, workers: uint) {
+    self.hash_pool = if workers > 1 { Some(HashPool::new(workers)) } else { None };
   }
 
   #[cfg(test)]
@@ -76,42 +232,110 @@ impl <KE: KeyEntry<KE> + Send, IT: Iterator<Vec<u8>>, B: blob_store::BlobStoreBa
     let kiP = Process::new(proc() { KeyIndex::new_for_testing() });
     let hiP = Process::new(proc() { hash_index::HashIndex::new_for_testing() });
     let bsP = Process::new(proc() { blob_store::BlobStore::new_for_testing(backend, 1024) });
-    KeyStore{index: kiP, hash_index: hiP, blob_store: bsP}
+    KeyStore::new(kiP, hiP, bsP)
   }
 
+  fn hash_store_backend(&self) -> HashStoreBackend<B> {
+    HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone(),
+                          self.byte_counts.clone(), self.chunk_cipher.clone())
+  }
+
+  /// Flush the blob store, hash index and key index, in that order.
+  ///
+  /// Hash entries only reach the hash index after the blob holding their data is committed, so
+  /// the hash index never references a blob that is missing after a crash. In the other direction,
+  /// a committed blob is only marked as indexed once all hash entries that were reserved before
+  /// it was committed are durable; a crash in between leaves it committed, but unindexed, for
+  /// recovery to reconcile.
   pub fn flush(&mut self) {
     self.blob_store.send_reply(blob_store::Flush);
-    self.hash_index.send_reply(hash_index::Flush);
+    crash_test::point("key_store: flushed blobs");
+    let unindexed = match self.blob_store.send_reply(blob_store::ListUnindexed) {
+      blob_store::Unindexed(blobs) => blobs,
+      _ => fail!("Unexpected reply from blob store."),
+    };
+    match self.hash_index.send_reply(hash_index::Flush) {
+      hash_index::FlushOK(true) if unindexed.len() > 0 => {
+        self.blob_store.send_reply(blob_store::MarkIndexed(unindexed));
+      },
+      // Entries reserved by other writers are still in flight; a later flush marks the blobs.
+      hash_index::FlushOK(_) => (),
+      _ => fail!("Unexpected reply from hash index."),
+    }
+    crash_test::point("key_store: flushed hash index");
     self.index.send_reply(key_index::Flush);
   }
+
+  fn self_check(&mut self) -> Vec<String> {
+    let mut problems = match self.index.send_reply(key_index::SelfCheck) {
+      key_index::SelfCheckResult(problems) => problems,
+      _ => fail!("Unexpected result from key index."),
+    };
+
+    let hashes = match self.index.send_reply(key_index::ListDataHashes) {
+      key_index::DataHashes(hashes) => hashes,
+      _ => fail!("Unexpected result from key index."),
+    };
+    for (hash, persistent_ref) in hashes.into_iter() {
+      if inline_data_of(persistent_ref.as_slice()).is_some() {
+        continue;  // Inline data is not in the hash index.
+      }
+      let hash = hash_index::Hash{bytes: hash};
+      match self.hash_index.send_reply(hash_index::HashExists(hash.clone())) {
+        hash_index::HashKnown => (),
+        hash_index::HashNotKnown => {
+          problems.push(format!("key_index: data hash {} is not in the hash index",
+                                hash.bytes.as_slice().to_hex()));
+        },
+        _ => fail!("Unexpected reply from hash index."),
+      }
+    }
+
+    problems
+  }
 }
 
 #[deriving(Clone)]
 pub struct HashStoreBackend<B> {
   hash_index: hash_index::HashIndexProcess,
   blob_store: blob_store::BlobStoreProcess<B>,
+  byte_counts: sync::Arc<sync::Mutex<ByteCounts>>,
+  chunk_cipher: Option<ChunkCipher>,
 }
 
 impl <B: blob_store::BlobStoreBackend> HashStoreBackend<B> {
-  fn new(hash_index: hash_index::HashIndexProcess, blob_store: blob_store::BlobStoreProcess<B>)
+  fn new(hash_index: hash_index::HashIndexProcess, blob_store: blob_store::BlobStoreProcess<B>,
+         byte_counts: sync::Arc<sync::Mutex<ByteCounts>>, chunk_cipher: Option<ChunkCipher>)
          -> HashStoreBackend<B> {
-    HashStoreBackend{hash_index: hash_index, blob_store: blob_store}
+    HashStoreBackend{hash_index: hash_index, blob_store: blob_store, byte_counts: byte_counts,
+                     chunk_cipher: chunk_cipher}
+  }
+
+  fn count_bytes(&self, level: i64, len: uint, is_new: bool) {
+    if level > 0 { return }  // Only count user-data, not tree meta-data.
+    let mut counts = self.byte_counts.lock();
+    if is_new { counts.new_bytes += len as u64 }
+    else { counts.dedup_bytes += len as u64 }
   }
 
   fn fetch_chunk_from_hash(&mut self, hash: hash_index::Hash) -> Option<Vec<u8>> {
     assert!(hash.bytes.len() > 0);
-    match self.hash_index.send_reply(hash_index::FetchPersistentRef(hash)) {
+    match self.hash_index.send_reply(hash_index::FetchPersistentRef(hash.clone())) {
       hash_index::PersistentRef(chunk_ref_bytes) => {
         let chunk_ref = blob_store::BlobID::from_bytes(chunk_ref_bytes);
-        self.fetch_chunk_from_persistent_ref(chunk_ref)
+        self.fetch_chunk_from_persistent_ref(&hash, chunk_ref)
       },
       _ => None  // TODO: Do we need to distinguish `missing` from `unknown ref`?
     }
   }
 
-  fn fetch_chunk_from_persistent_ref(&mut self, chunk_ref: blob_store::BlobID) -> Option<Vec<u8>> {
+  fn fetch_chunk_from_persistent_ref(&mut self, hash: &hash_index::Hash,
+                                     chunk_ref: blob_store::BlobID) -> Option<Vec<u8>> {
     match self.blob_store.send_reply(blob_store::Retrieve(chunk_ref)) {
-      blob_store::RetrieveOK(chunk) => Some(chunk),
+      blob_store::RetrieveOK(chunk) => match self.chunk_cipher {
+        Some(ref cipher) => cipher.open(hash.bytes.as_slice(), chunk.as_slice()),
+        None => Some(chunk),
+      },
       _ => None
     }
   }
@@ -144,6 +368,13 @@ impl <B: blob_store::BlobStoreBackend + Clone + Send> HashTreeBackend for HashSt
     }
   }
 
+  fn fetch_chunk_length(&mut self, _hash: hash_index::Hash, persistent_ref: &[u8])
+                        -> Option<uint> {
+    // The reference locates the chunk in its blob, which gives its length:
+    let stored = blob_store::BlobID::from_bytes(persistent_ref.into_vec()).length();
+    Some(if self.chunk_cipher.is_some() { stored - SEAL_OVERHEAD } else { stored })
+  }
+
   fn insert_chunk(&mut self, hash: hash_index::Hash, level: i64, payload: Option<Vec<u8>>,
                   chunk: Vec<u8>) -> Vec<u8> {
     assert!(hash.bytes.len() > 0);
@@ -154,11 +385,17 @@ impl <B: blob_store::BlobStoreBackend + Clone + Send> HashTreeBackend for HashSt
     match self.hash_index.send_reply(hash_index::Reserve(hash_entry.clone())) {
       hash_index::HashKnown => {
         // Someone came before us: piggyback on their result.
+        self.count_bytes(level, chunk.len(), false);
         return self.fetch_persistent_ref(hash).expect(
           "Could not find persistent_ref for known chunk.");
       },
       hash_index::ReserveOK => {
         // We came first: this data-chunk is ours to process.
+        self.count_bytes(level, chunk.len(), true);
+        let chunk = match self.chunk_cipher {
+          Some(ref cipher) => cipher.seal(hash.bytes.as_slice(), chunk.as_slice()),
+          None => chunk,
+        };
         let local_hash_index = self.hash_index.clone();
         let callback = proc(blobid: blob_store::BlobID){
           local_hash_index.send_reply(hash_index::Commit(hash, blobid.as_bytes()));
@@ -179,24 +416,278 @@ impl <B: blob_store::BlobStoreBackend + Clone + Send> HashTreeBackend for HashSt
 
 fn file_size_warning(name: Vec<u8>, wanted: u64, got: u64) {
   if wanted < got {
-    println!("Warning: File grew while reading it: {} (wanted {}, got {})", name, wanted, got)
+    warning!("Warning: File grew while reading it: {} (wanted {}, got {})", name, wanted, got)
   } else if wanted > got {
-    println!("Warning: Could not read whole file (or it shrank): {} (wanted {}, got {})",
+    warning!("Warning: Could not read whole file (or it shrank): {} (wanted {}, got {})",
              name, wanted, got)
   }
 }
 
+impl <KE: KeyEntry<KE> + Clone + Send, IT: Iterator<Vec<u8>> + Send,
+      B: blob_store::BlobStoreBackend + Clone + Send> KeyStore<KE, IT, B> {
+
+  /// Insert the keys of `entries` into the key index. Returns their IDs, in order, and the
+  /// newly inserted entries, whose data is still to be stored (see `insert_data`).
+  fn insert_keys(&mut self, entries: Vec<(KE, Option<proc():Send -> Option<IT>>)>)
+                 -> (Vec<Vec<u8>>, Vec<(KE, Vec<u8>, Option<proc():Send -> Option<IT>>)>) {
+    let mut keys = Vec::with_capacity(entries.len());
+    let mut chunk_its = Vec::with_capacity(entries.len());
+    for (entry, chunk_it_opt) in entries.into_iter() {
+      keys.push(entry);
+      chunk_its.push(chunk_it_opt);
+    }
+
+    let ids = match self.index.send_reply(key_index::InsertBatch(keys.clone())) {
+      key_index::Ids(ids) => ids,
+      _ => fail!("Unexpected reply from key index."),
+    };
+
+    let entry_ids = ids.iter().map(|&(ref id, _)| id.clone()).collect();
+    let mut pending = Vec::new();
+    for ((entry, chunk_it_opt), (id, inserted)) in
+      keys.into_iter().zip(chunk_its.into_iter()).zip(ids.into_iter())
+    {
+      if inserted {
+        pending.push((entry, id, chunk_it_opt));
+      }
+    }
+    (entry_ids, pending)
+  }
+
+  /// Store the data of the keys inserted by `InsertLater`, in the order they were inserted.
+  fn store_deferred(&mut self) {
+    let deferred = mem::replace(&mut self.deferred, Vec::new());
+    for (entry, id, chunk_it_opt) in deferred.into_iter() {
+      self.insert_data(entry, id, chunk_it_opt);
+    }
+  }
+
+  /// The persistent reference that holds `data` (whose hash is `hash`) inline. The data is
+  /// sealed like a data-chunk if chunks are encrypted, so the key index holds none in the clear.
+  fn seal_inline(&self, hash: &[u8], data: &[u8]) -> Vec<u8> {
+    match self.chunk_cipher {
+      Some(ref cipher) => inline_ref(cipher.seal(hash, data).as_slice()),
+      None => inline_ref(data),
+    }
+  }
+
+  /// The data that `persistent_ref` holds inline, if any (see `seal_inline`).
+  fn open_inline(&self, hash: &[u8], persistent_ref: &[u8]) -> Option<Vec<u8>> {
+    inline_data_of(persistent_ref).map(|data| match self.chunk_cipher {
+      Some(ref cipher) => cipher.open(hash, data).unwrap_or_else(|| {
+        fail!("Inline data of hash {} could not be decrypted.", hash.to_hex())
+      }),
+      None => data.into_vec(),
+    })
+  }
+
+  /// Store the data of a newly inserted entry, and record its data hash in the key index once
+  /// the data has been stored.
+  fn insert_data(&mut self, org_entry: KE, id: Vec<u8>,
+                 chunk_it_opt: Option<proc():Send -> Option<IT>>) {
+    // Check if we have an data source:
+    let it_opt = chunk_it_opt.and_then(|p| p());
+    if it_opt.is_none() {
+      // No data is associated with this entry.
+      self.index.send_reply(key_index::UpdateDataHash(org_entry, None, None));
+      // Bail out before storing data that does not exist:
+      return;
+    }
+
+    // Tiny files are stored inline in the key index:
+    let mut it = HashedChunks::new(it_opt.unwrap(), self.hash_pool.clone()).peekable();
+    let (first_hash, first) = it.next().unwrap_or_else(|| {
+      (hash_index::Hash::new(b""), b"".into_vec())
+    });
+    if first.len() < INLINE_THRESHOLD && it.peek().is_none() {
+      // A single data-chunk is the whole hash tree; its hash is the tree's top hash.
+      org_entry.size().map(|s| {
+        file_size_warning(org_entry.name(), s, first.len() as u64);
+      });
+      let persistent_ref = self.seal_inline(first_hash.bytes.as_slice(), first.as_slice());
+      self.index.send_reply(key_index::UpdateDataHash(
+        org_entry.with_id(id), Some(first_hash.bytes), Some(persistent_ref)));
+      return;
+    }
+
+    let mut backend = self.hash_store_backend();
+
+    // Read ahead and compute the top hash of the whole file (without storing anything).
+    // If it is already known, the file is a duplicate and its stored tree can be reused.
+    // Each chunk is hashed once; `HashOnlyBackend` needs only the hash, not a copy of the data:
+    let mut bytes_read = first.len() as u64;
+    let mut complete = false;
+    let mut whole_file = SimpleHashTreeWriter::new(8, HashOnlyBackend);
+    whole_file.append_hashed(first_hash.clone(), Vec::new());
+    let mut pending = vec![(first_hash, first)];
+    while !complete && bytes_read <= WHOLE_FILE_LOOKUP_LIMIT as u64 {
+      match it.next() {
+        Some((hash, chunk)) => {
+          bytes_read += chunk.len() as u64;
+          whole_file.append_hashed(hash.clone(), Vec::new());
+          pending.push((hash, chunk));
+        },
+        None => complete = true,
+      }
+    }
+    let known = if complete {
+      let (hash, _) = whole_file.hash();
+      backend.fetch_persistent_ref(hash.clone()).map(|r| (hash, r))
+    } else { None };
+
+    let (hash, persistent_ref) = match known {
+      Some(hash_and_ref) => {
+        backend.count_bytes(0, bytes_read as uint, false);
+        hash_and_ref
+      },
+      None => {
+        // Read and insert all file chunks:
+        // (see HashStoreBackend::insert_chunk above)
+        let mut tree = SimpleHashTreeWriter::new(8, backend);
+        for (hash, chunk) in pending.into_iter() {
+          tree.append_hashed(hash, chunk);
+        }
+        it.map(|(hash, chunk): (hash_index::Hash, Vec<u8>)| {
+          bytes_read += chunk.len() as u64;
+          tree.append_hashed(hash, chunk);
+        }).last();
+
+        // Get top tree hash:
+        tree.hash()
+      },
+    };
+
+    // Warn the user if we did not read the expected size:
+    org_entry.size().map(|s| { file_size_warning(org_entry.name(), s, bytes_read); });
+
+    // Install a callback for updating the entry's data hash once the data has been stored:
+    let new_entry = org_entry.with_id(id);
+    let local_index = self.index.clone();
+    let hash_bytes = hash.bytes.clone();
+    let callback = proc() {
+      let m = key_index::UpdateDataHash(new_entry, Some(hash_bytes), Some(persistent_ref));
+      local_index.send_reply(m);
+    };
+    self.hash_index.send_reply(hash_index::CallAfterHashIsComitted(hash, callback));
+  }
+
+  /// The data hash and persistent reference of the data of `it`, if that data is already stored
+  /// as a whole. Data small enough to be stored inline needs no storing.
+  fn stored_data(&mut self, it: IT) -> Option<(Vec<u8>, Vec<u8>)> {
+    let mut it = HashedChunks::new(it, self.hash_pool.clone()).peekable();
+    let (first_hash, first) = it.next().unwrap_or_else(|| {
+      (hash_index::Hash::new(b""), b"".into_vec())
+    });
+    if first.len() < INLINE_THRESHOLD && it.peek().is_none() {
+      let persistent_ref = self.seal_inline(first_hash.bytes.as_slice(), first.as_slice());
+      return Some((first_hash.bytes, persistent_ref));
+    }
+
+    let mut whole_file = SimpleHashTreeWriter::new(8, HashOnlyBackend);
+    whole_file.append_hashed(first_hash, Vec::new());
+    for (hash, _) in it {
+      whole_file.append_hashed(hash, Vec::new());
+    }
+    let (hash, _) = whole_file.hash();
+    let mut backend = self.hash_store_backend();
+    backend.fetch_persistent_ref(hash.clone()).map(|r| (hash.bytes, r))
+  }
+}
+
 impl <KE: KeyEntry<KE> + Clone + Send, IT: Iterator<Vec<u8>> + Send,
       B: blob_store::BlobStoreBackend + Clone + Send>
         MsgHandler<Msg<KE, IT>, Reply<B>> for KeyStore<KE, IT, B>
 {
   fn handle(&mut self, msg: Msg<KE, IT>, reply: |Reply<B>|) {
+    // Other requests may depend on the deferred data, e.g. `ListDir` lists its hashes:
+    match msg {
+      Insert(..) | InsertBatch(..) | InsertLater(..) => (),
+      _ => self.store_deferred(),
+    }
+
     match msg {
       Flush => {
         self.flush();
         return reply(FlushOK);
       },
 
+      Maintenance => {
+        self.index.send_reply(key_index::Maintenance);
+        return reply(MaintenanceOK);
+      },
+
+      BeginSnapshot => {
+        match self.index.send_reply(key_index::BeginSnapshot) {
+          key_index::SnapshotId(id) => return reply(SnapshotId(id)),
+          _ => fail!("Unexpected result from key index."),
+        }
+      },
+
+      BeginSnapshotAt(started) => {
+        match self.index.send_reply(key_index::BeginSnapshotAt(started)) {
+          key_index::SnapshotId(id) => return reply(SnapshotId(id)),
+          _ => fail!("Unexpected result from key index."),
+        }
+      },
+
+      PublishSnapshot => {
+        self.index.send_reply(key_index::PublishSnapshot);
+        return reply(PublishOK);
+      },
+
+      SelectSnapshot(id) => {
+        self.index.send_reply(key_index::SelectSnapshot(id));
+        return reply(SelectOK);
+      },
+
+      ListSnapshots => {
+        match self.index.send_reply(key_index::ListSnapshots) {
+          key_index::Snapshots(snapshots) => return reply(Snapshots(snapshots)),
+          _ => fail!("Unexpected result from key index."),
+        }
+      },
+
+      RollBackTo(id) => {
+        match self.index.send_reply(key_index::RollBackTo(id)) {
+          key_index::SnapshotId(copy) => return reply(SnapshotId(copy)),
+          key_index::NotFound => return reply(NotStored),
+          _ => fail!("Unexpected result from key index."),
+        }
+      },
+
+      SelfCheck => {
+        return reply(SelfCheckResult(self.self_check()));
+      },
+
+      FetchByteCounts => {
+        return reply(ByteCountsResult(self.byte_counts.lock().clone()));
+      },
+
+      PlanReads(reads) => {
+        match self.blob_store.send_reply(blob_store::PlanReads(reads)) {
+          blob_store::PlanOK => return reply(PlanOK),
+          _ => fail!("Unexpected reply from blob store."),
+        }
+      },
+
+      ListBlobs(hash, persistent_ref) => {
+        if inline_data_of(persistent_ref.as_slice()).is_some() {
+          return reply(BlobNames(Vec::new()));
+        }
+        let mut backend = self.hash_store_backend();
+        let refs = hash_tree::persistent_refs(&mut backend, hash_index::Hash{bytes: hash},
+                                              persistent_ref);
+        let mut names = Vec::new();
+        for r in refs.into_iter() {
+          match blob_store::BlobID::blob_name_of(r.as_slice()) {
+            Ok(Some(name)) => names.push(name),
+            Ok(None) => (),
+            Err(e) => fail!(e),
+          }
+        }
+        return reply(BlobNames(names));
+      },
+
       ListDir(parent) => {
         match self.index.send_reply(key_index::ListDir(parent)) {
           key_index::ListResult(entries) => {
@@ -207,12 +698,13 @@ impl <KE: KeyEntry<KE> + Clone + Send, IT: Iterator<Vec<u8>> + Send,
               let local_hash = hash_index::Hash{bytes: hash.clone()};
               let local_ref = persistent_ref.clone();
 
+              let reader = match self.open_inline(hash.as_slice(), persistent_ref.as_slice()) {
+                Some(data) => SingleBlock(data),
+                None => SimpleHashTreeReader::new(self.hash_store_backend(), local_hash,
+                                                  local_ref),
+              };
               my_entries.push(
-                (id, name, created, modified, accessed, hash, persistent_ref,
-                   SimpleHashTreeReader::new(
-                     HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone()),
-                     local_hash, local_ref)
-                 ));
+                (id, name, created, modified, accessed, hash, persistent_ref, reader));
             }
             return reply(ListResult(my_entries));
           },
@@ -237,45 +729,74 @@ impl <KE: KeyEntry<KE> + Clone + Send, IT: Iterator<Vec<u8>> + Send,
             // The bounded input-channel will prevent the client from overflowing us.
             reply(Id(id.clone()));
 
-            // Setup hash tree structure
-            let backend = HashStoreBackend::new(self.hash_index.clone(), self.blob_store.clone());
-            let mut tree = SimpleHashTreeWriter::new(8, backend);
-
-            // Check if we have an data source:
-            let it_opt = chunk_it_opt.and_then(|p| p());
-            if it_opt.is_none() {
-              // No data is associated with this entry.
-              self.index.send_reply(key_index::UpdateDataHash(org_entry, None, None));
-              // Bail out before storing data that does not exist:
-              return;
-            }
-
-            // Read and insert all file chunks:
-            // (see HashStoreBackend::insert_chunk above)
-            let mut bytes_read = 0u64;
-            it_opt.unwrap().map(|chunk: Vec<u8>| {
-              bytes_read += chunk.len() as u64;
-              tree.append(chunk);
-            }).last();
-
-            // Warn the user if we did not read the expected size:
-            org_entry.size().map(|s| { file_size_warning(org_entry.name(), s, bytes_read); });
-
-            // Get top tree hash:
-            let (hash, persistent_ref) = tree.hash();
-
-            // Install a callback for updating the entry's data hash once the data has been stored:
-            let new_entry = org_entry.with_id(id);
-            let local_index = self.index.clone();
-            let hash_bytes = hash.bytes.clone();
-            let callback = proc() {
-              let m = key_index::UpdateDataHash(new_entry, Some(hash_bytes), Some(persistent_ref));
-              local_index.send_reply(m);
-            };
-            self.hash_index.send_reply(hash_index::CallAfterHashIsComitted(hash, callback));
+            self.insert_data(org_entry, id, chunk_it_opt);
           }
         }
-      }
+      },
+
+      Seed(org_entry, chunk_it_opt) => {
+        match self.index.send_reply(key_index::LookupExact(org_entry.clone())) {
+          key_index::Id(entry_id) => return reply(Id(entry_id)),
+          _ => (),
+        }
+
+        let data = match chunk_it_opt {
+          None => None,
+          Some(chunk_it) => match chunk_it().and_then(|it| self.stored_data(it)) {
+            Some(data) => Some(data),
+            None => return reply(NotStored),
+          },
+        };
+        let id = match self.index.send_reply(key_index::Insert(org_entry.clone())) {
+          key_index::Id(entry_id) => entry_id,
+          _ => fail!("No ID returned from key index Insert()."),
+        };
+        let (hash, persistent_ref) = match data {
+          Some((hash, persistent_ref)) => (Some(hash), Some(persistent_ref)),
+          None => (None, None),
+        };
+        self.index.send_reply(key_index::UpdateDataHash(org_entry.with_id(id.clone()), hash,
+                                                        persistent_ref));
+        return reply(Id(id));
+      },
+
+      InsertStored(org_entry, hash, persistent_ref) => {
+        match self.index.send_reply(key_index::LookupExact(org_entry.clone())) {
+          key_index::Id(entry_id) => return reply(Id(entry_id)),
+          _ => (),
+        }
+
+        let id = match self.index.send_reply(key_index::Insert(org_entry.clone())) {
+          key_index::Id(entry_id) => entry_id,
+          _ => fail!("No ID returned from key index Insert()."),
+        };
+        self.index.send_reply(key_index::UpdateDataHash(org_entry.with_id(id.clone()),
+                                                        Some(hash), Some(persistent_ref)));
+        return reply(Id(id));
+      },
+
+      InsertBatch(entries) => {
+        let (ids, pending) = self.insert_keys(entries);
+
+        // As with `Insert`, send out the IDs before storing any data:
+        reply(Ids(ids));
+
+        for (entry, id, chunk_it_opt) in pending.into_iter() {
+          self.insert_data(entry, id, chunk_it_opt);
+        }
+      },
+
+      InsertLater(entries) => {
+        let (ids, pending) = self.insert_keys(entries);
+        reply(Ids(ids));
+
+        for entry in pending.into_iter() {
+          self.deferred.push(entry);
+        }
+        if self.deferred.len() >= DEFERRED_KEYS {
+          self.store_deferred();
+        }
+      },
     }
   }
 }
@@ -286,14 +807,20 @@ mod tests {
   use super::*;
 
   use key_index::{KeyEntry};
-  use blob_store::tests::{MemoryBackend, DevNullBackend};
+  use blob_store::{MemoryBackend};
+  use blob_store::tests::{DevNullBackend};
+  use blob_store::{BlobStoreBackend};
+  use keys::{ChunkCipher, RepositoryKey};
+  use hash_index;
   use hash_tree;
+  use super::{INLINE_THRESHOLD, inline_data_of};
 
   use std::rand::{Rng, task_rng};
   use quickcheck::{Config, Testable, gen};
   use quickcheck::{quickcheck_config};
   use process::{Process};
 
+  use std::sync::{Arc, Mutex};
   use test::{Bencher};
 
   // QuickCheck configuration
@@ -540,6 +1067,315 @@ mod tests {
     qcheck(prop);
   }
 
+  #[test]
+  fn byte_counts() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let data = vec![Vec::from_elem(1000, 1u8), Vec::from_elem(500, 2u8)];
+    for name in ["a", "b"].iter() {
+      let entry = KeyEntryStub::new(None, name.as_bytes().into_vec(), Some(data.clone()), None);
+      ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    }
+    ksP.send_reply(Flush);
+
+    match ksP.send_reply(FetchByteCounts) {
+      ByteCountsResult(counts) => {
+        assert_eq!(counts, ByteCounts{new_bytes: 1500, dedup_bytes: 1500});
+      },
+      _ => fail!("Unexpected result from key store."),
+    }
+  }
+
+  #[test]
+  fn insert_batch() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let entries: Vec<KeyEntryStub> = range(0u, 10).map(|i| {
+      KeyEntryStub::new(None, format!("file{}", i).into_bytes(),
+                        Some(vec![Vec::from_elem(300 * i, i as u8)]), None)
+    }).collect();
+
+    let mut batch = Vec::new();
+    for entry in entries.iter() {
+      let local_entry = entry.clone();
+      batch.push((entry.clone(), Some(proc() { Some(local_entry) })));
+    }
+    match ksP.send_reply(InsertBatch(batch)) {
+      Ids(ids) => assert_eq!(ids, entries.iter().map(|e| e.id.clone()).collect()),
+      _ => fail!("Unexpected result from key store."),
+    }
+    ksP.send_reply(Flush);
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), entries.len());
+    for (_, name, _, _, _, _, _, reader) in listing.into_iter() {
+      let entry = entries.iter().find(|e| e.name == name).expect("listed entry was inserted");
+      let data = match reader {
+        hash_tree::NoData => vec![],
+        hash_tree::SingleBlock(chunk) => vec![chunk],
+        hash_tree::Tree(mut it) => it.collect(),
+      };
+      assert_eq!(Some(data), entry.data);
+    }
+  }
+
+  /// An entry whose name is added to `order` when its data is read.
+  fn recording_entry(name: &str, order: &Arc<Mutex<Vec<Vec<u8>>>>)
+                     -> (KeyEntryStub, Option<proc():Send -> Option<KeyEntryStub>>) {
+    let entry = KeyEntryStub::new(None, name.as_bytes().into_vec(),
+                                  Some(vec![Vec::from_elem(1000, 1u8)]), None);
+    let (local_entry, local_order) = (entry.clone(), order.clone());
+    (entry, Some(proc() {
+      local_order.lock().push(local_entry.name.clone());
+      Some(local_entry)
+    }))
+  }
+
+  #[test]
+  fn deferred_data_is_stored_last() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+    let order = Arc::new(Mutex::new(Vec::new()));
+
+    match ksP.send_reply(InsertLater(vec![recording_entry("later", &order)])) {
+      Ids(ids) => assert_eq!(ids.len(), 1),
+      _ => fail!("Unexpected result from key store."),
+    }
+    let (entry, data) = recording_entry("first", &order);
+    ksP.send_reply(Insert(entry, data));
+    ksP.send_reply(Flush);
+    assert_eq!(*order.lock(), vec![b"first".into_vec(), b"later".into_vec()]);
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 2);
+    assert!(listing.iter().all(|&(_, _, _, _, _, ref hash, _, _)| hash.len() > 0));
+  }
+
+  #[test]
+  fn encrypted_chunks() {
+    let backend = MemoryBackend::new();
+    let mut local_backend = backend.clone();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() {
+        let mut ks = KeyStore::new_for_testing(backend);
+        ks.set_chunk_cipher(ChunkCipher::new(&RepositoryKey::new(b"shared secret")));
+        ks
+      });
+
+    let data = vec![Vec::from_elem(1000, 1u8), Vec::from_elem(500, 2u8)];
+    let entry = KeyEntryStub::new(None, b"a".into_vec(), Some(data.clone()), None);
+    ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    ksP.send_reply(Flush);
+
+    // The data is not stored in the clear:
+    let plain = Vec::from_elem(100, 1u8);
+    for name in local_backend.list().unwrap().iter() {
+      let blob = local_backend.retrieve(name.as_slice()).unwrap();
+      assert!(!blob.as_slice().windows(plain.len()).any(|w| w == plain.as_slice()));
+    }
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    for (_, _, _, _, _, _, _, reader) in listing.into_iter() {
+      match reader {
+        hash_tree::Tree(mut it) => assert_eq!(it.collect::<Vec<Vec<u8>>>(), data),
+        _ => fail!("Expected a hash tree."),
+      }
+    }
+  }
+
+  #[test]
+  fn tiny_files_are_stored_inline() {
+    let backend = MemoryBackend::new();
+    let mut local_backend = backend.clone();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let tiny = vec![Vec::from_elem(INLINE_THRESHOLD - 1, 1u8)];
+    let entry = KeyEntryStub::new(None, b"tiny".into_vec(), Some(tiny.clone()), None);
+    ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    ksP.send_reply(Flush);
+    assert_eq!(local_backend.list(), Ok(vec![]));
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    for (_, _, _, _, _, hash, persistent_ref, reader) in listing.into_iter() {
+      assert_eq!(hash, hash_index::Hash::new(tiny[0].as_slice()).bytes);
+      assert_eq!(inline_data_of(persistent_ref.as_slice()), Some(tiny[0].as_slice()));
+      match reader {
+        hash_tree::SingleBlock(block) => assert_eq!(vec![block], tiny),
+        _ => fail!("Expected inline data."),
+      }
+    }
+
+    // Files of the threshold size go to blobs:
+    let small = vec![Vec::from_elem(INLINE_THRESHOLD, 2u8)];
+    let entry = KeyEntryStub::new(None, b"small".into_vec(), Some(small), None);
+    ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    ksP.send_reply(Flush);
+    assert_eq!(local_backend.list().unwrap().len(), 1);
+  }
+
+  #[test]
+  fn encrypted_inline_data() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() {
+        let mut ks = KeyStore::new_for_testing(backend);
+        ks.set_chunk_cipher(ChunkCipher::new(&RepositoryKey::new(b"shared secret")));
+        ks
+      });
+
+    let data = vec![b"a tiny secret".into_vec()];
+    let entry = KeyEntryStub::new(None, b"a".into_vec(), Some(data.clone()), None);
+    ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    ksP.send_reply(Flush);
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 1);
+    for (_, _, _, _, _, _, persistent_ref, reader) in listing.into_iter() {
+      // The key index only holds the sealed data:
+      let plain = b"secret";
+      assert!(!persistent_ref.as_slice().windows(plain.len()).any(|w| w == plain));
+      match reader {
+        hash_tree::SingleBlock(block) => assert_eq!(vec![block], data),
+        _ => fail!("Expected inline data."),
+      }
+    }
+  }
+
+  #[test]
+  fn duplicate_file_reuses_tree() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let data = vec![Vec::from_elem(1000, 1u8), Vec::from_elem(500, 2u8)];
+    for name in ["a", "b"].iter() {
+      let entry = KeyEntryStub::new(None, name.as_bytes().into_vec(), Some(data.clone()), None);
+      ksP.send_reply(Insert(entry.clone(), Some(proc() { Some(entry) })));
+    }
+    ksP.send_reply(Flush);
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 2);
+    let refs: Vec<(Vec<u8>, Vec<u8>)> = listing.iter().map(
+      |&(_, _, _, _, _, ref hash, ref persistent_ref, _)| (hash.clone(), persistent_ref.clone())
+    ).collect();
+    assert_eq!(refs[0], refs[1]);
+
+    for (_, _, _, _, _, _, _, reader) in listing.into_iter() {
+      match reader {
+        hash_tree::Tree(mut it) => assert_eq!(it.collect::<Vec<Vec<u8>>>(), data),
+        _ => fail!("Expected a hash tree."),
+      }
+    }
+  }
+
+  #[test]
+  fn seed_records_only_stored_data() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let data = vec![Vec::from_elem(1000, 1u8), Vec::from_elem(500, 2u8)];
+    let stored = KeyEntryStub::new(None, b"stored".into_vec(), Some(data.clone()), None);
+    let local_stored = stored.clone();
+    ksP.send_reply(Insert(stored, Some(proc() { Some(local_stored) })));
+    ksP.send_reply(Flush);
+
+    let copy = KeyEntryStub::new(None, b"copy".into_vec(), Some(data.clone()), None);
+    let local_copy = copy.clone();
+    match ksP.send_reply(Seed(copy.clone(), Some(proc() { Some(local_copy) }))) {
+      Id(id) => assert_eq!(id, copy.id),
+      _ => fail!("Data that is stored was not seeded."),
+    }
+
+    let other = KeyEntryStub::new(None, b"other".into_vec(),
+                                  Some(vec![Vec::from_elem(1000, 3u8)]), None);
+    let local_other = other.clone();
+    match ksP.send_reply(Seed(other, Some(proc() { Some(local_other) }))) {
+      NotStored => (),
+      _ => fail!("Data that is not stored was seeded."),
+    }
+    ksP.send_reply(Flush);
+
+    match ksP.send_reply(FetchByteCounts) {
+      ByteCountsResult(counts) => assert_eq!(counts.new_bytes, 1500),
+      _ => fail!("Unexpected result from key store."),
+    }
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    let mut names: Vec<Vec<u8>> = listing.iter().map(|&(_, ref name, _, _, _, _, _, _)| {
+      name.clone()
+    }).collect();
+    names.sort();
+    assert_eq!(names, vec![b"copy".into_vec(), b"stored".into_vec()]);
+  }
+
+  #[test]
+  fn insert_stored_reads_nothing() {
+    let backend = MemoryBackend::new();
+    let ksP : KeyStoreProcess<KeyEntryStub, KeyEntryStub, MemoryBackend>
+      = Process::new(proc() { KeyStore::new_for_testing(backend) });
+
+    let data = vec![Vec::from_elem(1000, 1u8), Vec::from_elem(500, 2u8)];
+    let stored = KeyEntryStub::new(None, b"stored".into_vec(), Some(data.clone()), None);
+    let local_stored = stored.clone();
+    ksP.send_reply(Insert(stored, Some(proc() { Some(local_stored) })));
+    ksP.send_reply(Flush);
+
+    let (hash, persistent_ref) = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => {
+        let (_, _, _, _, _, ref hash, ref persistent_ref, _) = ls[0];
+        (hash.clone(), persistent_ref.clone())
+      },
+      _ => fail!("Unexpected result from key store."),
+    };
+    // The copy has no data of its own to read:
+    let copy = KeyEntryStub::new(None, b"copy".into_vec(), None, None);
+    match ksP.send_reply(InsertStored(copy.clone(), hash, persistent_ref)) {
+      Id(id) => assert_eq!(id, copy.id),
+      _ => fail!("Unexpected result from key store."),
+    }
+    ksP.send_reply(Flush);
+
+    let listing = match ksP.send_reply(ListDir(None)) {
+      ListResult(ls) => ls,
+      _ => fail!("Unexpected result from key store."),
+    };
+    assert_eq!(listing.len(), 2);
+    for (_, _, _, _, _, _, _, reader) in listing.into_iter() {
+      match reader {
+        hash_tree::Tree(mut it) => assert_eq!(it.collect::<Vec<Vec<u8>>>(), data),
+        _ => fail!("Expected a hash tree."),
+      }
+    }
+  }
+
 
   #[bench]
   fn insert_1_key_x_128000_zeros(bench: &mut Bencher) {
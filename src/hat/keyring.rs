@@ -0,0 +1,256 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Passphrases that unlock the secret of a repository.
+//!
+//! A keyring holds the secret (what a key file holds, see `keys::RepositoryKey`) sealed once per
+//! passphrase, each in a slot of its own. Changing a passphrase re-seals only its slot: the
+//! secret, and all data encrypted with keys derived from it, stay as they are. Replacing the
+//! secret itself is a different and much slower operation. Several passphrases can unlock the
+//! same keyring, e.g. one for each person with access.
+//!
+//! Passphrases are stretched with PBKDF2 (HMAC-SHA-512-256) and a random salt per slot, so that
+//! guessing them from a copy of the keyring is slow.
+
+use serialize::{json, Decodable};
+use serialize::hex::{FromHex, ToHex};
+use keys::{RepositoryKey};
+
+use sodiumoxide::crypto::auth;
+use sodiumoxide::crypto::hash::{sha256};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes::{randombytes};
+
+use std::io::{File, IoError, IoResult, InvalidInput, UserDir, UserRead, UserWrite};
+use std::io::fs::{chmod, mkdir_recursive, rename};
+use std::slice::bytes::{copy_memory};
+use std::str;
+
+
+/// PBKDF2 rounds for new slots.
+pub static DEFAULT_ROUNDS: uint = 100000;
+
+static SALT_BYTES: uint = 16;
+
+#[deriving(Clone, Encodable, Decodable)]
+struct Slot {
+  label: String,
+  salt: String,
+  rounds: uint,
+
+  /// The nonce followed by the sealed secret.
+  sealed: String,
+}
+
+#[deriving(Clone, Encodable, Decodable)]
+pub struct Keyring {
+  /// Identifies the secret without revealing it, so that all slots hold the same one.
+  check: String,
+  slots: Vec<Slot>,
+}
+
+fn invalid(detail: &str) -> IoError {
+  IoError{kind: InvalidInput, desc: "Invalid keyring", detail: Some(detail.to_string())}
+}
+
+/// The key that seals a slot, derived from its passphrase.
+fn passphrase_key(passphrase: &[u8], salt: &[u8], rounds: uint) -> secretbox::Key {
+  let sha256::Digest(digest) = sha256::hash(passphrase);
+  let prf_key = auth::Key(digest);
+  let mut block = salt.to_vec();
+  block.push_all([0, 0, 0, 1]);
+  let auth::Tag(mut u) = auth::authenticate(block.as_slice(), &prf_key);
+  let mut derived = u;
+  for _ in range(1, rounds) {
+    let auth::Tag(next) = auth::authenticate(u.as_slice(), &prf_key);
+    u = next;
+    for (d, b) in derived.iter_mut().zip(u.iter()) {
+      *d ^= *b;
+    }
+  }
+  let mut bytes = [0u8, ..secretbox::KEYBYTES];
+  copy_memory(bytes, derived.slice_to(secretbox::KEYBYTES));
+  secretbox::Key(bytes)
+}
+
+impl Slot {
+  fn seal(label: &str, passphrase: &[u8], secret: &[u8], rounds: uint) -> Slot {
+    let salt = randombytes(SALT_BYTES);
+    let key = passphrase_key(passphrase, salt.as_slice(), rounds);
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, randombytes(secretbox::NONCEBYTES).as_slice());
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.push_all(secretbox::seal(secret, &secretbox::Nonce(nonce_bytes), &key).as_slice());
+    Slot{label: label.to_string(), salt: salt.as_slice().to_hex(), rounds: rounds,
+         sealed: sealed.as_slice().to_hex()}
+  }
+
+  fn open(&self, passphrase: &[u8]) -> Option<Vec<u8>> {
+    let decoded = (self.salt.as_slice().from_hex(), self.sealed.as_slice().from_hex());
+    let (salt, sealed) = match decoded {
+      (Ok(salt), Ok(sealed)) => (salt, sealed),
+      _ => return None,
+    };
+    if sealed.len() < secretbox::NONCEBYTES {
+      return None;
+    }
+    let key = passphrase_key(passphrase, salt.as_slice(), self.rounds);
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, sealed.slice_to(secretbox::NONCEBYTES));
+    secretbox::open(sealed.slice_from(secretbox::NONCEBYTES), &secretbox::Nonce(nonce_bytes),
+                    &key)
+  }
+}
+
+impl Keyring {
+  pub fn new() -> Keyring {
+    Keyring{check: String::new(), slots: vec![]}
+  }
+
+  /// Check that `secret` is the one in the slots, or remember it for the first slot.
+  fn check_secret(&mut self, secret: &[u8]) -> Result<(), String> {
+    let check = RepositoryKey::new(secret).derive("keyring check").as_slice().to_hex();
+    if self.slots.len() == 0 {
+      self.check = check;
+    } else if self.check != check {
+      return Err("This is not the secret that the keyring holds.".to_string());
+    }
+    Ok(())
+  }
+
+  pub fn load(path: &Path) -> IoResult<Keyring> {
+    let text = try!(File::open(path).and_then(|mut f| f.read_to_end()));
+    let json = match str::from_utf8(text.as_slice()).and_then(|s| json::from_str(s).ok()) {
+      Some(json) => json,
+      None => return Err(invalid("not JSON")),
+    };
+    match Decodable::decode(&mut json::Decoder::new(json)) {
+      Ok(keyring) => Ok(keyring),
+      Err(_) => Err(invalid("unexpected contents")),
+    }
+  }
+
+  /// Write the keyring to `path`, readable only by its owner. The file is replaced in one step,
+  /// so a crash leaves either the old or the new keyring. Missing directories are created.
+  pub fn save(&self, path: &Path) -> IoResult<()> {
+    try!(mkdir_recursive(&path.dir_path(), UserDir));
+    let tmp = path.with_extension("tmp");
+    try!(File::create(&tmp).and_then(|mut file| {
+      try!(chmod(&tmp, UserRead | UserWrite));
+      try!(file.write(json::encode(self).as_bytes()));
+      file.fsync()
+    }));
+    rename(&tmp, path)
+  }
+
+  pub fn labels(&self) -> Vec<String> {
+    self.slots.iter().map(|slot| slot.label.clone()).collect()
+  }
+
+  /// The label of the slot that `passphrase` opens, and the secret.
+  pub fn unlock(&self, passphrase: &[u8]) -> Option<(String, Vec<u8>)> {
+    self.slots.iter().filter_map(|slot| {
+      slot.open(passphrase).map(|secret| (slot.label.clone(), secret))
+    }).next()
+  }
+
+  /// Add a slot that lets `passphrase` unlock `secret`.
+  pub fn add(&mut self, label: &str, passphrase: &[u8], secret: &[u8]) -> Result<(), String> {
+    if self.slots.iter().any(|slot| slot.label.as_slice() == label) {
+      return Err(format!("The keyring already has a passphrase labeled '{}'.", label));
+    }
+    if self.slots.iter().any(|slot| slot.open(passphrase).is_some()) {
+      return Err("The keyring already has this passphrase.".to_string());
+    }
+    try!(self.check_secret(secret));
+    self.slots.push(Slot::seal(label, passphrase, secret, DEFAULT_ROUNDS));
+    Ok(())
+  }
+
+  /// Replace the passphrase of slot `label` with `passphrase`.
+  pub fn change(&mut self, label: &str, passphrase: &[u8], secret: &[u8])
+                -> Result<(), String> {
+    try!(self.check_secret(secret));
+    match self.slots.iter_mut().find(|slot| slot.label.as_slice() == label) {
+      Some(slot) => {
+        *slot = Slot::seal(label, passphrase, secret, DEFAULT_ROUNDS);
+        Ok(())
+      },
+      None => Err(format!("The keyring has no passphrase labeled '{}'.", label)),
+    }
+  }
+
+  /// Remove slot `label`. The last slot cannot be removed, since nothing could unlock the secret.
+  pub fn remove(&mut self, label: &str) -> Result<(), String> {
+    let before = self.slots.len();
+    if before == 1 && self.slots[0].label.as_slice() == label {
+      return Err("The last passphrase of a keyring cannot be removed.".to_string());
+    }
+    self.slots.retain(|slot| slot.label.as_slice() != label);
+    if self.slots.len() == before {
+      return Err(format!("The keyring has no passphrase labeled '{}'.", label));
+    }
+    Ok(())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io::{TempDir};
+
+  #[test]
+  fn passphrases_share_the_secret() {
+    let mut keyring = Keyring::new();
+    keyring.add("alice", b"first", b"secret").unwrap();
+    keyring.add("bob", b"second", b"secret").unwrap();
+    assert!(keyring.add("alice", b"third", b"secret").is_err());
+    assert!(keyring.add("carol", b"second", b"secret").is_err());
+    assert!(keyring.add("carol", b"third", b"other secret").is_err());
+
+    assert_eq!(keyring.unlock(b"first"), Some(("alice".to_string(), b"secret".to_vec())));
+    assert_eq!(keyring.unlock(b"second"), Some(("bob".to_string(), b"secret".to_vec())));
+    assert_eq!(keyring.unlock(b"wrong"), None);
+  }
+
+  #[test]
+  fn change_and_remove() {
+    let mut keyring = Keyring::new();
+    keyring.add("alice", b"first", b"secret").unwrap();
+    keyring.change("alice", b"new", b"secret").unwrap();
+    assert_eq!(keyring.unlock(b"first"), None);
+    assert_eq!(keyring.unlock(b"new"), Some(("alice".to_string(), b"secret".to_vec())));
+    assert!(keyring.change("bob", b"other", b"secret").is_err());
+
+    assert!(keyring.remove("alice").is_err());
+    keyring.add("bob", b"second", b"secret").unwrap();
+    keyring.remove("alice").unwrap();
+    assert!(keyring.remove("alice").is_err());
+    assert_eq!(keyring.labels(), vec!["bob".to_string()]);
+  }
+
+  #[test]
+  fn save_and_load() {
+    let dir = TempDir::new("keyring").unwrap();
+    let path = dir.path().join("keys").join("keyring");
+    let mut keyring = Keyring::new();
+    keyring.add("alice", b"first", b"secret").unwrap();
+    keyring.save(&path).unwrap();
+
+    let loaded = Keyring::load(&path).unwrap();
+    assert_eq!(loaded.unlock(b"first"), Some(("alice".to_string(), b"secret".to_vec())));
+  }
+}
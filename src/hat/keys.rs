@@ -0,0 +1,297 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Repository key material and derivation of purpose-specific sub-keys.
+
+use serialize::hex::{ToHex};
+use sodiumoxide::crypto::auth;
+use sodiumoxide::crypto::hash::{sha256};
+use sodiumoxide::crypto::secretbox;
+use sodiumoxide::randombytes::{randombytes};
+
+use sqlite3::database::{Database};
+use sqlite3::types::{SQLITE_ROW};
+
+use std::io::{File, IoResult};
+use std::slice::bytes::{copy_memory};
+
+
+/// The secret that all of a repository's encryption keys are derived from.
+#[deriving(Clone)]
+pub struct RepositoryKey {
+  key: auth::Key,
+}
+
+impl RepositoryKey {
+
+  /// Create a repository key from arbitrary secret bytes.
+  pub fn new(secret: &[u8]) -> RepositoryKey {
+    let sha256::Digest(digest) = sha256::hash(secret);
+    RepositoryKey{key: auth::Key(digest)}
+  }
+
+  /// Read the secret bytes of a repository key from `path`.
+  pub fn from_file(path: &Path) -> IoResult<RepositoryKey> {
+    File::open(path).and_then(|mut f| f.read_to_end()).map(|secret| {
+      RepositoryKey::new(secret.as_slice())
+    })
+  }
+
+  /// Derive a 32-byte sub-key for `purpose`. Distinct purposes never share key material.
+  pub fn derive(&self, purpose: &str) -> Vec<u8> {
+    let auth::Tag(tag) = auth::authenticate(purpose.as_bytes(), &self.key);
+    tag.slice(0, auth::TAGBYTES).into_vec()
+  }
+}
+
+
+/// The number of bytes that `ChunkCipher::seal` adds to a chunk: its nonce and authenticator.
+pub static SEAL_OVERHEAD: uint = secretbox::NONCEBYTES + 16;
+
+/// Convergent encryption of data-chunks.
+///
+/// The key of a chunk is derived from its hash under a repository secret, and its nonce from its
+/// contents, so a chunk always encrypts to the same bytes. Clients that share the secret (e.g.
+/// tenants of a shared repository) therefore still deduplicate against each other, while the
+/// blob backend only sees encrypted data.
+///
+/// The trade-off: anyone who holds the secret can tell whether a file they know is stored, by
+/// encrypting it and looking for the result; and the backend can tell when the same chunk is
+/// stored twice. Repositories that need neither deduplication across clients nor protection from
+/// the backend should not share the secret.
+#[deriving(Clone)]
+pub struct ChunkCipher {
+  key: auth::Key,
+}
+
+impl ChunkCipher {
+  pub fn new(key: &RepositoryKey) -> ChunkCipher {
+    let derived = key.derive("chunks");
+    let mut bytes = [0u8, ..auth::KEYBYTES];
+    copy_memory(bytes, derived.as_slice());
+    ChunkCipher{key: auth::Key(bytes)}
+  }
+
+  /// A value that identifies the secret without revealing it, to detect a wrong one.
+  pub fn check_value(&self) -> i64 {
+    let auth::Tag(tag) = auth::authenticate(b"check", &self.key);
+    tag.iter().take(8).fold(0i64, |n, &b| (n << 8) | b as i64)
+  }
+
+  /// Derive a sub-key of the chunk with hash `hash` for `purpose`. Like the repository's own
+  /// sub-keys, the encryption key and the nonce key of a chunk never share key material.
+  fn chunk_subkey(&self, hash: &[u8], purpose: &str) -> [u8, ..auth::KEYBYTES] {
+    let auth::Tag(chunk_secret) = auth::authenticate(hash, &self.key);
+    let mut secret_bytes = [0u8, ..auth::KEYBYTES];
+    copy_memory(secret_bytes, chunk_secret.slice_to(auth::KEYBYTES));
+    let auth::Tag(tag) = auth::authenticate(purpose.as_bytes(), &auth::Key(secret_bytes));
+    let mut bytes = [0u8, ..auth::KEYBYTES];
+    copy_memory(bytes, tag.slice_to(auth::KEYBYTES));
+    bytes
+  }
+
+  fn chunk_key(&self, hash: &[u8]) -> secretbox::Key {
+    let mut bytes = [0u8, ..secretbox::KEYBYTES];
+    copy_memory(bytes, self.chunk_subkey(hash, "chunk_key").slice_to(secretbox::KEYBYTES));
+    secretbox::Key(bytes)
+  }
+
+  /// Encrypt `chunk`, whose hash is `hash`. The nonce is prepended to the encrypted data.
+  pub fn seal(&self, hash: &[u8], chunk: &[u8]) -> Vec<u8> {
+    let nonce_key = auth::Key(self.chunk_subkey(hash, "chunk_nonce"));
+    let auth::Tag(tag) = auth::authenticate(chunk, &nonce_key);
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, tag.slice_to(secretbox::NONCEBYTES));
+
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.push_all(secretbox::seal(chunk, &secretbox::Nonce(nonce_bytes),
+                                    &self.chunk_key(hash)).as_slice());
+    sealed
+  }
+
+  /// Decrypt a chunk sealed by `seal`. Returns `None` if it was not sealed with this secret and
+  /// `hash`, or was modified since.
+  pub fn open(&self, hash: &[u8], sealed: &[u8]) -> Option<Vec<u8>> {
+    if sealed.len() < SEAL_OVERHEAD {
+      return None;
+    }
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, sealed.slice_to(secretbox::NONCEBYTES));
+    secretbox::open(sealed.slice_from(secretbox::NONCEBYTES), &secretbox::Nonce(nonce_bytes),
+                    &self.chunk_key(hash))
+  }
+}
+
+
+/// Encryption of blobs and their names, so that the blob backend learns neither (see
+/// `blob_store::EncryptedBackend`).
+///
+/// Each blob is sealed with a random nonce. A name must seal to the same bytes every time to be
+/// found again, so its nonce is derived from the name itself: the backend can tell that a name is
+/// used again, but not what it is.
+#[deriving(Clone)]
+pub struct BlobCipher {
+  key: secretbox::Key,
+  name_key: secretbox::Key,
+  name_nonce_key: auth::Key,
+}
+
+fn secretbox_key(key: &RepositoryKey, purpose: &str) -> secretbox::Key {
+  let mut bytes = [0u8, ..secretbox::KEYBYTES];
+  copy_memory(bytes, key.derive(purpose).as_slice());
+  secretbox::Key(bytes)
+}
+
+fn open_with(key: &secretbox::Key, sealed: &[u8]) -> Option<Vec<u8>> {
+  if sealed.len() < SEAL_OVERHEAD {
+    return None;
+  }
+  let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+  copy_memory(nonce_bytes, sealed.slice_to(secretbox::NONCEBYTES));
+  secretbox::open(sealed.slice_from(secretbox::NONCEBYTES), &secretbox::Nonce(nonce_bytes), key)
+}
+
+impl BlobCipher {
+  pub fn new(key: &RepositoryKey) -> BlobCipher {
+    let mut nonce_key = [0u8, ..auth::KEYBYTES];
+    copy_memory(nonce_key, key.derive("blob_name_nonces").as_slice());
+    BlobCipher{key: secretbox_key(key, "blobs"),
+               name_key: secretbox_key(key, "blob_names"),
+               name_nonce_key: auth::Key(nonce_key)}
+  }
+
+  /// A value that identifies the secret without revealing it, to detect a wrong one.
+  pub fn check_value(&self) -> i64 {
+    let auth::Tag(tag) = auth::authenticate(b"check", &self.name_nonce_key);
+    tag.iter().take(8).fold(0i64, |n, &b| (n << 8) | b as i64)
+  }
+
+  /// Encrypt `blob` under a random nonce, which is prepended to the encrypted data.
+  pub fn seal(&self, blob: &[u8]) -> Vec<u8> {
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, randombytes(secretbox::NONCEBYTES).as_slice());
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.push_all(secretbox::seal(blob, &secretbox::Nonce(nonce_bytes), &self.key).as_slice());
+    sealed
+  }
+
+  /// Decrypt a blob sealed by `seal`. Returns `None` if it was not sealed with this secret, or
+  /// was modified since.
+  pub fn open(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+    open_with(&self.key, sealed)
+  }
+
+  /// Encrypt the blob name `name`. The same name always seals to the same bytes.
+  pub fn seal_name(&self, name: &[u8]) -> Vec<u8> {
+    let auth::Tag(tag) = auth::authenticate(name, &self.name_nonce_key);
+    let mut nonce_bytes = [0u8, ..secretbox::NONCEBYTES];
+    copy_memory(nonce_bytes, tag.slice_to(secretbox::NONCEBYTES));
+    let mut sealed = nonce_bytes.to_vec();
+    sealed.push_all(secretbox::seal(name, &secretbox::Nonce(nonce_bytes),
+                                    &self.name_key).as_slice());
+    sealed
+  }
+
+  /// Decrypt a blob name sealed by `seal_name`. Returns `None` for names that were not.
+  pub fn open_name(&self, sealed: &[u8]) -> Option<Vec<u8>> {
+    open_with(&self.name_key, sealed)
+  }
+}
+
+
+/// Apply an SQLCipher key to a freshly opened database.
+///
+/// This fails if sqlite was built without SQLCipher, since the database would otherwise silently
+/// stay unencrypted, and if the key does not match an existing database.
+pub fn unlock_database(dbh: &mut Database, key: &[u8]) -> Result<(), String> {
+  match dbh.exec(format!("PRAGMA key = \"x'{}'\"", key.to_hex()).as_slice()) {
+    Ok(true) => (),
+    _ => return Err(format!("Could not set database key: {}", dbh.get_errmsg())),
+  }
+
+  let has_cipher = match dbh.prepare("PRAGMA cipher_version", &None) {
+    Ok(mut cursor) => cursor.step() == SQLITE_ROW,
+    Err(_) => false,
+  };
+  if !has_cipher {
+    return Err("Index encryption requires sqlite to be built with SQLCipher.".to_string());
+  }
+
+  // The key is only checked once the database is first read:
+  match dbh.exec("SELECT COUNT(*) FROM sqlite_master") {
+    Ok(true) => Ok(()),
+    _ => Err("Wrong key, or the index is not encrypted.".to_string()),
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use sodiumoxide::crypto::{auth, secretbox};
+  use sqlite3::{open};
+
+  use std::io::{TempDir};
+
+  #[test]
+  fn derived_keys() {
+    let key = RepositoryKey::new(b"secret");
+    assert_eq!(key.derive("hash_index").len(), 32);
+    assert_eq!(key.derive("hash_index"), RepositoryKey::new(b"secret").derive("hash_index"));
+    assert!(key.derive("hash_index") != key.derive("key_index"));
+    assert!(key.derive("hash_index") != RepositoryKey::new(b"other").derive("hash_index"));
+  }
+
+  #[test]
+  fn chunks_seal_convergently() {
+    let cipher = ChunkCipher::new(&RepositoryKey::new(b"secret"));
+    let sealed = cipher.seal(b"hash", b"chunk");
+    assert_eq!(sealed.len(), b"chunk".len() + SEAL_OVERHEAD);
+    assert_eq!(sealed, ChunkCipher::new(&RepositoryKey::new(b"secret")).seal(b"hash", b"chunk"));
+    assert_eq!(cipher.open(b"hash", sealed.as_slice()), Some(b"chunk".into_vec()));
+
+    // The nonce is not derived with the encryption key:
+    let secretbox::Key(key_bytes) = cipher.chunk_key(b"hash");
+    let auth::Tag(tag) = auth::authenticate(b"chunk", &auth::Key(key_bytes));
+    assert!(sealed.slice_to(secretbox::NONCEBYTES) != tag.slice_to(secretbox::NONCEBYTES));
+
+    assert_eq!(cipher.open(b"other", sealed.as_slice()), None);
+    let other = ChunkCipher::new(&RepositoryKey::new(b"other"));
+    assert_eq!(other.open(b"hash", sealed.as_slice()), None);
+  }
+
+  #[test]
+  fn database_keys() {
+    let dir = TempDir::new("hat-keys").unwrap();
+    let path = dir.path().join("index.sqlite3");
+    let path = path.as_str().unwrap();
+    let key = RepositoryKey::new(b"secret").derive("index");
+    {
+      let mut dbh = open(path).unwrap();
+      match unlock_database(&mut dbh, key.as_slice()) {
+        // Without SQLCipher the index is refused rather than left unencrypted:
+        Err(ref e) if e.as_slice().contains("SQLCipher") => return,
+        result => assert_eq!(result, Ok(())),
+      }
+      assert!(dbh.exec("CREATE TABLE t (x INTEGER); INSERT INTO t VALUES (1)").is_ok());
+    }
+
+    let mut dbh = open(path).unwrap();
+    assert_eq!(unlock_database(&mut dbh, key.as_slice()), Ok(()));
+    let mut dbh = open(path).unwrap();
+    let wrong = RepositoryKey::new(b"other").derive("index");
+    assert!(unlock_database(&mut dbh, wrong.as_slice()).is_err());
+  }
+}
@@ -24,6 +24,7 @@
 // Standard Rust imports
 extern crate debug;
 extern crate libc;
+extern crate regex;
 extern crate serialize;
 extern crate test;
 extern crate time;
@@ -37,23 +38,57 @@ extern crate sqlite3;
 extern crate quickcheck;
 
 pub use blob_index::{BlobIndex};
-pub use blob_store::{BlobStore};
+pub use blob_store::{BlobStore, BoxedBackend};
 pub use hash_index::{HashIndex};
 pub use key_index::{KeyIndex};
 pub use key_store::{KeyStore};
 pub use process::{Process};
 
 
+// First, so that the other modules can use its macros:
+pub mod output;
+
 mod callback_container;
 mod cumulative_counter;
 mod ordered_collection;
 mod periodic_timer;
 mod unique_priority_queue;
 
+pub mod buffer_pool;
+pub mod bundle;
+pub mod cache;
+pub mod cancel;
+pub mod chunker;
+pub mod crash_test;
+pub mod curl;
+pub mod diff;
+pub mod exec;
+pub mod fifo;
+pub mod fingerprint;
+pub mod grep;
+pub mod keyring;
+pub mod keys;
 pub mod listdir;
+pub mod manifest;
+pub mod mirror;
+pub mod niceness;
+pub mod nofollow;
+pub mod notify;
 pub mod process;
+pub mod proxy;
+pub mod reflink;
+pub mod rest;
+pub mod retry;
+pub mod s3;
+pub mod sftp;
+pub mod tar;
+pub mod throttle;
+pub mod timestamp;
+pub mod volume_snapshot;
+pub mod webdav;
 
 pub mod hash_index;
+pub mod hash_pool;
 pub mod hash_tree;
 
 pub mod blob_index;
@@ -15,15 +15,21 @@
 //! Helpers for reading directory structures from the local filesystem.
 
 use std::sync;
+use std::os;
 use std::os::{last_os_error};
-use std::io::{TypeDirectory};
+use std::io::{FileStat, TypeDirectory, TypeFile};
 use std::io::fs::{lstat};
 
 use std::c_str::CString;
 use libc::funcs::posix88::dirent;
 use libc::types::common::posix88::{DIR,dirent_t};
 use libc::types::os::arch::c95::c_char;
-use libc::{c_int};
+use libc::{c_int, c_long, c_ulong, c_void, size_t};
+use libc::funcs::posix88::fcntl;
+use libc::funcs::posix88::unistd::{close, read};
+use libc::consts::os::posix88::{O_RDONLY, O_NONBLOCK};
+
+use nofollow::{O_NOFOLLOW};
 
 
 pub struct DirIterator {
@@ -78,15 +84,113 @@ impl Iterator<String> for DirIterator {
 }
 
 
+/// The signature that a `CACHEDIR.TAG` file must start with to be considered valid.
+/// See http://www.brynosaurus.com/cachedir/ for the full specification.
+static CACHEDIR_TAG_SIGNATURE: &'static [u8] = b"Signature: 8a477f597d28d172789f06886806bc55";
+
+/// Check whether `dir` has been tagged as a cache directory by a valid `CACHEDIR.TAG` file.
+pub fn is_tagged_cache_dir(dir: &Path) -> bool {
+  let mut tag = dir.clone();
+  tag.push("CACHEDIR.TAG");
+
+  // Only a regular file is read: opening a FIFO or device found in its place could block or have
+  // side effects, and a link swapped in after the check is not followed.
+  match lstat(&tag) {
+    Ok(ref stat) if stat.kind == TypeFile => (),
+    _ => return false,
+  }
+  let fd = tag.with_c_str(|c_str| unsafe {
+    fcntl::open(c_str, O_RDONLY | O_NONBLOCK | O_NOFOLLOW, 0)
+  });
+  if fd < 0 { return false }
+
+  let mut signature = Vec::from_elem(CACHEDIR_TAG_SIGNATURE.len(), 0u8);
+  let mut filled = 0;
+  while filled < signature.len() {
+    let n = unsafe {
+      read(fd, signature.slice_from_mut(filled).as_mut_ptr() as *mut c_void,
+           (signature.len() - filled) as size_t)
+    };
+    if n <= 0 { break }
+    filled += n as uint;
+  }
+  unsafe { close(fd) };
+
+  filled == signature.len() && signature.as_slice() == CACHEDIR_TAG_SIGNATURE
+}
+
+/// Check whether `dir` belongs to a hat repository: it holds the indexes of one, or it is a blob
+/// directory that the `hat` command keeps next to them (`blobs` and `archive` next to `repo`).
+pub fn is_hat_repository_dir(dir: &Path) -> bool {
+  if dir.join("blob_index.sqlite3").exists() {
+    return true;
+  }
+  match dir.filename_str() {
+    Some("blobs") | Some("archive") => {
+      dir.dir_path().join("repo").join("blob_index.sqlite3").exists()
+    },
+    _ => false,
+  }
+}
+
+/// Locate the user's XDG cache directory (`$XDG_CACHE_HOME`, falling back to `~/.cache`).
+pub fn xdg_cache_dir() -> Option<Path> {
+  match os::getenv("XDG_CACHE_HOME") {
+    Some(ref dir) if dir.len() > 0 => Some(Path::new(dir.as_slice())),
+    _ => os::homedir().map(|mut home| { home.push(".cache"); home }),
+  }
+}
+
+/// Check whether the file at `path` carries the "nodump" attribute (`chattr +d`). Only regular
+/// files and directories are checked: the attribute is read from an open file, and opening a
+/// device or FIFO can have side effects. The file's access time is left alone where allowed.
+#[cfg(target_os = "linux")]
+pub fn has_nodump_flag(path: &Path, stat: &FileStat) -> bool {
+  static FS_IOC_GETFLAGS: c_ulong = 0x80086601;
+  static FS_NODUMP_FL: c_long = 0x00000040;
+  static O_NOATIME: c_int = 0o1000000;
+
+  extern {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+  }
+
+  if stat.kind != TypeFile && stat.kind != TypeDirectory { return false }
+
+  let open = |flags: c_int| path.with_c_str(|c_str| unsafe { fcntl::open(c_str, flags, 0) });
+  let mut fd = open(O_RDONLY | O_NONBLOCK | O_NOATIME);
+  if fd < 0 {
+    // Only the owner of a file may open it with O_NOATIME:
+    fd = open(O_RDONLY | O_NONBLOCK);
+  }
+  if fd < 0 { return false }
+
+  let mut flags: c_long = 0;
+  let retval = unsafe { ioctl(fd, FS_IOC_GETFLAGS, &mut flags) };
+  unsafe { close(fd) };
+
+  retval == 0 && (flags & FS_NODUMP_FL) != 0
+}
+
+/// Check whether the file at `path` carries the `UF_NODUMP` flag (`chflags nodump`).
+#[cfg(not(target_os = "linux"))]
+pub fn has_nodump_flag(_path: &Path, stat: &FileStat) -> bool {
+  static UF_NODUMP: u64 = 0x00000001;
+  (stat.unstable.flags & UF_NODUMP) != 0
+}
+
+
 pub trait PathHandler<D> {
   fn handle_path(&mut self, D, Path) -> Option<D>;
+
+  /// Called after all entries of a directory have been passed to `handle_path`.
+  fn end_dir(&mut self) {}
 }
 
 
+/// Call `worker` for each path below `root`, using `threads` worker tasks.
 pub fn iterate_recursively<P: Send + Clone, W: PathHandler<P> + Send + Clone>
-  (root: (Path, P), worker: &mut W)
+  (root: (Path, P), worker: &mut W, threads: uint)
 {
-  let threads = 5;
   let (push_ch, work_ch) = sync_channel(threads);
   let mut pool = sync::TaskPool::new(threads, || proc(_){()});
 
@@ -128,6 +232,7 @@ pub fn iterate_recursively<P: Send + Clone, W: PathHandler<P> + Send + Clone>
                 root.pop();
               }
             }
+            t_worker.end_dir();
           }
 
           // Count this pool thread as idle:
@@ -158,3 +263,84 @@ impl PathHandler<()> for PrintPathHandler {
     }
   }
 }
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use libc::funcs::posix88::stat_::{mkfifo};
+
+  use std::io::{Command, File, TempDir, UserDir};
+  use std::io::fs::{lstat, mkdir, symlink};
+
+  fn tagged_dir(tag: &[u8]) -> TempDir {
+    let dir = TempDir::new("hat-listdir").unwrap();
+    File::create(&dir.path().join("CACHEDIR.TAG")).write(tag).unwrap();
+    dir
+  }
+
+  #[test]
+  fn cache_dir_tags() {
+    let valid = b"Signature: 8a477f597d28d172789f06886806bc55\n# This is a cache directory.\n";
+    assert!(is_tagged_cache_dir(tagged_dir(valid).path()));
+    assert!(is_tagged_cache_dir(tagged_dir(b"Signature: 8a477f597d28d172789f06886806bc55")
+                                .path()));
+
+    let wrong = b"Signature: 8a477f597d28d172789f06886806bc56";
+    assert!(!is_tagged_cache_dir(tagged_dir(wrong).path()));
+    assert!(!is_tagged_cache_dir(tagged_dir(b"Signature: 8a477f59").path()));
+    assert!(!is_tagged_cache_dir(tagged_dir(b"").path()));
+    assert!(!is_tagged_cache_dir(TempDir::new("hat-listdir").unwrap().path()));
+
+    // A FIFO in place of the tag is not opened (which would block), nor is a link followed:
+    let dir = TempDir::new("hat-listdir").unwrap();
+    let tag = dir.path().join("CACHEDIR.TAG");
+    assert_eq!(tag.with_c_str(|c_str| unsafe { mkfifo(c_str, 0o600) }), 0);
+    assert!(!is_tagged_cache_dir(dir.path()));
+
+    let (target, linked) = (tagged_dir(valid), TempDir::new("hat-listdir").unwrap());
+    symlink(&target.path().join("CACHEDIR.TAG"), &linked.path().join("CACHEDIR.TAG")).unwrap();
+    assert!(!is_tagged_cache_dir(linked.path()));
+  }
+
+  #[test]
+  fn nodump_flags() {
+    let dir = TempDir::new("hat-listdir").unwrap();
+    let file = dir.path().join("file");
+    File::create(&file).write(b"data").unwrap();
+    assert!(!has_nodump_flag(&file, &lstat(&file).unwrap()));
+
+    // Only regular files and directories are opened to read the flag:
+    let fifo = dir.path().join("fifo");
+    assert_eq!(fifo.with_c_str(|c_str| unsafe { mkfifo(c_str, 0o600) }), 0);
+    assert!(!has_nodump_flag(&fifo, &lstat(&fifo).unwrap()));
+
+    // Setting the flag needs a filesystem that supports it (tmpfs does not):
+    let marked = Command::new("chattr").arg("+d").arg(&file).status()
+                                       .map(|status| status.success()).unwrap_or(false);
+    if marked {
+      assert!(has_nodump_flag(&file, &lstat(&file).unwrap()));
+    }
+  }
+
+  #[test]
+  fn hat_repository_dirs() {
+    let dir = TempDir::new("hat-listdir").unwrap();
+    let repo = dir.path().join("repo");
+    let blobs = dir.path().join("blobs");
+    let archive = dir.path().join("archive");
+    let other = dir.path().join("other");
+    for d in [&repo, &blobs, &archive, &other].iter() {
+      mkdir(*d, UserDir).unwrap();
+    }
+    assert!(!is_hat_repository_dir(&blobs));
+
+    File::create(&repo.join("blob_index.sqlite3")).unwrap();
+    assert!(is_hat_repository_dir(&repo));
+    assert!(is_hat_repository_dir(&blobs));
+    assert!(is_hat_repository_dir(&archive));
+    assert!(!is_hat_repository_dir(&other));
+    assert!(!is_hat_repository_dir(dir.path()));
+  }
+}
@@ -23,7 +23,9 @@
 
 // Standard Rust imports
 extern crate debug;
+extern crate getopts;
 extern crate libc;
+extern crate regex;
 extern crate serialize;
 extern crate test;
 extern crate time;
@@ -36,19 +38,66 @@ extern crate sqlite3;
 #[cfg(test)]
 extern crate quickcheck;
 
+use std::cmp;
+use std::io::{File, UserDir, UserRead, UserWrite};
+use std::io::fs::{chmod, mkdir_recursive};
+use std::io::stdio;
+use std::io::process::{Command, ExitStatus, InheritFd, ProcessExit};
 use std::os;
+use std::sync;
+use getopts::{optflag, optmulti, optopt, getopts};
+use serialize::{json};
+use serialize::hex::{FromHex, ToHex};
+
+use blob_store::{BlobStoreBackend};
+
+// First, so that the other modules can use its macros:
+mod output;
 
 mod callback_container;
 mod cumulative_counter;
+mod curl;
 mod ordered_collection;
 mod periodic_timer;
 mod unique_priority_queue;
 
+mod bench;
+mod borg;
+mod buffer_pool;
+mod bundle;
+mod cache;
+mod cancel;
+mod chunker;
+mod crash_test;
+mod diff;
+mod exec;
+mod fifo;
+mod fingerprint;
+mod grep;
 mod hat;
+mod keyring;
+mod keys;
 mod listdir;
+mod manifest;
+mod mirror;
+mod niceness;
+mod nofollow;
+mod notify;
 mod process;
+mod proxy;
+mod reflink;
+mod rest;
+mod retry;
+mod s3;
+mod sftp;
+mod tar;
+mod throttle;
+mod timestamp;
+mod volume_snapshot;
+mod webdav;
 
 mod hash_index;
+mod hash_pool;
 mod hash_tree;
 
 mod blob_index;
@@ -60,11 +109,573 @@ mod key_store;
 
 static MAX_BLOB_SIZE: uint = 4 * 1024 * 1024;
 
+/// The commands that store new blobs, and that `--probe-backend` checks the blob store for.
+static STORING_COMMANDS: &'static [&'static str] = &["snapshot", "seed", "import-borg",
+                                                     "unbundle", "archive"];
+
+/// The exit status of a snapshot that completed, but left out files it could not read. Fatal
+/// errors exit with status 1 (or 101, if hat fails unexpectedly).
+static EXIT_PARTIAL: int = 2;
+
 fn blob_dir() -> Path { Path::new("blobs") }
 
+fn archive_dir() -> Path { Path::new("archive") }
+
+/// Where copies of the blobs read from the primary backend are kept (see `--read-cache-size`).
+fn read_cache_dir() -> Path { Path::new("read-cache") }
+
+/// Where new blobs are stored: in the local blob directory, in an S3 bucket (`--s3-bucket`), on a
+/// server over SFTP (`--sftp`), on a WebDAV share (`--webdav`), on a server that speaks the
+/// protocol of `rest` (`--rest`), or wherever a command speaking the protocol of `exec` puts them
+/// (`--exec`). With `--mirror`, new blobs are stored both in the local blob directory and on each
+/// of the other backends.
+type PrimaryBackend = blob_store::BoxedBackend;
+
+/// The proxy given with `--proxy`, if any, for the backends that reach other machines.
+fn proxy_config(matches: &getopts::Matches) -> Option<proxy::ProxyConfig> {
+  matches.opt_str("proxy").map(|url| {
+    let mut config = proxy::ProxyConfig::new(url);
+    config.credentials = matches.opt_str("proxy-user").map(|user| {
+      let password = matches.opt_str("proxy-password-file").map(|file| {
+        let text = File::open(&Path::new(file.as_slice())).and_then(|mut f| f.read_to_string())
+          .ok().expect("could not read --proxy-password-file");
+        text.as_slice().lines().next().unwrap_or("").to_string()
+      }).unwrap_or("".to_string());
+      (user, password)
+    });
+    config
+  })
+}
+
+/// The S3 bucket given with `--s3-bucket`, if any.
+fn s3_config(matches: &getopts::Matches) -> Option<s3::S3Config> {
+  matches.opt_str("s3-bucket").map(|bucket| {
+    let mut config = s3::S3Config::new(bucket,
+                                       matches.opt_str("s3-prefix").unwrap_or("".to_string()));
+    config.region = matches.opt_str("s3-region");
+    config.profile = matches.opt_str("s3-profile");
+    for mapping in matches.opt_strs("s3-storage-class").iter() {
+      let hint = mapping.as_slice().find('=').and_then(|i| {
+        blob_store::StorageHint::from_name(mapping.as_slice().slice_to(i))
+          .map(|hint| (hint, mapping.as_slice().slice_from(i + 1).to_string()))
+      });
+      config.storage_classes.push(hint.expect(
+        "--s3-storage-class must be of the form frequent|infrequent|archive=CLASS"));
+    }
+    config.proxy = proxy_config(matches);
+    config.object_lock = matches.opt_str("s3-object-lock").map(|mode| {
+      s3::ObjectLock{
+        mode: s3::LockMode::from_name(mode.as_slice()).expect(
+          "--s3-object-lock must be governance or compliance"),
+        days: matches.opt_str("s3-lock-days").and_then(|days| {
+          from_str::<u64>(days.as_slice())
+        }).expect("--s3-object-lock needs --s3-lock-days DAYS")}
+    });
+    config
+  })
+}
+
+/// The server directory given with `--sftp`, if any.
+fn sftp_config(matches: &getopts::Matches) -> Option<sftp::SftpConfig> {
+  matches.opt_str("sftp").map(|location| {
+    let mut config = sftp::SftpConfig::parse(location.as_slice()).expect(
+      "--sftp must be of the form [USER@]HOST:DIR");
+    config.port = matches.opt_str("sftp-port").map(|port| {
+      from_str::<u16>(port.as_slice()).expect("--sftp-port must be a port number")
+    });
+    config.identity = matches.opt_str("sftp-identity");
+    config.proxy = proxy_config(matches);
+    config
+  })
+}
+
+/// The WebDAV collection given with `--webdav`, if any.
+fn webdav_config(matches: &getopts::Matches) -> Option<webdav::WebDavConfig> {
+  matches.opt_str("webdav").map(|url| {
+    let mut config = webdav::WebDavConfig::new(url);
+    config.credentials = matches.opt_str("webdav-user").map(|user| {
+      let password = matches.opt_str("webdav-password-file").map(|file| {
+        let text = File::open(&Path::new(file.as_slice())).and_then(|mut f| f.read_to_string())
+          .ok().expect("could not read --webdav-password-file");
+        text.as_slice().lines().next().unwrap_or("").to_string()
+      }).unwrap_or("".to_string());
+      (user, password)
+    });
+    config.auth = match matches.opt_str("webdav-auth") {
+      None => webdav::BasicAuth,
+      Some(ref auth) if auth.as_slice() == "basic" => webdav::BasicAuth,
+      Some(ref auth) if auth.as_slice() == "digest" => webdav::DigestAuth,
+      Some(_) => fail!("--webdav-auth must be basic or digest"),
+    };
+    config.chunked = matches.opt_present("webdav-chunked");
+    config.auth_hook = auth_hook(matches);
+    config.proxy = proxy_config(matches);
+    config
+  })
+}
+
+/// The hook given with `--http-auth-command`, if any.
+fn auth_hook(matches: &getopts::Matches) -> Option<curl::AuthHook> {
+  matches.opt_str("http-auth-command").map(|command| {
+    curl::AuthHook::new(curl::CommandAuth::new(command))
+  })
+}
+
+/// The storage server given with `--rest`, if any.
+fn rest_config(matches: &getopts::Matches) -> Option<rest::RestConfig> {
+  matches.opt_str("rest").map(|url| {
+    let mut config = rest::RestConfig::new(url);
+    config.token = matches.opt_str("rest-token-file").map(|file| {
+      let text = File::open(&Path::new(file.as_slice())).and_then(|mut f| f.read_to_string())
+        .ok().expect("could not read --rest-token-file");
+      text.as_slice().lines().next().unwrap_or("").to_string()
+    });
+    config.auth = auth_hook(matches);
+    config.proxy = proxy_config(matches);
+    config
+  })
+}
+
+/// The failure domain of the backend `kind` (local, s3, sftp, webdav, rest or exec), as given with
+/// `--failure-domain`. By default, the local blob directory is onsite and the others offsite.
+fn failure_domain(matches: &getopts::Matches, kind: &str) -> String {
+  for mapping in matches.opt_strs("failure-domain").iter() {
+    match mapping.as_slice().find('=') {
+      Some(i) if mapping.as_slice().slice_to(i) == kind => {
+        return mapping.as_slice().slice_from(i + 1).to_string();
+      },
+      Some(_) => (),
+      None => fail!("--failure-domain must be of the form BACKEND=DOMAIN"),
+    }
+  }
+  if kind == "local" { "onsite".to_string() } else { "offsite".to_string() }
+}
+
+/// The backends that new blobs are stored on: a description of where each keeps them, its
+/// failure domain, and the backend. The local blob directory is only used without other
+/// backends, or with `--mirror`.
+fn labeled_backends(matches: &getopts::Matches) -> Vec<(String, String, PrimaryBackend)> {
+  let mut configured = vec![];
+  s3_config(matches).map(|config| {
+    configured.push((format!("S3 bucket (s3://{}/{})", config.bucket, config.prefix),
+                     failure_domain(matches, "s3"),
+                     blob_store::BoxedBackend::new(s3::S3Backend::new(config))));
+  });
+  sftp_config(matches).map(|config| {
+    configured.push((format!("SFTP directory ({}:{})", config.host, config.dir),
+                     failure_domain(matches, "sftp"),
+                     blob_store::BoxedBackend::new(sftp::SftpBackend::new(config))));
+  });
+  webdav_config(matches).map(|config| {
+    configured.push((format!("WebDAV collection ({})", config.url),
+                     failure_domain(matches, "webdav"),
+                     blob_store::BoxedBackend::new(webdav::WebDavBackend::new(config))));
+  });
+  rest_config(matches).map(|config| {
+    configured.push((format!("storage server ({})", config.url),
+                     failure_domain(matches, "rest"),
+                     blob_store::BoxedBackend::new(rest::RestBackend::new(config))));
+  });
+  matches.opt_str("exec").map(|command| {
+    configured.push((format!("storage command ({})", command),
+                     failure_domain(matches, "exec"),
+                     blob_store::BoxedBackend::new(exec::ExecBackend::new(command))));
+  });
+  let local = (format!("blob directory ({})", blob_dir().display()),
+               failure_domain(matches, "local"),
+               blob_store::BoxedBackend::new(blob_store::FileBackend::new(blob_dir())));
+  if matches.opt_present("mirror") || configured.len() == 0 {
+    configured.insert(0, local);
+  } else if configured.len() > 1 {
+    fail!("only one of --s3-bucket, --sftp, --webdav, --rest and --exec can be used, \
+           unless with --mirror");
+  }
+  configured
+}
+
+/// A mirror of `backends` (see `labeled_backends`), as configured by `--mirror-read` and
+/// `--mirror-placement`.
+fn mirror_of(matches: &getopts::Matches, backends: Vec<(String, String, PrimaryBackend)>)
+             -> mirror::MirrorBackend<PrimaryBackend> {
+  let policy = matches.opt_str("mirror-read").map(|name| {
+    mirror::ReadPolicy::from_name(name.as_slice()).expect(
+      "--mirror-read must be in-order or fastest")
+  }).unwrap_or(mirror::InOrder);
+  let placement = matches.opt_str("mirror-placement").map(|name| {
+    mirror::Placement::from_name(name.as_slice()).expect(
+      "--mirror-placement must be every-replica or every-domain")
+  }).unwrap_or(mirror::EveryReplica);
+  let replicas = backends.into_iter().map(|(_, domain, backend)| (domain, backend)).collect();
+  mirror::MirrorBackend::with_domains(replicas, policy, placement)
+}
+
+/// The backend for new blobs, and a description of where it keeps them.
+fn labeled_primary_backend(matches: &getopts::Matches) -> (String, PrimaryBackend) {
+  let mut configured = labeled_backends(matches);
+  if matches.opt_present("mirror") {
+    let labels: Vec<String> = configured.iter().map(|&(ref label, _, _)| {
+      label.clone()
+    }).collect();
+    return (format!("mirror of the {}", labels.connect(" and the ")),
+            blob_store::BoxedBackend::new(mirror_of(matches, configured)));
+  }
+  let (label, _, backend) = configured.pop().unwrap();
+  (label, backend)
+}
+
+fn primary_backend(matches: &getopts::Matches) -> PrimaryBackend {
+  let (_, backend) = labeled_primary_backend(matches);
+  backend
+}
+
+type Backend = blob_store::TieredBackend<
+  blob_store::EncryptedBackend<
+    cache::CachingBackend<retry::RetryingBackend<throttle::ThrottledBackend<PrimaryBackend>>>>,
+  blob_store::EncryptedBackend<blob_store::FileBackend>>;
+
+fn repository_root() -> Path { Path::new("repo") }
+
+/// The passphrases that unlock the repository secret, if not given as a `--key-file`.
+fn keyring_path() -> Path { hat::keyring_path(&repository_root()) }
+
+/// The first line of the file given by option `name`, if any.
+fn passphrase(matches: &getopts::Matches, name: &str) -> Option<Vec<u8>> {
+  matches.opt_str(name).map(|path| {
+    match File::open(&Path::new(path.clone())).and_then(|mut f| f.read_to_end()) {
+      Ok(text) => text.as_slice().split(|&b| b == b'\n').next().map(|line| line.to_vec())
+                      .unwrap_or(vec![]),
+      Err(e) => fail!(format!("Could not read passphrase file '{}': {}", path, e)),
+    }
+  })
+}
+
+/// The label of the keyring slot that `--passphrase-file` unlocks, and the repository secret.
+fn unlock_keyring(matches: &getopts::Matches) -> Option<(String, Vec<u8>)> {
+  passphrase(matches, "passphrase-file").map(|passphrase| {
+    let path = keyring_path();
+    let keyring = keyring::Keyring::load(&path).unwrap_or_else(|e| {
+      fail!(format!("Could not read keyring '{}': {}", path.display(), e))
+    });
+    keyring.unlock(passphrase.as_slice()).expect("The passphrase does not unlock the keyring.")
+  })
+}
+
+/// The secret that the local indexes are encrypted with: the contents of `--key-file`, or the
+/// secret of the keyring that `--passphrase-file` unlocks.
+fn index_secret(matches: &getopts::Matches) -> Option<Vec<u8>> {
+  match (key_secret(matches, "key-file"), unlock_keyring(matches)) {
+    (Some(_), Some(_)) => fail!("--key-file and --passphrase-file cannot be used together"),
+    (Some(secret), None) => secret.as_slice().from_hex().ok(),
+    (None, Some((_, secret))) => Some(secret),
+    (None, None) => None,
+  }
+}
+
+fn open_repository(matches: &getopts::Matches) -> hat::Hat<Backend> {
+  let key = index_secret(matches).map(|secret| keys::RepositoryKey::new(secret.as_slice()));
+  let primary = throttle::ThrottledBackend::new(primary_backend(matches),
+                                                rate_opt(matches, "upload-limit"),
+                                                rate_opt(matches, "download-limit"));
+  let mut retries = retry::RetryPolicy::new();
+  size_opt(matches, "retries").map(|n| retries.retries = n);
+  let primary = retry::RetryingBackend::new(primary, retries);
+  let cache_size = matches.opt_str("read-cache-size").map(|n| {
+    from_str::<u64>(n.as_slice()).expect("--read-cache-size must be a number of bytes")
+  }).unwrap_or(0);
+  let primary = cache::CachingBackend::new(primary, read_cache_dir(), cache_size);
+  let blob_cipher = matches.opt_str("blob-key-file").map(|path| {
+    match keys::RepositoryKey::from_file(&Path::new(path.clone())) {
+      Ok(key) => keys::BlobCipher::new(&key),
+      Err(e) => fail!(format!("Could not read blob key file '{}': {}", path, e)),
+    }
+  });
+  let primary = blob_store::EncryptedBackend::new(primary, blob_cipher.clone());
+  let archive = blob_store::EncryptedBackend::new(blob_store::FileBackend::new(archive_dir()),
+                                                  blob_cipher.clone());
+  let backend = blob_store::TieredBackend::new(primary, archive);
+  let shards = matches.opt_str("hash-index-shards").map(|n| {
+    match from_str::<uint>(n.as_slice()) {
+      Some(n) if hash_index::check_shards(n).is_ok() => n,
+      _ => fail!("--hash-index-shards must be a number from 1 to {}", hash_index::MAX_SHARDS),
+    }
+  });
+  let sparse = matches.opt_str("sparse-index").map(|n| {
+    match from_str::<uint>(n.as_slice()) {
+      Some(n) if n > 0 && n <= 256 => n,
+      _ => fail!("--sparse-index must be a number from 1 to 256"),
+    }
+  });
+  let mut hat = match hat::Hat::open_repository(&repository_root(), backend, MAX_BLOB_SIZE,
+                                                key, shards, sparse) {
+    Ok(hat) => hat,
+    Err(e) => fail!(format!("Could not open repository in {}: {}", repository_root().display(),
+                            e)),
+  };
+  matches.opt_str("chunk-key-file").map(|path| {
+    let key = match keys::RepositoryKey::from_file(&Path::new(path.clone())) {
+      Ok(key) => key,
+      Err(e) => fail!(format!("Could not read chunk key file '{}': {}", path, e)),
+    };
+    match hat.set_chunk_key(&key) {
+      Ok(()) => (),
+      Err(e) => fail!(e),
+    }
+  });
+  blob_cipher.map(|cipher| {
+    match hat.set_blob_cipher(&cipher) {
+      Ok(()) => (),
+      Err(e) => fail!(e),
+    }
+  });
+  match hat.check_keys() {
+    Ok(()) => (),
+    Err(e) => fail!(e),
+  }
+  hat.load_archive_locations();
+  if matches.opt_present("probe-backend") &&
+     STORING_COMMANDS.contains(&matches.free[0].as_slice()) {
+    match hat.probe_backend() {
+      Ok(_) => (),
+      Err(e) => fail!(format!("Could not store blobs: {}", e)),
+    }
+  }
+  hat
+}
+
+/// Stream the selected snapshot of `family` as a tar archive into the standard input of `command`
+/// (run by sh), e.g. `ssh host tar -x -C /srv`. Returns the number of entries written.
+fn checkout_to_command(family: &hat::Family<Backend>, command: &str) -> Result<uint, String> {
+  let mut process = try!(Command::new("sh").arg("-c").arg(command)
+                                           .stdout(InheritFd(1)).stderr(InheritFd(2))
+                                           .spawn().map_err(|e| e.to_string()));
+  let written = {
+    let input = process.stdin.take().expect("stdin is piped");
+    let mut tar = tar::TarWriter::new(input);
+    match family.export_tar(&mut tar) {
+      Ok(count) => tar.finish().map(|_| count),
+      Err(e) => Err(e),
+    }
+  };
+  let status = try!(process.wait().map_err(|e| e.to_string()));
+  match written {
+    Err(e) => Err(e.to_string()),
+    Ok(_) if !status.success() => Err(format!("the command failed ({})", status)),
+    Ok(count) => Ok(count),
+  }
+}
+
+/// The key that bundles are sealed with (see `bundle`).
+fn bundle_key(matches: &getopts::Matches) -> keys::RepositoryKey {
+  let path = matches.opt_str("bundle-key-file").expect(
+    "bundle and unbundle need a --bundle-key-file");
+  match keys::RepositoryKey::from_file(&Path::new(path.clone())) {
+    Ok(key) => key,
+    Err(e) => fail!(format!("Could not read bundle key file '{}': {}", path, e)),
+  }
+}
+
+/// The hex-encoded secret in the key file given by option `name`, if any.
+fn key_secret(matches: &getopts::Matches, name: &str) -> Option<String> {
+  matches.opt_str(name).map(|path| {
+    match File::open(&Path::new(path.clone())).and_then(|mut f| f.read_to_end()) {
+      Ok(secret) => secret.as_slice().to_hex(),
+      Err(e) => fail!(format!("Could not read key file '{}': {}", path, e)),
+    }
+  })
+}
+
+/// Write `secret` from a bundle to the key file given by option `name`, unless the file exists.
+fn restore_key_secret(matches: &getopts::Matches, name: &str, secret: &Option<String>) {
+  let (path, secret) = match (matches.opt_str(name), secret) {
+    (Some(path), &Some(ref secret)) => (Path::new(path), secret),
+    _ => return,
+  };
+  if path.exists() {
+    return;
+  }
+  let bytes = secret.as_slice().from_hex().unwrap_or_else(|_| {
+    fail!("The bundle holds an invalid key.")
+  });
+  let written = File::create(&path).and_then(|mut file| {
+    try!(chmod(&path, UserRead | UserWrite));
+    file.write(bytes.as_slice())
+  });
+  written.unwrap_or_else(|e| fail!(format!("Could not write '{}': {}", path.display(), e)));
+  info!("Wrote the key from the bundle to '{}'.", path.display());
+}
+
+/// The ID of the snapshot that `--snapshot`, `--at` or `--before` select among `snapshots` of
+/// family `name`, if any of them is given.
+fn selected_snapshot(matches: &getopts::Matches, name: &str, snapshots: &[(i64, u64)])
+                     -> Option<i64> {
+  let at = matches.opt_str("at").map(|time| (time, true));
+  let before = matches.opt_str("before").map(|time| (time, false));
+  let (time, inclusive) = match (matches.opt_str("snapshot"), at, before) {
+    (None, None, None) => return None,
+    (Some(id), None, None) => {
+      return Some(from_str::<i64>(id.as_slice()).expect("--snapshot must be a snapshot ID"));
+    },
+    (None, Some(selector), None) | (None, None, Some(selector)) => selector,
+    _ => fail!("only one of --snapshot, --at and --before can be used"),
+  };
+  let seconds = timestamp::parse(time.as_slice(), &time::now()).expect(
+    "--at and --before must be a time such as 2014-10-01, \"2014-10-01 02:00\", 02:00 or \
+     @SECONDS");
+  match timestamp::select(snapshots, seconds, inclusive) {
+    Some(id) => Some(id),
+    None => fail!(format!("Family '{}' has no snapshot from {} '{}'.", name,
+                          if inclusive { "as of" } else { "before" }, time)),
+  }
+}
+
+/// The files of the snapshot that `--changed-since FAMILY[@ID|@TIME]` refers to, if it is given:
+/// snapshot `ID`, the newest snapshot as of `TIME` (as for `--at`), or the latest snapshot.
+fn changed_since_files(matches: &getopts::Matches, hat: &hat::Hat<Backend>)
+                       -> Option<hat::ReferenceFiles> {
+  matches.opt_str("changed-since").map(|reference| {
+    let (name, selector) = match reference.as_slice().find('@') {
+      Some(i) => (reference.as_slice().slice_to(i).to_string(),
+                  Some(reference.as_slice().slice_from(i + 1).to_string())),
+      None => (reference.clone(), None),
+    };
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    let snapshots = family.list_snapshots();
+    let id = match selector {
+      None => snapshots.last().map(|&(id, _)| id),
+      Some(text) => match from_str::<i64>(text.as_slice()) {
+        Some(id) => snapshots.iter().find(|&&(other, _)| other == id).map(|&(id, _)| id),
+        None => {
+          let seconds = timestamp::parse(text.as_slice(), &time::now()).expect(
+            "--changed-since must be FAMILY, FAMILY@ID or FAMILY@TIME (as for --at)");
+          timestamp::select(snapshots.as_slice(), seconds, true)
+        },
+      },
+    };
+    match id {
+      Some(id) => family.select_snapshot(id),
+      None => fail!(format!("There is no snapshot '{}' to compare with.", reference)),
+    }
+    family.reference_files()
+  })
+}
+
+fn size_opt(matches: &getopts::Matches, name: &str) -> Option<uint> {
+  matches.opt_str(name).map(|n| {
+    from_str::<uint>(n.as_slice()).expect(format!("--{} must be a number", name).as_slice())
+  })
+}
+
+fn rate_opt(matches: &getopts::Matches, name: &str) -> Option<u64> {
+  matches.opt_str(name).map(|n| {
+    from_str::<u64>(n.as_slice()).expect(
+      format!("--{} must be a number of bytes per second", name).as_slice())
+  })
+}
+
+
+/// Run this program with `args`, passing on the options that select the repository.
+fn run_self(matches: &getopts::Matches, args: &[String]) -> ProcessExit {
+  let exe = os::self_exe_name().expect("Could not locate this program.");
+  let mut command = Command::new(exe);
+  matches.opt_str("key-file").map(|path| { command.arg("--key-file").arg(path); });
+  matches.opt_str("passphrase-file").map(|path| {
+    command.arg("--passphrase-file").arg(path);
+  });
+  for flag in ["quiet", "verbose"].iter() {
+    if matches.opt_present(*flag) { command.arg(format!("--{}", flag)); }
+  }
+  match command.args(args).status() {
+    Ok(status) => status,
+    Err(e) => fail!(format!("Could not run {}: {}", args, e)),
+  }
+}
+
+/// Snapshot `path` into family `name` again and again while injecting crashes, and check the
+/// repository after each crash. Returns whether all checks passed.
+fn crash_test(matches: &getopts::Matches, name: &String, path: &String) -> bool {
+  let rounds = size_opt(matches, "crash-rounds").unwrap_or(20);
+  let probability = matches.opt_str("crash-probability").unwrap_or("0.02".to_string());
+  let snapshot = vec!["snapshot".to_string(), name.clone(), path.clone()];
+
+  for round in range(0, rounds) {
+    let mut args = vec!["--crash-probability".to_string(), probability.clone()];
+    args.push_all(snapshot.as_slice());
+    let status = run_self(matches, args.as_slice());
+    let crashed = status == ExitStatus(crash_test::CRASH_EXIT_STATUS);
+    if !crashed && !status.success() {
+      error!("Round {}: snapshot failed ({}).", round + 1, status);
+      return false;
+    }
+    if !run_self(matches, ["check".to_string(), name.clone()]).success() {
+      error!("Round {}: repository check failed after {}.", round + 1,
+             if crashed { "a crash" } else { "a complete snapshot" });
+      return false;
+    }
+  }
+
+  // A final snapshot without crashes must store the tree as it is:
+  if !run_self(matches, snapshot.as_slice()).success() {
+    error!("Final snapshot failed.");
+    return false;
+  }
+  let hat = open_repository(matches);
+  let family = hat.open_family(name.clone()).expect(
+    format!("Could not open family '{}'", name).as_slice());
+  let chunker = chunker::ChunkerOptions::new(hat::DEFAULT_CHUNK_SIZE);
+  match fingerprint::fingerprint_of_dir(&Path::new(path.clone()), &chunker) {
+    Ok(ref fp) if *fp == family.fingerprint() => true,
+    Ok(_) => { error!("Final snapshot does not match '{}'.", path); false },
+    Err(e) => fail!(format!("Could not read '{}': {}", path, e)),
+  }
+}
+
 
-fn usage() {
-  println!("Usage: {} [snapshot|checkout] name path", os::args()[0]);
+fn usage(opts: &[getopts::OptGroup]) {
+  let brief = format!("Usage: {0} [options] [snapshot|checkout] name path\n       \
+                       {0} [options] snapshot name\n       \
+                       {0} [options] checkout --to-command COMMAND name\n       \
+                       {0} [options] seed name path\n       \
+                       {0} [options] maintenance [name...]\n       \
+                       {0} [options] compact\n       \
+                       {0} [options] check [name...]\n       \
+                       {0} [options] check-backend\n       \
+                       {0} [options] verify-blobs\n       \
+                       {0} [options] verify\n       \
+                       {0} [options] archive days\n       \
+                       {0} [options] hint-storage days infrequent|archive\n       \
+                       {0} [options] lock-blobs days\n       \
+                       {0} [options] stats [--placement]\n       \
+                       {0} [options] schedule [task days]\n       \
+                       {0} [options] run-due\n       \
+                       {0} [options] snapshots\n       \
+                       {0} [options] history name\n       \
+                       {0} [options] family fork name new-name\n       \
+                       {0} [options] family rollback [--snapshot ID|--at TIME] name\n       \
+                       {0} [options] import-borg name borg-repository\n       \
+                       {0} [options] verify-tree fingerprint path\n       \
+                       {0} [options] manifest path\n       \
+                       {0} [options] diff name other-name\n       \
+                       {0} [options] grep pattern [name...]\n       \
+                       {0} [options] [bundle|unbundle] name file\n       \
+                       {0} [options] passwd [change [label]|add label|remove label|list]\n       \
+                       {0} [options] bench scratch-dir\n       \
+                       {0} [options] crash-test name path\n\n\
+                       Exit status: 0 on success, {1} if a snapshot left out files it could not \
+                       read, {2} if the watchdog aborted the run, and 1 on failure.",
+                      os::args()[0], EXIT_PARTIAL, process::WATCHDOG_EXIT_STATUS);
+  print!("{}", getopts::usage(brief.as_slice(), opts));
+}
+
+/// Write `failures` to `path`, one JSON object per line.
+fn write_failure_list(path: &Path, failures: &[hat::FileFailure]) {
+  let written = File::create(path).and_then(|mut file| {
+    for failure in failures.iter() {
+      try!(file.write_line(json::encode(failure).as_slice()));
+    }
+    Ok(())
+  });
+  written.unwrap_or_else(|e| fail!(format!("Could not write '{}': {}", path.display(), e)));
 }
 
 fn license() {
@@ -77,61 +688,1215 @@ fn main() {
   // Initialize sodium (must only be called once)
   sodiumoxide::init();
 
+  let opts = [
+    optflag("h", "help", "print this help message"),
+    optflag("q", "quiet", "only print results, warnings and errors"),
+    optflag("v", "verbose", "print more detailed progress, e.g. every file of a snapshot"),
+    optflag("", "license", "print the license"),
+    optopt("", "key-file", "encrypt the local indexes with the secret in FILE (needs SQLCipher)",
+           "FILE"),
+    optopt("", "passphrase-file",
+           "encrypt the local indexes with the secret that the passphrase on the first line of \
+            FILE unlocks from the repository keyring (see passwd)", "FILE"),
+    optopt("", "new-passphrase-file",
+           "passwd: the new passphrase, on the first line of FILE", "FILE"),
+    optopt("", "chunk-key-file",
+           "encrypt stored data with a key derived from the secret in FILE; clients that share \
+            the secret deduplicate against each other (must be given before any data is stored, \
+            and then always)", "FILE"),
+    optopt("", "blob-key-file",
+           "encrypt blobs and their names with a key derived from the secret in FILE before \
+            they are stored, so the storage provider can read neither (must be given before any \
+            data is stored, and then always)", "FILE"),
+    optopt("", "bundle-key-file",
+           "bundle, unbundle: encrypt the bundle with a key derived from the secret in FILE",
+           "FILE"),
+    optflag("", "bundle-keys",
+            "bundle: include the secrets of --key-file and --chunk-key-file in the bundle; \
+             unbundle writes them to these files if they do not exist"),
+    optopt("", "s3-bucket",
+           "store new blobs in the S3 bucket BUCKET (through the aws command) instead of the \
+            local blob directory", "BUCKET"),
+    optopt("", "s3-prefix", "store blobs under PREFIX in the S3 bucket", "PREFIX"),
+    optopt("", "s3-region", "the region of the S3 bucket", "REGION"),
+    optopt("", "s3-profile", "take S3 credentials from this profile of the aws configuration",
+           "PROFILE"),
+    optmulti("", "s3-storage-class",
+             "store blobs with this storage hint in S3 storage class CLASS (defaults: STANDARD, \
+              STANDARD_IA and GLACIER_IR)", "frequent|infrequent|archive=CLASS"),
+    optopt("", "s3-object-lock",
+           "lock uploaded blobs with S3 Object Lock in this mode (the bucket must have Object \
+            Lock enabled); see also lock-blobs", "governance|compliance"),
+    optopt("", "s3-lock-days", "how many days --s3-object-lock locks uploaded blobs for", "DAYS"),
+    optopt("", "sftp",
+           "store new blobs in the directory DIR on a server, over SFTP (through the sftp \
+            command, with SSH keys or agent)", "[USER@]HOST:DIR"),
+    optopt("", "sftp-port", "the SSH port of the --sftp server", "PORT"),
+    optopt("", "sftp-identity", "log in to the --sftp server with the private key in FILE",
+           "FILE"),
+    optopt("", "webdav",
+           "store new blobs in the collection at URL on a WebDAV server, e.g. a Nextcloud or \
+            ownCloud share (through the curl command)", "URL"),
+    optopt("", "webdav-user", "log in to the --webdav server as USER", "USER"),
+    optopt("", "webdav-password-file",
+           "log in to the --webdav server with the password on the first line of FILE", "FILE"),
+    optopt("", "webdav-auth", "how to log in to the --webdav server: basic (default) or digest",
+           "METHOD"),
+    optflag("", "webdav-chunked", "upload to the --webdav server with chunked transfer encoding"),
+    optflag("", "probe-backend",
+            "before storing new blobs, write, read back and delete a small probe blob, to stop \
+             early if the blob store is unreachable or read-only"),
+    optflag("", "mirror",
+            "store new blobs both in the local blob directory and on each of the backends given \
+             by --s3-bucket, --sftp, --webdav, --rest and --exec"),
+    optopt("", "mirror-read",
+           "with --mirror, read blobs from the replicas in the order given (the default) or from \
+            the fastest one first", "in-order|fastest"),
+    optopt("", "mirror-placement",
+           "with --mirror, only count a blob as stored once every replica has stored it (the \
+            default), or once a replica in each failure domain has", "every-replica|every-domain"),
+    optmulti("", "failure-domain",
+             "put a backend (local, s3, sftp, webdav, rest or exec) in failure domain DOMAIN \
+              (defaults: local=onsite, and offsite for the others)", "BACKEND=DOMAIN"),
+    optflag("", "placement",
+            "with stats, list the blobs that are not stored in every failure domain"),
+    optopt("", "rest",
+           "store new blobs on the storage server at URL, which speaks hat's HTTP blob protocol \
+            (through the curl command)", "URL"),
+    optopt("", "rest-token-file",
+           "authenticate to the --rest server with the bearer token on the first line of FILE",
+           "FILE"),
+    optopt("", "proxy",
+           "reach the --s3-bucket, --sftp, --webdav and --rest backends through the HTTP proxy \
+            at URL, instead of the one in https_proxy and the like (SFTP needs nc, or socat with \
+            --proxy-user)", "URL"),
+    optopt("", "proxy-user", "log in to the --proxy as USER", "USER"),
+    optopt("", "proxy-password-file",
+           "log in to the --proxy with the password on the first line of FILE", "FILE"),
+    optopt("", "http-auth-command",
+           "authenticate each request to the --webdav or --rest server with the headers that \
+            COMMAND prints, one 'Name: value' per line; it is run with sh -c and the method and \
+            URL of the request, e.g. to sign requests or refresh OAuth tokens", "COMMAND"),
+    optopt("", "exec",
+           "store new blobs through COMMAND, run with sh -c and the arguments store NAME (blob \
+            on stdin), retrieve NAME (blob on stdout), delete NAME or list", "COMMAND"),
+    optopt("", "upload-limit",
+           "upload to the blob store at no more than RATE bytes per second on average", "RATE"),
+    optopt("", "download-limit",
+           "download from the blob store at no more than RATE bytes per second on average",
+           "RATE"),
+    optopt("", "retries",
+           "retry reads and writes of blobs that fail transiently (e.g. a timeout) N times, \
+            waiting longer each time (default 4)",
+           "N"),
+    optopt("", "read-cache-size",
+           "keep up to BYTES of the blobs read from the blob store in a local cache, so that they \
+            are not downloaded again (default 0, no cache)", "BYTES"),
+    optopt("", "hash-index-shards",
+           "split the hash index of a new repository across N database files (max 10)", "N"),
+    optopt("", "sparse-index",
+           "look up only 1/N of the hash index when storing data, to save memory and IO at the \
+            cost of some duplicate data (max 256)", "N"),
+    optflag("", "no-skip-caches",
+            "snapshot: include the contents of directories tagged with CACHEDIR.TAG"),
+    optflag("", "skip-xdg-cache",
+            "snapshot: skip the contents of the XDG cache directory (~/.cache)"),
+    optflag("", "include-repositories",
+            "snapshot: include the contents of hat repositories, which are skipped with a \
+             warning by default"),
+    optflag("", "honor-nodump",
+            "snapshot: skip files and directories with the nodump flag set (chattr +d)"),
+    optopt("", "read-fifos",
+           "snapshot: store the data of named pipes, giving up on a pipe when no data arrives \
+            for SECONDS (by default, named pipes, sockets and devices are stored as empty \
+            files)", "SECONDS"),
+    optmulti("", "command-source",
+             "snapshot: also store the output of COMMAND (run by sh) as the file PATH of the \
+              snapshot (repeatable)", "PATH=COMMAND"),
+    optflag("", "estimate",
+            "snapshot: count files and bytes first, to report progress as a percentage"),
+    optopt("", "volume-snapshot",
+           "snapshot: back up from a temporary btrfs or LVM snapshot of the source",
+           "btrfs|lvm:VG/LV"),
+    optopt("", "changed-since",
+           "snapshot: only read the files that differ from snapshot ID (or the newest as of TIME, \
+            or the latest) of FAMILY, e.g. after restoring it and making a few changes",
+           "FAMILY[@ID|@TIME]"),
+    optflag("", "reflink",
+            "checkout: share identical chunks between restored files (btrfs, XFS)"),
+    optopt("", "blob-size",
+           "snapshot: combine data into blobs of SIZE bytes for this family (remembered)", "SIZE"),
+    optopt("", "min-blob-size",
+           "snapshot: adapt the blob size to the backend's latency, between SIZE and --blob-size \
+            (remembered)", "SIZE"),
+    optopt("", "chunk-size",
+           "snapshot, verify-tree, manifest: split files into chunks of SIZE bytes (remembered \
+            by snapshot)", "SIZE"),
+    optflag("", "format-chunking",
+            "snapshot, verify-tree, manifest: align chunks with the entries of tar, zip and \
+             SQLite files (remembered by snapshot)"),
+    optmulti("", "exclude",
+             "snapshot: leave out PATH (relative to the snapshotted directory) and everything \
+              below it", "PATH"),
+    optmulti("", "priority",
+             "snapshot: hash and upload PATH (relative to the snapshotted directory, with \
+              everything below it) before other files; *SUFFIX matches files by name, e.g. \
+              *.sqlite", "PATH"),
+    optopt("", "priority-recent",
+           "snapshot: hash and upload files modified in the last HOURS hours before other files",
+           "HOURS"),
+    optopt("", "manifest",
+           "snapshot: only publish the snapshot if it captured every file listed in FILE, with \
+            the listed size and data (see the manifest command)", "FILE"),
+    optflag("", "save-defaults",
+            "snapshot: remember the directory and --exclude options as the family's defaults, \
+             used when no directory is given"),
+    optopt("", "snapshot-every",
+           "snapshot: with --save-defaults, make a snapshot due every DAYS days (see run-due)",
+           "DAYS"),
+    optflag("", "content", "diff: show how the contents of modified text files differ"),
+    optflag("", "regex", "grep: treat the pattern as a regular expression"),
+    optopt("", "path", "grep: only search files whose path starts with PREFIX", "PREFIX"),
+    optopt("", "snapshot",
+           "checkout, bundle: restore or export snapshot ID (see history) instead of the latest; \
+            family rollback: make it the latest snapshot again", "ID"),
+    optopt("", "at",
+           "checkout, bundle, family rollback: use the newest snapshot started at or before TIME \
+            (2014-10-01, \"2014-10-01 02:00\", 02:00 for the last 02:00, or @SECONDS; local \
+            time unless it ends in Z)", "TIME"),
+    optopt("", "before",
+           "checkout, bundle, family rollback: use the newest snapshot started before TIME (as \
+            for --at)", "TIME"),
+    optflag("", "show-id", "snapshots: show the content fingerprint of each snapshot"),
+    optopt("", "scan-workers",
+           "snapshot: list directories and read files in N tasks (default 5)", "N"),
+    optopt("", "hash-workers", "snapshot: hash data-chunks in N tasks (default: one per CPU)",
+           "N"),
+    optopt("", "upload-workers", "snapshot: upload N blobs in parallel (default 2)", "N"),
+    optopt("", "fetch-workers", "checkout: fetch the data of N files in parallel (default 4)", "N"),
+    optopt("", "strip-components",
+           "checkout: leave out the first N components of each restored path", "N"),
+    optmulti("", "map-path",
+             "checkout: restore the snapshot path FROM to TO (relative to the checkout path) \
+              instead; only mapped paths are restored (repeatable)", "FROM=TO"),
+    optopt("", "failure-list",
+           "snapshot: write the files that could not be read to FILE, one JSON object per line",
+           "FILE"),
+    optopt("", "to-command",
+           "checkout: instead of restoring into a directory, stream the snapshot as a tar \
+            archive into COMMAND (run by sh), e.g. 'ssh host tar -x -C /srv'", "COMMAND"),
+    optopt("", "restore-order",
+           "checkout: restore files in path order (default), smallest first, or grouped by blob",
+           "path|smallest|blob"),
+    optopt("", "bench-files", "bench: generate N files (default 1000)", "N"),
+    optopt("", "bench-file-size", "bench: generate files of SIZE bytes (default 65536)", "SIZE"),
+    optopt("", "bench-duplication",
+           "bench: make PERCENT of the files copies of other files (default 20)", "PERCENT"),
+    optopt("", "crash-probability",
+           "crash at each step of the commit protocol with probability P (debug builds only)",
+           "P"),
+    optopt("", "crash-rounds", "crash-test: snapshot with injected crashes N times (default 20)",
+           "N"),
+    optopt("", "read-data-subset",
+           "verify: download and re-hash N percent of the blobs, or blobs worth SIZE bytes \
+            (default 100%)", "N%|SIZE"),
+    optflag("", "nice", "run with low CPU and IO priority, and fewer parallel workers"),
+    optopt("", "watchdog",
+           "abort the run when a pipeline stage makes no progress for SECONDS (default: never)",
+           "SECONDS"),
+    optopt("", "notify-webhook",
+           "post a JSON summary of snapshot, verify, verify-blobs and run-due, and of any run \
+            that fails, to URL (uses curl)", "URL"),
+    optopt("", "notify-mail",
+           "mail the same summary to ADDRESS (uses sendmail)", "ADDRESS"),
+  ];
+
   let args = os::args();
-  if args.len() == 2 {
-    let ref flag = args[1];
-    if flag == &"--license".to_string() {
-        license();
+  let matches = match getopts(args.tail(), opts) {
+    Ok(m) => m,
+    Err(f) => { error!("{}", f.to_string()); return usage(opts); },
+  };
+
+  match (matches.opt_present("quiet"), matches.opt_present("verbose")) {
+    (true, true) => fail!("--quiet and --verbose cannot be used together"),
+    (true, false) => output::set_verbosity(output::Quiet),
+    (false, true) => output::set_verbosity(output::Verbose),
+    (false, false) => (),
+  }
+
+  if matches.opt_present("license") {
+    return license();
+  }
+  if matches.opt_present("help") {
+    usage(opts);
+    return license();
+  }
+
+  if matches.free.len() == 0 {
+    return usage(opts);
+  }
+
+  let nice = matches.opt_present("nice");
+  if nice {
+    for problem in niceness::lower_priority().iter() {
+      warning!("Warning: {}", problem);
+    }
+  }
+
+  matches.opt_str("crash-probability").map(|p| {
+    if !crash_test::supported() {
+      fail!("Crash injection is not available in this build.");
+    }
+    let probability = from_str::<f64>(p.as_slice()).expect(
+      "--crash-probability must be a number");
+    // Only the child processes of crash-test crash:
+    if matches.free[0] != "crash-test".to_string() {
+      crash_test::enable(probability);
+    }
+  });
+
+  matches.opt_str("watchdog").map(|seconds| {
+    process::enable_watchdog(from_str::<u64>(seconds.as_slice()).expect(
+      "--watchdog must be a number of seconds"));
+  });
+
+  let ref cmd = matches.free[0];
+
+  let mut notifiers = Vec::new();
+  matches.opt_str("notify-webhook").map(|url| notifiers.push(notify::Webhook(url)));
+  matches.opt_str("notify-mail").map(|address| notifiers.push(notify::Mail(address)));
+  let failure_guard = notify::FailureGuard::new(notifiers.clone(), cmd.as_slice());
+  let notify = |status: notify::RunStatus, summary: String, problems: Vec<String>| {
+    let run = notify::RunSummary::new(cmd.as_slice(), status, failure_guard.started(),
+                                      notify::now(), summary, problems);
+    notify::send_all(notifiers.as_slice(), &run);
+  };
+
+  if cmd == &"maintenance".to_string() {
+    let hat = open_repository(&matches);
+    info!("Running maintenance on repository indexes...");
+    hat.maintenance();
+
+    for name in matches.free.slice_from(1).iter() {
+      let family_opt = hat.open_family(name.clone());
+      let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
+      info!("Running maintenance on family '{}'...", name);
+      family.maintenance();
     }
-    else if flag == &"--help".to_string() {
-      usage();
-      license();
+    return;
+  }
+
+  if cmd == &"compact".to_string() {
+    let hat = open_repository(&matches);
+    info!("Compacting the hash index...");
+    let (merged, removed) = hat.compact_hash_index();
+    info!("Merged {} duplicate hash entry(s) and removed {} entry(s) of deleted blobs.",
+          merged, removed);
+    return;
+  }
+
+  if cmd == &"check".to_string() {
+    let hat = open_repository(&matches);
+    let mut problems = hat.check();
+
+    for name in matches.free.slice_from(1).iter() {
+      let family_opt = hat.open_family(name.clone());
+      let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
+      problems.extend(family.check().into_iter());
+    }
+
+    for problem in problems.iter() {
+      println!("{}", problem);
+    }
+    if problems.len() > 0 {
+      info!("Found {} problem(s).", problems.len());
+      os::set_exit_status(1);
+    } else {
+      info!("No problems found.");
     }
     return;
   }
 
-  if args.len() != 4 {
-    return usage();
+  if cmd == &"schedule".to_string() {
+    let hat = open_repository(&matches);
+    match matches.free.len() {
+      1 => {
+        for (task, days, last_run) in hat.maintenance_schedule().into_iter() {
+          let last = match last_run {
+            Some(t) => time::at(time::Timespec::new(t as i64, 0)).rfc3339(),
+            None => "never".to_string(),
+          };
+          if days > 0 {
+            println!("{}: every {} day(s), last run {}", task, days, last);
+          } else {
+            println!("{}: not scheduled, last run {}", task, last);
+          }
+        }
+      },
+      3 => {
+        let days = from_str::<u64>(matches.free[2].as_slice()).expect(
+          "schedule: days must be a number");
+        match hat.schedule_maintenance(matches.free[1].as_slice(), days) {
+          Ok(()) => (),
+          Err(e) => {
+            error!("{} Known tasks: {}.", e, hat::MAINTENANCE_TASKS.connect(", "));
+            os::set_exit_status(1);
+          },
+        }
+      },
+      _ => usage(opts),
+    }
+    return;
   }
 
-  let ref cmd = args[1];
+  if cmd == &"run-due".to_string() {
+    let (mut ran, mut problems, due) = {
+      let hat = open_repository(&matches);
+      let (ran, problems) = hat.run_due_maintenance();
+      let ran: Vec<String> = ran.iter().map(|task| task.to_string()).collect();
+      (ran, problems, hat.due_snapshots())
+    };
+    // Snapshots run in a process of their own, as they would from the command line:
+    for name in due.into_iter() {
+      info!("Taking the due snapshot of '{}'...", name);
+      let status = run_self(&matches, ["snapshot".to_string(), name.clone()]);
+      if !status.success() {
+        problems.push(format!("snapshot of '{}' failed ({})", name, status));
+      }
+      ran.push(format!("snapshot of '{}'", name));
+    }
+    if ran.len() == 0 {
+      info!("No maintenance is due.");
+    } else {
+      info!("Ran: {}", ran.connect(", "));
+    }
+    for problem in problems.iter() {
+      println!("{}", problem);
+    }
+    if problems.len() > 0 {
+      info!("Found {} problem(s).", problems.len());
+      os::set_exit_status(1);
+    }
+    let summary = if ran.len() == 0 { "No maintenance was due.".to_string() }
+                  else { format!("Ran: {}.", ran.connect(", ")) };
+    notify(if problems.len() > 0 { notify::Failure } else { notify::Success }, summary,
+           problems);
+    return;
+  }
+
+  if cmd == &"check-backend".to_string() {
+    let mut backends = vec![labeled_primary_backend(&matches)];
+    if archive_dir().exists() {
+      backends.push((format!("archive directory ({})", archive_dir().display()),
+                     blob_store::BoxedBackend::new(blob_store::FileBackend::new(archive_dir()))));
+    }
+    let mut failed = false;
+    for (label, mut backend) in backends.into_iter() {
+      info!("Checking the {}...", label);
+      for (step, result) in blob_store::check_backend(&mut backend).into_iter() {
+        match result {
+          Ok(()) => println!("  {}: ok", step),
+          Err(e) => {
+            println!("  {}: FAILED: {}", step, e);
+            failed = true;
+          },
+        }
+      }
+      match backend.probe() {
+        Ok(probe) => {
+          println!("  read latency: {} ms", probe.latency_ms);
+          println!("  free space: {}", probe.free_bytes.map(|bytes| format!("{} bytes", bytes))
+                                           .unwrap_or("unknown".to_string()));
+        },
+        Err(e) => {
+          println!("  probe: FAILED: {}", e);
+          failed = true;
+        },
+      }
+    }
+    if failed {
+      error!("The backend is not usable; fix the problems above before taking a snapshot.");
+      os::set_exit_status(1);
+    } else {
+      info!("The backend works.");
+    }
+    return;
+  }
+
+  if cmd == &"verify-blobs".to_string() {
+    let hat = open_repository(&matches);
+    let (problems, unverified) = match hat.verify_blobs() {
+      Ok(result) => result,
+      Err(e) => {
+        error!("{}", e);
+        os::set_exit_status(1);
+        return;
+      },
+    };
+    for problem in problems.iter() {
+      println!("{}", problem);
+    }
+    if unverified > 0 {
+      info!("{} blob(s) could not be verified: the backend keeps no checksums for them.",
+            unverified);
+    }
+    if problems.len() > 0 {
+      info!("Found {} problem(s).", problems.len());
+      os::set_exit_status(1);
+    } else {
+      info!("No problems found.");
+    }
+    notify(if problems.len() > 0 { notify::Failure } else { notify::Success },
+           format!("Found {} problem(s); {} blob(s) could not be verified.", problems.len(),
+                   unverified),
+           problems);
+    return;
+  }
+
+  if cmd == &"seed".to_string() {
+    if matches.free.len() != 3 {
+      return usage(opts);
+    }
+    let ref name = matches.free[1];
+    let ref path = matches.free[2];
+
+    let mut options = hat::SnapshotOptions::new();
+    options.skip_tagged_cache_dirs = !matches.opt_present("no-skip-caches");
+    options.skip_xdg_cache_dir = matches.opt_present("skip-xdg-cache");
+    options.skip_repositories = !matches.opt_present("include-repositories");
+    options.honor_nodump = matches.opt_present("honor-nodump");
+    size_opt(&matches, "read-fifos").map(|seconds| {
+      options.special_files = hat::ReadFifos(seconds as u64 * 1000);
+    });
+    if nice {
+      options.workers = 1;
+    }
+    size_opt(&matches, "scan-workers").map(|n| options.workers = cmp::max(1, n));
+
+    let hat = open_repository(&matches);
+    let family_opt = hat.open_family(name.clone());
+    let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
+    let counts = family.seed_dir(Path::new(path.clone()), options);
+    family.flush();
+    info!("Seeded {} file(s); {} file(s) are not stored yet, and will be read by the next \
+           snapshot.", counts.seeded, counts.missing);
+    return;
+  }
+
+  if cmd == &"archive".to_string() {
+    if matches.free.len() != 2 {
+      return usage(opts);
+    }
+    let days = from_str::<u64>(matches.free[1].as_slice()).expect("days must be a number");
+    mkdir_recursive(&archive_dir(), UserDir).unwrap();
+    let hat = open_repository(&matches);
+    let report = hat.archive_old_blobs(days);
+    for error in report.errors.iter() {
+      error!("Could not move blob {}", error);
+    }
+    info!("Moved {} blob(s) to the archive, and {} blob(s) back from it.", report.archived,
+          report.recalled);
+    if report.errors.len() > 0 {
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"hint-storage".to_string() {
+    if matches.free.len() != 3 {
+      return usage(opts);
+    }
+    let days = from_str::<u64>(matches.free[1].as_slice()).expect("days must be a number");
+    let hint = match blob_store::StorageHint::from_name(matches.free[2].as_slice()) {
+      Some(blob_store::FrequentAccess) | None => {
+        fail!("the storage hint must be infrequent or archive")
+      },
+      Some(hint) => hint,
+    };
+    let hat = open_repository(&matches);
+    let report = hat.hint_old_blobs(days, hint);
+    for error in report.errors.iter() {
+      error!("Could not change the storage hint of blob {}", error);
+    }
+    info!("Gave {} blob(s) the storage hint '{}', and {} blob(s) the default hint again.",
+          report.hinted, matches.free[2], report.restored);
+    if report.errors.len() > 0 {
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"stats".to_string() {
+    let backends = labeled_backends(&matches);
+    let labels: Vec<String> = backends.iter().map(|&(ref label, _, _)| label.clone()).collect();
+    let mut mirror = mirror_of(&matches, backends);
+    let report = match mirror.placement_report() {
+      Ok(report) => report,
+      Err(e) => fail!(format!("Could not list the stored blobs: {}", e)),
+    };
+    for (i, label) in labels.iter().enumerate() {
+      println!("{}: {} blob(s) ({})", label, report.stored[i], mirror.domains()[i]);
+    }
+    if matches.opt_present("placement") {
+      for &(ref name, ref missing) in report.misplaced.iter() {
+        println!("{} is missing from: {}", name.as_slice().to_hex(), missing.connect(", "));
+      }
+      info!("{} of {} blob(s) are stored in every failure domain.",
+            report.blobs - report.misplaced.len(), report.blobs);
+      if report.misplaced.len() > 0 {
+        os::set_exit_status(1);
+      }
+    }
+    return;
+  }
+
+  if cmd == &"lock-blobs".to_string() {
+    if matches.free.len() != 2 {
+      return usage(opts);
+    }
+    let days = from_str::<u64>(matches.free[1].as_slice()).expect("days must be a number");
+    let hat = open_repository(&matches);
+    let report = hat.lock_recent_blobs(days);
+    for error in report.errors.iter() {
+      error!("Could not lock blob {}", error);
+    }
+    if report.locked > 0 {
+      info!("Locked {} blob(s) until {}.", report.locked,
+            time::at(time::Timespec::new(report.until as i64, 0)).rfc3339());
+    }
+    if report.errors.len() > 0 {
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"verify".to_string() {
+    let subset = match matches.opt_str("read-data-subset") {
+      None => hat::PercentOfBlobs(100),
+      Some(ref arg) if arg.as_slice().ends_with("%") => {
+        hat::PercentOfBlobs(from_str::<uint>(arg.as_slice().slice_to(arg.len() - 1)).expect(
+          "--read-data-subset must be a percentage (N%) or a number of bytes"))
+      },
+      Some(arg) => hat::ByteBudget(from_str::<u64>(arg.as_slice()).expect(
+        "--read-data-subset must be a percentage (N%) or a number of bytes")),
+    };
+    let hat = open_repository(&matches);
+    let report = hat.verify_data(subset);
+    for problem in report.problems.iter() {
+      println!("{}", problem);
+    }
+    let summary = format!("Verified {} chunk(s) in {} blob(s) ({} bytes).", report.chunks,
+                          report.blobs, report.bytes);
+    info!("{}", summary);
+    if report.problems.len() > 0 {
+      info!("Found {} problem(s).", report.problems.len());
+      os::set_exit_status(1);
+    } else {
+      info!("No problems found.");
+    }
+    notify(if report.problems.len() > 0 { notify::Failure } else { notify::Success }, summary,
+           report.problems);
+    return;
+  }
+
+  if cmd == &"snapshots".to_string() {
+    let hat = open_repository(&matches);
+    for name in hat.list_families().into_iter() {
+      if matches.opt_present("show-id") {
+        let family_opt = hat.open_family(name.clone());
+        let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
+        println!("{}  {}", fingerprint::to_printable(&family.fingerprint()), name);
+      } else {
+        println!("{}", name);
+      }
+    }
+    return;
+  }
+
+  if cmd == &"import-borg".to_string() {
+    if matches.free.len() != 3 {
+      return usage(opts);
+    }
+    let ref name = matches.free[1];
+    let ref repository = matches.free[2];
+
+    let archives = borg::list_archives(repository.as_slice()).unwrap_or_else(|e| fail!(e));
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    // Archives imported by an earlier (interrupted) run are recognised by their start time:
+    let imported: Vec<u64> = family.list_snapshots().into_iter().map(|(_, t)| t).collect();
+    for archive in archives.iter() {
+      if imported.contains(&archive.started) {
+        detail!("Skipping archive '{}': already imported", archive.name);
+        continue;
+      }
+      match borg::import_archive(&family, repository.as_slice(), archive) {
+        Ok(count) => info!("Imported archive '{}' ({} entries)", archive.name, count),
+        Err(e) => {
+          error!("{}", e);
+          os::set_exit_status(1);
+          return;
+        },
+      }
+    }
+    return;
+  }
+
+  if cmd == &"history".to_string() {
+    if matches.free.len() != 2 {
+      return usage(opts);
+    }
+    let ref name = matches.free[1];
+
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    for (id, started) in family.list_snapshots().into_iter() {
+      if started > 0 {
+        let tm = time::at(time::Timespec::new(started as i64, 0));
+        println!("{}  {}", id, tm.rfc3339());
+      } else {
+        println!("{}  (from before snapshot history)", id);
+      }
+    }
+    return;
+  }
+
+  if cmd == &"family".to_string() && matches.free.len() == 3 &&
+     matches.free[1] == "rollback".to_string() {
+    let ref name = matches.free[2];
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    let snapshots = family.list_snapshots();
+    let id = selected_snapshot(&matches, name.as_slice(), snapshots.as_slice()).expect(
+      "family rollback needs --snapshot, --at or --before");
+    match family.roll_back_to(id) {
+      Ok(copy) => info!("Snapshot {} of '{}' is the latest again, as snapshot {}; the snapshots \
+                         in between are kept.", id, name, copy),
+      Err(e) => {
+        error!("Could not roll back '{}': {}", name, e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if cmd == &"family".to_string() {
+    if matches.free.len() != 4 || matches.free[1] != "fork".to_string() {
+      return usage(opts);
+    }
+    let ref name = matches.free[2];
+    let ref new_name = matches.free[3];
+
+    let hat = open_repository(&matches);
+    match hat.fork_family(name.clone(), new_name.clone()) {
+      Ok(()) => info!("Created family '{}' from '{}'.", new_name, name),
+      Err(e) => {
+        error!("Could not fork family: {}", e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if cmd == &"grep".to_string() {
+    if matches.free.len() < 2 {
+      return usage(opts);
+    }
+    let ref pattern_str = matches.free[1];
+    let pattern = if matches.opt_present("regex") {
+      match regex::Regex::new(pattern_str.as_slice()) {
+        Ok(re) => grep::RegexPattern(re),
+        Err(e) => fail!(format!("Invalid regular expression '{}': {}", pattern_str, e)),
+      }
+    } else {
+      grep::LiteralPattern(pattern_str.clone())
+    };
+    let path_prefix = matches.opt_str("path").unwrap_or(String::new());
+
+    let hat = open_repository(&matches);
+    let names = if matches.free.len() > 2 { matches.free.slice_from(2).into_vec() }
+                else { hat.list_families() };
+
+    let mut any_match = false;
+    for name in names.into_iter() {
+      let family_opt = hat.open_family(name.clone());
+      let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
+      for (path, binary, lines) in family.grep(&pattern, path_prefix.as_bytes()).into_iter() {
+        any_match = true;
+        let path = String::from_utf8_lossy(path.as_slice()).into_string();
+        if binary {
+          println!("Binary file {}:{} matches", name, path);
+          continue;
+        }
+        for (line_no, line) in lines.into_iter() {
+          println!("{}:{}:{}:{}", name, path, line_no, line);
+        }
+      }
+    }
+    if !any_match {
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"bench".to_string() {
+    if matches.free.len() != 2 {
+      return usage(opts);
+    }
+    let scratch_dir = Path::new(matches.free[1].clone());
+    if scratch_dir.exists() {
+      fail!(format!("Scratch directory '{}' already exists.", scratch_dir.display()));
+    }
+
+    let mut options = bench::BenchOptions::new();
+    size_opt(&matches, "bench-files").map(|n| options.files = n);
+    size_opt(&matches, "bench-file-size").map(|n| options.file_size = n);
+    size_opt(&matches, "bench-duplication").map(|n| options.duplication = cmp::min(n, 100));
+    size_opt(&matches, "blob-size").map(|n| options.max_blob_size = n);
+
+    let mut settings = hat::FamilySettings::new();
+    settings.min_blob_size = size_opt(&matches, "min-blob-size");
+    settings.chunk_size = size_opt(&matches, "chunk-size");
+    if matches.opt_present("format-chunking") {
+      settings.format_aware_chunking = Some(true);
+    }
+
+    let mut blobs = scratch_dir.clone();
+    blobs.push("blobs");
+    mkdir_recursive(&blobs, UserDir).unwrap();
+    let backend = blob_store::FileBackend::new(blobs);
+
+    match bench::run(&scratch_dir, backend, &options, settings) {
+      Ok((stages, counts)) => {
+        for stage in stages.iter() {
+          println!("{:<10} {:>12} bytes {:>9.3} s {:>9.1} MiB/s", stage.name, stage.bytes,
+                   stage.nanoseconds as f64 / 1e9, stage.throughput());
+        }
+        println!("Stored {} new bytes; {} bytes were deduplicated.",
+                 counts.new_bytes, counts.dedup_bytes);
+      },
+      Err(e) => {
+        error!("Benchmark failed: {}", e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if cmd == &"checkout".to_string() && matches.opt_present("to-command") {
+    if matches.free.len() != 2 {
+      return usage(opts);
+    }
+    let ref name = matches.free[1];
+    let command = matches.opt_str("to-command").unwrap();
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    let snapshots = family.list_snapshots();
+    selected_snapshot(&matches, name.as_slice(), snapshots.as_slice()).map(|id| {
+      family.select_snapshot(id);
+    });
+    match checkout_to_command(&family, command.as_slice()) {
+      Ok(count) => info!("Wrote {} entries of '{}' to '{}'.", count, name, command),
+      Err(e) => {
+        error!("Could not write '{}' to '{}': {}", name, command, e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if cmd == &"manifest".to_string() && matches.free.len() == 2 {
+    let ref path = matches.free[1];
+    let chunker = chunker::ChunkerOptions{
+      chunk_size: size_opt(&matches, "chunk-size").unwrap_or(hat::DEFAULT_CHUNK_SIZE),
+      format_aware: matches.opt_present("format-chunking"),
+    };
+    match manifest::manifest_of_dir(&Path::new(path.clone()), &chunker, &mut stdio::stdout()) {
+      Ok(count) => info!("Listed {} file(s).", count),
+      Err(e) => {
+        error!("Could not read '{}': {}", path, e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if matches.free.len() != 3 && !(cmd == &"snapshot".to_string() && matches.free.len() == 2) {
+    return usage(opts);
+  }
+
+  if cmd == &"crash-test".to_string() {
+    if crash_test(&matches, &matches.free[1], &matches.free[2]) {
+      info!("The repository survived all crashes.");
+    } else {
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"verify-tree".to_string() {
+    let ref expected = matches.free[1];
+    let ref path = matches.free[2];
+
+    let chunker = chunker::ChunkerOptions{
+      chunk_size: size_opt(&matches, "chunk-size").unwrap_or(hat::DEFAULT_CHUNK_SIZE),
+      format_aware: matches.opt_present("format-chunking"),
+    };
+    let found = match fingerprint::fingerprint_of_dir(&Path::new(path.clone()), &chunker) {
+      Ok(fp) => fp,
+      Err(e) => fail!(format!("Could not read '{}': {}", path, e)),
+    };
+    if fingerprint::matches_printable(&found, expected.as_slice()) {
+      println!("OK: '{}' matches fingerprint {}", path, expected);
+    } else {
+      println!("MISMATCH: '{}' has fingerprint {}", path, fingerprint::to_printable(&found));
+      os::set_exit_status(1);
+    }
+    return;
+  }
+
+  if cmd == &"diff".to_string() {
+    let ref name = matches.free[1];
+    let ref other_name = matches.free[2];
+
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    let other = hat.open_family(other_name.clone()).expect(
+      format!("Could not open family '{}'", other_name).as_slice());
+
+    for change in family.diff(&other, matches.opt_present("content")).into_iter() {
+      match change {
+        hat::Added(path) => println!("A {}", String::from_utf8_lossy(path.as_slice())),
+        hat::Removed(path) => println!("D {}", String::from_utf8_lossy(path.as_slice())),
+        hat::Modified(path, description) => {
+          println!("M {}", String::from_utf8_lossy(path.as_slice()));
+          description.map(|text| print!("{}", text));
+        },
+      }
+    }
+    return;
+  }
+
+  if cmd == &"bundle".to_string() {
+    let ref name = matches.free[1];
+    let path = Path::new(matches.free[2].clone());
+    let key = bundle_key(&matches);
+    let (index_key, chunk_key) = if matches.opt_present("bundle-keys") {
+      (index_secret(&matches).map(|secret| secret.as_slice().to_hex()),
+       key_secret(&matches, "chunk-key-file"))
+    } else { (None, None) };
+
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    let snapshots = family.list_snapshots();
+    let selected = match selected_snapshot(&matches, name.as_slice(), snapshots.as_slice()) {
+      Some(id) => {
+        family.select_snapshot(id);
+        snapshots.iter().find(|&&(i, _)| i == id)
+      },
+      None => snapshots.last(),
+    };
+    let started = match selected {
+      Some(&(_, started)) => if started > 0 { Some(started) } else { None },
+      None => fail!(format!("Family '{}' has no such snapshot.", name)),
+    };
+
+    let info = bundle::BundleInfo{family: name.clone(), started: started, created: notify::now(),
+                                  index_key: index_key, chunk_key: chunk_key};
+    let written = File::create(&path).and_then(|file| {
+      let mut writer = try!(bundle::BundleWriter::new(file, &key, &info));
+      let count = try!(family.export_bundle(&mut writer));
+      try!(writer.finish());
+      Ok(count)
+    });
+    match written {
+      Ok(count) => info!("Wrote {} entries of '{}' to '{}'.", count, name, path.display()),
+      Err(e) => {
+        error!("Could not write bundle '{}': {}", path.display(), e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
+
+  if cmd == &"passwd".to_string() {
+    let path = keyring_path();
+    let mut keyring = if path.exists() {
+      keyring::Keyring::load(&path).unwrap_or_else(|e| {
+        fail!(format!("Could not read keyring '{}': {}", path.display(), e))
+      })
+    } else { keyring::Keyring::new() };
+    let action = if matches.free.len() > 1 { matches.free[1].clone() }
+                 else { "change".to_string() };
+    if action.as_slice() == "list" {
+      for label in keyring.labels().iter() {
+        println!("{}", label);
+      }
+      return;
+    }
+
+    // Changing passphrases needs the secret, but leaves it (and all data) as it is:
+    let (unlocked, secret) = match (unlock_keyring(&matches), key_secret(&matches, "key-file")) {
+      (Some(_), Some(_)) => fail!("--key-file and --passphrase-file cannot be used together"),
+      (Some((label, secret)), None) => (Some(label), secret),
+      (None, Some(secret)) => (None, secret.as_slice().from_hex().unwrap()),
+      (None, None) => fail!("passwd needs the current --passphrase-file, or the --key-file"),
+    };
+    let label = if matches.free.len() > 2 { Some(matches.free[2].clone()) } else { unlocked };
+    let new = || passphrase(&matches, "new-passphrase-file").expect(
+      "passwd needs the new passphrase in --new-passphrase-file");
+    let changed = match (action.as_slice(), label) {
+      ("change", Some(label)) => keyring.change(label.as_slice(), new().as_slice(),
+                                                secret.as_slice()),
+      ("add", Some(ref label)) if matches.free.len() > 2 =>
+        keyring.add(label.as_slice(), new().as_slice(), secret.as_slice()),
+      ("remove", Some(ref label)) if matches.free.len() > 2 => keyring.remove(label.as_slice()),
+      _ => return usage(opts),
+    };
+    match changed {
+      Ok(()) => (),
+      Err(e) => {
+        error!("{}", e);
+        os::set_exit_status(1);
+        return;
+      },
+    }
+    mkdir_recursive(&repository_root(), UserDir).and_then(|()| keyring.save(&path))
+      .unwrap_or_else(|e| fail!(format!("Could not write keyring '{}': {}", path.display(), e)));
+    info!("Updated the passphrases of the repository keyring ({}).",
+          keyring.labels().connect(", "));
+    return;
+  }
+
+  if cmd == &"unbundle".to_string() {
+    let ref name = matches.free[1];
+    let path = Path::new(matches.free[2].clone());
+    let key = bundle_key(&matches);
+    let opened = File::open(&path).and_then(|file| bundle::BundleReader::open(file, &key));
+    let (info, mut reader) = opened.unwrap_or_else(|e| {
+      fail!(format!("Could not read bundle '{}': {}", path.display(), e))
+    });
+    restore_key_secret(&matches, "key-file", &info.index_key);
+    restore_key_secret(&matches, "chunk-key-file", &info.chunk_key);
+
+    let hat = open_repository(&matches);
+    let family = hat.open_family(name.clone()).expect(
+      format!("Could not open family '{}'", name).as_slice());
+    match family.snapshot_bundle(&mut reader, info.started) {
+      Ok(count) => {
+        family.flush();
+        info!("Restored {} entries of '{}' from '{}' as a snapshot of '{}'.", count,
+              info.family, path.display(), name);
+      },
+      Err(e) => {
+        error!("Could not read bundle '{}': {}; no snapshot was taken.", path.display(), e);
+        os::set_exit_status(1);
+      },
+    }
+    return;
+  }
 
   if cmd == &"snapshot".to_string() {
-    let ref name = args[2];  // used for naming the key index
-    let ref path = args[3];
+    let ref name = matches.free[1];  // used for naming the key index
+    let path_arg = if matches.free.len() > 2 { Some(matches.free[2].clone()) } else { None };
 
+    let mut options = hat::SnapshotOptions::new();
+    options.skip_tagged_cache_dirs = !matches.opt_present("no-skip-caches");
+    options.skip_xdg_cache_dir = matches.opt_present("skip-xdg-cache");
+    options.skip_repositories = !matches.opt_present("include-repositories");
+    options.honor_nodump = matches.opt_present("honor-nodump");
+    size_opt(&matches, "read-fifos").map(|seconds| {
+      options.special_files = hat::ReadFifos(seconds as u64 * 1000);
+    });
+    let mut workers = hat::PipelineWorkers::auto();
+    if nice {
+      options.workers = 1;
+      workers = hat::PipelineWorkers{hash: 1, upload: 1};
+    }
+    size_opt(&matches, "scan-workers").map(|n| options.workers = cmp::max(1, n));
+    size_opt(&matches, "hash-workers").map(|n| workers.hash = cmp::max(1, n));
+    size_opt(&matches, "upload-workers").map(|n| workers.upload = cmp::max(1, n));
+
+    let command_sources: Vec<(String, String)> = matches.opt_strs("command-source").iter()
+      .map(|source| match source.as_slice().find('=') {
+        Some(i) => (source.as_slice().slice_to(i).to_string(),
+                    source.as_slice().slice_from(i + 1).to_string()),
+        None => fail!("--command-source must be of the form PATH=COMMAND"),
+      }).collect();
+
+    let snapshot_manifest = matches.opt_str("manifest").map(|path| {
+      let file = File::open(&Path::new(path.clone())).unwrap_or_else(|e| {
+        fail!(format!("Could not open manifest '{}': {}", path, e))
+      });
+      match manifest::SnapshotManifest::read(file) {
+        Ok(manifest) => sync::Arc::new(manifest),
+        Err(e) => fail!(format!("Could not read manifest '{}': {}", path, e)),
+      }
+    });
+    options.manifest = snapshot_manifest.clone();
+
+    let mut settings = hat::FamilySettings::new();
+    settings.max_blob_size = size_opt(&matches, "blob-size");
+    settings.min_blob_size = size_opt(&matches, "min-blob-size");
+    settings.chunk_size = size_opt(&matches, "chunk-size");
+    if matches.opt_present("format-chunking") {
+      settings.format_aware_chunking = Some(true);
+    }
+    if matches.opt_present("save-defaults") {
+      let root = match path_arg {
+        Some(ref path) => os::make_absolute(&Path::new(path.clone())).display().to_string(),
+        None => fail!("--save-defaults needs the directory to snapshot"),
+      };
+      settings.defaults = Some(hat::FamilyDefaults{
+        root: Some(root),
+        excludes: matches.opt_strs("exclude"),
+        snapshot_interval_days: size_opt(&matches, "snapshot-every").unwrap_or(0) as u64,
+      });
+    }
+
+    let mut problems = Vec::new();
+    let mut incomplete = false;
+    let summary;
     {
-      let backend = blob_store::FileBackend::new(blob_dir());
-      let hat_opt = hat::Hat::open_repository(
-        &Path::new("repo"), backend, MAX_BLOB_SIZE);
-      let hat = hat_opt.expect(format!("Could not open repository in {}.", path).as_slice());
+      let mut hat = open_repository(&matches);
+      hat.set_pipeline_workers(workers);
 
-      let family_opt = hat.open_family(name.clone());
+      options.reference = changed_since_files(&matches, &hat).map(|files| sync::Arc::new(files));
+
+      let family_opt = hat.open_family_with_settings(name.clone(), settings);
       let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
 
-      family.snapshot_dir(Path::new(path.clone()));
-      family.flush();
+      let path = match path_arg.clone().or(family.defaults().root.clone()) {
+        Some(path) => path,
+        None => fail!(format!("Family '{}' has no default directory; give one, and remember \
+                               it with --save-defaults", name)),
+      };
+      let mut excludes = matches.opt_strs("exclude");
+      for exclude in family.defaults().excludes.iter() {
+        if !excludes.contains(exclude) { excludes.push(exclude.clone()) }
+      }
+      options.excludes = excludes.into_iter().map(|exclude| Path::new(exclude)).collect();
+      options.priority_paths = matches.opt_strs("priority");
+      options.priority_since = size_opt(&matches, "priority-recent").map(|hours| {
+        let now = time::get_time().sec as u64;
+        (now - cmp::min(now, hours as u64 * 3600)) * 1000
+      });
+
+      let volume_snapshot = matches.opt_str("volume-snapshot").map(|kind_str| {
+        let kind = volume_snapshot::VolumeSnapshotKind::from_str(kind_str.as_slice()).expect(
+          format!("Unknown volume snapshot kind '{}'", kind_str).as_slice());
+        match volume_snapshot::VolumeSnapshot::create(kind, &Path::new(path.clone())) {
+          Ok(vs) => vs,
+          Err(e) => fail!(format!("Could not create volume snapshot: {}", e)),
+        }
+      });
+      let source = match volume_snapshot {
+        Some(ref vs) => vs.path().clone(),
+        None => Path::new(path.clone()),
+      };
+
+      if matches.opt_present("estimate") {
+        let estimate = family.estimate_dir(source.clone(), options.clone());
+        info!("Estimate: {} files and directories, {} bytes; {} file(s) with {} bytes changed \
+               since the last snapshot.", estimate.entries, estimate.bytes,
+              estimate.changed_files, estimate.changed_bytes);
+        options.expected = Some(estimate);
+      }
+
+      family.snapshot_dir(source, options).unwrap();
+      for &(ref path, ref command) in command_sources.iter() {
+        family.snapshot_command(path.as_slice(), command.as_slice());
+      }
+      let checked = match snapshot_manifest {
+        Some(ref manifest) => family.flush_checked(&**manifest),
+        None => Ok(family.flush()),
+      };
+      let failures = family.failed_files();
+      if failures.len() > 0 {
+        error!("{} file(s) could not be read in full; see the errors above.", failures.len());
+        problems = failures.iter().map(|f| format!("{}: {}", f.path, f.error)).collect();
+      }
+      match checked {
+        Ok(()) => (),
+        Err(missing) => {
+          for problem in missing.iter() {
+            error!("{}", problem);
+          }
+          error!("The snapshot does not match the manifest; it was not published.");
+          incomplete = true;
+          problems.push_all(missing.as_slice());
+        },
+      }
+      matches.opt_str("failure-list").map(|path| {
+        write_failure_list(&Path::new(path), failures.as_slice());
+      });
+
+      let counts = family.byte_counts();
+      let total = counts.new_bytes + counts.dedup_bytes;
+      summary = format!("Stored {} new bytes; {} bytes were deduplicated ({}% of {} bytes).",
+                        counts.new_bytes, counts.dedup_bytes,
+                        if total > 0 { counts.dedup_bytes * 100 / total } else { 0 }, total);
+      info!("{}", summary);
     }
 
-    println!("Waiting for final flush...");
+    info!("Waiting for final flush...");
+    if incomplete {
+      os::set_exit_status(1);
+    } else if problems.len() > 0 {
+      os::set_exit_status(EXIT_PARTIAL);
+    }
+    let status = if incomplete { notify::Failure }
+                 else if problems.len() > 0 { notify::Partial }
+                 else { notify::Success };
+    notify(status, summary, problems);
     return;
   }
   else if cmd == &"checkout".to_string() {
-    let ref name = args[2];  // used for naming the key index
-    let ref path = args[3];
+    let ref name = matches.free[1];  // used for naming the key index
+    let ref path = matches.free[2];
 
-    let backend = blob_store::FileBackend::new(blob_dir());
-    let hat_opt = hat::Hat::open_repository(
-      &Path::new("repo"), backend, MAX_BLOB_SIZE);
-    let hat = hat_opt.expect(format!("Could not open repository in {}.", path).as_slice());
+    let hat = open_repository(&matches);
 
     let family_opt = hat.open_family(name.clone());
     let family = family_opt.expect(format!("Could not open family '{}'", name).as_slice());
 
-    family.checkout_in_dir(&mut Path::new(path.clone()), None);
+    let mut options = hat::CheckoutOptions::new();
+    options.reflink = matches.opt_present("reflink");
+    if nice {
+      options.fetch_workers = 1;
+    }
+    matches.opt_str("fetch-workers").map(|n| {
+      options.fetch_workers = from_str::<uint>(n.as_slice()).expect(
+        "--fetch-workers must be a number");
+    });
+    matches.opt_str("restore-order").map(|order| {
+      options.order = hat::RestoreOrder::from_name(order.as_slice()).expect(
+        "--restore-order must be one of path, smallest or blob");
+    });
+    size_opt(&matches, "strip-components").map(|n| options.strip_components = n);
+    for mapping in matches.opt_strs("map-path").iter() {
+      match mapping.as_slice().find('=') {
+        Some(i) => options.map_path(mapping.as_slice().slice_to(i),
+                                    Path::new(mapping.as_slice().slice_from(i + 1))),
+        None => fail!("--map-path must be of the form FROM=TO"),
+      }
+    }
+
+    let snapshots = family.list_snapshots();
+    selected_snapshot(&matches, name.as_slice(), snapshots.as_slice()).map(|id| {
+      family.select_snapshot(id);
+    });
+
+    family.checkout_in_dir(&mut Path::new(path.clone()), None, &options).unwrap();
     return;
   }
 
-  usage();
+  usage(opts);
 
 }
@@ -0,0 +1,227 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Manifests of the files that a snapshot must capture.
+//!
+//! A manifest lists one file per line, as `HASH SIZE PATH`: the hex data hash of the file (see
+//! `fingerprint::file_data_hash`), its size in bytes, and its path relative to the directory
+//! being snapshotted. It is typically written where the data is produced (see `manifest_of_dir`)
+//! and handed to the snapshot (see `SnapshotOptions::manifest`). The snapshot is checked against
+//! it when it is flushed, and is not published if a file is missing or holds other data (see
+//! `Family::flush_checked`).
+
+use chunker::{ChunkerOptions};
+use fingerprint::{file_data_hash};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::collections::hashmap::{HashMap};
+use std::io::{BufferedReader, EndOfFile, IoResult, TypeDirectory, TypeFile};
+use std::io::fs::{lstat, readdir};
+use std::str;
+use std::sync::{Mutex};
+
+
+/// A file that the snapshot must capture.
+#[deriving(Clone, Show, PartialEq)]
+pub struct ManifestEntry {
+  pub path: Vec<u8>,
+  pub size: u64,
+  pub hash: Vec<u8>,
+}
+
+pub struct SnapshotManifest {
+  entries: Vec<ManifestEntry>,
+
+  // The sizes of the files inserted into the snapshot so far, by path:
+  seen: Mutex<HashMap<Vec<u8>, u64>>,
+}
+
+fn parse_line(line: &[u8]) -> Result<ManifestEntry, String> {
+  let mut fields = line.splitn(2, |&b| b == b' ');
+  let hash = fields.next().and_then(|hash| str::from_utf8(hash))
+                          .and_then(|hash| hash.from_hex().ok());
+  let size = fields.next().and_then(|size| str::from_utf8(size))
+                          .and_then(|size| from_str::<u64>(size));
+  let path = fields.next().map(|path| {
+    if path.starts_with(b"./") { path.slice_from(2) } else { path }
+  });
+  match (hash, size, path) {
+    (None, _, _) => Err("the hash is not hexadecimal".to_string()),
+    (_, None, _) => Err("the size is not a number".to_string()),
+    (_, _, None) => Err("there is no path".to_string()),
+    (_, _, Some(path)) if path.len() == 0 || path[0] == b'/' => {
+      Err("the path is not relative".to_string())
+    },
+    (Some(hash), Some(size), Some(path)) => {
+      Ok(ManifestEntry{path: path.into_vec(), size: size, hash: hash})
+    },
+  }
+}
+
+impl SnapshotManifest {
+  pub fn new(entries: Vec<ManifestEntry>) -> SnapshotManifest {
+    SnapshotManifest{entries: entries, seen: Mutex::new(HashMap::new())}
+  }
+
+  /// Read a manifest. Paths may start with `./`, as listed by `find`; empty lines are skipped.
+  pub fn read<R: Reader>(reader: R) -> Result<SnapshotManifest, String> {
+    let mut reader = BufferedReader::new(reader);
+    let mut entries = Vec::new();
+    let mut number = 0u;
+    loop {
+      let line = match reader.read_until(b'\n') {
+        Ok(line) => line,
+        Err(ref e) if e.kind == EndOfFile => break,
+        Err(e) => return Err(e.to_string()),
+      };
+      number += 1;
+      let end = if line.last() == Some(&b'\n') { line.len() - 1 } else { line.len() };
+      if end == 0 {
+        continue;
+      }
+      match parse_line(line.slice_to(end)) {
+        Ok(entry) => entries.push(entry),
+        Err(e) => return Err(format!("line {}: {}", number, e)),
+      }
+    }
+    Ok(SnapshotManifest::new(entries))
+  }
+
+  pub fn entries(&self) -> &[ManifestEntry] {
+    self.entries.as_slice()
+  }
+
+  /// Record that the snapshot inserted a file of `size` bytes at `path`.
+  pub fn saw(&self, path: &[u8], size: u64) {
+    self.seen.lock().insert(path.into_vec(), size);
+  }
+
+  /// Check the files of the snapshot, given as their data hashes by path, against the manifest.
+  /// Returns a description of each entry that was not captured as listed (empty if none).
+  pub fn check(&self, files: &HashMap<Vec<u8>, Vec<u8>>) -> Vec<String> {
+    let seen = self.seen.lock();
+    let mut problems = Vec::new();
+    for entry in self.entries.iter() {
+      let path = String::from_utf8_lossy(entry.path.as_slice()).into_string();
+      match files.find(&entry.path) {
+        None => problems.push(format!("{}: missing from the snapshot", path)),
+        Some(hash) if *hash != entry.hash => {
+          problems.push(format!("{}: the snapshot holds data with hash {}, not {}", path,
+                                hash.as_slice().to_hex(), entry.hash.as_slice().to_hex()));
+        },
+        Some(_) => match seen.find(&entry.path) {
+          Some(&size) if size != entry.size => {
+            problems.push(format!("{}: the snapshot holds {} bytes, not {}", path, size,
+                                  entry.size));
+          },
+          _ => (),
+        },
+      }
+    }
+    problems
+  }
+}
+
+fn manifest_of_dir_rec(dir: &Path, prefix: &[u8], chunker: &ChunkerOptions,
+                       out: &mut Writer) -> IoResult<uint> {
+  let mut count = 0;
+  let mut paths = try!(readdir(dir));
+  paths.sort_by(|a, b| a.as_vec().cmp(b.as_vec()));
+  for path in paths.into_iter() {
+    let mut relative = prefix.into_vec();
+    relative.push_all(path.filename().expect("directory entry has a name"));
+    let stat = try!(lstat(&path));
+    if stat.kind == TypeDirectory {
+      relative.push(b'/');
+      count += try!(manifest_of_dir_rec(&path, relative.as_slice(), chunker, out));
+    } else if stat.kind == TypeFile {
+      let hash = try!(file_data_hash(&path, chunker));
+      try!(write!(out, "{} {} ", hash.bytes.as_slice().to_hex(), stat.size));
+      try!(out.write(relative.as_slice()));
+      try!(out.write(b"\n"));
+      count += 1;
+    }
+  }
+  Ok(count)
+}
+
+/// Write a manifest of the files below `dir`, split into data-chunks as given by `chunker` (the
+/// chunking of the family that will snapshot it). Symbolic links are left out, as snapshots skip
+/// them. Returns the number of files listed.
+pub fn manifest_of_dir(dir: &Path, chunker: &ChunkerOptions, out: &mut Writer)
+                       -> IoResult<uint> {
+  manifest_of_dir_rec(dir, b"", chunker, out)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use chunker::{ChunkerOptions};
+  use fingerprint::{file_data_hash};
+
+  use std::collections::hashmap::{HashMap};
+  use std::io::{File, MemReader, MemWriter, TempDir, UserDir};
+  use std::io::fs::{mkdir};
+
+  #[test]
+  fn parse() {
+    let manifest = SnapshotManifest::read(MemReader::new(
+      b"ab01 3 ./a file\n\nff 0 dir/b\n".into_vec())).unwrap();
+    assert_eq!(manifest.entries(),
+               [ManifestEntry{path: b"a file".into_vec(), size: 3, hash: vec![0xab, 1]},
+                ManifestEntry{path: b"dir/b".into_vec(), size: 0, hash: vec![0xff]}].as_slice());
+
+    let error = |text: &[u8]| SnapshotManifest::read(MemReader::new(text.into_vec())).err();
+    assert_eq!(error(b"xy 3 a\n"), Some("line 1: the hash is not hexadecimal".to_string()));
+    assert_eq!(error(b"ab 3 a\nab three b\n"),
+               Some("line 2: the size is not a number".to_string()));
+    assert_eq!(error(b"ab 3\n"), Some("line 1: there is no path".to_string()));
+    assert_eq!(error(b"ab 3 /etc/passwd\n"),
+               Some("line 1: the path is not relative".to_string()));
+  }
+
+  #[test]
+  fn manifest_of_a_directory_is_checked() {
+    let dir = TempDir::new("hat-manifest").unwrap();
+    let chunker = ChunkerOptions::new(1024);
+    mkdir(&dir.path().join("sub"), UserDir).unwrap();
+    File::create(&dir.path().join("a")).write(b"data of a").unwrap();
+    File::create(&dir.path().join("sub/b")).write(b"data of b").unwrap();
+
+    let mut out = MemWriter::new();
+    assert_eq!(manifest_of_dir(dir.path(), &chunker, &mut out).unwrap(), 2);
+    let manifest = SnapshotManifest::read(MemReader::new(out.unwrap())).unwrap();
+    let hash_of = |name: &str| file_data_hash(&dir.path().join(name), &chunker).unwrap().bytes;
+
+    let mut files = HashMap::new();
+    files.insert(b"a".into_vec(), hash_of("a"));
+    files.insert(b"sub/b".into_vec(), hash_of("sub/b"));
+    manifest.saw(b"a", 9);
+    manifest.saw(b"sub/b", 9);
+    assert_eq!(manifest.check(&files), Vec::<String>::new());
+
+    manifest.saw(b"sub/b", 4);
+    assert_eq!(manifest.check(&files).len(), 1);
+
+    files.insert(b"a".into_vec(), hash_of("sub/b"));
+    files.remove(&b"sub/b".into_vec());
+    let problems = manifest.check(&files);
+    assert_eq!(problems.len(), 2);
+    assert!(problems[0].as_slice().starts_with("a: the snapshot holds data with hash"));
+    assert_eq!(problems[1].as_slice(), "sub/b: missing from the snapshot");
+  }
+}
@@ -0,0 +1,418 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that keeps a copy of every blob on each of several other backends
+//! (replicas), e.g. on a local disk and in an S3 bucket.
+//!
+//! A blob is only reported as stored once every replica has stored it, so a committed snapshot
+//! survives the loss of all but one replica. Reads go to one replica at a time, falling back to
+//! the next if a read fails; the `ReadPolicy` decides which replica is asked first. Interrupted
+//! uploads are not resumed, since the replicas may have stored different parts of a blob.
+//!
+//! Replicas can be labeled with the failure domain they are in (e.g. "onsite" and "offsite"), so
+//! that a store only needs to succeed on one replica of each domain (see `Placement`), and so
+//! that `placement_report` can tell which blobs are missing from a domain.
+
+use blob_store::{BackendProbe, BlobStoreBackend, StorageHint};
+
+use serialize::hex::{ToHex};
+
+use std::collections::hashmap::{HashMap, HashSet};
+use std::sync::{Arc, Mutex};
+
+use time;
+
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum ReadPolicy {
+  /// Read from the replicas in the order they were given.
+  InOrder,
+
+  /// Read from the replica that has answered reads the fastest so far. Replicas that have not
+  /// been read from yet are tried first, so that every replica gets measured.
+  Fastest,
+}
+
+impl ReadPolicy {
+  pub fn from_name(name: &str) -> Option<ReadPolicy> {
+    match name {
+      "in-order" => Some(InOrder),
+      "fastest" => Some(Fastest),
+      _ => None,
+    }
+  }
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Placement {
+  /// A blob is only stored once every replica has stored it.
+  EveryReplica,
+
+  /// A blob is stored once at least one replica in each failure domain has stored it, so that
+  /// an unreachable replica does not hold up backups while another replica covers its domain.
+  EveryDomain,
+}
+
+impl Placement {
+  pub fn from_name(name: &str) -> Option<Placement> {
+    match name {
+      "every-replica" => Some(EveryReplica),
+      "every-domain" => Some(EveryDomain),
+      _ => None,
+    }
+  }
+}
+
+/// The outcome of `placement_report`.
+pub struct PlacementReport {
+  /// The number of blobs stored on each replica.
+  pub stored: Vec<uint>,
+
+  /// The number of different blobs stored on any replica.
+  pub blobs: uint,
+
+  /// The blobs that are missing from some failure domain, with the domains they are missing from.
+  pub misplaced: Vec<(Vec<u8>, Vec<String>)>,
+}
+
+/// The order in which to read from replicas, given the average read time of each (in
+/// nanoseconds) or `None` for replicas that have not been read from yet.
+fn read_order(policy: &ReadPolicy, read_times: &[Option<u64>]) -> Vec<uint> {
+  let mut order: Vec<uint> = range(0, read_times.len()).collect();
+  if *policy == Fastest {
+    // Stable, so that replicas that are equally fast keep their order:
+    order.sort_by(|&a, &b| read_times[a].unwrap_or(0).cmp(&read_times[b].unwrap_or(0)));
+  }
+  order
+}
+
+#[deriving(Clone)]
+pub struct MirrorBackend<B> {
+  replicas: Vec<B>,
+  policy: ReadPolicy,
+
+  // The failure domain of each replica:
+  domains: Vec<String>,
+  placement: Placement,
+
+  // The average read time of each replica, shared between all clones of the backend:
+  read_times: Arc<Mutex<Vec<Option<u64>>>>,
+}
+
+impl <B: BlobStoreBackend> MirrorBackend<B> {
+  pub fn new(replicas: Vec<B>, policy: ReadPolicy) -> MirrorBackend<B> {
+    let replicas = replicas.into_iter().enumerate().map(|(i, replica)| {
+      (format!("replica {}", i), replica)
+    }).collect();
+    MirrorBackend::with_domains(replicas, policy, EveryReplica)
+  }
+
+  /// A mirror of replicas that are each in the given failure domain.
+  pub fn with_domains(replicas: Vec<(String, B)>, policy: ReadPolicy, placement: Placement)
+                      -> MirrorBackend<B> {
+    assert!(replicas.len() > 0, "a mirror needs at least one replica");
+    let read_times = Vec::from_elem(replicas.len(), None);
+    let mut domains = vec![];
+    let mut backends = vec![];
+    for (domain, replica) in replicas.into_iter() {
+      domains.push(domain);
+      backends.push(replica);
+    }
+    MirrorBackend{replicas: backends, policy: policy, domains: domains, placement: placement,
+                  read_times: Arc::new(Mutex::new(read_times))}
+  }
+
+  /// The failure domain of each replica.
+  pub fn domains(&self) -> &[String] {
+    self.domains.as_slice()
+  }
+
+  /// Find the blobs that are not stored in every failure domain, by listing every replica.
+  pub fn placement_report(&mut self) -> Result<PlacementReport, String> {
+    let mut stored = vec![];
+    let mut found: HashMap<Vec<u8>, HashSet<String>> = HashMap::new();
+    for (i, replica) in self.replicas.iter_mut().enumerate() {
+      let names = try!(replica.list().map_err(|e| format!("replica {}: {}", i, e)));
+      stored.push(names.len());
+      for name in names.into_iter() {
+        found.find_or_insert(name, HashSet::new()).insert(self.domains[i].clone());
+      }
+    }
+
+    let mut all_domains = self.domains.clone();
+    all_domains.sort();
+    all_domains.dedup();
+    let mut misplaced: Vec<(Vec<u8>, Vec<String>)> = found.iter().filter_map(|(name, domains)| {
+      let missing: Vec<String> = all_domains.iter().filter(|&domain| {
+        !domains.contains(domain)
+      }).map(|domain| domain.clone()).collect();
+      if missing.len() > 0 { Some((name.clone(), missing)) } else { None }
+    }).collect();
+    misplaced.sort();
+    Ok(PlacementReport{stored: stored, blobs: found.len(), misplaced: misplaced})
+  }
+
+  fn record_read_time(&self, replica: uint, nanoseconds: u64) {
+    let mut read_times = self.read_times.lock();
+    let average = match read_times[replica] {
+      None => nanoseconds,
+      // Weigh in recent reads, without letting a single slow read decide:
+      Some(average) => (average * 3 + nanoseconds) / 4,
+    };
+    *read_times.get_mut(replica) = Some(average);
+  }
+
+  /// Run `request` on every replica, even if it fails on some. Fails unless the placement policy
+  /// is met: with `EveryReplica`, every replica must succeed; with `EveryDomain`, at least one
+  /// replica in each failure domain.
+  fn on_placed(&mut self, what: &str, name: &[u8], request: |&mut B| -> Result<(), String>)
+               -> Result<(), String> {
+    let mut errors = vec![];
+    let mut placed = HashSet::new();
+    for (i, replica) in self.replicas.iter_mut().enumerate() {
+      match request(replica) {
+        Ok(()) => { placed.insert(self.domains[i].clone()); },
+        Err(e) => {
+          warning!("Warning: {} of blob {} failed on replica {} ({})", what, name.to_hex(), i,
+                   e);
+          errors.push(format!("replica {}: {}", i, e));
+        },
+      }
+    }
+    let ok = match self.placement {
+      EveryReplica => errors.len() == 0,
+      EveryDomain => self.domains.iter().all(|domain| placed.contains(domain)),
+    };
+    if ok { Ok(()) } else { Err(errors.connect("; ")) }
+  }
+
+  /// Run `request` on every replica, even if it fails on some. Returns the first error, if any.
+  fn on_all(&mut self, what: &str, name: &[u8], request: |&mut B| -> Result<(), String>)
+            -> Result<(), String> {
+    let mut first_error = None;
+    for (i, replica) in self.replicas.iter_mut().enumerate() {
+      match request(replica) {
+        Ok(()) => (),
+        Err(e) => {
+          warning!("Warning: {} of blob {} failed on replica {} ({})", what, name.to_hex(), i,
+                   e);
+          if first_error.is_none() {
+            first_error = Some(format!("replica {}: {}", i, e));
+          }
+        },
+      }
+    }
+    match first_error {
+      None => Ok(()),
+      Some(e) => Err(e),
+    }
+  }
+}
+
+impl <B: BlobStoreBackend> BlobStoreBackend for MirrorBackend<B> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.on_placed("store", name, |replica| replica.store(name, data))
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let order = read_order(&self.policy, self.read_times.lock().as_slice());
+    let mut errors = vec![];
+    for &i in order.iter() {
+      let started = time::precise_time_ns();
+      match self.replicas.get_mut(i).retrieve(name) {
+        Ok(data) => {
+          self.record_read_time(i, time::precise_time_ns() - started);
+          return Ok(data);
+        },
+        Err(e) => errors.push(format!("replica {}: {}", i, e)),
+      }
+    }
+    Err(errors.connect("; "))
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    self.on_placed("store", name, |replica| replica.store_with_hint(name, data, hint.clone()))
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    // Replicas that cannot store blobs differently (e.g. a local disk) do not fail the others:
+    let mut errors = vec![];
+    for (i, replica) in self.replicas.iter_mut().enumerate() {
+      match replica.set_storage_hint(name, hint.clone()) {
+        Ok(()) => (),
+        Err(e) => errors.push(format!("replica {}: {}", i, e)),
+      }
+    }
+    if errors.len() < self.replicas.len() { Ok(()) } else { Err(errors.connect("; ")) }
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    for replica in self.replicas.iter_mut() {
+      match try!(replica.stored_checksum(name)) {
+        Some(sum) => return Ok(Some(sum)),
+        None => (),
+      }
+    }
+    Ok(None)
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.on_all("delete", name, |replica| replica.delete(name))
+  }
+
+  fn supports_retention(&self) -> bool {
+    self.replicas.iter().any(|replica| replica.supports_retention())
+  }
+
+  /// Locks the blob on the replicas that support retention; the others keep it unlocked.
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    if !self.supports_retention() {
+      return Err("No replica supports retention.".to_string());
+    }
+    self.on_all("retention", name, |replica| {
+      if replica.supports_retention() { replica.set_retention(name, until) } else { Ok(()) }
+    })
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let mut names = vec![];
+    for replica in self.replicas.iter_mut() {
+      names.push_all(try!(replica.list()).as_slice());
+    }
+    names.sort();
+    names.dedup();
+    Ok(names)
+  }
+
+  fn supports_listing(&self) -> bool {
+    self.replicas.iter().all(|replica| replica.supports_listing())
+  }
+
+  /// Every replica must be usable, since blobs are stored on all of them. Reads are as fast as
+  /// the fastest replica, and the mirror is full when any replica is.
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    let mut probes = vec![];
+    for (i, replica) in self.replicas.iter_mut().enumerate() {
+      probes.push(try!(replica.probe().map_err(|e| format!("replica {}: {}", i, e))));
+    }
+    Ok(BackendProbe{supports_delete: probes.iter().all(|probe| probe.supports_delete),
+                    supports_listing: probes.iter().all(|probe| probe.supports_listing),
+                    latency_ms: probes.iter().map(|probe| probe.latency_ms).min().unwrap(),
+                    free_bytes: probes.iter().filter_map(|probe| probe.free_bytes).min()})
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{read_order};
+
+  use blob_store::{BlobStoreBackend, MemoryBackend};
+
+  use std::sync::{Arc, Mutex};
+
+  /// Fails every request while `broken` is set.
+  #[deriving(Clone)]
+  struct BrokenBackend {
+    backend: MemoryBackend,
+    broken: Arc<Mutex<bool>>,
+  }
+
+  impl BrokenBackend {
+    fn new() -> BrokenBackend {
+      BrokenBackend{backend: MemoryBackend::new(), broken: Arc::new(Mutex::new(false))}
+    }
+
+    fn fail(&self) -> Result<(), String> {
+      if *self.broken.lock() { Err("broken".to_string()) } else { Ok(()) }
+    }
+  }
+
+  impl BlobStoreBackend for BrokenBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+      try!(self.fail());
+      self.backend.store(name, data)
+    }
+
+    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+      try!(self.fail());
+      self.backend.retrieve(name)
+    }
+
+    fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+      try!(self.fail());
+      self.backend.list()
+    }
+  }
+
+  #[test]
+  fn stores_on_every_replica() {
+    let (a, b) = (BrokenBackend::new(), BrokenBackend::new());
+    let mut mirror = MirrorBackend::new(vec![a.clone(), b.clone()], InOrder);
+    mirror.store(b"x", b"data").unwrap();
+    assert_eq!(a.backend.len(), 1);
+    assert_eq!(b.backend.len(), 1);
+
+    // A failed replica fails the store, but the others still store the blob:
+    *a.broken.lock() = true;
+    assert!(mirror.store(b"y", b"data").is_err());
+    assert_eq!(b.backend.len(), 2);
+  }
+
+  #[test]
+  fn reads_fall_back_to_other_replicas() {
+    let (a, b) = (BrokenBackend::new(), BrokenBackend::new());
+    let mut mirror = MirrorBackend::new(vec![a.clone(), b.clone()], InOrder);
+    mirror.store(b"x", b"data").unwrap();
+    *a.broken.lock() = true;
+    assert_eq!(mirror.retrieve(b"x").unwrap(), b"data".to_vec());
+    *b.broken.lock() = true;
+    assert!(mirror.retrieve(b"x").is_err());
+  }
+
+  #[test]
+  fn stores_in_every_failure_domain() {
+    let (a, b, c) = (BrokenBackend::new(), BrokenBackend::new(), BrokenBackend::new());
+    let replicas = vec![("onsite".to_string(), a.clone()), ("offsite".to_string(), b.clone()),
+                        ("offsite".to_string(), c.clone())];
+    let mut mirror = MirrorBackend::with_domains(replicas, InOrder, EveryDomain);
+    mirror.store(b"x", b"data").unwrap();
+
+    // Another replica covers the offsite domain:
+    *b.broken.lock() = true;
+    mirror.store(b"y", b"data").unwrap();
+    assert_eq!(b.backend.len(), 1);
+
+    // Nothing covers the onsite domain:
+    *a.broken.lock() = true;
+    assert!(mirror.store(b"z", b"data").is_err());
+    *a.broken.lock() = false;
+    *b.broken.lock() = false;
+
+    let report = mirror.placement_report().unwrap();
+    assert_eq!(report.stored, vec![2, 2, 3]);
+    assert_eq!(report.blobs, 3);
+    assert_eq!(report.misplaced, vec![(b"z".to_vec(), vec!["onsite".to_string()])]);
+  }
+
+  #[test]
+  fn fastest_replica_is_read_first() {
+    let times = [Some(300), Some(100), None, Some(200)];
+    assert_eq!(read_order(&InOrder, times.as_slice()), vec![0, 1, 2, 3]);
+    assert_eq!(read_order(&Fastest, times.as_slice()), vec![2, 1, 3, 0]);
+  }
+}
@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Lowering the CPU and IO priority of the running process.
+//!
+//! Priorities are inherited by threads when they are created, so this must be called before any
+//! worker tasks are started.
+
+use std::os::{last_os_error};
+use libc::{c_int};
+
+
+/// The lowest CPU scheduling priority.
+static LOWEST_NICE_VALUE: c_int = 19;
+
+/// Lower the CPU priority of this process to the lowest value, and put its IO in the idle
+/// scheduling class where the platform supports it.
+/// Returns a description of each adjustment that could not be made.
+pub fn lower_priority() -> Vec<String> {
+  static PRIO_PROCESS: c_int = 0;
+
+  extern {
+    fn setpriority(which: c_int, who: c_int, prio: c_int) -> c_int;
+  }
+
+  let mut problems = Vec::new();
+  if unsafe { setpriority(PRIO_PROCESS, 0, LOWEST_NICE_VALUE) } != 0 {
+    problems.push(format!("Could not lower CPU priority: {}", last_os_error()));
+  }
+  match set_idle_io_class() {
+    Ok(()) => (),
+    Err(e) => problems.push(format!("Could not set idle IO priority: {}", e)),
+  }
+  problems
+}
+
+/// System call number of `ioprio_set` on Linux, which has no libc wrapper.
+#[cfg(target_arch = "x86_64")]
+static SYS_IOPRIO_SET: Option<c_int> = Some(251);
+#[cfg(target_arch = "x86")]
+static SYS_IOPRIO_SET: Option<c_int> = Some(289);
+#[cfg(target_arch = "arm")]
+static SYS_IOPRIO_SET: Option<c_int> = Some(314);
+#[cfg(not(target_arch = "x86_64"), not(target_arch = "x86"), not(target_arch = "arm"))]
+static SYS_IOPRIO_SET: Option<c_int> = None;
+
+#[cfg(target_os = "linux")]
+fn set_idle_io_class() -> Result<(), String> {
+  static IOPRIO_WHO_PROCESS: c_int = 1;
+  static IOPRIO_CLASS_IDLE: c_int = 3;
+  static IOPRIO_CLASS_SHIFT: uint = 13;
+
+  extern {
+    fn syscall(number: c_int, ...) -> c_int;
+  }
+
+  let number = match SYS_IOPRIO_SET {
+    Some(number) => number,
+    None => return Err("not supported on this architecture".to_string()),
+  };
+  let retval = unsafe {
+    syscall(number, IOPRIO_WHO_PROCESS, 0 as c_int, IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT)
+  };
+  if retval == 0 { Ok(()) } else { Err(last_os_error()) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn set_idle_io_class() -> Result<(), String> {
+  Err("not supported on this platform".to_string())
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{LOWEST_NICE_VALUE};
+
+  use libc::{c_int};
+
+  #[test]
+  #[cfg(target_os = "linux")]
+  fn lowest_priority() {
+    extern {
+      fn getpriority(which: c_int, who: c_int) -> c_int;
+    }
+
+    // Lowering one's own priority needs no privileges:
+    assert_eq!(lower_priority(), Vec::<String>::new());
+    assert_eq!(unsafe { getpriority(0, 0) }, LOWEST_NICE_VALUE);
+  }
+}
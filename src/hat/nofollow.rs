@@ -0,0 +1,254 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Creating files and directories below a directory without following symbolic links.
+//!
+//! Checking a path with `lstat` before opening it leaves a window in which another process can
+//! put a symbolic link in its place. Here every component below the root is opened relative to
+//! its parent directory, with `O_NOFOLLOW`, so that a link swapped in at any point makes the open
+//! fail instead of leading outside the root.
+
+use libc::{c_char, c_int, c_long, c_void, mode_t, off_t, size_t, time_t};
+use libc::funcs::posix88::fcntl;
+use libc::funcs::posix88::unistd::{close, lseek, write};
+use libc::consts::os::posix88::{O_RDONLY, O_WRONLY, O_CREAT, O_EXCL, EEXIST, EINTR,
+                                SEEK_SET, SEEK_CUR, SEEK_END};
+use libc::types::os::common::posix01::{timespec};
+
+use std::io;
+use std::io::{IoError, IoResult, Seek, SeekStyle, SeekSet, SeekCur, SeekEnd, Writer};
+use std::os;
+
+
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+pub static O_NOFOLLOW: c_int = 0o100000;
+#[cfg(any(target_arch = "arm", target_arch = "aarch64"))]
+static O_DIRECTORY: c_int = 0o40000;
+
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+pub static O_NOFOLLOW: c_int = 0o400000;
+#[cfg(not(any(target_arch = "arm", target_arch = "aarch64")))]
+static O_DIRECTORY: c_int = 0o200000;
+
+extern {
+  fn openat(dirfd: c_int, path: *const c_char, flags: c_int, ...) -> c_int;
+  fn mkdirat(dirfd: c_int, path: *const c_char, mode: mode_t) -> c_int;
+  fn unlinkat(dirfd: c_int, path: *const c_char, flags: c_int) -> c_int;
+  fn futimens(fd: c_int, times: *const timespec) -> c_int;
+}
+
+
+/// An open directory, closed when dropped.
+struct Dir {
+  fd: c_int,
+}
+
+impl Dir {
+  /// Open `path` itself. A symbolic link in its last component is not followed.
+  fn open(path: &Path) -> IoResult<Dir> {
+    let fd = path.with_c_str(|c_str| unsafe {
+      fcntl::open(c_str, O_RDONLY | O_DIRECTORY | O_NOFOLLOW, 0)
+    });
+    if fd < 0 { Err(IoError::last_error()) } else { Ok(Dir{fd: fd}) }
+  }
+
+  /// Open the directory `name` in this one, creating it if it does not exist.
+  fn create_dir(&self, name: &[u8]) -> IoResult<Dir> {
+    let fd = name.with_c_str(|c_str| unsafe {
+      if mkdirat(self.fd, c_str, 0o777) != 0 && os::errno() != EEXIST as int {
+        return -1;
+      }
+      openat(self.fd, c_str, O_RDONLY | O_DIRECTORY | O_NOFOLLOW)
+    });
+    if fd < 0 { Err(IoError::last_error()) } else { Ok(Dir{fd: fd}) }
+  }
+
+  /// Create the file `name` in this one. Whatever was there before, other than a directory, is
+  /// removed first; a symbolic link is removed itself, not followed.
+  fn create_file(&self, name: &[u8]) -> IoResult<NewFile> {
+    name.with_c_str(|c_str| {
+      for _ in range(0u, 2) {
+        let fd = unsafe {
+          openat(self.fd, c_str, O_WRONLY | O_CREAT | O_EXCL | O_NOFOLLOW, 0o666 as mode_t)
+        };
+        if fd >= 0 {
+          return Ok(NewFile{fd: fd});
+        }
+        if os::errno() != EEXIST as int || unsafe { unlinkat(self.fd, c_str, 0) } != 0 {
+          break;
+        }
+      }
+      Err(IoError::last_error())
+    })
+  }
+}
+
+impl Drop for Dir {
+  fn drop(&mut self) {
+    unsafe { close(self.fd) };
+  }
+}
+
+
+/// A file created by `create_file`, closed when dropped.
+pub struct NewFile {
+  fd: c_int,
+}
+
+impl NewFile {
+  /// Give the file these access and modification times (in milliseconds since the epoch).
+  pub fn set_times(&self, accessed: u64, modified: u64) -> IoResult<()> {
+    let times = [to_timespec(accessed), to_timespec(modified)];
+    if unsafe { futimens(self.fd, times.as_ptr()) } != 0 {
+      return Err(IoError::last_error());
+    }
+    Ok(())
+  }
+}
+
+fn to_timespec(ms: u64) -> timespec {
+  timespec{tv_sec: (ms / 1000) as time_t, tv_nsec: ((ms % 1000) * 1000000) as c_long}
+}
+
+impl Writer for NewFile {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    let mut buf = buf;
+    while buf.len() > 0 {
+      let n = unsafe { write(self.fd, buf.as_ptr() as *const c_void, buf.len() as size_t) };
+      if n < 0 {
+        if os::errno() == EINTR as int { continue }
+        return Err(IoError::last_error());
+      }
+      buf = buf.slice_from(n as uint);
+    }
+    Ok(())
+  }
+}
+
+impl Seek for NewFile {
+  fn tell(&self) -> IoResult<u64> {
+    let pos = unsafe { lseek(self.fd, 0, SEEK_CUR) };
+    if pos < 0 { Err(IoError::last_error()) } else { Ok(pos as u64) }
+  }
+
+  fn seek(&mut self, pos: i64, style: SeekStyle) -> IoResult<()> {
+    let whence = match style { SeekSet => SEEK_SET, SeekCur => SEEK_CUR, SeekEnd => SEEK_END };
+    if unsafe { lseek(self.fd, pos as off_t, whence) } < 0 {
+      return Err(IoError::last_error());
+    }
+    Ok(())
+  }
+}
+
+impl Drop for NewFile {
+  fn drop(&mut self) {
+    unsafe { close(self.fd) };
+  }
+}
+
+
+/// The components of `path` below `root`, or an error if it is not below it.
+fn components_below(root: &Path, path: &Path) -> IoResult<Vec<Vec<u8>>> {
+  let outside = IoError{kind: io::InvalidInput, desc: "path is outside the directory",
+                        detail: Some(format!("'{}' is not below '{}'", path.display(),
+                                             root.display()))};
+  let relative = match path.path_relative_from(root) {
+    Some(relative) => relative,
+    None => return Err(outside),
+  };
+  let components: Vec<Vec<u8>> = relative.components().filter(|c| *c != b".")
+                                          .map(|c| c.into_vec()).collect();
+  if components.iter().any(|c| c.as_slice() == b"..") {
+    return Err(outside);
+  }
+  Ok(components)
+}
+
+/// Open the directory `root`, and below it each of `components`, creating those that do not
+/// exist. Fails if any of them is a symbolic link.
+fn open_dirs(root: &Path, components: &[Vec<u8>]) -> IoResult<Dir> {
+  let mut dir = try!(Dir::open(root));
+  for name in components.iter() {
+    dir = try!(dir.create_dir(name.as_slice()));
+  }
+  Ok(dir)
+}
+
+/// Create the directory `path` below `root`, with any missing parents.
+pub fn create_dir_all(root: &Path, path: &Path) -> IoResult<()> {
+  let components = try!(components_below(root, path));
+  open_dirs(root, components.as_slice()).map(|_| ())
+}
+
+/// Create the file `path` below `root`, with any missing parent directories. An existing file in
+/// its place is replaced.
+pub fn create_file(root: &Path, path: &Path) -> IoResult<NewFile> {
+  let components = try!(components_below(root, path));
+  match components.last() {
+    Some(name) => try!(open_dirs(root, components.init())).create_file(name.as_slice()),
+    None => Err(IoError{kind: io::InvalidInput, desc: "cannot create a file in place of the root",
+                        detail: Some(root.display().to_string())}),
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io;
+  use std::io::{File, TempDir};
+  use std::io::fs::{lstat, mkdir, symlink};
+
+  #[test]
+  fn creates_files_and_parents() {
+    let root = TempDir::new("hat-nofollow").unwrap();
+    let path = root.path().join("a").join("b").join("file");
+    create_file(root.path(), &path).unwrap().write(b"data").unwrap();
+    assert_eq!(File::open(&path).read_to_end().unwrap(), b"data".into_vec());
+
+    // An existing file is replaced:
+    create_file(root.path(), &path).unwrap().write(b"new").unwrap();
+    assert_eq!(File::open(&path).read_to_end().unwrap(), b"new".into_vec());
+
+    create_dir_all(root.path(), &root.path().join("c").join("d")).unwrap();
+    assert!(root.path().join("c").join("d").is_dir());
+  }
+
+  #[test]
+  fn does_not_follow_links() {
+    let root = TempDir::new("hat-nofollow").unwrap();
+    let outside = TempDir::new("hat-outside").unwrap();
+    File::create(&outside.path().join("file")).write(b"outside").unwrap();
+    symlink(outside.path(), &root.path().join("dir")).unwrap();
+    symlink(&outside.path().join("file"), &root.path().join("file")).unwrap();
+
+    // A link to a directory is not entered:
+    assert!(create_file(root.path(), &root.path().join("dir").join("new")).is_err());
+    assert!(create_dir_all(root.path(), &root.path().join("dir").join("sub")).is_err());
+    assert!(!outside.path().join("new").exists() && !outside.path().join("sub").exists());
+
+    // A link to a file is replaced, not written through:
+    create_file(root.path(), &root.path().join("file")).unwrap().write(b"inside").unwrap();
+    assert_eq!(File::open(&outside.path().join("file")).read_to_end().unwrap(),
+               b"outside".into_vec());
+    assert_eq!(lstat(&root.path().join("file")).unwrap().kind, io::TypeFile);
+
+    // Nor are paths that lead out of the root:
+    mkdir(&root.path().join("real"), io::UserDir).unwrap();
+    let escape = root.path().join("real").join("..").join("..").join("escaped");
+    assert!(create_file(root.path(), &escape).is_err());
+    assert!(create_file(root.path(), &outside.path().join("other")).is_err());
+  }
+}
@@ -0,0 +1,186 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Notifications about the outcome of a run, so that problems with unattended backups surface.
+//!
+//! A run summary is posted as JSON to a webhook (through `curl`), or mailed through `sendmail`.
+
+use serialize::json;
+
+use std::io::process::{Command};
+use std::task;
+use time;
+
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum RunStatus {
+  /// Everything went well.
+  Success,
+  /// The run completed, but left something out (e.g. files a snapshot could not read).
+  Partial,
+  /// The run found problems (e.g. corrupt blobs), or could not complete.
+  Failure,
+}
+
+impl RunStatus {
+  pub fn name(&self) -> &'static str {
+    match *self {
+      Success => "success",
+      Partial => "partial",
+      Failure => "failure",
+    }
+  }
+}
+
+#[deriving(Clone, Encodable)]
+pub struct RunSummary {
+  /// The command that ran, e.g. "snapshot".
+  pub command: String,
+  /// One of "success", "partial" or "failure" (see `RunStatus`).
+  pub status: String,
+  /// Start and end of the run, in seconds since the epoch.
+  pub started: u64,
+  pub finished: u64,
+  /// A one-line description of what the run did.
+  pub summary: String,
+  /// A description of each problem found.
+  pub problems: Vec<String>,
+}
+
+impl RunSummary {
+  pub fn new(command: &str, status: RunStatus, started: u64, finished: u64, summary: String,
+             problems: Vec<String>) -> RunSummary {
+    RunSummary{command: command.to_string(), status: status.name().to_string(),
+               started: started, finished: finished, summary: summary, problems: problems}
+  }
+
+  /// The summary as the text of a mail, with headers.
+  fn as_mail(&self, to: &str) -> String {
+    let mut mail = format!("To: {}\nSubject: hat {}: {}\nContent-Type: text/plain\n\n{}\n",
+                           to, self.command, self.status, self.summary);
+    if self.problems.len() > 0 {
+      mail.push_str(format!("\n{} problem(s):\n", self.problems.len()).as_slice());
+      for problem in self.problems.iter() {
+        mail.push_str(format!("{}\n", problem).as_slice());
+      }
+    }
+    mail
+  }
+}
+
+#[deriving(Clone, Show)]
+pub enum Notifier {
+  /// POST the run summary as JSON to this URL.
+  Webhook(String),
+  /// Mail the run summary to this address.
+  Mail(String),
+}
+
+/// Run `program` with `input` on its standard input.
+fn pipe_to(program: &str, args: &[String], input: &[u8]) -> Result<(), String> {
+  let mut process = match Command::new(program).args(args).spawn() {
+    Ok(process) => process,
+    Err(e) => return Err(format!("{}: {}", program, e)),
+  };
+  {
+    let mut stdin = process.stdin.take().expect("stdin is piped");
+    match stdin.write(input) {
+      Ok(()) => (),
+      Err(e) => return Err(format!("{}: {}", program, e)),
+    }
+  }
+  match process.wait_with_output() {
+    Err(e) => Err(format!("{}: {}", program, e)),
+    Ok(ref out) if out.status.success() => Ok(()),
+    Ok(out) => Err(format!("{} failed ({}): {}", program, out.status,
+                           String::from_utf8_lossy(out.error.as_slice()))),
+  }
+}
+
+impl Notifier {
+  pub fn send(&self, run: &RunSummary) -> Result<(), String> {
+    match *self {
+      Webhook(ref url) => {
+        let args = vec!["--silent".to_string(), "--show-error".to_string(),
+                        "--fail".to_string(),
+                        "--header".to_string(), "Content-Type: application/json".to_string(),
+                        "--data-binary".to_string(), "@-".to_string(), url.clone()];
+        pipe_to("curl", args.as_slice(), json::encode(run).as_bytes())
+      },
+      Mail(ref address) => {
+        pipe_to("sendmail", ["-t".to_string()], run.as_mail(address.as_slice()).as_bytes())
+      },
+    }
+  }
+}
+
+/// Send `run` with each of `notifiers`. Notifications that cannot be sent are only warned about,
+/// so they never change the outcome of the run.
+pub fn send_all(notifiers: &[Notifier], run: &RunSummary) {
+  for notifier in notifiers.iter() {
+    match notifier.send(run) {
+      Ok(()) => (),
+      Err(e) => warning!("Warning: could not send notification: {}", e),
+    }
+  }
+}
+
+/// The current time in seconds since the epoch.
+pub fn now() -> u64 {
+  time::get_time().sec as u64
+}
+
+/// Sends a failure notification if the task fails (i.e. the run aborts on an error) while the
+/// guard is alive.
+pub struct FailureGuard {
+  notifiers: Vec<Notifier>,
+  command: String,
+  started: u64,
+}
+
+impl FailureGuard {
+  pub fn new(notifiers: Vec<Notifier>, command: &str) -> FailureGuard {
+    FailureGuard{notifiers: notifiers, command: command.to_string(), started: now()}
+  }
+
+  pub fn started(&self) -> u64 {
+    self.started
+  }
+}
+
+impl Drop for FailureGuard {
+  fn drop(&mut self) {
+    if task::failing() && self.notifiers.len() > 0 {
+      let run = RunSummary::new(self.command.as_slice(), Failure, self.started, now(),
+                                "The run aborted on an error; see its output.".to_string(),
+                                Vec::new());
+      send_all(self.notifiers.as_slice(), &run);
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn mail_text() {
+    let run = RunSummary::new("verify", Failure, 1, 2, "Verified 3 blob(s).".to_string(),
+                              vec!["blob 00: corrupt".to_string()]);
+    assert_eq!(run.as_mail("ops@example.com"),
+               "To: ops@example.com\nSubject: hat verify: failure\nContent-Type: text/plain\n\n\
+                Verified 3 blob(s).\n\n1 problem(s):\nblob 00: corrupt\n".to_string());
+  }
+}
@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Messages for the person running hat, as opposed to the results of a command.
+//!
+//! Results (listings, diffs, matches) are printed to stdout as before, so that they can be piped
+//! into other programs. Everything else goes to stderr through the macros of this module, and is
+//! shown depending on the verbosity (`--quiet` or `--verbose`):
+//!
+//! * `error!` and `warning!`: problems, which are always shown;
+//! * `info!`: progress and summaries, which `--quiet` hides;
+//! * `detail!`: more detailed progress, which only `--verbose` shows.
+
+#![macro_escape]
+
+use std::io;
+
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Verbosity {
+  Quiet,
+  Normal,
+  Verbose,
+}
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum Level {
+  Error,
+  Warning,
+  Info,
+  Detail,
+}
+
+static mut VERBOSITY: Verbosity = Normal;
+
+/// Show messages according to `verbosity`. Must be called before any worker tasks are started.
+pub fn set_verbosity(verbosity: Verbosity) {
+  unsafe { VERBOSITY = verbosity };
+}
+
+pub fn verbosity() -> Verbosity {
+  unsafe { VERBOSITY }
+}
+
+/// Whether messages of `level` are shown at `verbosity`.
+pub fn shown(verbosity: Verbosity, level: Level) -> bool {
+  match level {
+    Error | Warning => true,
+    Info => verbosity != Quiet,
+    Detail => verbosity == Verbose,
+  }
+}
+
+/// Print `message` to stderr, if messages of `level` are shown. Use the macros instead.
+pub fn emit(level: Level, message: String) {
+  if shown(verbosity(), level) {
+    let _ = io::stderr().write_line(message.as_slice());
+  }
+}
+
+macro_rules! error(
+  ($($arg:tt)*) => (::output::emit(::output::Error, format!($($arg)*)))
+)
+
+macro_rules! warning(
+  ($($arg:tt)*) => (::output::emit(::output::Warning, format!($($arg)*)))
+)
+
+macro_rules! info(
+  ($($arg:tt)*) => (::output::emit(::output::Info, format!($($arg)*)))
+)
+
+macro_rules! detail(
+  ($($arg:tt)*) => (::output::emit(::output::Detail, format!($($arg)*)))
+)
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn levels_by_verbosity() {
+    assert!(shown(Quiet, Error));
+    assert!(shown(Quiet, Warning));
+    assert!(!shown(Quiet, Info));
+    assert!(shown(Normal, Info));
+    assert!(!shown(Normal, Detail));
+    assert!(shown(Verbose, Detail));
+  }
+}
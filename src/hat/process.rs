@@ -19,6 +19,65 @@
 //! currently that the `process` has a bounded input-channel and a standard implementation of a
 //! synchronous `send_reply()`.
 
+use libc;
+
+use std::cmp;
+use std::io::{Timer};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicUint, SeqCst};
+use std::time::duration::{Duration};
+use time;
+
+
+/// The exit status of a run that was aborted by the watchdog.
+pub static WATCHDOG_EXIT_STATUS: int = 87;
+
+static mut WATCHDOG_TIMEOUT: u64 = 0;
+
+/// Abort the run when a process has been handling the same message for `seconds` or more.
+/// Must be called before any processes are started.
+pub fn enable_watchdog(seconds: u64) {
+  unsafe { WATCHDOG_TIMEOUT = seconds };
+}
+
+/// What a process is doing, as seen by its watchdog.
+struct Status {
+  name: &'static str,
+  /// When the process started handling its current message, in seconds since the epoch; 0 while
+  /// it waits for a message.
+  busy_since: AtomicUint,
+  /// The process whose reply it waits for, if any.
+  waiting_for: Mutex<Option<&'static str>>,
+}
+
+local_data_key!(current_status: Arc<Status>)
+
+fn now() -> uint {
+  time::get_time().sec as uint
+}
+
+/// Watch `status` until `stop` is closed, and exit the process when it is busy for too long.
+fn watch(status: Arc<Status>, timeout: u64, stop: Receiver<()>) {
+  let mut timer = Timer::new().unwrap();
+  let ticks = timer.periodic(Duration::seconds(cmp::max(1, cmp::min(timeout / 4, 60)) as i64));
+  loop {
+    select! {
+      _ = stop.recv_opt() => return,
+      _ = ticks.recv() => ()
+    }
+    let since = status.busy_since.load(SeqCst);
+    if since > 0 && now() >= since + timeout as uint {
+      let waiting = match *status.waiting_for.lock() {
+        Some(other) => format!(" while waiting for a reply from {}", other),
+        None => String::new(),
+      };
+      error!("Watchdog: {} has made no progress for {} seconds{}; aborting the run.",
+             status.name, now() - since, waiting);
+      unsafe { libc::exit(WATCHDOG_EXIT_STATUS as libc::c_int) };
+    }
+  }
+}
+
 /// A long-living `thread` is promoted to a standard `process`.
 ///
 /// To create a new `process`, simply define a `Msg` type, a `Reply` type and a state struct
@@ -49,10 +108,13 @@
 ///
 /// A handler is allowed to call `reply()` **exactly** once. Not calling it or calling it multiple
 /// times will likely cause runtime failure when using `send_reply()`.
-
+///
+/// A watchdog (see `enable_watchdog()`) can abort the run when a `process` stops making progress,
+/// e.g. because it waits for a reply that never comes.
 pub struct Process<Msg, Reply, Handler>
 {
   sender: SyncSender<(Msg, Option<Sender<Reply>>)>,
+  name: &'static str,
 }
 
 /// When cloning a `process` we clone the input-channel, allowing multiple threads to share the same
@@ -60,7 +122,7 @@ pub struct Process<Msg, Reply, Handler>
 impl <Msg: Send, Reply: Send, Handler> Clone
   for Process<Msg, Reply, Handler> {
   fn clone(&self) -> Process<Msg, Reply, Handler> {
-    Process{sender: self.sender.clone()}
+    Process{sender: self.sender.clone(), name: self.name}
   }
 }
 
@@ -73,9 +135,15 @@ impl <Msg:Send, Reply:Send, Handler: MsgHandler<Msg, Reply>> Process<Msg, Reply,
 
   /// Create and start a new process using `handler`.
   pub fn new(handler_proc: proc():Send -> Handler) -> Process<Msg, Reply, Handler> {
+    Process::new_named("process", handler_proc)
+  }
+
+  /// Create and start a new process using `handler`. The watchdog refers to it by `name`.
+  pub fn new_named(name: &'static str, handler_proc: proc():Send -> Handler)
+                   -> Process<Msg, Reply, Handler> {
 
     let (sender, receiver) = sync_channel(10);
-    let p = Process{sender: sender};
+    let p = Process{sender: sender, name: name};
 
     p.start(receiver, handler_proc);
 
@@ -86,11 +154,25 @@ impl <Msg:Send, Reply:Send, Handler: MsgHandler<Msg, Reply>> Process<Msg, Reply,
   fn start(&self, receiver: Receiver<(Msg, Option<Sender<Reply>>)>,
            handler_proc: proc():Send -> Handler)
   {
+    let status = Arc::new(Status{name: self.name, busy_since: AtomicUint::new(0),
+                                 waiting_for: Mutex::new(None)});
+    // The watchdog stops when `stop_tx` is dropped, i.e. when the process ends or fails.
+    let (stop_tx, stop_rx) = channel::<()>();
+    let timeout = unsafe { WATCHDOG_TIMEOUT };
+    if timeout > 0 {
+      let watched = status.clone();
+      spawn(proc() { watch(watched, timeout, stop_rx) });
+    }
+
     spawn(proc() {
+      let _stop = stop_tx;
+      current_status.replace(Some(status.clone()));
       // fork handler
       let mut my_handler = handler_proc();
       loop {
-        match receiver.recv_opt() {
+        let next = receiver.recv_opt();
+        status.busy_since.store(now(), SeqCst);
+        match next {
           Ok((msg, None)) => {
             my_handler.handle(msg, |_r: Reply| {});
           },
@@ -99,6 +181,7 @@ impl <Msg:Send, Reply:Send, Handler: MsgHandler<Msg, Reply>> Process<Msg, Reply,
           },
           Err(()) => break,
         };
+        status.busy_since.store(0, SeqCst);
       };
     });
   }
@@ -109,6 +192,59 @@ impl <Msg:Send, Reply:Send, Handler: MsgHandler<Msg, Reply>> Process<Msg, Reply,
   pub fn send_reply(&self, msg: Msg) -> Reply {
     let (sender, receiver) = channel();
     self.sender.send((msg, Some(sender)));
-    return receiver.recv();
+
+    // Let the watchdog of the calling process (if any) know what it waits for:
+    let caller = current_status.get().map(|status| (*status).clone());
+    caller.as_ref().map(|status| *status.waiting_for.lock() = Some(self.name));
+    let reply = receiver.recv();
+    caller.as_ref().map(|status| *status.waiting_for.lock() = None);
+    return reply;
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io::process::{Command, ExitStatus};
+  use std::io::timer;
+  use std::os;
+  use std::time::duration::{Duration};
+
+  static CHILD_VARIABLE: &'static str = "HAT_WATCHDOG_TEST_CHILD";
+
+  /// Replies after sleeping for the given number of seconds.
+  struct Sleeper;
+
+  impl MsgHandler<i64, i64> for Sleeper {
+    fn handle(&mut self, seconds: i64, reply: |i64|) {
+      timer::sleep(Duration::seconds(seconds));
+      reply(seconds);
+    }
+  }
+
+  /// Run in a child process by `watchdog_aborts_stuck_processes`, which checks that it was aborted.
+  #[test]
+  fn watchdog_child() {
+    if os::getenv(CHILD_VARIABLE).is_some() {
+      enable_watchdog(2);
+      let sleeper = Process::new_named("sleeper", proc() { Sleeper });
+      // Quick messages are fine:
+      assert_eq!(sleeper.send_reply(0), 0);
+      sleeper.send_reply(60);
+      fail!("The watchdog did not abort the run.");
+    }
+  }
+
+  #[test]
+  fn watchdog_aborts_stuck_processes() {
+    // Without a watchdog, slow messages are fine:
+    let sleeper = Process::new_named("sleeper", proc() { Sleeper });
+    assert_eq!(sleeper.send_reply(1), 1);
+
+    let test_binary = os::self_exe_name().expect("the test binary is known");
+    let status = Command::new(test_binary).arg("watchdog_child").env(CHILD_VARIABLE, "1")
+      .status().unwrap();
+    assert_eq!(status, ExitStatus(WATCHDOG_EXIT_STATUS));
   }
 }
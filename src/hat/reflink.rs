@@ -0,0 +1,167 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Sharing of identical data-chunks between restored files on copy-on-write filesystems.
+//!
+//! Filesystems clone whole blocks only, and data-chunks rarely start or end at a block boundary.
+//! The blocks that lie entirely within a chunk are cloned, if the chunk is equally aligned in
+//! both files; the rest of it is written as usual.
+
+use std::collections::hashmap::{HashMap};
+use std::io::fs::{stat};
+
+#[cfg(target_os = "linux")]
+use std::os;
+#[cfg(target_os = "linux")]
+use libc::{c_int, c_ulong};
+#[cfg(target_os = "linux")]
+use libc::funcs::posix88::fcntl;
+#[cfg(target_os = "linux")]
+use libc::funcs::posix88::unistd::{close};
+#[cfg(target_os = "linux")]
+use libc::consts::os::posix88::{O_RDONLY, O_WRONLY, EOPNOTSUPP, EXDEV, ENOTTY};
+
+
+/// Keeps track of where each chunk was first written during a restore, so later copies of the same
+/// chunk can be cloned (reflinked) from there instead of being written again.
+pub struct ReflinkTable {
+  written: HashMap<Vec<u8>, (Path, u64, u64)>,
+  supported: bool,
+  block_size: Option<u64>,
+}
+
+impl ReflinkTable {
+
+  pub fn new() -> ReflinkTable {
+    ReflinkTable{written: HashMap::new(), supported: true, block_size: None}
+  }
+
+  /// Record that the chunk with hash `hash` now lives in `path` at `offset`.
+  pub fn remember(&mut self, hash: Vec<u8>, path: Path, offset: u64, len: u64) {
+    if self.supported && !self.written.contains_key(&hash) {
+      self.written.insert(hash, (path, offset, len));
+    }
+  }
+
+  /// Try to place the chunk with hash `hash` at `offset` in `dest` by cloning the blocks of a
+  /// previously written copy. Returns the range within the chunk that was cloned; the caller
+  /// must write the data before and after it itself, or all of it if nothing was cloned.
+  pub fn try_clone(&mut self, hash: &Vec<u8>, dest: &Path, offset: u64) -> Option<(u64, u64)> {
+    if !self.supported { return None }
+
+    let (src, src_offset, len) = match self.written.find(hash) {
+      Some(&(ref src, src_offset, len)) => (src.clone(), src_offset, len),
+      None => return None,
+    };
+    if self.block_size.is_none() {
+      self.block_size = stat(dest).ok().map(|st| st.unstable.blksize);
+    }
+    let (start, end) = match aligned_range(src_offset, offset, len, self.block_size.unwrap_or(0)) {
+      Some(range) => range,
+      None => return None,
+    };
+
+    match clone_range(&src, src_offset + start, end - start, dest, offset + start) {
+      Ok(()) => Some((start, end)),
+      Err(fatal) => {
+        if fatal {
+          // The filesystem does not support cloning; stop trying.
+          self.supported = false;
+          self.written.clear();
+        }
+        None
+      },
+    }
+  }
+}
+
+/// The largest range within a chunk of `len` bytes that covers whole blocks of `block` bytes,
+/// both where the chunk lies at `src_offset` and where it goes at `dest_offset`.
+fn aligned_range(src_offset: u64, dest_offset: u64, len: u64, block: u64) -> Option<(u64, u64)> {
+  if block == 0 || src_offset % block != dest_offset % block {
+    return None;
+  }
+  let start = (block - dest_offset % block) % block;
+  if start >= len {
+    return None;
+  }
+  let end = start + (len - start) / block * block;
+  if end > start { Some((start, end)) } else { None }
+}
+
+
+/// Clone `len` bytes at `src_offset` in `src` into `dest` at `dest_offset`.
+/// On failure, returns whether cloning is unsupported altogether (as opposed to just this range).
+#[cfg(target_os = "linux")]
+fn clone_range(src: &Path, src_offset: u64, len: u64,
+               dest: &Path, dest_offset: u64) -> Result<(), bool> {
+  static FICLONERANGE: c_ulong = 0x4020940d;
+
+  #[repr(C)]
+  struct FileCloneRange {
+    src_fd: i64,
+    src_offset: u64,
+    src_length: u64,
+    dest_offset: u64,
+  }
+
+  extern {
+    fn ioctl(fd: c_int, request: c_ulong, ...) -> c_int;
+  }
+
+  let src_fd = src.with_c_str(|c_str| unsafe { fcntl::open(c_str, O_RDONLY, 0) });
+  if src_fd < 0 { return Err(false) }
+  let dest_fd = dest.with_c_str(|c_str| unsafe { fcntl::open(c_str, O_WRONLY, 0) });
+  if dest_fd < 0 {
+    unsafe { close(src_fd) };
+    return Err(false);
+  }
+
+  let range = FileCloneRange{src_fd: src_fd as i64, src_offset: src_offset,
+                             src_length: len, dest_offset: dest_offset};
+  let retval = unsafe { ioctl(dest_fd, FICLONERANGE, &range) };
+  let errno = os::errno() as c_int;
+
+  unsafe {
+    close(dest_fd);
+    close(src_fd);
+  };
+
+  if retval == 0 { Ok(()) }
+  else { Err(errno == EOPNOTSUPP || errno == EXDEV || errno == ENOTTY) }
+}
+
+#[cfg(not(target_os = "linux"))]
+fn clone_range(_src: &Path, _src_offset: u64, _len: u64,
+               _dest: &Path, _dest_offset: u64) -> Result<(), bool> {
+  Err(true)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::{aligned_range};
+
+  #[test]
+  fn aligned_ranges() {
+    // Chunks that start and end at block boundaries are cloned whole:
+    assert_eq!(aligned_range(0, 8192, 8192, 4096), Some((0, 8192)));
+    // Otherwise only the whole blocks inside them:
+    assert_eq!(aligned_range(100, 4196, 10000, 4096), Some((3996, 8092)));
+    assert_eq!(aligned_range(100, 4196, 5000, 4096), None);
+    // The copies are not equally aligned, so no block of one is a block of the other:
+    assert_eq!(aligned_range(0, 100, 10000, 4096), None);
+    assert_eq!(aligned_range(0, 0, 10000, 0), None);
+  }
+}
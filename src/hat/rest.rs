@@ -0,0 +1,201 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that speaks a small HTTP protocol, so that anyone can write a storage
+//! server for hat without depending on a particular cloud service.
+//!
+//! # Protocol
+//!
+//! Paths are relative to the base URL that hat is given, e.g. `https://store.example.com/hat`.
+//! `NAME` is the name of a blob in lower-case hex. If hat was given a token, every request has an
+//! `Authorization: Bearer TOKEN` header; servers that need other authentication can get it from
+//! an auth hook (see `curl::RequestAuth`). Statuses other than 2xx are errors.
+//!
+//! * `PUT /blob/NAME` stores the request body as blob `NAME`, replacing any blob of that name. The
+//!   `X-Hat-Sha256` header holds the hex SHA-256 checksum of the body; the server should refuse
+//!   the request (e.g. with 400) if the body does not match it. The server answers only once the
+//!   blob is stored durably, and never makes part of a blob visible as `NAME`.
+//! * `GET /blob/NAME` returns the blob, or 404 if there is none.
+//! * `HEAD /blob/NAME` returns 404 if there is no such blob. Servers that keep the checksum from
+//!   the `PUT` return it in the `X-Hat-Sha256` header, so that hat can verify blobs without
+//!   downloading them.
+//! * `DELETE /blob/NAME` removes the blob, or returns 404 if there is none.
+//! * `GET /blobs?after=NAME` lists the names of blobs that sort after `NAME`, in order, one per
+//!   line (`text/plain`). Without `after`, the listing starts at the first blob. The server may
+//!   return as many names at a time as it likes; an empty listing means there are no more.
+
+use blob_store::{BlobStoreBackend, blob_checksum};
+use curl;
+use curl::{AuthHook, SpoolFile};
+use proxy::{ProxyConfig};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::str;
+
+
+/// The header that holds the checksum of a blob.
+pub static CHECKSUM_HEADER: &'static str = "X-Hat-Sha256";
+
+#[deriving(Clone, Show)]
+pub struct RestConfig {
+  /// The base URL of the server.
+  pub url: String,
+
+  /// A token to send as `Authorization: Bearer TOKEN`.
+  pub token: Option<String>,
+
+  /// A hook that authenticates each request, in addition to the token.
+  pub auth: Option<AuthHook>,
+
+  /// A proxy to send requests through, instead of the one in the environment (if any).
+  pub proxy: Option<ProxyConfig>,
+}
+
+impl RestConfig {
+  pub fn new(url: String) -> RestConfig {
+    RestConfig{url: url, token: None, auth: None, proxy: None}
+  }
+}
+
+#[deriving(Clone)]
+pub struct RestBackend {
+  config: RestConfig,
+}
+
+impl RestBackend {
+  pub fn new(config: RestConfig) -> RestBackend {
+    let mut config = config;
+    config.url = config.url.as_slice().trim_right_chars('/').to_string();
+    RestBackend{config: config}
+  }
+
+  fn blob_url(&self, name: &[u8]) -> String {
+    format!("{}/blob/{}", self.config.url, name.to_hex())
+  }
+
+  /// The `curl` options of a request: the proxy, the token and those of the auth hook, followed
+  /// by `extra`.
+  fn options(&self, method: &str, url: &str, extra: &[String]) -> Result<Vec<String>, String> {
+    let mut options = self.config.proxy.as_ref().map(|proxy| proxy.curl_options())
+                                                .unwrap_or(vec![]);
+    match self.config.token {
+      Some(ref token) => {
+        options.push(curl::header("Authorization", format!("Bearer {}", token).as_slice()));
+      },
+      None => (),
+    }
+    match self.config.auth {
+      Some(ref auth) => options.push_all(try!(auth.options(method, url)).as_slice()),
+      None => (),
+    }
+    options.push_all(extra);
+    Ok(options)
+  }
+
+  /// Send a request, and fail unless its status is 2xx. Returns the response body.
+  fn send(&self, method: &str, url: &str, extra: &[String]) -> Result<Vec<u8>, String> {
+    let options = try!(self.options(method, url, extra));
+    curl::expect_success(method, url, options.as_slice())
+  }
+}
+
+/// The value of header `name` in `headers`, as output by `curl --head`.
+fn header_value(headers: &str, name: &str) -> Option<String> {
+  headers.lines().filter_map(|line| {
+    let colon = match line.find(':') { Some(i) => i, None => return None };
+    let field = line.slice_to(colon).trim();
+    let matches = field.len() == name.len() && field.chars().zip(name.chars()).all(|(a, b)| {
+      a.to_lowercase() == b.to_lowercase()
+    });
+    if !matches {
+      return None;
+    }
+    Some(line.slice_from(colon + 1).trim().to_string())
+  }).last()
+}
+
+impl BlobStoreBackend for RestBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let spool = try!(SpoolFile::create(data));
+    let extra = [spool.upload_option(),
+                 curl::header(CHECKSUM_HEADER, blob_checksum(data).as_slice().to_hex().as_slice())];
+    self.send("PUT", self.blob_url(name).as_slice(), extra.as_slice()).map(|_| ())
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    self.send("GET", self.blob_url(name).as_slice(), [])
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let headers = try!(self.send("HEAD", self.blob_url(name).as_slice(), ["head".to_string()]));
+    let value = header_value(str::from_utf8(headers.as_slice()).unwrap_or(""), CHECKSUM_HEADER);
+    match value {
+      None => Ok(None),
+      Some(text) => match text.as_slice().from_hex() {
+        Ok(sum) => Ok(Some(sum)),
+        Err(_) => Err(format!("{}: invalid checksum '{}'", self.blob_url(name), text)),
+      },
+    }
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.send("DELETE", self.blob_url(name).as_slice(), []).map(|_| ())
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let mut names = vec![];
+    let mut after: Option<String> = None;
+    loop {
+      let url = match after {
+        Some(ref name) => format!("{}/blobs?after={}", self.config.url, name),
+        None => format!("{}/blobs", self.config.url),
+      };
+      let body = try!(self.send("GET", url.as_slice(), []));
+      let text = str::from_utf8(body.as_slice()).unwrap_or("");
+      let page: Vec<&str> = text.lines().map(|line| line.trim()).filter(|line| line.len() > 0)
+                                .collect();
+      let last = match page.last() {
+        Some(last) => last.to_string(),
+        None => return Ok(names),
+      };
+      if after.as_ref() == Some(&last) {
+        return Err(format!("GET {}: the listing does not advance", url));
+      }
+      for line in page.iter() {
+        match line.from_hex() {
+          Ok(name) => names.push(name),
+          Err(_) => return Err(format!("GET {}: invalid blob name '{}'", url, line)),
+        }
+      }
+      after = Some(last);
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::{header_value};
+
+  #[test]
+  fn checksum_header() {
+    let headers = "HTTP/1.1 200 OK\r\nContent-Length: 3\r\nx-hat-sha256: ab01 \r\n\r\n";
+    assert_eq!(header_value(headers, "X-Hat-Sha256"), Some("ab01".to_string()));
+    assert_eq!(header_value(headers, "ETag"), None);
+  }
+}
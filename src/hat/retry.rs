@@ -0,0 +1,251 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that retries the failed requests of another backend, so that a transient
+//! failure (a dropped connection, a busy server) does not fail a whole snapshot or checkout.
+//!
+//! Only requests that can safely be repeated are retried: storing a whole blob, reading one,
+//! changing its storage hint and asking for the stored length of a partial upload. Appending to
+//! a partial upload is not, since a failed append may have stored part of its data. Only errors
+//! that the backend marks as transient (see `blob_store::transient`) are retried; others, like a
+//! missing blob or a denied login, are returned right away. Retries are spaced by exponential
+//! backoff, with random jitter so that parallel workers do not retry in lock-step.
+
+use blob_store::{BackendProbe, BlobStoreBackend, StorageHint, is_transient};
+
+use serialize::hex::{ToHex};
+
+use std::cmp;
+use std::io::timer;
+use std::rand::{Rng, task_rng};
+use std::time::duration::{Duration};
+
+
+#[deriving(Clone, Show)]
+pub struct RetryPolicy {
+  /// How often a failed request is retried before its error is returned.
+  pub retries: uint,
+
+  /// The delay before the first retry, in milliseconds. It doubles with every further retry.
+  pub initial_delay_ms: u64,
+
+  /// The longest delay between retries, in milliseconds.
+  pub max_delay_ms: u64,
+}
+
+impl RetryPolicy {
+  pub fn new() -> RetryPolicy {
+    RetryPolicy{retries: 4, initial_delay_ms: 1000, max_delay_ms: 60000}
+  }
+
+  /// The delay before retry `retry` (counting from 0), given a `random` number in [0, 1). The
+  /// delay is between half and all of the backoff, so that it still grows with every retry.
+  pub fn delay_ms(&self, retry: uint, random: f64) -> u64 {
+    let mut backoff = self.initial_delay_ms;
+    for _ in range(0, retry) {
+      if backoff >= self.max_delay_ms { break }
+      backoff *= 2;
+    }
+    let backoff = cmp::min(backoff, self.max_delay_ms);
+    backoff / 2 + (random * (backoff - backoff / 2) as f64) as u64
+  }
+}
+
+#[deriving(Clone)]
+pub struct RetryingBackend<B> {
+  backend: B,
+  policy: RetryPolicy,
+}
+
+impl <B: BlobStoreBackend> RetryingBackend<B> {
+  pub fn new(backend: B, policy: RetryPolicy) -> RetryingBackend<B> {
+    RetryingBackend{backend: backend, policy: policy}
+  }
+
+  /// Run `request` (described by `what`, for blob `name`) until it succeeds, fails with an error
+  /// that is not transient, or is out of retries.
+  fn retry<T>(&mut self, what: &str, name: &[u8], request: |&mut B| -> Result<T, String>)
+              -> Result<T, String> {
+    let mut retry = 0;
+    loop {
+      let error = match request(&mut self.backend) {
+        Ok(result) => return Ok(result),
+        Err(e) => e,
+      };
+      if !is_transient(error.as_slice()) || retry == self.policy.retries {
+        return Err(error);
+      }
+      let delay = self.policy.delay_ms(retry, task_rng().gen::<f64>());
+      warning!("Warning: {} of blob {} failed ({}); retrying in {} ms", what, name.to_hex(),
+               error, delay);
+      timer::sleep(Duration::milliseconds(delay as i64));
+      retry += 1;
+    }
+  }
+}
+
+impl <B: BlobStoreBackend> BlobStoreBackend for RetryingBackend<B> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.retry("store", name, |backend| backend.store(name, data))
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    self.retry("retrieve", name, |backend| backend.retrieve(name))
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    self.retry("store", name, |backend| backend.store_with_hint(name, data, hint.clone()))
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    self.retry("storage hint", name, |backend| backend.set_storage_hint(name, hint.clone()))
+  }
+
+  fn supports_resume(&self) -> bool { self.backend.supports_resume() }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.retry("stored length", name, |backend| backend.stored_length(name))
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.backend.append(name, data)
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.backend.stored_checksum(name)
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.delete(name)
+  }
+
+  fn supports_retention(&self) -> bool { self.backend.supports_retention() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    self.retry("retention", name, |backend| backend.set_retention(name, until))
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    self.backend.list()
+  }
+
+  fn supports_listing(&self) -> bool { self.backend.supports_listing() }
+
+  /// Not retried, so that a backend that cannot be used is reported right away.
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.backend.probe()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use blob_store::{BlobStoreBackend, MemoryBackend, transient};
+
+  use std::sync::{Arc, Mutex};
+
+  /// Fails the first `failures` requests with `error`.
+  #[deriving(Clone)]
+  struct FlakyBackend {
+    backend: MemoryBackend,
+    failures: Arc<Mutex<uint>>,
+    error: String,
+  }
+
+  impl FlakyBackend {
+    fn new(failures: uint) -> FlakyBackend {
+      FlakyBackend::failing_with(failures, transient("connection reset".to_string()))
+    }
+
+    fn failing_with(failures: uint, error: String) -> FlakyBackend {
+      FlakyBackend{backend: MemoryBackend::new(), failures: Arc::new(Mutex::new(failures)),
+                   error: error}
+    }
+
+    fn fail(&self) -> Result<(), String> {
+      let mut failures = self.failures.lock();
+      if *failures == 0 { return Ok(()) }
+      *failures -= 1;
+      Err(self.error.clone())
+    }
+  }
+
+  impl BlobStoreBackend for FlakyBackend {
+    fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+      try!(self.fail());
+      self.backend.store(name, data)
+    }
+
+    fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+      try!(self.fail());
+      self.backend.retrieve(name)
+    }
+  }
+
+  fn no_delay(retries: uint) -> RetryPolicy {
+    RetryPolicy{retries: retries, initial_delay_ms: 0, max_delay_ms: 0}
+  }
+
+  #[test]
+  fn transient_failures_are_retried() {
+    let flaky = FlakyBackend::new(3);
+    let mut backend = RetryingBackend::new(flaky.clone(), no_delay(3));
+    backend.store(b"a", b"data").unwrap();
+    *flaky.failures.lock() = 2;
+    assert_eq!(backend.retrieve(b"a").unwrap(), b"data".to_vec());
+  }
+
+  #[test]
+  fn gives_up_after_the_last_retry() {
+    let flaky = FlakyBackend::new(3);
+    let mut backend = RetryingBackend::new(flaky.clone(), no_delay(2));
+    assert!(backend.store(b"a", b"data").is_err());
+    assert_eq!(flaky.backend.len(), 0);
+  }
+
+  #[test]
+  fn other_errors_are_not_retried() {
+    let flaky = FlakyBackend::failing_with(2, "access denied".to_string());
+    let mut backend = RetryingBackend::new(flaky.clone(), no_delay(3));
+    assert_eq!(backend.store(b"a", b"data"), Err("access denied".to_string()));
+    assert_eq!(*flaky.failures.lock(), 1);
+  }
+
+  #[test]
+  fn probes_are_not_retried() {
+    let flaky = FlakyBackend::new(1);
+    let mut backend = RetryingBackend::new(flaky.clone(), no_delay(3));
+    assert!(backend.probe().is_err());
+    assert!(backend.probe().is_ok());
+  }
+
+  #[test]
+  fn backoff_doubles_up_to_the_limit() {
+    let policy = RetryPolicy{retries: 10, initial_delay_ms: 1000, max_delay_ms: 5000};
+    assert_eq!(policy.delay_ms(0, 0.0), 500);
+    assert_eq!(policy.delay_ms(0, 0.999), 999);
+    assert_eq!(policy.delay_ms(1, 0.0), 1000);
+    assert_eq!(policy.delay_ms(2, 0.5), 3000);
+    assert_eq!(policy.delay_ms(3, 0.0), 2500);
+    assert_eq!(policy.delay_ms(9, 0.0), 2500);
+  }
+}
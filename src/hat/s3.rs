@@ -0,0 +1,369 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that keeps blobs in an Amazon S3 bucket.
+//!
+//! Requests go through the AWS command line interface (`aws`), which signs them and finds
+//! credentials in its usual places (environment, `~/.aws`, instance roles) unless they are given
+//! explicitly. Large blobs are uploaded in parts by `aws s3 cp` (multi-part upload). Requests that
+//! fail are returned as errors; errors that look transient, like timeouts and throttling, say so,
+//! so that the caller can retry the request later.
+//!
+//! Storage hints (see `blob_store::StorageHint`) select the storage class of a blob: by default
+//! `STANDARD_IA` for infrequently read data and `GLACIER_IR` for archived data, both of which can
+//! still be read right away. The classes can be configured, e.g. for S3-compatible services with
+//! classes of their own.
+//!
+//! In a bucket with Object Lock enabled, blobs can be locked for a number of days as they are
+//! uploaded (see `ObjectLock`), and their retention extended later (see `set_retention`), so that
+//! not even a client with the bucket's credentials can delete recent backups. Locked blobs stay
+//! in the bucket until their retention ends, even once they have been archived elsewhere.
+
+use blob_store::{BlobStoreBackend, StorageHint, blob_checksum, transient};
+use blob_store;
+use proxy::{ProxyConfig};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::io::process::{Command};
+use std::str;
+
+use time;
+
+
+/// Error messages of `aws` that mean the request may succeed when retried.
+static TRANSIENT_ERRORS: &'static [&'static str] = &[
+  "timed out", "Timeout", "SlowDown", "Throttl", "InternalError", "ServiceUnavailable",
+  "Could not connect", "Connection reset", "Connection was closed", "EndpointConnectionError",
+];
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum LockMode {
+  /// Users with the `s3:BypassGovernanceRetention` permission can still delete locked blobs.
+  Governance,
+
+  /// No one can delete locked blobs, not even the account's root user.
+  Compliance,
+}
+
+impl LockMode {
+  pub fn from_name(name: &str) -> Option<LockMode> {
+    match name {
+      "governance" => Some(Governance),
+      "compliance" => Some(Compliance),
+      _ => None,
+    }
+  }
+
+  fn code(&self) -> &'static str {
+    match *self {
+      Governance => "GOVERNANCE",
+      Compliance => "COMPLIANCE",
+    }
+  }
+}
+
+/// Lock every uploaded blob for `days` days, in a bucket with Object Lock enabled.
+#[deriving(Clone, Show)]
+pub struct ObjectLock {
+  pub mode: LockMode,
+  pub days: u64,
+}
+
+#[deriving(Clone, Show)]
+pub struct S3Config {
+  pub bucket: String,
+
+  /// Blobs are stored as `prefix` followed by their hex-encoded name; an empty prefix stores
+  /// them at the top of the bucket.
+  pub prefix: String,
+
+  /// The region of the bucket (by default, as configured for `aws`).
+  pub region: Option<String>,
+
+  /// A named profile of the `aws` configuration to take credentials from.
+  pub profile: Option<String>,
+
+  /// An access key ID and secret access key to use instead of the configured credentials.
+  pub credentials: Option<(String, String)>,
+
+  /// Storage classes to use instead of the default ones for these hints.
+  pub storage_classes: Vec<(StorageHint, String)>,
+
+  /// A proxy to send requests through, instead of the one in the environment (if any).
+  pub proxy: Option<ProxyConfig>,
+
+  /// How to lock uploaded blobs; without it, blobs are not locked and retention is not supported.
+  pub object_lock: Option<ObjectLock>,
+}
+
+impl S3Config {
+  pub fn new(bucket: String, prefix: String) -> S3Config {
+    S3Config{bucket: bucket, prefix: prefix, region: None, profile: None, credentials: None,
+             storage_classes: Vec::new(), proxy: None, object_lock: None}
+  }
+
+  /// The storage class for blobs with `hint`.
+  pub fn storage_class(&self, hint: &StorageHint) -> String {
+    match self.storage_classes.iter().find(|&&(ref h, _)| h == hint) {
+      Some(&(_, ref class)) => class.clone(),
+      None => match *hint {
+        blob_store::FrequentAccess => "STANDARD",
+        blob_store::InfrequentAccess => "STANDARD_IA",
+        blob_store::ArchiveAccess => "GLACIER_IR",
+      }.to_string(),
+    }
+  }
+}
+
+#[deriving(Clone)]
+pub struct S3Backend {
+  config: S3Config,
+}
+
+impl S3Backend {
+  pub fn new(config: S3Config) -> S3Backend {
+    let mut config = config;
+    let prefix = config.prefix.as_slice().trim_chars('/').to_string();
+    config.prefix = if prefix.len() == 0 { prefix } else { format!("{}/", prefix) };
+    S3Backend{config: config}
+  }
+
+  fn key(&self, name: &[u8]) -> String {
+    format!("{}{}", self.config.prefix, name.to_hex())
+  }
+
+  fn url(&self, name: &[u8]) -> String {
+    format!("s3://{}/{}", self.config.bucket, self.key(name))
+  }
+
+  /// Run `aws` with `args` and `input` on its standard input. Returns its standard output.
+  fn aws(&self, args: &[String], input: &[u8]) -> Result<Vec<u8>, String> {
+    let mut command = Command::new("aws");
+    command.args(args);
+    match self.config.region {
+      Some(ref region) => { command.arg("--region").arg(region.as_slice()); },
+      None => (),
+    }
+    match self.config.profile {
+      Some(ref profile) => { command.arg("--profile").arg(profile.as_slice()); },
+      None => (),
+    }
+    match self.config.credentials {
+      Some((ref id, ref secret)) => {
+        command.env("AWS_ACCESS_KEY_ID", id.as_slice())
+               .env("AWS_SECRET_ACCESS_KEY", secret.as_slice());
+      },
+      None => (),
+    }
+    match self.config.proxy {
+      Some(ref proxy) => {
+        let url = proxy.url_with_credentials();
+        command.env("HTTPS_PROXY", url.as_slice()).env("HTTP_PROXY", url.as_slice());
+      },
+      None => (),
+    }
+
+    let mut process = match command.spawn() {
+      Ok(process) => process,
+      Err(e) => return Err(format!("aws: {}", e)),
+    };
+    {
+      let mut stdin = process.stdin.take().expect("stdin is piped");
+      match stdin.write(input) {
+        Ok(()) => (),
+        Err(e) => return Err(transient(format!("aws {}: {}", args[1], e))),
+      }
+    }
+    match process.wait_with_output() {
+      Err(e) => Err(transient(format!("aws {}: {}", args[1], e))),
+      Ok(ref out) if out.status.success() => Ok(out.output.clone()),
+      Ok(out) => {
+        let error = String::from_utf8_lossy(out.error.as_slice()).into_string();
+        let message = format!("aws {} failed ({}): {}", args[1], out.status,
+                              error.as_slice().trim());
+        if TRANSIENT_ERRORS.iter().any(|e| error.as_slice().contains(*e)) {
+          Err(transient(message))
+        } else {
+          Err(message)
+        }
+      },
+    }
+  }
+
+  /// Upload a blob, in `class` or else the bucket's default storage class.
+  fn upload(&self, name: &[u8], data: &[u8], class: Option<String>) -> Result<(), String> {
+    // The checksum is kept as object metadata, for `stored_checksum`:
+    let mut cp = strings(["s3", "cp", "-", "--only-show-errors", "--metadata"]);
+    cp.push(format!("sha256={}", blob_checksum(data).to_hex()));
+    cp.push("--expected-size".to_string());
+    cp.push(data.len().to_string());
+    match class {
+      Some(class) => { cp.push("--storage-class".to_string()); cp.push(class); },
+      None => (),
+    }
+    cp.push(self.url(name));
+    try!(self.aws(cp.as_slice(), data));
+    self.lock_for_configured_days(name)
+  }
+
+  /// Lock a blob for the configured number of days from now, if blobs are locked.
+  fn lock_for_configured_days(&self, name: &[u8]) -> Result<(), String> {
+    match self.config.object_lock {
+      Some(ref lock) => {
+        let until = time::get_time().sec as u64 + lock.days * 24 * 60 * 60;
+        self.put_retention(name, lock, until)
+      },
+      None => Ok(()),
+    }
+  }
+
+  fn put_retention(&self, name: &[u8], lock: &ObjectLock, until: u64) -> Result<(), String> {
+    let mut put = strings(["s3api", "put-object-retention", "--bucket"]);
+    put.push(self.config.bucket.clone());
+    put.push("--key".to_string());
+    put.push(self.key(name));
+    put.push("--retention".to_string());
+    put.push(retention_argument(&lock.mode, until));
+    self.aws(put.as_slice(), []).map(|_| ())
+  }
+}
+
+/// The `--retention` argument of `put-object-retention` for a lock until `until` (in seconds
+/// since the epoch).
+fn retention_argument(mode: &LockMode, until: u64) -> String {
+  let date = time::at_utc(time::Timespec::new(until as i64, 0));
+  format!("Mode={},RetainUntilDate={}", mode.code(), date.rfc3339())
+}
+
+fn strings(args: &[&str]) -> Vec<String> {
+  args.iter().map(|a| a.to_string()).collect()
+}
+
+impl BlobStoreBackend for S3Backend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    self.upload(name, data, None)
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    let class = self.config.storage_class(&hint);
+    self.upload(name, data, Some(class))
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    // Copying an object onto itself changes its storage class, and keeps its metadata:
+    let mut cp = strings(["s3", "cp", "--only-show-errors", "--metadata-directive", "COPY",
+                          "--storage-class"]);
+    cp.push(self.config.storage_class(&hint));
+    cp.push(self.url(name));
+    cp.push(self.url(name));
+    try!(self.aws(cp.as_slice(), []));
+    // The copy is a new version of the object, which is not locked like the old one was:
+    self.lock_for_configured_days(name)
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let mut cp = strings(["s3", "cp", "--only-show-errors"]);
+    cp.push(self.url(name));
+    cp.push("-".to_string());
+    self.aws(cp.as_slice(), []).map_err(|e| format!("{}: {}", self.url(name), e))
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    let mut head = strings(["s3api", "head-object", "--query", "Metadata.sha256", "--output",
+                            "text", "--bucket"]);
+    head.push(self.config.bucket.clone());
+    head.push("--key".to_string());
+    head.push(self.key(name));
+    let out = try!(self.aws(head.as_slice(), []));
+    let text = str::from_utf8(out.as_slice()).unwrap_or("").trim();
+    if text == "None" || text.len() == 0 {
+      return Ok(None);
+    }
+    match text.from_hex() {
+      Ok(sum) => Ok(Some(sum)),
+      Err(_) => Err(format!("{}: invalid checksum metadata '{}'", self.url(name), text)),
+    }
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    let mut rm = strings(["s3", "rm", "--only-show-errors"]);
+    rm.push(self.url(name));
+    self.aws(rm.as_slice(), []).map(|_| ())
+  }
+
+  fn supports_retention(&self) -> bool { self.config.object_lock.is_some() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    match self.config.object_lock {
+      Some(ref lock) => self.put_retention(name, lock, until),
+      None => Err("Object Lock is not configured for this bucket.".to_string()),
+    }
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    // The listing is paginated by `aws` itself:
+    let mut list = strings(["s3api", "list-objects-v2", "--query", "Contents[].Key", "--output",
+                            "text", "--bucket"]);
+    list.push(self.config.bucket.clone());
+    list.push("--prefix".to_string());
+    list.push(self.config.prefix.clone());
+    let out = try!(self.aws(list.as_slice(), []));
+    let text = str::from_utf8(out.as_slice()).unwrap_or("");
+    Ok(text.words().filter_map(|key| {
+      if !key.starts_with(self.config.prefix.as_slice()) { return None }
+      key.slice_from(self.config.prefix.len()).from_hex().ok()
+    }).collect())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{retention_argument};
+
+  use blob_store;
+
+  #[test]
+  fn keys_and_urls() {
+    let backend = S3Backend::new(S3Config::new("bucket".to_string(), "/hat/blobs/".to_string()));
+    assert_eq!(backend.key([0xab, 0x01]), "hat/blobs/ab01".to_string());
+    assert_eq!(backend.url([0xab]), "s3://bucket/hat/blobs/ab".to_string());
+
+    let backend = S3Backend::new(S3Config::new("bucket".to_string(), "".to_string()));
+    assert_eq!(backend.key([0xab]), "ab".to_string());
+  }
+
+  #[test]
+  fn storage_classes() {
+    let mut config = S3Config::new("bucket".to_string(), "".to_string());
+    assert_eq!(config.storage_class(&blob_store::InfrequentAccess), "STANDARD_IA".to_string());
+    config.storage_classes.push((blob_store::InfrequentAccess, "NEARLINE".to_string()));
+    assert_eq!(config.storage_class(&blob_store::InfrequentAccess), "NEARLINE".to_string());
+    assert_eq!(config.storage_class(&blob_store::ArchiveAccess), "GLACIER_IR".to_string());
+  }
+
+  #[test]
+  fn retention_arguments() {
+    assert_eq!(LockMode::from_name("compliance"), Some(Compliance));
+    assert_eq!(LockMode::from_name("COMPLIANCE"), None);
+    assert_eq!(retention_argument(&Governance, 1412121600),
+               "Mode=GOVERNANCE,RetainUntilDate=2014-10-01T00:00:00Z".to_string());
+  }
+}
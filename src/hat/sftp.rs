@@ -0,0 +1,244 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that keeps blobs in a directory on a remote server, over SFTP.
+//!
+//! Requests go through OpenSSH's `sftp` in batch mode, so the server only needs to allow SFTP
+//! (not a shell), and logins use the usual SSH keys and agent; batch mode cannot ask for a
+//! password. Requests share one connection through an SSH control socket. A request that fails
+//! because the connection broke is a transient error, so that `RetryingBackend` repeats it on a
+//! new connection.
+//!
+//! Blobs are uploaded under a temporary name and renamed into place, so an interrupted upload
+//! never leaves part of a blob under its real name.
+
+use blob_store::{BlobStoreBackend, transient};
+use proxy::{ProxyConfig, PROXY_LOGIN_VARIABLE};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::io::{File, TempDir, UserRead, UserWrite};
+use std::io::fs::{chmod};
+use std::io::process::{Command};
+use std::os;
+use std::str;
+
+
+/// Error messages of `sftp` and `ssh` that mean the connection failed, rather than the request.
+static CONNECTION_ERRORS: &'static [&'static str] = &[
+  "Connection closed", "Connection reset", "Connection timed out", "Connection refused",
+  "Broken pipe", "Could not resolve hostname", "No route to host", "Network is unreachable",
+];
+
+#[deriving(Clone, Show)]
+pub struct SftpConfig {
+  /// The server, as `[user@]host`.
+  pub host: String,
+
+  /// The directory on the server that holds the blobs. Relative paths start at the login
+  /// directory.
+  pub dir: String,
+
+  pub port: Option<u16>,
+
+  /// The private key to log in with (by default, as configured for `ssh`).
+  pub identity: Option<String>,
+
+  /// An HTTP proxy to connect through (see `proxy`).
+  pub proxy: Option<ProxyConfig>,
+}
+
+impl SftpConfig {
+  /// Parse a location of the form `[user@]host:dir`, as for `scp`.
+  pub fn parse(location: &str) -> Option<SftpConfig> {
+    let colon = match location.find(':') { Some(i) => i, None => return None };
+    let host = location.slice_to(colon);
+    let dir = location.slice_from(colon + 1).trim_right_chars('/');
+    // Paths are quoted in the batch commands:
+    if host.len() == 0 || dir.contains("\"") {
+      return None;
+    }
+    Some(SftpConfig{host: host.to_string(),
+                    dir: if dir.len() == 0 { ".".to_string() } else { dir.to_string() },
+                    port: None, identity: None, proxy: None})
+  }
+}
+
+#[deriving(Clone)]
+pub struct SftpBackend {
+  config: SftpConfig,
+}
+
+/// A local copy of a blob, in a directory of its own that only the user can read. Both are
+/// removed again when dropped.
+struct SpoolFile {
+  path: Path,
+  _dir: TempDir,
+}
+
+impl SpoolFile {
+  fn new() -> Result<SpoolFile, String> {
+    let dir = try!(TempDir::new("hat-sftp").map_err(|e| e.to_string()));
+    Ok(SpoolFile{path: dir.path().join("blob"), _dir: dir})
+  }
+
+  fn create(data: &[u8]) -> Result<SpoolFile, String> {
+    let spool = try!(SpoolFile::new());
+    try!(File::create(&spool.path).and_then(|mut f| {
+      try!(chmod(&spool.path, UserRead | UserWrite));
+      f.write(data)
+    }).map_err(|e| e.to_string()));
+    Ok(spool)
+  }
+}
+
+impl SftpBackend {
+  pub fn new(config: SftpConfig) -> SftpBackend {
+    SftpBackend{config: config}
+  }
+
+  fn path(&self, name: &[u8]) -> String {
+    format!("{}/{}", self.config.dir, name.to_hex())
+  }
+
+  /// Run `batch` (sftp commands, one per line) on the server. Returns the standard output.
+  fn sftp(&self, batch: &str) -> Result<Vec<u8>, String> {
+    let socket = format!("ControlPath={}/hat-sftp-%C", os::tmpdir().display());
+    let mut command = Command::new("sftp");
+    command.arg("-q").arg("-b").arg("-")
+           .arg("-o").arg("BatchMode=yes")
+           .arg("-o").arg("ControlMaster=auto")
+           .arg("-o").arg(socket.as_slice())
+           .arg("-o").arg("ControlPersist=60")
+           .arg("-o").arg("ServerAliveInterval=15");
+    match self.config.port {
+      Some(port) => { command.arg("-P").arg(port.to_string()); },
+      None => (),
+    }
+    match self.config.identity {
+      Some(ref identity) => { command.arg("-i").arg(identity.as_slice()); },
+      None => (),
+    }
+    match self.config.proxy {
+      Some(ref proxy) => {
+        command.arg("-o").arg(format!("ProxyCommand={}", proxy.ssh_proxy_command()));
+        match proxy.login() {
+          Some(login) => { command.env(PROXY_LOGIN_VARIABLE, login.as_slice()); },
+          None => (),
+        }
+      },
+      None => (),
+    }
+    command.arg(self.config.host.as_slice());
+
+    let mut process = match command.spawn() {
+      Ok(process) => process,
+      Err(e) => return Err(format!("sftp: {}", e)),
+    };
+    {
+      let mut stdin = process.stdin.take().expect("stdin is piped");
+      match stdin.write(batch.as_bytes()) {
+        Ok(()) => (),
+        Err(e) => return Err(transient(format!("sftp: {}", e))),
+      }
+    }
+    match process.wait_with_output() {
+      Err(e) => Err(transient(format!("sftp: {}", e))),
+      Ok(ref out) if out.status.success() => Ok(out.output.clone()),
+      Ok(out) => {
+        let error = String::from_utf8_lossy(out.error.as_slice()).into_string();
+        let message = format!("sftp failed ({}): {}", out.status, error.as_slice().trim());
+        if CONNECTION_ERRORS.iter().any(|e| error.as_slice().contains(*e)) {
+          Err(transient(message))
+        } else {
+          Err(message)
+        }
+      },
+    }
+  }
+}
+
+impl BlobStoreBackend for SftpBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let spool = try!(SpoolFile::create(data));
+    let path = self.path(name);
+    // A leading '-' lets the batch go on when the directory exists already:
+    self.sftp(format!("-mkdir \"{}\"\nput \"{}\" \"{}.part\"\nrename \"{}.part\" \"{}\"\n",
+                      self.config.dir, spool.path.display(), path, path, path).as_slice())
+      .map(|_| ())
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let spool = try!(SpoolFile::new());
+    try!(self.sftp(format!("get \"{}\" \"{}\"\n", self.path(name),
+                           spool.path.display()).as_slice()));
+    File::open(&spool.path).and_then(|mut f| f.read_to_end()).map_err(|e| e.to_string())
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.sftp(format!("rm \"{}\"\n", self.path(name)).as_slice()).map(|_| ())
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    // The directory does not exist until the first blob is stored:
+    let out = try!(self.sftp(format!("-ls -1 \"{}\"\n", self.config.dir).as_slice()));
+    let text = str::from_utf8(out.as_slice()).unwrap_or("");
+    // Batch mode echoes the commands; uploads in progress end in `.part`, which is not hex.
+    Ok(text.lines().filter(|line| !line.starts_with("sftp>")).filter_map(|line| {
+      let name = match line.trim().rfind('/') {
+        Some(i) => line.trim().slice_from(i + 1),
+        None => line.trim(),
+      };
+      if name.len() == 0 { return None }
+      name.from_hex().ok()
+    }).collect())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{SpoolFile};
+
+  use std::io::{File, UserDir, UserRead, UserWrite};
+  use std::io::fs::{stat};
+
+  #[test]
+  fn spool_files_are_private() {
+    let spool = SpoolFile::create(b"blob").unwrap();
+    assert_eq!(stat(&spool.path).unwrap().perm, UserRead | UserWrite);
+    assert_eq!(stat(&spool.path.dir_path()).unwrap().perm, UserDir);
+    assert_eq!(File::open(&spool.path).read_to_end().unwrap(), b"blob".to_vec());
+
+    let path = spool.path.clone();
+    drop(spool);
+    assert!(!path.dir_path().exists());
+  }
+
+  #[test]
+  fn parse_locations() {
+    let config = SftpConfig::parse("backup@nas.local:/srv/hat/").unwrap();
+    assert_eq!(config.host, "backup@nas.local".to_string());
+    assert_eq!(config.dir, "/srv/hat".to_string());
+
+    assert_eq!(SftpConfig::parse("nas:").unwrap().dir, ".".to_string());
+    assert!(SftpConfig::parse("nas").is_none());
+    assert!(SftpConfig::parse(":blobs").is_none());
+    assert!(SftpConfig::parse("nas:\"quoted\"").is_none());
+  }
+}
@@ -0,0 +1,478 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A streaming reader and writer for tar archives.
+//!
+//! Understands POSIX ustar headers, GNU long names and pax extended paths, which covers the
+//! archives written by GNU tar, bsdtar and `borg export-tar`. Entries are read one at a time, and
+//! the data of the current entry is read through the `Reader` implementation, so an archive can
+//! be consumed straight from a pipe. Archives are written the same way, with GNU long names for
+//! paths that do not fit a ustar header.
+
+use std::io::{IoError, IoResult, Reader, Writer, EndOfFile, InvalidInput, OtherIoError};
+use std::str;
+
+
+static BLOCK_SIZE: uint = 512;
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum EntryKind {
+  RegularFile,
+  Directory,
+  /// Links, devices, FIFOs and other entries without file data.
+  Other,
+}
+
+#[deriving(Clone, Show)]
+pub struct Header {
+  /// The path of the entry, without a leading `./` or `/`, and without a trailing `/`.
+  pub path: Vec<u8>,
+  pub kind: EntryKind,
+  pub size: u64,
+  pub mode: u32,
+  /// Modification time in seconds since the epoch.
+  pub modified: u64,
+}
+
+/// A stream of archive entries, each followed by its data, which is read through the `Reader`
+/// implementation.
+pub trait EntryReader: Reader {
+  /// Skip the rest of the current entry and read the header of the next one. Returns `None` at
+  /// the end of the archive.
+  fn next_header(&mut self) -> IoResult<Option<Header>>;
+}
+
+pub struct TarReader<R> {
+  reader: R,
+  // Data bytes left of the current entry, and the padding that follows them:
+  remaining: u64,
+  padding: u64,
+}
+
+fn invalid(detail: String) -> IoError {
+  IoError{kind: InvalidInput, desc: "Invalid tar archive", detail: Some(detail)}
+}
+
+/// Parse a numeric header field: octal digits terminated by NUL or space, or GNU base-256 for
+/// values that do not fit.
+fn parse_number(field: &[u8]) -> IoResult<u64> {
+  if field.len() > 0 && field[0] & 0x80 != 0 {
+    let mut n = (field[0] & 0x7f) as u64;
+    for &b in field.slice_from(1).iter() {
+      n = (n << 8) | b as u64;
+    }
+    return Ok(n);
+  }
+  let mut n = 0u64;
+  for &b in field.iter().skip_while(|&&b| b == b' ') {
+    if b == 0 || b == b' ' { break }
+    if b < b'0' || b > b'7' {
+      return Err(invalid(format!("bad number {}", String::from_utf8_lossy(field))));
+    }
+    n = n * 8 + (b - b'0') as u64;
+  }
+  Ok(n)
+}
+
+/// The part of `field` before its first NUL byte.
+fn c_string<'a>(field: &'a [u8]) -> &'a [u8] {
+  match field.iter().position(|&b| b == 0) {
+    Some(end) => field.slice_to(end),
+    None => field,
+  }
+}
+
+/// Extract the `path` record from pax extended header data (lines of `LEN key=value\n`).
+fn pax_path(data: &[u8]) -> Option<Vec<u8>> {
+  let mut rest = data;
+  while rest.len() > 0 {
+    let space = match rest.iter().position(|&b| b == b' ') { Some(i) => i, None => return None };
+    let len = match str::from_utf8(rest.slice_to(space)).and_then(|s| from_str::<uint>(s)) {
+      Some(len) if len > space + 1 && len <= rest.len() => len,
+      _ => return None,
+    };
+    let record = rest.slice(space + 1, len - 1);  // Without the trailing newline.
+    if record.starts_with(b"path=") {
+      return Some(record.slice_from(5).into_vec());
+    }
+    rest = rest.slice_from(len);
+  }
+  None
+}
+
+fn clean_path(path: &[u8]) -> Vec<u8> {
+  let mut path = path;
+  loop {
+    if path.starts_with(b"./") { path = path.slice_from(2) }
+    else if path.starts_with(b"/") { path = path.slice_from(1) }
+    else { break }
+  }
+  while path.ends_with(b"/") {
+    path = path.slice_to(path.len() - 1);
+  }
+  if path == b"." { Vec::new() } else { path.into_vec() }
+}
+
+fn padding_of(size: u64) -> u64 {
+  (BLOCK_SIZE as u64 - size % BLOCK_SIZE as u64) % BLOCK_SIZE as u64
+}
+
+impl <R: Reader> TarReader<R> {
+
+  pub fn new(reader: R) -> TarReader<R> {
+    TarReader{reader: reader, remaining: 0, padding: 0}
+  }
+
+  /// Read the data of a meta-data entry, including its padding.
+  fn read_entry_data(&mut self, size: u64) -> IoResult<Vec<u8>> {
+    let data = try!(self.reader.read_exact(size as uint));
+    try!(self.reader.read_exact(padding_of(size) as uint));
+    Ok(data)
+  }
+
+  fn skip_data(&mut self) -> IoResult<()> {
+    let mut left = self.remaining + self.padding;
+    let mut buf = [0u8, ..4096];
+    while left > 0 {
+      let want = if left < buf.len() as u64 { left as uint } else { buf.len() };
+      left -= try!(self.reader.read(buf.slice_to_mut(want))) as u64;
+    }
+    self.remaining = 0;
+    self.padding = 0;
+    Ok(())
+  }
+}
+
+impl <R: Reader> EntryReader for TarReader<R> {
+
+  fn next_header(&mut self) -> IoResult<Option<Header>> {
+    try!(self.skip_data());
+
+    let mut long_path = None;
+    loop {
+      let block = match self.reader.read_exact(BLOCK_SIZE) {
+        Ok(block) => block,
+        // Some writers leave out the end-of-archive blocks:
+        Err(ref e) if e.kind == EndOfFile => return Ok(None),
+        Err(e) => return Err(e),
+      };
+      if block.iter().all(|&b| b == 0) {
+        return Ok(None);
+      }
+
+      let checksum = try!(parse_number(block.slice(148, 156)));
+      let sum = block.iter().enumerate().fold(0u64, |sum, (i, &b)| {
+        sum + if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 }
+      });
+      if sum != checksum {
+        return Err(invalid("header checksum mismatch".to_string()));
+      }
+
+      let size = try!(parse_number(block.slice(124, 136)));
+      let typeflag = block[156];
+      match typeflag {
+        b'L' => {
+          long_path = Some(c_string(try!(self.read_entry_data(size)).as_slice()).into_vec());
+          continue;
+        },
+        b'x' => {
+          let data = try!(self.read_entry_data(size));
+          pax_path(data.as_slice()).map(|path| long_path = Some(path));
+          continue;
+        },
+        b'g' | b'K' => {
+          try!(self.read_entry_data(size));
+          continue;
+        },
+        _ => (),
+      }
+
+      let name = c_string(block.slice(0, 100));
+      let path = match long_path {
+        Some(path) => path,
+        None => {
+          let prefix = c_string(block.slice(345, 500));
+          if block.slice(257, 262) == b"ustar" && prefix.len() > 0 {
+            let mut path = prefix.into_vec();
+            path.push(b'/');
+            path.push_all(name);
+            path
+          } else { name.into_vec() }
+        },
+      };
+      let kind = match typeflag {
+        b'5' => Directory,
+        // Old archives mark directories with a trailing slash only:
+        b'0' | 0 if path.as_slice().ends_with(b"/") => Directory,
+        b'0' | b'7' | 0 => RegularFile,
+        _ => Other,
+      };
+
+      // Links, devices, directories and FIFOs have no data, whatever their size field says:
+      self.remaining = if typeflag >= b'1' && typeflag <= b'6' { 0 } else { size };
+      self.padding = padding_of(self.remaining);
+
+      return Ok(Some(Header{path: clean_path(path.as_slice()),
+                            kind: kind,
+                            size: if kind == RegularFile { size } else { 0 },
+                            mode: try!(parse_number(block.slice(100, 108))) as u32,
+                            modified: try!(parse_number(block.slice(136, 148)))}));
+    }
+  }
+}
+
+/// Reads the data of the current entry.
+impl <R: Reader> Reader for TarReader<R> {
+  fn read(&mut self, buf: &mut [u8]) -> IoResult<uint> {
+    if self.remaining == 0 {
+      return Err(IoError{kind: EndOfFile, desc: "end of tar entry", detail: None});
+    }
+    let want = if (buf.len() as u64) < self.remaining { buf.len() }
+               else { self.remaining as uint };
+    let n = try!(self.reader.read(buf.slice_to_mut(want)));
+    self.remaining -= n as u64;
+    Ok(n)
+  }
+}
+
+
+/// Store `n` in a numeric header field: octal digits and a NUL, or GNU base-256 if it does not
+/// fit.
+fn put_number(field: &mut [u8], n: u64) {
+  let digits = field.len() - 1;
+  if digits * 3 >= 64 || n < 1u64 << (digits * 3) {
+    let text = format!("{:o}", n);
+    let text = "0".repeat(digits - text.len()) + text.as_slice();
+    field.slice_to_mut(digits).copy_from(text.as_bytes());
+    field[digits] = 0;
+  } else {
+    let len = field.len();
+    for i in range(0, len) {
+      let shift = (len - 1 - i) * 8;
+      field[i] = if shift >= 64 { 0 } else { (n >> shift) as u8 };
+    }
+    field[0] |= 0x80;
+  }
+}
+
+/// A ustar header block.
+fn header_block(name: &[u8], typeflag: u8, size: u64, mode: u32, modified: u64) -> Vec<u8> {
+  let mut block = Vec::from_elem(BLOCK_SIZE, 0u8);
+  block.slice_mut(0, name.len()).copy_from(name);
+  put_number(block.slice_mut(100, 108), mode as u64);
+  put_number(block.slice_mut(108, 116), 0);
+  put_number(block.slice_mut(116, 124), 0);
+  put_number(block.slice_mut(124, 136), size);
+  put_number(block.slice_mut(136, 148), modified);
+  *block.get_mut(156) = typeflag;
+  block.slice_mut(257, 265).copy_from(b"ustar\x0000");
+  let sum = block.iter().enumerate().fold(0u64, |sum, (i, &b)| {
+    sum + if i >= 148 && i < 156 { b' ' as u64 } else { b as u64 }
+  });
+  block.slice_mut(148, 156).copy_from(format!("{:06o}\0 ", sum).as_bytes());
+  block
+}
+
+/// Writes a tar archive: each entry is added with `add_dir` or `add_file`, and the data of a file
+/// is written through the `Writer` implementation before the next entry is added.
+pub struct TarWriter<W> {
+  writer: W,
+  // Data bytes left to write of the current file:
+  remaining: u64,
+  padding: u64,
+}
+
+impl <W: Writer> TarWriter<W> {
+
+  pub fn new(writer: W) -> TarWriter<W> {
+    TarWriter{writer: writer, remaining: 0, padding: 0}
+  }
+
+  /// Write the header of an entry, preceded by a GNU long name entry if `path` is too long for
+  /// the header itself.
+  fn write_header(&mut self, path: &[u8], typeflag: u8, size: u64, mode: u32, modified: u64)
+                  -> IoResult<()> {
+    if self.remaining > 0 {
+      return Err(IoError{kind: OtherIoError, desc: "The data of the previous file is incomplete",
+                         detail: None});
+    }
+    try!(self.writer.write(Vec::from_elem(self.padding as uint, 0u8).as_slice()));
+    self.padding = 0;
+    if path.len() > 100 {
+      let mut name = path.into_vec();
+      name.push(0);
+      try!(self.writer.write(header_block(b"././@LongLink", b'L', name.len() as u64, 0, 0)
+                             .as_slice()));
+      name.grow(padding_of(name.len() as u64) as uint, 0);
+      try!(self.writer.write(name.as_slice()));
+    }
+    let name = if path.len() > 100 { path.slice_to(100) } else { path };
+    self.writer.write(header_block(name, typeflag, size, mode, modified).as_slice())
+  }
+
+  pub fn add_dir(&mut self, path: &[u8], mode: u32, modified: u64) -> IoResult<()> {
+    let mut path = path.into_vec();
+    path.push(b'/');
+    self.write_header(path.as_slice(), b'5', 0, mode, modified)
+  }
+
+  /// Add a file of `size` bytes. Exactly that many bytes must be written before the next entry.
+  pub fn add_file(&mut self, path: &[u8], size: u64, mode: u32, modified: u64) -> IoResult<()> {
+    try!(self.write_header(path, b'0', size, mode, modified));
+    self.remaining = size;
+    self.padding = padding_of(size);
+    Ok(())
+  }
+
+  /// End the archive, and return the writer it was written to.
+  pub fn finish(mut self) -> IoResult<W> {
+    if self.remaining > 0 {
+      return Err(IoError{kind: OtherIoError, desc: "The data of the last file is incomplete",
+                         detail: None});
+    }
+    let end = Vec::from_elem(self.padding as uint + 2 * BLOCK_SIZE, 0u8);
+    try!(self.writer.write(end.as_slice()));
+    try!(self.writer.flush());
+    Ok(self.writer)
+  }
+}
+
+/// Writes the data of the current file.
+impl <W: Writer> Writer for TarWriter<W> {
+  fn write(&mut self, buf: &[u8]) -> IoResult<()> {
+    if buf.len() as u64 > self.remaining {
+      return Err(IoError{kind: OtherIoError, desc: "More data than the size of the file",
+                         detail: None});
+    }
+    self.remaining -= buf.len() as u64;
+    self.writer.write(buf)
+  }
+
+  fn flush(&mut self) -> IoResult<()> {
+    self.writer.flush()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use std::io::{MemReader, MemWriter};
+
+  fn header(path: &str, typeflag: u8, data: &[u8]) -> Vec<u8> {
+    let mut block = Vec::from_elem(512, 0u8);
+    block.slice_mut(0, path.len()).copy_from(path.as_bytes());
+    block.slice_mut(100, 107).copy_from(b"0000644");
+    block.slice_mut(124, 135).copy_from(format!("{:011o}", data.len()).as_bytes());
+    block.slice_mut(136, 147).copy_from(b"12345670123");
+    *block.get_mut(156) = typeflag;
+    block.slice_mut(257, 263).copy_from(b"ustar\0");
+    let sum = block.iter().enumerate().fold(0u, |sum, (i, &b)| {
+      sum + if i >= 148 && i < 156 { b' ' as uint } else { b as uint }
+    });
+    block.slice_mut(148, 155).copy_from(format!("{:06o}\0", sum).as_bytes());
+
+    block.push_all(data);
+    let padding = (512 - data.len() % 512) % 512;
+    block.grow(padding, 0);
+    block
+  }
+
+  #[test]
+  fn entries_and_data() {
+    let long_name = "d/".to_string() + "x".repeat(150).as_slice();
+    let mut archive = Vec::new();
+    archive.push_all(header("./d/", b'5', b"").as_slice());
+    archive.push_all(header("d/a", b'0', b"hello").as_slice());
+    archive.push_all(header("././@LongLink", b'L', (long_name.clone() + "\0").as_bytes())
+                     .as_slice());
+    archive.push_all(header("d/xxxx", b'0', Vec::from_elem(1000, 7u8).as_slice()).as_slice());
+    archive.push_all(header("d/link", b'2', b"").as_slice());
+    archive.push_all(Vec::from_elem(1024, 0u8).as_slice());
+
+    let mut tar = TarReader::new(MemReader::new(archive));
+
+    let dir = tar.next_header().unwrap().unwrap();
+    assert_eq!(dir.path, b"d".into_vec());
+    assert_eq!(dir.kind, Directory);
+    assert_eq!(dir.modified, 0o12345670123);
+
+    let file = tar.next_header().unwrap().unwrap();
+    assert_eq!(file.path, b"d/a".into_vec());
+    assert_eq!(file.kind, RegularFile);
+    assert_eq!(file.mode, 0o644);
+    assert_eq!(tar.read_to_end().unwrap(), b"hello".into_vec());
+
+    // The data of this entry is skipped:
+    let long = tar.next_header().unwrap().unwrap();
+    assert_eq!(long.path, long_name.into_bytes());
+    assert_eq!(long.size, 1000);
+
+    let link = tar.next_header().unwrap().unwrap();
+    assert_eq!(link.kind, Other);
+
+    assert!(tar.next_header().unwrap().is_none());
+  }
+
+  #[test]
+  fn pax_paths() {
+    let record = "30 path=a/very/long/path/name\n";
+    assert_eq!(record.len(), 30);
+    let mut archive = header("PaxHeaders/x", b'x', record.as_bytes());
+    archive.push_all(header("x", b'0', b"").as_slice());
+
+    let mut tar = TarReader::new(MemReader::new(archive));
+    assert_eq!(tar.next_header().unwrap().unwrap().path, b"a/very/long/path/name".into_vec());
+    assert!(tar.next_header().unwrap().is_none());
+  }
+
+  #[test]
+  fn written_archives_read_back() {
+    let long_name = "d/".to_string() + "x".repeat(150).as_slice();
+    let mut tar = TarWriter::new(MemWriter::new());
+    tar.add_dir(b"d", 0o755, 1412164800).unwrap();
+    tar.add_file(b"d/a", 5, 0o644, 1412164800).unwrap();
+    tar.write(b"hello").unwrap();
+    tar.add_file(long_name.as_bytes(), 1000, 0o600, 0).unwrap();
+    tar.write(Vec::from_elem(1000, 7u8).as_slice()).unwrap();
+    let archive = tar.finish().unwrap().unwrap();
+    assert_eq!(archive.len() % 512, 0);
+
+    let mut tar = TarReader::new(MemReader::new(archive));
+    let dir = tar.next_header().unwrap().unwrap();
+    assert_eq!((dir.path, dir.kind, dir.mode), (b"d".into_vec(), Directory, 0o755));
+    let file = tar.next_header().unwrap().unwrap();
+    assert_eq!((file.path, file.modified), (b"d/a".into_vec(), 1412164800));
+    assert_eq!(tar.read_to_end().unwrap(), b"hello".into_vec());
+    let long = tar.next_header().unwrap().unwrap();
+    assert_eq!(long.path, long_name.into_bytes());
+    assert_eq!(tar.read_to_end().unwrap(), Vec::from_elem(1000, 7u8));
+    assert!(tar.next_header().unwrap().is_none());
+  }
+
+  #[test]
+  fn file_sizes_are_enforced() {
+    let mut tar = TarWriter::new(MemWriter::new());
+    tar.add_file(b"a", 2, 0o644, 0).unwrap();
+    assert!(tar.write(b"abc").is_err());
+    assert!(tar.add_dir(b"d", 0o755, 0).is_err());
+  }
+
+  #[test]
+  fn corrupt_header() {
+    let mut archive = header("a", b'0', b"");
+    *archive.get_mut(0) = b'b';
+    assert!(TarReader::new(MemReader::new(archive)).next_header().is_err());
+  }
+}
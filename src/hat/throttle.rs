@@ -0,0 +1,194 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that caps the bandwidth used by another backend, e.g. so that a nightly
+//! snapshot does not saturate a home uplink.
+//!
+//! Blobs are transferred whole by the wrapped backend, at whatever speed it manages. After each
+//! transfer, the caller waits until the transfer's bytes fit the configured rate. The limits are
+//! shared between all clones of the backend, so parallel uploads share one budget.
+
+use blob_store::{BackendProbe, BlobStoreBackend, StorageHint};
+
+use std::cmp;
+use std::io::timer;
+use std::sync::{Arc, Mutex};
+use std::time::duration::{Duration};
+
+use time;
+
+
+static NANOSECONDS: u64 = 1000000000;
+
+/// Transfers in one direction, paced to `rate` bytes per second.
+struct Limiter {
+  rate: u64,
+
+  // When the transfers accounted for so far would have completed at `rate`, in nanoseconds (see
+  // `time::precise_time_ns`):
+  next: u64,
+}
+
+impl Limiter {
+  fn new(rate: u64) -> Limiter {
+    Limiter{rate: cmp::max(1, rate), next: 0}
+  }
+
+  /// Account for a transfer of `bytes` that started at `started` and completed at `now`. Returns
+  /// how many nanoseconds to wait before the next transfer.
+  fn wait(&mut self, started: u64, now: u64, bytes: uint) -> u64 {
+    // Time that passed without transfers is not saved up for later:
+    let start = cmp::max(self.next, started);
+    self.next = start + bytes as u64 * NANOSECONDS / self.rate;
+    if self.next > now { self.next - now } else { 0 }
+  }
+}
+
+fn pace(limiter: &Option<Arc<Mutex<Limiter>>>, started: u64, bytes: uint) {
+  let wait = match *limiter {
+    Some(ref limiter) => limiter.lock().wait(started, time::precise_time_ns(), bytes),
+    None => return,
+  };
+  if wait > 0 {
+    // Round up, so that the rate is never exceeded:
+    let milliseconds = (wait + 999999) / 1000000;
+    timer::sleep(Duration::milliseconds(milliseconds as i64));
+  }
+}
+
+#[deriving(Clone)]
+pub struct ThrottledBackend<B> {
+  backend: B,
+  upload: Option<Arc<Mutex<Limiter>>>,
+  download: Option<Arc<Mutex<Limiter>>>,
+}
+
+impl <B: BlobStoreBackend> ThrottledBackend<B> {
+  /// Wrap `backend`, limiting uploads and downloads to the given number of bytes per second.
+  /// Directions without a limit are not throttled.
+  pub fn new(backend: B, upload: Option<u64>, download: Option<u64>) -> ThrottledBackend<B> {
+    ThrottledBackend{backend: backend,
+                     upload: upload.map(|rate| Arc::new(Mutex::new(Limiter::new(rate)))),
+                     download: download.map(|rate| Arc::new(Mutex::new(Limiter::new(rate))))}
+  }
+}
+
+impl <B: BlobStoreBackend> BlobStoreBackend for ThrottledBackend<B> {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let started = time::precise_time_ns();
+    let result = self.backend.store(name, data);
+    pace(&self.upload, started, data.len());
+    result
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let started = time::precise_time_ns();
+    let result = self.backend.retrieve(name);
+    pace(&self.download, started, result.as_ref().map(|data| data.len()).unwrap_or(0));
+    result
+  }
+
+  fn store_with_hint(&mut self, name: &[u8], data: &[u8], hint: StorageHint)
+                     -> Result<(), String> {
+    let started = time::precise_time_ns();
+    let result = self.backend.store_with_hint(name, data, hint);
+    pace(&self.upload, started, data.len());
+    result
+  }
+
+  fn set_storage_hint(&mut self, name: &[u8], hint: StorageHint) -> Result<(), String> {
+    self.backend.set_storage_hint(name, hint)
+  }
+
+  fn supports_resume(&self) -> bool { self.backend.supports_resume() }
+
+  fn stored_length(&mut self, name: &[u8]) -> Result<uint, String> {
+    self.backend.stored_length(name)
+  }
+
+  fn append(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let started = time::precise_time_ns();
+    let result = self.backend.append(name, data);
+    pace(&self.upload, started, data.len());
+    result
+  }
+
+  fn finish_append(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.finish_append(name)
+  }
+
+  fn stored_checksum(&mut self, name: &[u8]) -> Result<Option<Vec<u8>>, String> {
+    self.backend.stored_checksum(name)
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    self.backend.delete(name)
+  }
+
+  fn supports_retention(&self) -> bool { self.backend.supports_retention() }
+
+  fn set_retention(&mut self, name: &[u8], until: u64) -> Result<(), String> {
+    self.backend.set_retention(name, until)
+  }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    self.backend.list()
+  }
+
+  fn supports_listing(&self) -> bool { self.backend.supports_listing() }
+
+  fn probe(&mut self) -> Result<BackendProbe, String> {
+    self.backend.probe()
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{Limiter};
+
+  use blob_store::{BlobStoreBackend, MemoryBackend};
+
+  use time;
+
+  static MS: u64 = 1000000;
+
+  #[test]
+  fn limiter_paces_transfers() {
+    // 1000 bytes per second is a millisecond per byte:
+    let mut limiter = Limiter::new(1000);
+    // A transfer that was faster than the rate waits for the rest of its time:
+    assert_eq!(limiter.wait(1000 * MS, 1010 * MS, 100), 90 * MS);
+    // The next one, started right away, queues behind it:
+    assert_eq!(limiter.wait(1010 * MS, 1020 * MS, 100), 180 * MS);
+    // One that took longer than its time does not wait:
+    assert_eq!(limiter.wait(5000 * MS, 5500 * MS, 100), 0);
+  }
+
+  #[test]
+  fn throttled_store_and_retrieve() {
+    let memory = MemoryBackend::new();
+    let mut backend = ThrottledBackend::new(memory.clone(), Some(100000), None);
+    let started = time::precise_time_ns();
+    for name in ["a", "b", "c"].iter() {
+      backend.store(name.as_bytes(), Vec::from_elem(10000, 1u8).as_slice()).unwrap();
+    }
+    // 30000 bytes at 100000 bytes per second:
+    assert!(time::precise_time_ns() - started >= 300 * MS);
+    assert_eq!(memory.len(), 3);
+    assert_eq!(backend.retrieve(b"b").unwrap(), Vec::from_elem(10000, 1u8));
+  }
+}
@@ -0,0 +1,102 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Points in time that select a snapshot, e.g. "the state as of 02:00" for a restore.
+
+use time;
+use time::{Tm};
+
+
+static FORMATS: &'static [&'static str] = &["%Y-%m-%d %H:%M:%S", "%Y-%m-%d %H:%M", "%Y-%m-%d"];
+
+static DAY: i64 = 24 * 60 * 60;
+
+/// Parse `text` as a point in time, in seconds since the epoch. It is one of:
+///
+/// * `@SECONDS`, seconds since the epoch;
+/// * a date, optionally with a time of day: `2014-10-01`, `2014-10-01 02:00` or
+///   `2014-10-01T02:00:30`;
+/// * a time of day alone, `02:00` or `02:00:30`, for the last time it was that time up to `now`.
+///
+/// Dates and times are in the time zone of `now`, or in UTC if they end in `Z`.
+pub fn parse(text: &str, now: &Tm) -> Option<u64> {
+  let text = text.trim();
+  if text.starts_with("@") {
+    return from_str::<u64>(text.slice_from(1));
+  }
+  let (text, utc) = if text.ends_with("Z") { (text.slice_to(text.len() - 1), true) }
+                    else { (text, false) };
+  let time_only = !text.contains("-");
+  let full = if time_only {
+    format!("{:04}-{:02}-{:02} {}", now.tm_year + 1900, now.tm_mon + 1, now.tm_mday, text)
+  } else {
+    text.replace("T", " ")
+  };
+
+  let mut tm = match FORMATS.iter().filter_map(|f| time::strptime(full.as_slice(), *f).ok())
+                                   .next() {
+    Some(tm) => tm,
+    None => return None,
+  };
+  // A non-zero offset makes `to_timespec` use the local time zone, including its daylight saving:
+  tm.tm_gmtoff = if utc { 0 } else { now.tm_gmtoff };
+  tm.tm_isdst = -1;
+  let mut seconds = tm.to_timespec().sec;
+  if time_only && seconds > now.to_timespec().sec {
+    seconds -= DAY;
+  }
+  if seconds < 0 { None } else { Some(seconds as u64) }
+}
+
+/// The ID of the newest of `snapshots` (IDs and start times) that started before `time`, or at
+/// `time` if `inclusive`. Snapshots without a start time are never selected.
+pub fn select(snapshots: &[(i64, u64)], time: u64, inclusive: bool) -> Option<i64> {
+  snapshots.iter().filter(|&&(_, started)| {
+    started > 0 && (started < time || (inclusive && started == time))
+  }).max_by(|&&(id, started)| (started, id)).map(|&(id, _)| id)
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use time;
+
+  #[test]
+  fn parse_points_in_time() {
+    // 2014-10-01 12:00:00 UTC:
+    let now = time::at_utc(time::Timespec::new(1412164800, 0));
+    assert_eq!(parse("@1412164800", &now), Some(1412164800));
+    assert_eq!(parse("2014-10-01", &now), Some(1412121600));
+    assert_eq!(parse("2014-10-01 02:00", &now), Some(1412128800));
+    assert_eq!(parse("2014-10-01T02:00:30Z", &now), Some(1412128830));
+
+    // Times of day are today, unless that is still to come:
+    assert_eq!(parse("02:00", &now), Some(1412128800));
+    assert_eq!(parse("14:00", &now), Some(1412128800 - 24 * 3600 + 12 * 3600));
+
+    assert_eq!(parse("yesterday", &now), None);
+    assert_eq!(parse("2014-10-01 02", &now), None);
+  }
+
+  #[test]
+  fn select_newest_before() {
+    let snapshots = [(1, 0), (2, 100), (3, 200), (4, 300)];
+    assert_eq!(select(snapshots.as_slice(), 200, true), Some(3));
+    assert_eq!(select(snapshots.as_slice(), 200, false), Some(2));
+    assert_eq!(select(snapshots.as_slice(), 1000, true), Some(4));
+    assert_eq!(select(snapshots.as_slice(), 50, true), None);
+  }
+}
@@ -0,0 +1,178 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! Frozen views of a source volume through btrfs or LVM snapshots.
+//!
+//! A `VolumeSnapshot` is created before a backup starts and torn down again when it is dropped, so
+//! that databases and busy trees are read from a consistent point-in-time view.
+
+use serialize::hex::{ToHex};
+use sodiumoxide::randombytes::{randombytes};
+
+use std::io::{UserDir};
+use std::io::fs::{mkdir, rmdir};
+use std::io::process::{Command};
+use std::os;
+
+
+#[deriving(Clone, Show)]
+pub enum VolumeSnapshotKind {
+  /// The source directory is a btrfs subvolume; take a read-only snapshot of it.
+  Btrfs,
+
+  /// The source directory is the mount point of the given LVM logical volume (`VG/LV`).
+  Lvm(String),
+}
+
+impl VolumeSnapshotKind {
+  /// Parse `btrfs` or `lvm:VG/LV`.
+  pub fn from_str(s: &str) -> Option<VolumeSnapshotKind> {
+    if s == "btrfs" {
+      Some(Btrfs)
+    } else if s.starts_with("lvm:") && s.len() > 4 {
+      Some(Lvm(s.slice_from(4).to_string()))
+    } else {
+      None
+    }
+  }
+}
+
+
+pub struct VolumeSnapshot {
+  kind: VolumeSnapshotKind,
+  view: Path,
+  lvm_snapshot: Option<String>,
+}
+
+fn run(program: &str, args: &[String]) -> Result<(), String> {
+  match Command::new(program).args(args).output() {
+    Err(e) => Err(format!("{}: {}", program, e.to_string())),
+    Ok(ref out) if out.status.success() => Ok(()),
+    Ok(out) => Err(format!("{} failed ({}): {}", program, out.status,
+                           String::from_utf8_lossy(out.error.as_slice()))),
+  }
+}
+
+impl VolumeSnapshot {
+
+  /// Freeze `source` according to `kind`. The frozen view is available through `path()`.
+  pub fn create(kind: VolumeSnapshotKind, source: &Path) -> Result<VolumeSnapshot, String> {
+    let tag = format!("hat-snapshot-{}", randombytes(8).as_slice().to_hex());
+
+    match kind {
+      Btrfs => {
+        // btrfs snapshots must live on the same filesystem as their source. Nested subvolumes
+        // (like this snapshot) are not part of a snapshot, so it cannot include itself.
+        let mut view = source.clone();
+        view.push(tag.as_slice());
+        try!(run("btrfs", [
+          "subvolume".to_string(), "snapshot".to_string(), "-r".to_string(),
+          source.display().to_string(), view.display().to_string()]));
+        Ok(VolumeSnapshot{kind: Btrfs, view: view, lvm_snapshot: None})
+      },
+
+      Lvm(ref volume) => {
+        let vg = match volume.as_slice().split('/').next() {
+          Some(vg) if vg.len() > 0 && vg.len() < volume.len() => vg.to_string(),
+          _ => return Err(format!("Invalid LVM volume '{}', expected VG/LV.", volume)),
+        };
+        let lvm_snapshot = format!("{}/{}", vg, tag);
+
+        try!(run("lvcreate", [
+          "--snapshot".to_string(), "--extents".to_string(), "10%ORIGIN".to_string(),
+          "--name".to_string(), tag.clone(), volume.clone()]));
+
+        let mut view = os::tmpdir();
+        view.push(tag.as_slice());
+        let mounted = mkdir(&view, UserDir).map_err(|e| e.to_string()).and_then(|()| {
+          run("mount", ["-o".to_string(), "ro".to_string(),
+                        format!("/dev/{}", lvm_snapshot), view.display().to_string()])
+        });
+
+        let snapshot = VolumeSnapshot{kind: kind.clone(), view: view,
+                                      lvm_snapshot: Some(lvm_snapshot)};
+        match mounted {
+          Ok(()) => Ok(snapshot),
+          // Dropping `snapshot` removes the logical volume again.
+          Err(e) => Err(e),
+        }
+      },
+    }
+  }
+
+  /// The frozen view of the source directory.
+  pub fn path<'a>(&'a self) -> &'a Path {
+    &self.view
+  }
+
+  fn teardown(&mut self) -> Result<(), String> {
+    match self.kind {
+      Btrfs => {
+        run("btrfs", ["subvolume".to_string(), "delete".to_string(),
+                      self.view.display().to_string()])
+      },
+      Lvm(_) => {
+        // The mount may have failed; unmounting is best-effort before removing the volume.
+        let _ = run("umount", [self.view.display().to_string()]);
+        let _ = rmdir(&self.view);
+        match self.lvm_snapshot {
+          Some(ref lv) => run("lvremove", ["--force".to_string(), lv.clone()]),
+          None => Ok(()),
+        }
+      },
+    }
+  }
+}
+
+impl Drop for VolumeSnapshot {
+  fn drop(&mut self) {
+    match self.teardown() {
+      Ok(()) => (),
+      Err(e) => warning!("Warning: Could not remove volume snapshot '{}': {}",
+                         self.view.display(), e),
+    }
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+  use super::{run};
+
+  #[test]
+  fn kinds() {
+    assert!(match VolumeSnapshotKind::from_str("btrfs") { Some(Btrfs) => true, _ => false });
+    assert!(match VolumeSnapshotKind::from_str("lvm:vg0/home") {
+      Some(Lvm(ref volume)) => volume.as_slice() == "vg0/home",
+      _ => false,
+    });
+    assert!(VolumeSnapshotKind::from_str("lvm:").is_none());
+    assert!(VolumeSnapshotKind::from_str("zfs").is_none());
+  }
+
+  #[test]
+  fn invalid_lvm_volume() {
+    // Rejected before any logical volume is created:
+    let error = VolumeSnapshot::create(Lvm("home".to_string()), &Path::new("/home")).err();
+    assert_eq!(error, Some("Invalid LVM volume 'home', expected VG/LV.".to_string()));
+  }
+
+  #[test]
+  fn failed_commands() {
+    assert_eq!(run("true", []), Ok(()));
+    let error = run("sh", ["-c".to_string(), "echo no such volume >&2; exit 5".to_string()]);
+    assert!(error.unwrap_err().as_slice().contains("no such volume"));
+  }
+}
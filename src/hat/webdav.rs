@@ -0,0 +1,225 @@
+// Copyright 2014 Google Inc. All rights reserved.
+//
+// Licensed under the Apache License, Version 2.0 (the "License");
+// you may not use this file except in compliance with the License.
+// You may obtain a copy of the License at
+//
+//     http://www.apache.org/licenses/LICENSE-2.0
+//
+// Unless required by applicable law or agreed to in writing, software
+// distributed under the License is distributed on an "AS IS" BASIS,
+// WITHOUT WARRANTIES OR CONDITIONS OF ANY KIND, either express or implied.
+// See the License for the specific language governing permissions and
+// limitations under the License.
+
+//! A blob store backend that keeps blobs in a collection on a WebDAV server, such as a
+//! Nextcloud or ownCloud share.
+//!
+//! Requests go through `curl` (see `curl`), which keeps the login out of the process list.
+//! Servers that need more than a login can be given an auth hook (see `curl::RequestAuth`).
+//! Blobs are uploaded under a temporary name and moved into place, so an interrupted upload never
+//! leaves part of a blob under its real name. Uploads can use chunked transfer encoding, for
+//! servers and proxies that limit the declared size of a request body.
+
+use blob_store::{BlobStoreBackend, transient};
+use curl;
+use curl::{AuthHook, SpoolFile};
+use proxy::{ProxyConfig};
+
+use serialize::hex::{FromHex, ToHex};
+
+use std::str;
+
+
+/// Lists the members of a collection, without asking for their properties.
+static PROPFIND_BODY: &'static str =
+  "<?xml version=\"1.0\"?><propfind xmlns=\"DAV:\"><prop><resourcetype/></prop></propfind>";
+
+#[deriving(Clone, Show, PartialEq)]
+pub enum WebDavAuth {
+  BasicAuth,
+  DigestAuth,
+}
+
+#[deriving(Clone, Show)]
+pub struct WebDavConfig {
+  /// The URL of the collection that holds the blobs, e.g.
+  /// `https://cloud.example.com/remote.php/dav/files/USER/hat`.
+  pub url: String,
+
+  /// A user name and password to log in with.
+  pub credentials: Option<(String, String)>,
+
+  pub auth: WebDavAuth,
+
+  /// Upload blobs with chunked transfer encoding rather than with a declared length.
+  pub chunked: bool,
+
+  /// A hook that authenticates each request, in addition to the login.
+  pub auth_hook: Option<AuthHook>,
+
+  /// A proxy to send requests through, instead of the one in the environment (if any).
+  pub proxy: Option<ProxyConfig>,
+}
+
+impl WebDavConfig {
+  pub fn new(url: String) -> WebDavConfig {
+    WebDavConfig{url: url, credentials: None, auth: BasicAuth, chunked: false, auth_hook: None,
+                 proxy: None}
+  }
+}
+
+#[deriving(Clone)]
+pub struct WebDavBackend {
+  config: WebDavConfig,
+}
+
+impl WebDavBackend {
+  pub fn new(config: WebDavConfig) -> WebDavBackend {
+    let mut config = config;
+    config.url = config.url.as_slice().trim_right_chars('/').to_string();
+    WebDavBackend{config: config}
+  }
+
+  fn url(&self, name: &str) -> String {
+    format!("{}/{}", self.config.url, name)
+  }
+
+  /// The `curl` options of a request: the proxy, the login and those of the auth hook, followed
+  /// by `extra`.
+  fn options(&self, method: &str, url: &str, extra: &[String]) -> Result<Vec<String>, String> {
+    let mut options = self.config.proxy.as_ref().map(|proxy| proxy.curl_options())
+                                                .unwrap_or(vec![]);
+    match self.config.credentials {
+      Some((ref user, ref password)) => {
+        options.push(format!("user = {}",
+                             curl::quote(format!("{}:{}", user, password).as_slice())));
+        options.push(match self.config.auth { BasicAuth => "basic", DigestAuth => "digest" }
+                     .to_string());
+      },
+      None => (),
+    }
+    match self.config.auth_hook {
+      Some(ref hook) => options.push_all(try!(hook.options(method, url)).as_slice()),
+      None => (),
+    }
+    options.push_all(extra);
+    Ok(options)
+  }
+
+  /// Send a request. Returns the HTTP status and the response body.
+  fn request(&self, method: &str, url: &str, extra: &[String])
+             -> Result<(uint, Vec<u8>), String> {
+    let options = try!(self.options(method, url, extra));
+    curl::request(method, url, options.as_slice())
+  }
+
+  /// Send a request, and fail unless its status is 2xx. Returns the response body.
+  fn send(&self, method: &str, url: &str, extra: &[String]) -> Result<Vec<u8>, String> {
+    let options = try!(self.options(method, url, extra));
+    curl::expect_success(method, url, options.as_slice())
+  }
+
+  fn upload(&self, url: &str, spool: &SpoolFile) -> Result<uint, String> {
+    let mut extra = vec![spool.upload_option()];
+    if self.config.chunked {
+      extra.push(curl::header("Transfer-Encoding", "chunked"));
+    }
+    self.request("PUT", url, extra.as_slice()).map(|(status, _)| status)
+  }
+}
+
+/// The names of the members of a collection, from the body of a PROPFIND response. Members that
+/// are collections themselves (including the listed collection) end in `/` and are left out.
+fn member_names(response: &str) -> Vec<String> {
+  // Elements may have any namespace prefix, e.g. `<d:href>` or `<D:href>`:
+  response.split('<').filter_map(|element| {
+    let close = match element.find('>') { Some(i) => i, None => return None };
+    let tag = element.slice_to(close);
+    if tag != "href" && !(tag.ends_with(":href") && !tag.starts_with("/")) {
+      return None;
+    }
+    let href = element.slice_from(close + 1).trim();
+    if href.len() == 0 || href.ends_with("/") {
+      return None;
+    }
+    let name = match href.rfind('/') { Some(i) => href.slice_from(i + 1), None => href };
+    Some(name.to_string())
+  }).collect()
+}
+
+impl BlobStoreBackend for WebDavBackend {
+
+  fn store(&mut self, name: &[u8], data: &[u8]) -> Result<(), String> {
+    let spool = try!(SpoolFile::create(data));
+    let part = self.url(format!("{}.part", name.to_hex()).as_slice());
+    let mut status = try!(self.upload(part.as_slice(), &spool));
+    if status == 404 || status == 409 {
+      // The collection does not exist until the first blob is stored:
+      try!(self.send("MKCOL", self.config.url.as_slice(), []));
+      status = try!(self.upload(part.as_slice(), &spool));
+    }
+    if status < 200 || status >= 300 {
+      let message = format!("PUT {} failed: HTTP status {}", part, status);
+      return Err(if curl::is_transient_status(status) { transient(message) } else { message });
+    }
+    let destination = self.url(name.to_hex().as_slice());
+    let extra = [curl::header("Destination", destination.as_slice()),
+                 curl::header("Overwrite", "T")];
+    self.send("MOVE", part.as_slice(), extra.as_slice()).map(|_| ())
+  }
+
+  fn retrieve(&mut self, name: &[u8]) -> Result<Vec<u8>, String> {
+    let url = self.url(name.to_hex().as_slice());
+    self.send("GET", url.as_slice(), [])
+  }
+
+  fn delete(&mut self, name: &[u8]) -> Result<(), String> {
+    let url = self.url(name.to_hex().as_slice());
+    self.send("DELETE", url.as_slice(), []).map(|_| ())
+  }
+
+  fn supports_listing(&self) -> bool { true }
+
+  fn list(&mut self) -> Result<Vec<Vec<u8>>, String> {
+    let url = format!("{}/", self.config.url);
+    let extra = [format!("data = {}", curl::quote(PROPFIND_BODY)), curl::header("Depth", "1"),
+                 curl::header("Content-Type", "application/xml")];
+    let (status, body) = try!(self.request("PROPFIND", url.as_slice(), extra.as_slice()));
+    if status == 404 {
+      // The collection does not exist until the first blob is stored:
+      return Ok(vec![]);
+    } else if status < 200 || status >= 300 {
+      let message = format!("PROPFIND {} failed: HTTP status {}", url, status);
+      return Err(if curl::is_transient_status(status) { transient(message) } else { message });
+    }
+    let text = str::from_utf8(body.as_slice()).unwrap_or("");
+    // Uploads in progress end in `.part`, which is not hex:
+    Ok(member_names(text).into_iter().filter_map(|name| {
+      if name.len() == 0 { return None }
+      name.as_slice().from_hex().ok()
+    }).collect())
+  }
+}
+
+
+#[cfg(test)]
+mod tests {
+  use super::{member_names};
+
+  #[test]
+  fn propfind_members() {
+    let response = "<?xml version=\"1.0\"?>\
+      <d:multistatus xmlns:d=\"DAV:\">\
+        <d:response><d:href>/dav/hat/</d:href><d:propstat/></d:response>\
+        <d:response><d:href>/dav/hat/ab01</d:href><d:propstat/></d:response>\
+        <d:response><d:href>/dav/hat/ff.part</d:href><d:propstat/></d:response>\
+        <d:response><d:href>/dav/hat/sub/</d:href><d:propstat/></d:response>\
+      </d:multistatus>";
+    assert_eq!(member_names(response), vec!["ab01".to_string(), "ff.part".to_string()]);
+
+    let response = "<D:multistatus xmlns:D=\"DAV:\"><D:response>\
+      <D:href>https://dav.example.com/hat/0a</D:href></D:response></D:multistatus>";
+    assert_eq!(member_names(response), vec!["0a".to_string()]);
+  }
+}